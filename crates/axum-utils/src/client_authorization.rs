@@ -22,18 +22,13 @@ use axum::{
         Form, FromRequest, RequestParts, TypedHeader,
     },
     response::IntoResponse,
-    BoxError,
 };
 use headers::{authorization::Basic, Authorization};
 use http::StatusCode;
 use mas_config::Encrypter;
-use mas_data_model::{Client, JwksOrJwksUri, StorageBackend};
-use mas_http::HttpServiceExt;
+use mas_data_model::{Client, StorageBackend};
 use mas_iana::oauth::OAuthClientAuthenticationMethod;
-use mas_jose::{
-    DecodedJsonWebToken, DynamicJwksStore, Either, JsonWebKeySet, JsonWebTokenParts, JwtHeader,
-    SharedSecret, StaticJwksStore, VerifyingKeystore,
-};
+use mas_jose::{DecodedJsonWebToken, JsonWebTokenParts, JwtHeader, SharedSecret, StaticJwksStore};
 use mas_storage::{
     oauth2::client::{lookup_client_by_client_id, ClientFetchError},
     PostgresqlBackend,
@@ -42,7 +37,8 @@ use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::Value;
 use sqlx::PgExecutor;
 use thiserror::Error;
-use tower::ServiceExt;
+
+use crate::client_jwks::{ClientJwksCache, ClientJwksError};
 
 static JWT_BEARER_CLIENT_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
 
@@ -97,6 +93,7 @@ impl Credentials {
     pub async fn verify<S: StorageBackend>(
         &self,
         encrypter: &Encrypter,
+        jwks_cache: &ClientJwksCache,
         method: OAuthClientAuthenticationMethod,
         client: &Client<S>,
     ) -> Result<(), CredentialsVerificationError> {
@@ -137,10 +134,15 @@ impl Credentials {
                     .as_ref()
                     .ok_or(CredentialsVerificationError::InvalidClientConfig)?;
 
-                let store: Either<StaticJwksStore, DynamicJwksStore> = jwks_key_store(jwks);
+                let store = StaticJwksStore::new(jwks_cache.get(jwks, false).await?);
                 let fut = jwt.verify(header, &store);
-                fut.await
-                    .map_err(|_| CredentialsVerificationError::InvalidAssertionSignature)?;
+                if fut.await.is_err() {
+                    // The client might have rotated its keys, try again with a fresh key set
+                    let store = StaticJwksStore::new(jwks_cache.get(jwks, true).await?);
+                    let fut = jwt.verify(header, &store);
+                    fut.await
+                        .map_err(|_| CredentialsVerificationError::InvalidAssertionSignature)?;
+                }
             }
 
             (
@@ -171,39 +173,6 @@ impl Credentials {
     }
 }
 
-fn jwks_key_store(jwks: &JwksOrJwksUri) -> Either<StaticJwksStore, DynamicJwksStore> {
-    // Assert that the output is both a VerifyingKeystore and Send
-    fn assert<T: Send + VerifyingKeystore>(t: T) -> T {
-        t
-    }
-
-    let inner = match jwks {
-        JwksOrJwksUri::Jwks(jwks) => Either::Left(StaticJwksStore::new(jwks.clone())),
-        JwksOrJwksUri::JwksUri(uri) => {
-            let uri = uri.clone();
-
-            // TODO: get the client from somewhere else?
-            let exporter = mas_http::client("fetch-jwks")
-                .json::<JsonWebKeySet>()
-                .map_request(move |_: ()| {
-                    http::Request::builder()
-                        .method("GET")
-                        // TODO: change the Uri type in config to avoid reparsing here
-                        .uri(uri.to_string())
-                        .body(http_body::Empty::new())
-                        .unwrap()
-                })
-                .map_response(http::Response::into_body)
-                .map_err(BoxError::from)
-                .boxed_clone();
-
-            Either::Right(DynamicJwksStore::new(exporter))
-        }
-    };
-
-    assert(inner)
-}
-
 #[derive(Debug, Error)]
 pub enum CredentialsVerificationError {
     #[error("failed to decrypt client credentials")]
@@ -220,6 +189,9 @@ pub enum CredentialsVerificationError {
 
     #[error("invalid assertion signature")]
     InvalidAssertionSignature,
+
+    #[error("failed to fetch the client keys")]
+    Jwks(#[from] ClientJwksError),
 }
 
 #[derive(Debug, PartialEq, Eq)]
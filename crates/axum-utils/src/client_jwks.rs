@@ -0,0 +1,176 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fetch the key sets clients publish on their `jwks_uri`.
+//!
+//! Key sets are cached per URI so that client authentication and response
+//! encryption don't hit the client on every request, and the URIs are only
+//! fetched over HTTPS from public addresses. The addresses are checked when
+//! connecting, so that the name can't resolve somewhere else in the meantime.
+
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use mas_data_model::JwksOrJwksUri;
+use mas_http::HttpServiceExt;
+use mas_jose::JsonWebKeySet;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tower::{BoxError, ServiceExt};
+use url::Url;
+
+use crate::client_url::{check_client_url, ClientUrlError};
+
+/// How long a fetched key set is used before fetching it again
+const CACHE_TTL: i64 = 60 * 60;
+
+/// Minimum time between two forced refreshes of the same key set
+const MIN_REFRESH_INTERVAL: i64 = 5 * 60;
+
+/// How many key sets are kept at most. Clients register their own URIs, so
+/// this can't be left unbounded.
+const MAX_CACHED: usize = 1000;
+
+#[derive(Debug, Error)]
+pub enum ClientJwksError {
+    #[error("invalid jwks_uri")]
+    Uri(#[from] ClientUrlError),
+
+    #[error("failed to fetch jwks_uri {0}")]
+    Fetch(Url, #[source] BoxError),
+}
+
+struct CachedJwks {
+    jwks: JsonWebKeySet,
+    fetched_at: DateTime<Utc>,
+}
+
+/// The key sets fetched from the `jwks_uri` of clients
+#[derive(Clone, Default)]
+pub struct ClientJwksCache {
+    inner: Arc<RwLock<HashMap<Url, CachedJwks>>>,
+}
+
+impl ClientJwksCache {
+    /// Get the keys of a client, either from its registered JWKS or from its
+    /// `jwks_uri`.
+    ///
+    /// When `refresh` is set, the key set is fetched again unless it was
+    /// fetched very recently. This is used when a signature did not verify
+    /// with the cached keys, in case the client rotated them.
+    pub async fn get(
+        &self,
+        jwks: &JwksOrJwksUri,
+        refresh: bool,
+    ) -> Result<JsonWebKeySet, ClientJwksError> {
+        let uri = match jwks {
+            JwksOrJwksUri::Jwks(jwks) => return Ok(jwks.clone()),
+            JwksOrJwksUri::JwksUri(uri) => uri,
+        };
+
+        let now = Utc::now();
+        let max_age = if refresh {
+            Duration::seconds(MIN_REFRESH_INTERVAL)
+        } else {
+            Duration::seconds(CACHE_TTL)
+        };
+
+        if let Some(cached) = self.inner.read().await.get(uri) {
+            if now - cached.fetched_at < max_age {
+                return Ok(cached.jwks.clone());
+            }
+        }
+
+        let jwks = fetch(uri).await?;
+        self.insert(uri.clone(), jwks.clone(), now).await;
+
+        Ok(jwks)
+    }
+
+    async fn insert(&self, uri: Url, jwks: JsonWebKeySet, fetched_at: DateTime<Utc>) {
+        let mut cache = self.inner.write().await;
+
+        // Make room by dropping the expired entries, or the oldest one if none
+        // expired yet
+        if cache.len() >= MAX_CACHED && !cache.contains_key(&uri) {
+            let ttl = Duration::seconds(CACHE_TTL);
+            cache.retain(|_, cached| fetched_at - cached.fetched_at < ttl);
+
+            if cache.len() >= MAX_CACHED {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.fetched_at)
+                    .map(|(uri, _)| uri.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+
+        cache.insert(uri, CachedJwks { jwks, fetched_at });
+    }
+}
+
+#[tracing::instrument(err)]
+async fn fetch(uri: &Url) -> Result<JsonWebKeySet, ClientJwksError> {
+    check_client_url(uri)?;
+
+    let request = http::Request::builder()
+        .method("GET")
+        .uri(uri.as_str())
+        .body(http_body::Empty::new())
+        .map_err(|e| ClientJwksError::Fetch(uri.clone(), e.into()))?;
+
+    // The client refuses to connect to non-public addresses, and redirects to
+    // other origins are not followed
+    let response = mas_http::public_client("fetch-jwks")
+        .json::<JsonWebKeySet>()
+        .oneshot(request)
+        .await
+        .map_err(|e| ClientJwksError::Fetch(uri.clone(), e.into()))?;
+
+    Ok(response.into_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let cache = ClientJwksCache::default();
+        let now = Utc::now();
+        for i in 0..MAX_CACHED {
+            let uri: Url = format!("https://example.com/{}", i).parse().unwrap();
+            let fetched_at = now - Duration::seconds(i64::try_from(i).unwrap());
+            cache
+                .insert(uri, JsonWebKeySet::new(Vec::new()), fetched_at)
+                .await;
+        }
+
+        // The oldest entry makes room for the new one
+        let uri: Url = "https://example.com/new".parse().unwrap();
+        cache
+            .insert(uri.clone(), JsonWebKeySet::new(Vec::new()), now)
+            .await;
+
+        let inner = cache.inner.read().await;
+        assert_eq!(inner.len(), MAX_CACHED);
+        assert!(inner.contains_key(&uri));
+        let oldest: Url = format!("https://example.com/{}", MAX_CACHED - 1)
+            .parse()
+            .unwrap();
+        assert!(!inner.contains_key(&oldest));
+    }
+}
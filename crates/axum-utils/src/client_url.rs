@@ -0,0 +1,88 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks for URLs registered by clients which the server makes requests to,
+//! like `jwks_uri` or the backchannel notification endpoint.
+//!
+//! Those requests should go through [`mas_http::public_client`], which refuses
+//! names resolving to non-public addresses. Hosts given as IP literals are not
+//! resolved, so they are checked here.
+
+use std::net::IpAddr;
+
+use mas_http::is_public_ip;
+use thiserror::Error;
+use url::{Host, Url};
+
+#[derive(Debug, Error)]
+pub enum ClientUrlError {
+    #[error("{0} is not an https URL")]
+    Insecure(Url),
+
+    #[error("{0} points to a non-public address")]
+    ForbiddenAddress(Url),
+}
+
+/// Check that a URL uses HTTPS, and that it doesn't point to a non-public IP.
+/// Names are checked by the client when they are resolved.
+pub fn check_client_url(url: &Url) -> Result<(), ClientUrlError> {
+    if url.scheme() != "https" {
+        return Err(ClientUrlError::Insecure(url.clone()));
+    }
+
+    let ip = match url.host() {
+        Some(Host::Domain(_)) => return Ok(()),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        None => return Err(ClientUrlError::Insecure(url.clone())),
+    };
+
+    if is_public_ip(ip) {
+        Ok(())
+    } else {
+        Err(ClientUrlError::ForbiddenAddress(url.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_client_url() {
+        let url: Url = "http://example.com/jwks.json".parse().unwrap();
+        assert!(matches!(
+            check_client_url(&url),
+            Err(ClientUrlError::Insecure(_))
+        ));
+
+        let url: Url = "https://127.0.0.1/jwks.json".parse().unwrap();
+        assert!(matches!(
+            check_client_url(&url),
+            Err(ClientUrlError::ForbiddenAddress(_))
+        ));
+
+        let url: Url = "https://[::1]:8443/jwks.json".parse().unwrap();
+        assert!(matches!(
+            check_client_url(&url),
+            Err(ClientUrlError::ForbiddenAddress(_))
+        ));
+
+        let url: Url = "https://93.184.216.34/jwks.json".parse().unwrap();
+        assert!(check_client_url(&url).is_ok());
+
+        let url: Url = "https://example.com/jwks.json".parse().unwrap();
+        assert!(check_client_url(&url).is_ok());
+    }
+}
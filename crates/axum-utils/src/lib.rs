@@ -13,6 +13,8 @@
 // limitations under the License.
 
 pub mod client_authorization;
+pub mod client_jwks;
+pub mod client_url;
 pub mod cookies;
 pub mod csrf;
 pub mod fancy_error;
@@ -22,5 +24,5 @@ pub mod user_authorization;
 pub use self::{
     cookies::CookieExt,
     fancy_error::FancyError,
-    session::{SessionInfo, SessionInfoExt},
+    session::{SessionClient, SessionInfo, SessionInfoExt, TrustedProxies},
 };
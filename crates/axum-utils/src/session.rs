@@ -12,14 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequest, RequestParts};
 use axum_extra::extract::cookie::{Cookie, PrivateCookieJar};
+use http::{
+    header::{HeaderName, USER_AGENT},
+    HeaderMap,
+};
 use mas_data_model::BrowserSession;
 use mas_storage::{
-    user::{lookup_active_session, ActiveSessionLookupError},
+    user::{lookup_active_session, touch_session, ActiveSessionLookupError},
     PostgresqlBackend,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::{Executor, Postgres};
+use sqlx::PgConnection;
 
 use crate::CookieExt;
 
@@ -27,6 +39,10 @@ use crate::CookieExt;
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct SessionInfo {
     current: Option<i64>,
+
+    /// Other sessions which were started in this browser
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    others: Vec<i64>,
 }
 
 impl SessionInfo {
@@ -35,9 +51,32 @@ impl SessionInfo {
     pub fn from_session(session: &BrowserSession<PostgresqlBackend>) -> Self {
         Self {
             current: Some(session.data),
+            others: Vec::new(),
         }
     }
 
+    /// Make the given [`BrowserSession`] the current one, keeping the
+    /// previous one around
+    #[must_use]
+    pub fn switch_to(self, session: &BrowserSession<PostgresqlBackend>) -> Self {
+        let mut info = self.detach_current();
+        info.others.retain(|id| *id != session.data);
+        info.current = Some(session.data);
+        info
+    }
+
+    /// Stop using the current session without ending it, so that another user
+    /// can log in
+    #[must_use]
+    pub fn detach_current(mut self) -> Self {
+        if let Some(current) = self.current.take() {
+            if !self.others.contains(&current) {
+                self.others.push(current);
+            }
+        }
+        self
+    }
+
     /// Mark the session as ended
     #[must_use]
     pub fn mark_session_ended(mut self) -> Self {
@@ -45,10 +84,11 @@ impl SessionInfo {
         self
     }
 
-    /// Load the [`BrowserSession`] from database
+    /// Load the [`BrowserSession`] from database, recording that it is being
+    /// used
     pub async fn load_session(
         &self,
-        executor: impl Executor<'_, Database = Postgres>,
+        conn: &mut PgConnection,
     ) -> Result<Option<BrowserSession<PostgresqlBackend>>, ActiveSessionLookupError> {
         let session_id = if let Some(id) = self.current {
             id
@@ -56,9 +96,129 @@ impl SessionInfo {
             return Ok(None);
         };
 
-        let res = lookup_active_session(executor, session_id).await?;
+        let res = lookup_active_session(&mut *conn, session_id).await?;
+        touch_session(conn, &res).await?;
         Ok(Some(res))
     }
+
+    /// Load all the active [`BrowserSession`]s known to this browser, starting
+    /// with the current one
+    pub async fn load_sessions(
+        &self,
+        conn: &mut PgConnection,
+    ) -> Result<Vec<BrowserSession<PostgresqlBackend>>, ActiveSessionLookupError> {
+        let mut sessions = Vec::new();
+        for id in self.current.iter().chain(self.others.iter()) {
+            match lookup_active_session(&mut *conn, *id).await {
+                Ok(session) => sessions.push(session),
+                // The session might have ended since then
+                Err(e) if e.not_found() => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(sessions)
+    }
+}
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The reverse proxies in front of the server. The `X-Forwarded-For` header is
+/// only trusted for the hops added by them.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<[IpAddr]>);
+
+/// Addresses of IPv4 peers on a dual-stack socket are IPv4-mapped IPv6
+/// addresses, which should match their IPv4 form
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+impl TrustedProxies {
+    #[must_use]
+    pub fn new(proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self(proxies.into_iter().map(canonical).collect())
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.contains(&ip)
+    }
+
+    /// Find the address of the client, from the address of the peer and the
+    /// `X-Forwarded-For` header. The header is read from the right, skipping
+    /// the hops added by trusted proxies, so that clients can't choose the
+    /// address they are seen from.
+    #[must_use]
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = canonical(peer?);
+
+        let hops: Vec<&str> = headers
+            .get_all(&X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+
+        for hop in hops.into_iter().rev() {
+            if !self.trusts(client) {
+                break;
+            }
+
+            match hop.trim().parse() {
+                Ok(ip) => client = canonical(ip),
+                // Keep the last proxy which was trusted
+                Err(_) => break,
+            }
+        }
+
+        Some(client)
+    }
+}
+
+/// Where a request comes from, recorded when starting a [`BrowserSession`]
+#[derive(Debug, Clone, Default)]
+pub struct SessionClient {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<IpAddr>,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for SessionClient {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        // Only available when the server was started with connect info
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        let ip_address = req
+            .extensions()
+            .get::<TrustedProxies>()
+            .cloned()
+            .unwrap_or_default()
+            .client_ip(peer, req.headers());
+
+        Ok(Self {
+            user_agent,
+            ip_address,
+        })
+    }
 }
 
 pub trait SessionInfoExt {
@@ -68,8 +228,9 @@ pub trait SessionInfoExt {
     where
         Self: Sized,
     {
-        let session_info = SessionInfo::from_session(session);
-        self.update_session_info(&session_info)
+        let (session_info, this) = self.session_info();
+        let session_info = session_info.switch_to(session);
+        this.update_session_info(&session_info)
     }
 }
 
@@ -94,3 +255,121 @@ impl<K> SessionInfoExt for PrivateCookieJar<K> {
         self.add(cookie)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use mas_data_model::SessionExpiration;
+    use mas_storage::{
+        test_utils::{random_name, test_transaction},
+        user::{end_session, register_passwordless_user, start_session},
+    };
+
+    use super::*;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(&X_FORWARDED_FOR, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "198.51.100.7".parse().unwrap();
+        let spoofed: IpAddr = "203.0.113.1".parse().unwrap();
+        let proxies = TrustedProxies::new([proxy, "10.0.0.2".parse().unwrap()]);
+
+        // The header is ignored without trusted proxies
+        assert_eq!(
+            TrustedProxies::default().client_ip(Some(proxy), &headers(&["198.51.100.7"])),
+            Some(proxy)
+        );
+        assert_eq!(
+            proxies.client_ip(Some(spoofed), &headers(&["198.51.100.7"])),
+            Some(spoofed)
+        );
+
+        // Hops are read from the right, up to the first untrusted one
+        assert_eq!(
+            proxies.client_ip(Some(proxy), &headers(&["203.0.113.1, 198.51.100.7"])),
+            Some(client)
+        );
+        assert_eq!(
+            proxies.client_ip(
+                Some(proxy),
+                &headers(&["203.0.113.1", "198.51.100.7, 10.0.0.2"])
+            ),
+            Some(client)
+        );
+
+        // IPv4 peers of a dual-stack socket
+        assert_eq!(
+            proxies.client_ip(
+                Some("::ffff:10.0.0.1".parse().unwrap()),
+                &headers(&["198.51.100.7"])
+            ),
+            Some(client)
+        );
+
+        // Garbage stops at the last trusted proxy
+        assert_eq!(
+            proxies.client_ip(Some(proxy), &headers(&["198.51.100.7, unknown"])),
+            Some(proxy)
+        );
+        assert_eq!(
+            proxies.client_ip(Some(proxy), &HeaderMap::new()),
+            Some(proxy)
+        );
+        assert_eq!(proxies.client_ip(None, &headers(&["198.51.100.7"])), None);
+    }
+
+    #[tokio::test]
+    async fn test_switch_sessions() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let expiration = SessionExpiration::default();
+        let alice = register_passwordless_user(&mut txn, &random_name("alice"))
+            .await
+            .unwrap();
+        let bob = register_passwordless_user(&mut txn, &random_name("bob"))
+            .await
+            .unwrap();
+        let alice_session = start_session(&mut txn, alice, None, None, &expiration)
+            .await
+            .unwrap();
+        let bob_session = start_session(&mut txn, bob, None, None, &expiration)
+            .await
+            .unwrap();
+
+        // Switching keeps the previous session around, after the current one
+        let info = SessionInfo::from_session(&alice_session).switch_to(&bob_session);
+        let ids = |sessions: Vec<BrowserSession<PostgresqlBackend>>| -> Vec<i64> {
+            sessions.into_iter().map(|session| session.data).collect()
+        };
+        let sessions = info.load_sessions(&mut txn).await.unwrap();
+        assert_eq!(ids(sessions), [bob_session.data, alice_session.data]);
+
+        // Switching back doesn't duplicate it
+        let info = info.switch_to(&alice_session);
+        let sessions = info.load_sessions(&mut txn).await.unwrap();
+        assert_eq!(ids(sessions), [alice_session.data, bob_session.data]);
+
+        // Detaching leaves no current session, but the others can still be picked
+        let info = info.detach_current();
+        assert!(info.load_session(&mut txn).await.unwrap().is_none());
+        let sessions = info.load_sessions(&mut txn).await.unwrap();
+        assert_eq!(ids(sessions), [bob_session.data, alice_session.data]);
+
+        // Ended sessions are skipped
+        end_session(&mut txn, &bob_session).await.unwrap();
+        let sessions = info.load_sessions(&mut txn).await.unwrap();
+        assert_eq!(ids(sessions), [alice_session.data]);
+    }
+}
@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use argon2::Argon2;
 use clap::Parser;
 use mas_config::{DatabaseConfig, RootConfig};
 use mas_storage::{
+    login_throttle::clear_login_failures,
     oauth2::client::{insert_client_from_config, lookup_client_by_client_id, truncate_clients},
+    password::add_password_hash,
     user::{
         lookup_user_by_username, lookup_user_email, mark_user_email_as_verified, register_user,
     },
@@ -40,6 +41,20 @@ enum Subcommand {
     /// Mark email address as verified
     VerifyEmail { username: String, email: String },
 
+    /// Lift the temporary lock after too many failed login attempts
+    UnlockUser { username: String },
+
+    /// Import a password hash made by another service, e.g. Synapse. It gets
+    /// replaced with a hash of the current scheme on the next successful login
+    ImportPasswordHash {
+        username: String,
+        hashed_password: String,
+
+        /// Version of the password scheme the hash was made with
+        #[clap(long)]
+        version: u16,
+    },
+
     /// Import clients from config
     ImportClients {
         /// Remove all clients before importing
@@ -53,12 +68,12 @@ impl Options {
         use Subcommand as SC;
         match &self.subcommand {
             SC::Register { username, password } => {
-                let config: DatabaseConfig = root.load_config()?;
-                let pool = config.connect().await?;
+                let config: RootConfig = root.load_config()?;
+                let schemes = super::password_schemes(&config.passwords, &config.secrets)?;
+                let pool = config.database.connect().await?;
                 let mut txn = pool.begin().await?;
-                let hasher = Argon2::default();
 
-                let user = register_user(&mut txn, hasher, username, password).await?;
+                let user = register_user(&mut txn, &schemes, username, password).await?;
                 txn.commit().await?;
                 info!(?user, "User registered");
 
@@ -83,6 +98,40 @@ impl Options {
 
                 Ok(())
             }
+            SC::UnlockUser { username } => {
+                let config: DatabaseConfig = root.load_config()?;
+                let pool = config.connect().await?;
+
+                if clear_login_failures(&pool, username).await? {
+                    info!(%username, "User unlocked");
+                } else {
+                    warn!(%username, "No failed login attempts on this user");
+                }
+
+                Ok(())
+            }
+            SC::ImportPasswordHash {
+                username,
+                hashed_password,
+                version,
+            } => {
+                let config: RootConfig = root.load_config()?;
+                let schemes = super::password_schemes(&config.passwords, &config.secrets)?;
+                if !schemes.knows(*version) {
+                    anyhow::bail!("unknown password scheme version {}", version);
+                }
+
+                let pool = config.database.connect().await?;
+                let mut txn = pool.begin().await?;
+
+                let user = lookup_user_by_username(&mut txn, username).await?;
+                add_password_hash(&mut txn, &user, *version, hashed_password).await?;
+
+                txn.commit().await?;
+                info!(%username, version, "Password hash imported");
+
+                Ok(())
+            }
             SC::ImportClients { truncate } => {
                 let config: RootConfig = root.load_config()?;
                 let pool = config.database.connect().await?;
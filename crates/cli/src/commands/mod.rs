@@ -16,7 +16,8 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use clap::Parser;
-use mas_config::ConfigurationSection;
+use mas_config::{ConfigurationSection, PasswordAlgorithmConfig, PasswordsConfig, SecretsConfig};
+use mas_storage::password::{PasswordAlgorithm, PasswordScheme, PasswordSchemes};
 
 mod config;
 mod database;
@@ -87,3 +88,34 @@ impl Options {
         T::load_from_files(&configs).context("could not load configuration")
     }
 }
+
+/// Build the password hashing schemes out of the config
+fn password_schemes(
+    passwords: &PasswordsConfig,
+    secrets: &SecretsConfig,
+) -> anyhow::Result<PasswordSchemes> {
+    let schemes = passwords
+        .schemes
+        .iter()
+        .map(|scheme| PasswordScheme {
+            version: scheme.version,
+            algorithm: match scheme.algorithm {
+                PasswordAlgorithmConfig::Argon2id {
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                } => PasswordAlgorithm::Argon2id {
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                },
+                PasswordAlgorithmConfig::Bcrypt { cost } => PasswordAlgorithm::Bcrypt { cost },
+                PasswordAlgorithmConfig::Pbkdf2 { rounds } => PasswordAlgorithm::Pbkdf2 { rounds },
+            },
+            peppered: scheme.peppered,
+        })
+        .collect();
+
+    PasswordSchemes::new(schemes, secrets.password_pepper().map(ToOwned::to_owned))
+        .context("invalid password schemes")
+}
@@ -24,10 +24,15 @@ use futures::stream::{StreamExt, TryStreamExt};
 use hyper::Server;
 use mas_config::RootConfig;
 use mas_email::{MailTransport, Mailer};
+use mas_handlers::HomeserverConnection;
 use mas_http::ServerLayer;
-use mas_policy::PolicyFactory;
+use mas_ldap::LdapBackend;
+use mas_policy::{BreachedPasswords, PolicyFactory};
 use mas_router::UrlBuilder;
-use mas_storage::MIGRATOR;
+use mas_storage::{
+    password::{PasswordBackend, PasswordBackends},
+    MIGRATOR,
+};
 use mas_tasks::TaskQueue;
 use mas_templates::Templates;
 use tokio::io::AsyncRead;
@@ -165,6 +170,10 @@ impl Options {
         info!("Starting task scheduler");
         let queue = TaskQueue::default();
         queue.recuring(Duration::from_secs(15), mas_tasks::cleanup_expired(&pool));
+        queue.recuring(
+            Duration::from_secs(60),
+            mas_tasks::end_expired_sessions(&pool),
+        );
         queue.start();
 
         // Initialize the key store
@@ -199,6 +208,13 @@ impl Options {
         )
         .await
         .context("failed to load the policy")?;
+        let policy_factory = if let Some(path) = &config.policy.breached_passwords {
+            let breached_passwords = BreachedPasswords::open(path)
+                .context("failed to open the breached passwords dataset")?;
+            policy_factory.with_breached_passwords(breached_passwords)
+        } else {
+            policy_factory
+        };
         let policy_factory = Arc::new(policy_factory);
 
         // Load and compile the templates
@@ -213,11 +229,28 @@ impl Options {
             &config.email.reply_to,
         );
 
+        // Check passwords against the LDAP directories first, in order
+        let mut password_backends: Vec<Arc<dyn PasswordBackend>> = Vec::new();
+        for server in &config.ldap.servers {
+            let backend = LdapBackend::from_config(server)
+                .await
+                .with_context(|| format!("could not set up LDAP server {}", server.url))?;
+            password_backends.push(Arc::new(backend));
+        }
+        let password_backends = PasswordBackends::new(password_backends);
+        let password_schemes = super::password_schemes(&config.passwords, &config.secrets)?;
+
         let url_builder = UrlBuilder::new(config.http.public_base.clone());
 
         let static_files = mas_static_files::service(&config.http.web_root);
 
         let matrix_config = config.matrix.clone();
+        let homeserver = HomeserverConnection::new(&config.matrix);
+        let authentication_config = config.authentication.clone();
+        let appservices_config = config.appservices.clone();
+        let upstream_oauth2_config = config.upstream_oauth2.clone();
+        let upstream_saml_config = config.upstream_saml.clone();
+        let trusted_proxies = config.http.trusted_proxies.clone();
 
         // Explicitely the config to properly zeroize secret keys
         drop(config);
@@ -242,7 +275,15 @@ impl Options {
             &mailer,
             &url_builder,
             &matrix_config,
+            &homeserver,
             &policy_factory,
+            &authentication_config,
+            &appservices_config,
+            &upstream_oauth2_config,
+            &upstream_saml_config,
+            &password_backends,
+            &password_schemes,
+            &trusted_proxies,
         )
         .fallback(static_files)
         .layer(ServerLayer::default());
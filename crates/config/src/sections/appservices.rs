@@ -0,0 +1,192 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ops::{Deref, DerefMut};
+
+use async_trait::async_trait;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use subtle::ConstantTimeEq;
+
+use super::ConfigurationSection;
+
+/// A namespace of Matrix IDs reserved by an application service
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AppServiceNamespaceConfig {
+    /// Regular expression matching the IDs in this namespace
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    #[schemars(with = "String")]
+    pub regex: Regex,
+
+    /// Whether the application service is the only one allowed to use IDs in
+    /// this namespace
+    #[serde(default)]
+    pub exclusive: bool,
+}
+
+impl AppServiceNamespaceConfig {
+    fn matches(&self, id: &str) -> bool {
+        // Like in Synapse, the regex only has to match the start of the ID. The
+        // leftmost match starts at the beginning if any match does.
+        self.regex.find(id).map_or(false, |m| m.start() == 0)
+    }
+}
+
+/// Namespaces reserved by an application service
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AppServiceNamespacesConfig {
+    /// Namespaces of user IDs
+    #[serde(default)]
+    pub users: Vec<AppServiceNamespaceConfig>,
+}
+
+/// An application service registration
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AppServiceConfig {
+    /// A unique identifier for this application service
+    pub id: String,
+
+    /// The token the application service uses to authenticate its requests
+    pub as_token: String,
+
+    /// The localpart of the user the application service acts as
+    pub sender_localpart: String,
+
+    /// Namespaces reserved by the application service
+    #[serde(default)]
+    pub namespaces: AppServiceNamespacesConfig,
+}
+
+impl AppServiceConfig {
+    /// Check whether a user is the sender of the application service or is in
+    /// one of its user namespaces
+    #[must_use]
+    pub fn is_interested_in_user(&self, user_id: &str, server_name: &str) -> bool {
+        if user_id == format!("@{}:{}", self.sender_localpart, server_name) {
+            return true;
+        }
+
+        self.namespaces.users.iter().any(|ns| ns.matches(user_id))
+    }
+}
+
+/// List of application service registrations
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct AppServicesConfig(Vec<AppServiceConfig>);
+
+impl AppServicesConfig {
+    /// Find the application service using the given token
+    #[must_use]
+    pub fn find_by_token(&self, token: &str) -> Option<&AppServiceConfig> {
+        self.0
+            .iter()
+            .find(|appservice| bool::from(appservice.as_token.as_bytes().ct_eq(token.as_bytes())))
+    }
+}
+
+impl Deref for AppServicesConfig {
+    type Target = Vec<AppServiceConfig>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for AppServicesConfig {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for AppServicesConfig {
+    fn path() -> &'static str {
+        "appservices"
+    }
+
+    #[tracing::instrument]
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                  appservices:
+                    - id: irc
+                      as_token: secret
+                      sender_localpart: irc-bridge
+                      namespaces:
+                        users:
+                          - regex: "@irc_.*:example.com"
+                            exclusive: true
+                "#,
+            )?;
+
+            let config = AppServicesConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.len(), 1);
+            assert!(config.find_by_token("wrong").is_none());
+
+            let appservice = config.find_by_token("secret").unwrap();
+            assert_eq!(appservice.id, "irc");
+            assert!(appservice.is_interested_in_user("@irc-bridge:example.com", "example.com"));
+            assert!(appservice.is_interested_in_user("@irc_alice:example.com", "example.com"));
+            assert!(!appservice.is_interested_in_user("@alice:example.com", "example.com"));
+            assert!(!appservice.is_interested_in_user("@alice_irc_bob:example.com", "example.com"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn invalid_regex() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                  appservices:
+                    - id: irc
+                      as_token: secret
+                      sender_localpart: irc-bridge
+                      namespaces:
+                        users:
+                          - regex: "@irc_(.*:example.com"
+                "#,
+            )?;
+
+            assert!(AppServicesConfig::load_from_file("config.yaml").is_err());
+
+            Ok(())
+        });
+    }
+}
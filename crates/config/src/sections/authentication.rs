@@ -0,0 +1,246 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use chrono::Duration;
+use mas_data_model::{AuthenticationMethod, SessionExpiration};
+use schemars::JsonSchema;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{serde_as, DeserializeAs, DurationSeconds, SerializeAs};
+
+use super::ConfigurationSection;
+
+/// An authentication context class, and the authentication methods which
+/// satisfy it
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AcrLevelConfig {
+    /// Value of the class, as requested by clients in `acr_values` and as
+    /// emitted in the `acr` claim
+    pub value: String,
+
+    /// Authentication methods which satisfy this class
+    #[schemars(with = "Vec<String>")]
+    pub methods: Vec<AuthenticationMethod>,
+}
+
+fn default_acr_levels() -> Vec<AcrLevelConfig> {
+    vec![AcrLevelConfig {
+        value: "urn:mas:acr:password".to_owned(),
+        methods: vec![AuthenticationMethod::Password, AuthenticationMethod::Reauth],
+    }]
+}
+
+/// Shortest idle timeout allowed. The last activity of sessions is only
+/// recorded once a minute, so shorter timeouts would end sessions which are
+/// still in use.
+const MIN_SESSION_IDLE_TIMEOUT: i64 = 5 * 60;
+
+/// A duration in seconds, no shorter than [`MIN_SESSION_IDLE_TIMEOUT`]
+struct IdleTimeoutSeconds;
+
+impl SerializeAs<Duration> for IdleTimeoutSeconds {
+    fn serialize_as<S>(source: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        DurationSeconds::<i64>::serialize_as(source, serializer)
+    }
+}
+
+impl<'de> DeserializeAs<'de, Duration> for IdleTimeoutSeconds {
+    fn deserialize_as<D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let duration: Duration = DurationSeconds::<i64>::deserialize_as(deserializer)?;
+        if duration < Duration::seconds(MIN_SESSION_IDLE_TIMEOUT) {
+            return Err(D::Error::custom(format!(
+                "session_idle_timeout must be at least {} seconds",
+                MIN_SESSION_IDLE_TIMEOUT
+            )));
+        }
+
+        Ok(duration)
+    }
+}
+
+/// Configuration related to the authentication of users
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AuthenticationConfig {
+    /// Authentication context classes, ordered from the weakest to the
+    /// strongest
+    #[serde(default = "default_acr_levels")]
+    pub acr_levels: Vec<AcrLevelConfig>,
+
+    /// Let users sign in with a one-time code or link sent to their verified
+    /// email address, instead of their password
+    #[serde(default)]
+    pub email_login: bool,
+
+    /// End browser sessions which were not used for this many seconds. Each
+    /// request made with the session pushes this deadline back, but the last
+    /// activity is only recorded once a minute, so this must be at least 300
+    #[schemars(with = "Option<u64>", range(min = 300))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<IdleTimeoutSeconds>")]
+    pub session_idle_timeout: Option<Duration>,
+
+    /// End browser sessions this many seconds after they started, even if they
+    /// are still being used
+    #[schemars(with = "Option<u64>", range(min = 60))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<serde_with::DurationSeconds<i64>>")]
+    pub session_max_lifetime: Option<Duration>,
+}
+
+impl Default for AuthenticationConfig {
+    fn default() -> Self {
+        Self {
+            acr_levels: default_acr_levels(),
+            email_login: false,
+            session_idle_timeout: None,
+            session_max_lifetime: None,
+        }
+    }
+}
+
+impl AuthenticationConfig {
+    /// Limits to apply to the browser sessions started from now on
+    #[must_use]
+    pub fn session_expiration(&self) -> SessionExpiration {
+        SessionExpiration {
+            idle_timeout: self.session_idle_timeout,
+            max_lifetime: self.session_max_lifetime,
+        }
+    }
+
+    /// Get the strongest class satisfied by the given authentication method
+    #[must_use]
+    pub fn acr_for(&self, method: AuthenticationMethod) -> Option<&str> {
+        self.acr_levels
+            .iter()
+            .rev()
+            .find(|level| level.methods.contains(&method))
+            .map(|level| level.value.as_str())
+    }
+
+    /// Check if the given authentication method satisfies at least one of the
+    /// requested classes
+    #[must_use]
+    pub fn satisfies(&self, method: AuthenticationMethod, acr_values: &HashSet<String>) -> bool {
+        self.acr_levels
+            .iter()
+            .any(|level| acr_values.contains(&level.value) && level.methods.contains(&method))
+    }
+
+    /// Check if one of the requested classes can be reached by reauthenticating
+    /// the user
+    #[must_use]
+    pub fn reachable_by_reauth(&self, acr_values: &HashSet<String>) -> bool {
+        self.satisfies(AuthenticationMethod::Reauth, acr_values)
+    }
+
+    /// List the values of all the known classes
+    pub fn supported_values(&self) -> impl Iterator<Item = &str> {
+        self.acr_levels.iter().map(|level| level.value.as_str())
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for AuthenticationConfig {
+    fn path() -> &'static str {
+        "authentication"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    authentication:
+                      acr_levels:
+                        - value: "urn:example:password"
+                          methods: [password, reauth]
+                        - value: "urn:example:fresh"
+                          methods: [reauth]
+                      email_login: true
+                      session_idle_timeout: 3600
+                      session_max_lifetime: 604800
+                "#,
+            )?;
+
+            let config = AuthenticationConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.acr_levels.len(), 2);
+            assert!(config.email_login);
+            assert_eq!(
+                config.session_expiration(),
+                SessionExpiration {
+                    idle_timeout: Some(Duration::hours(1)),
+                    max_lifetime: Some(Duration::weeks(1)),
+                }
+            );
+            assert_eq!(
+                config.acr_for(AuthenticationMethod::Password),
+                Some("urn:example:password")
+            );
+            assert_eq!(
+                config.acr_for(AuthenticationMethod::Reauth),
+                Some("urn:example:fresh")
+            );
+
+            let requested = HashSet::from(["urn:example:fresh".to_owned()]);
+            assert!(!config.satisfies(AuthenticationMethod::Password, &requested));
+            assert!(config.reachable_by_reauth(&requested));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reject_short_idle_timeout() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    authentication:
+                      session_idle_timeout: 90
+                "#,
+            )?;
+
+            assert!(AuthenticationConfig::load_from_file("config.yaml").is_err());
+
+            Ok(())
+        });
+    }
+}
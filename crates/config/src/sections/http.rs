@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf};
 
 use async_trait::async_trait;
 use schemars::JsonSchema;
@@ -62,6 +62,12 @@ pub struct HttpConfig {
 
     /// Public URL base from where the authentication service is reachable
     pub public_base: Url,
+
+    /// Addresses of the reverse proxies in front of the server. The client
+    /// address is taken from the `X-Forwarded-For` header when the request
+    /// comes through one of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl Default for HttpConfig {
@@ -70,6 +76,7 @@ impl Default for HttpConfig {
             address: default_http_address(),
             web_root: None,
             public_base: default_public_base(),
+            trusted_proxies: Vec::new(),
         }
     }
 }
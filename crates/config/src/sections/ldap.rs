@@ -0,0 +1,228 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none};
+use url::Url;
+
+use super::ConfigurationSection;
+
+fn default_filter() -> String {
+    "(uid={username})".to_string()
+}
+
+fn default_username_attribute() -> String {
+    "uid".to_string()
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn default_email_attribute() -> Option<String> {
+    Some("mail".to_string())
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn default_displayname_attribute() -> Option<String> {
+    Some("displayName".to_string())
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// How to find the entry of a user in the directory
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum LdapBindConfig {
+    /// Search for the user entry, optionally binding with a service account
+    /// first, then bind as the user entry
+    Search {
+        /// The DN of the service account to search with. The search is
+        /// anonymous if not set
+        bind_dn: Option<String>,
+
+        /// The password of the service account
+        bind_password: Option<String>,
+
+        /// The DN under which to search for users
+        base_dn: String,
+
+        /// The search filter, in which `{username}` is replaced by the escaped
+        /// username
+        #[serde(default = "default_filter")]
+        filter: String,
+    },
+
+    /// Bind directly as the user entry, with a DN built from the username
+    Direct {
+        /// The DN template, in which `{username}` is replaced by the escaped
+        /// username, e.g. `uid={username},ou=people,dc=example,dc=org`
+        dn_template: String,
+    },
+}
+
+/// Which attributes of the user entry to import when provisioning new users
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LdapAttributesConfig {
+    /// The attribute holding the canonical username of the user, which is the
+    /// one used for the local account
+    #[serde(default = "default_username_attribute")]
+    pub username: String,
+
+    /// The attribute holding the email address of the user
+    #[serde(default = "default_email_attribute")]
+    pub email: Option<String>,
+
+    /// The attribute holding the display name of the user
+    #[serde(default = "default_displayname_attribute")]
+    pub displayname: Option<String>,
+}
+
+impl Default for LdapAttributesConfig {
+    fn default() -> Self {
+        Self {
+            username: default_username_attribute(),
+            email: default_email_attribute(),
+            displayname: default_displayname_attribute(),
+        }
+    }
+}
+
+/// An LDAP directory users can log in against with their password
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LdapServerConfig {
+    /// URL of the server, with either the `ldap` or the `ldaps` scheme
+    #[schemars(url)]
+    pub url: Url,
+
+    /// Upgrade the connection with `StartTLS`. Only valid with the `ldap` scheme
+    #[serde(default)]
+    pub starttls: bool,
+
+    /// Path to a PEM file with additional CA certificates to trust
+    pub ca_certificate: Option<PathBuf>,
+
+    /// How to find the entry of a user
+    pub bind: LdapBindConfig,
+
+    /// Which attributes to import when provisioning new users
+    #[serde(default)]
+    pub attributes: LdapAttributesConfig,
+
+    /// How long to wait for the server to answer
+    #[schemars(with = "u64")]
+    #[serde(default = "default_timeout")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub timeout: Duration,
+}
+
+/// Configuration related to LDAP password authentication
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct LdapConfig {
+    /// List of LDAP directories to check passwords against, in order, before
+    /// falling back to the local passwords
+    #[serde(default)]
+    pub servers: Vec<LdapServerConfig>,
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for LdapConfig {
+    fn path() -> &'static str {
+        "ldap"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                  ldap:
+                    servers:
+                      - url: ldap://ldap.example.org
+                        starttls: true
+                        bind:
+                          mode: search
+                          bind_dn: cn=mas,dc=example,dc=org
+                          bind_password: hunter2
+                          base_dn: ou=people,dc=example,dc=org
+                      - url: ldaps://ad.example.org
+                        ca_certificate: /etc/mas/ad-ca.pem
+                        timeout: 10
+                        bind:
+                          mode: direct
+                          dn_template: uid={username},ou=people,dc=example,dc=org
+                        attributes:
+                          username: sAMAccountName
+                          email: userPrincipalName
+                          displayname: ~
+                "#,
+            )?;
+
+            let config = LdapConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.servers.len(), 2);
+
+            let first = &config.servers[0];
+            assert!(first.starttls);
+            assert_eq!(first.timeout, Duration::from_secs(5));
+            assert!(matches!(
+                &first.bind,
+                LdapBindConfig::Search { bind_dn: Some(_), filter, .. } if filter == "(uid={username})"
+            ));
+            assert_eq!(first.attributes.username, "uid");
+            assert_eq!(first.attributes.email.as_deref(), Some("mail"));
+            assert_eq!(first.attributes.displayname.as_deref(), Some("displayName"));
+
+            let second = &config.servers[1];
+            assert!(!second.starttls);
+            assert_eq!(second.timeout, Duration::from_secs(10));
+            assert_eq!(
+                second.ca_certificate,
+                Some(PathBuf::from("/etc/mas/ad-ca.pem"))
+            );
+            assert!(matches!(second.bind, LdapBindConfig::Direct { .. }));
+            assert_eq!(second.attributes.username, "sAMAccountName");
+            assert_eq!(
+                second.attributes.email.as_deref(),
+                Some("userPrincipalName")
+            );
+            assert_eq!(second.attributes.displayname, None);
+
+            Ok(())
+        });
+    }
+}
@@ -13,9 +13,11 @@
 // limitations under the License.
 
 use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use url::Url;
 
 use super::ConfigurationSection;
 
@@ -23,19 +25,35 @@ fn default_homeserver() -> String {
     "localhost:8008".to_string()
 }
 
+fn default_endpoint() -> Url {
+    Url::parse("http://localhost:8008/").unwrap()
+}
+
 /// Configuration related to the Matrix homeserver
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct MatrixConfig {
-    /// Time-to-live of a CSRF token in seconds
+    /// The server name of the homeserver
     #[serde(default = "default_homeserver")]
     pub homeserver: String,
+
+    /// Base URL of the homeserver, used to call its admin API
+    #[serde(default = "default_endpoint")]
+    pub endpoint: Url,
+
+    /// Shared secret used to authenticate against the homeserver admin API.
+    /// Users and devices are not provisioned on the homeserver if this is not
+    /// set.
+    #[serde(default)]
+    pub secret: Option<String>,
 }
 
 impl Default for MatrixConfig {
     fn default() -> Self {
         Self {
             homeserver: default_homeserver(),
+            endpoint: default_endpoint(),
+            secret: None,
         }
     }
 }
@@ -47,7 +65,16 @@ impl ConfigurationSection<'_> for MatrixConfig {
     }
 
     async fn generate() -> anyhow::Result<Self> {
-        Ok(Self::default())
+        let secret = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        Ok(Self {
+            secret: Some(secret),
+            ..Self::default()
+        })
     }
 
     fn test() -> Self {
@@ -69,12 +96,19 @@ mod tests {
                 r#"
                     matrix:
                       homeserver: matrix.org
+                      endpoint: https://matrix-client.matrix.org/
+                      secret: hunter2
                 "#,
             )?;
 
             let config = MatrixConfig::load_from_file("config.yaml")?;
 
             assert_eq!(config.homeserver, "matrix.org".to_string());
+            assert_eq!(
+                config.endpoint.as_str(),
+                "https://matrix-client.matrix.org/"
+            );
+            assert_eq!(config.secret.as_deref(), Some("hunter2"));
 
             Ok(())
         });
@@ -16,24 +16,36 @@ use async_trait::async_trait;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+mod appservices;
+mod authentication;
 mod clients;
 mod csrf;
 mod database;
 mod email;
 mod http;
+mod ldap;
 mod matrix;
+mod passwords;
 mod policy;
 mod secrets;
 mod telemetry;
 mod templates;
+mod upstream_oauth2;
+mod upstream_saml;
 
 pub use self::{
+    appservices::{
+        AppServiceConfig, AppServiceNamespaceConfig, AppServiceNamespacesConfig, AppServicesConfig,
+    },
+    authentication::{AcrLevelConfig, AuthenticationConfig},
     clients::{ClientAuthMethodConfig, ClientConfig, ClientsConfig},
     csrf::CsrfConfig,
     database::DatabaseConfig,
     email::{EmailConfig, EmailSmtpMode, EmailTransportConfig},
     http::HttpConfig,
+    ldap::{LdapAttributesConfig, LdapBindConfig, LdapConfig, LdapServerConfig},
     matrix::MatrixConfig,
+    passwords::{PasswordAlgorithmConfig, PasswordSchemeConfig, PasswordsConfig},
     policy::PolicyConfig,
     secrets::{Encrypter, SecretsConfig},
     telemetry::{
@@ -41,6 +53,8 @@ pub use self::{
         TracingExporterConfig,
     },
     templates::TemplatesConfig,
+    upstream_oauth2::{ClaimsMappingConfig, UpstreamOAuth2Config, UpstreamProviderConfig},
+    upstream_saml::{MetadataOrPath, SamlProviderConfig, UpstreamSamlConfig},
 };
 use crate::util::ConfigurationSection;
 
@@ -85,6 +99,30 @@ pub struct RootConfig {
     /// Configuration related to the OPA policies
     #[serde(default)]
     pub policy: PolicyConfig,
+
+    /// Configuration related to the authentication of users
+    #[serde(default)]
+    pub authentication: AuthenticationConfig,
+
+    /// List of application services allowed to log in their users
+    #[serde(default)]
+    pub appservices: AppServicesConfig,
+
+    /// Configuration related to upstream identity providers
+    #[serde(default)]
+    pub upstream_oauth2: UpstreamOAuth2Config,
+
+    /// Configuration related to upstream SAML identity providers
+    #[serde(default)]
+    pub upstream_saml: UpstreamSamlConfig,
+
+    /// Configuration related to LDAP password authentication
+    #[serde(default)]
+    pub ldap: LdapConfig,
+
+    /// Configuration related to the hashing of local passwords
+    #[serde(default)]
+    pub passwords: PasswordsConfig,
 }
 
 #[async_trait]
@@ -105,6 +143,12 @@ impl ConfigurationSection<'_> for RootConfig {
             secrets: SecretsConfig::generate().await?,
             matrix: MatrixConfig::generate().await?,
             policy: PolicyConfig::generate().await?,
+            authentication: AuthenticationConfig::generate().await?,
+            appservices: AppServicesConfig::generate().await?,
+            upstream_oauth2: UpstreamOAuth2Config::generate().await?,
+            upstream_saml: UpstreamSamlConfig::generate().await?,
+            ldap: LdapConfig::generate().await?,
+            passwords: PasswordsConfig::generate().await?,
         })
     }
 
@@ -120,6 +164,12 @@ impl ConfigurationSection<'_> for RootConfig {
             secrets: SecretsConfig::test(),
             matrix: MatrixConfig::test(),
             policy: PolicyConfig::test(),
+            authentication: AuthenticationConfig::test(),
+            appservices: AppServicesConfig::test(),
+            upstream_oauth2: UpstreamOAuth2Config::test(),
+            upstream_saml: UpstreamSamlConfig::test(),
+            ldap: LdapConfig::test(),
+            passwords: PasswordsConfig::test(),
         }
     }
 }
@@ -0,0 +1,184 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+fn default_argon2_m_cost() -> u32 {
+    4096
+}
+
+fn default_argon2_t_cost() -> u32 {
+    3
+}
+
+fn default_argon2_p_cost() -> u32 {
+    1
+}
+
+fn default_bcrypt_cost() -> u32 {
+    12
+}
+
+fn default_pbkdf2_rounds() -> u32 {
+    310_000
+}
+
+/// A password hashing algorithm, and its parameters for new hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum PasswordAlgorithmConfig {
+    /// Argon2id
+    Argon2id {
+        /// Memory size, in KiB
+        #[serde(default = "default_argon2_m_cost")]
+        m_cost: u32,
+
+        /// Number of iterations
+        #[serde(default = "default_argon2_t_cost")]
+        t_cost: u32,
+
+        /// Degree of parallelism
+        #[serde(default = "default_argon2_p_cost")]
+        p_cost: u32,
+    },
+
+    /// bcrypt, as used by Synapse
+    Bcrypt {
+        /// Logarithmic cost
+        #[serde(default = "default_bcrypt_cost")]
+        cost: u32,
+    },
+
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2 {
+        /// Number of iterations
+        #[serde(default = "default_pbkdf2_rounds")]
+        rounds: u32,
+    },
+}
+
+/// A versioned password hashing scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PasswordSchemeConfig {
+    /// Version of the scheme, stored alongside each password hash
+    pub version: u16,
+
+    /// Algorithm of the scheme
+    #[serde(flatten)]
+    pub algorithm: PasswordAlgorithmConfig,
+
+    /// Append the `password_pepper` secret to passwords before hashing them
+    #[serde(default)]
+    pub peppered: bool,
+}
+
+fn default_schemes() -> Vec<PasswordSchemeConfig> {
+    vec![PasswordSchemeConfig {
+        version: 1,
+        algorithm: PasswordAlgorithmConfig::Argon2id {
+            m_cost: default_argon2_m_cost(),
+            t_cost: default_argon2_t_cost(),
+            p_cost: default_argon2_p_cost(),
+        },
+        peppered: false,
+    }]
+}
+
+/// Configuration related to the hashing of local passwords
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PasswordsConfig {
+    /// Password hashing schemes. New passwords are hashed with the scheme of
+    /// the highest version, and passwords hashed with another scheme are
+    /// hashed again after the next successful login.
+    #[serde(default = "default_schemes")]
+    pub schemes: Vec<PasswordSchemeConfig>,
+}
+
+impl Default for PasswordsConfig {
+    fn default() -> Self {
+        Self {
+            schemes: default_schemes(),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for PasswordsConfig {
+    fn path() -> &'static str {
+        "passwords"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    passwords:
+                      schemes:
+                        - version: 1
+                          algorithm: bcrypt
+                          peppered: true
+                        - version: 2
+                          algorithm: argon2id
+                          m_cost: 19456
+                          t_cost: 2
+                "#,
+            )?;
+
+            let config = PasswordsConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(
+                config.schemes,
+                vec![
+                    PasswordSchemeConfig {
+                        version: 1,
+                        algorithm: PasswordAlgorithmConfig::Bcrypt { cost: 12 },
+                        peppered: true,
+                    },
+                    PasswordSchemeConfig {
+                        version: 2,
+                        algorithm: PasswordAlgorithmConfig::Argon2id {
+                            m_cost: 19456,
+                            t_cost: 2,
+                            p_cost: 1,
+                        },
+                        peppered: false,
+                    },
+                ]
+            );
+
+            Ok(())
+        });
+    }
+}
@@ -48,6 +48,11 @@ pub struct PolicyConfig {
     /// Arbitrary data to pass to the policy
     #[serde(default)]
     pub data: Option<serde_json::Value>,
+
+    /// Path to a dataset of breached passwords, either a directory of Pwned
+    /// Passwords range files or a single file ordered by SHA-1 hash
+    #[serde(default)]
+    pub breached_passwords: Option<PathBuf>,
 }
 
 impl Default for PolicyConfig {
@@ -57,6 +62,7 @@ impl Default for PolicyConfig {
             client_registration_entrypoint: default_client_registration_endpoint(),
             register_entrypoint: default_register_endpoint(),
             data: None,
+            breached_passwords: None,
         }
     }
 }
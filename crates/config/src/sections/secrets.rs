@@ -161,6 +161,12 @@ pub struct SecretsConfig {
     /// List of private keys to use for signing and encrypting payloads
     #[serde(default)]
     keys: Vec<KeyConfig>,
+
+    /// Secret appended to passwords before hashing them with the peppered
+    /// password schemes. It has to match the `pepper` of the Synapse
+    /// `password_config` to verify the hashes imported from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_pepper: Option<String>,
 }
 
 impl SecretsConfig {
@@ -228,6 +234,12 @@ impl SecretsConfig {
     pub fn encrypter(&self) -> Encrypter {
         Encrypter::new(&self.encryption)
     }
+
+    /// The server-side pepper of passwords, if any
+    #[must_use]
+    pub fn password_pepper(&self) -> Option<&str> {
+        self.password_pepper.as_deref()
+    }
 }
 
 #[async_trait]
@@ -274,6 +286,7 @@ impl ConfigurationSection<'_> for SecretsConfig {
         Ok(Self {
             encryption: rand::random(),
             keys: vec![rsa_key, ecdsa_key],
+            password_pepper: None,
         })
     }
 
@@ -313,6 +326,7 @@ impl ConfigurationSection<'_> for SecretsConfig {
         Self {
             encryption: [0xEA; 32],
             keys: vec![rsa_key, ecdsa_key],
+            password_pepper: None,
         }
     }
 }
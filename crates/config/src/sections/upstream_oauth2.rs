@@ -0,0 +1,197 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use url::Url;
+
+use super::ConfigurationSection;
+
+fn default_scope() -> String {
+    "openid".to_owned()
+}
+
+fn default_localpart_template() -> String {
+    "{{ user.preferred_username }}".to_owned()
+}
+
+fn default_displayname_template() -> String {
+    "{{ user.name }}".to_owned()
+}
+
+fn default_email_template() -> String {
+    "{{ user.email }}".to_owned()
+}
+
+/// How to derive the attributes of a new local user from the claims of the
+/// upstream ID token.
+///
+/// Each field is a template, where the claims are available under the `user`
+/// variable. An empty template disables the mapping of that attribute.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClaimsMappingConfig {
+    /// Template for the localpart of the user
+    #[serde(default = "default_localpart_template")]
+    pub localpart: String,
+
+    /// Template for the display name of the user
+    #[serde(default = "default_displayname_template")]
+    pub displayname: String,
+
+    /// Template for the email address of the user. It is imported as verified
+    /// only if the `email_verified` claim is true
+    #[serde(default = "default_email_template")]
+    pub email: String,
+}
+
+impl Default for ClaimsMappingConfig {
+    fn default() -> Self {
+        Self {
+            localpart: default_localpart_template(),
+            displayname: default_displayname_template(),
+            email: default_email_template(),
+        }
+    }
+}
+
+/// An upstream OIDC provider users can log in with
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UpstreamProviderConfig {
+    /// A unique identifier for the provider, used in URLs and advertised to
+    /// Matrix clients
+    pub id: String,
+
+    /// Human-readable name of the provider
+    pub name: String,
+
+    /// Optional icon of the provider, as an `mxc://` URI
+    pub icon: Option<String>,
+
+    /// Optional brand of the provider, which clients can use to style the
+    /// login button
+    pub brand: Option<String>,
+
+    /// The issuer of the provider, used for OIDC discovery
+    pub issuer: Url,
+
+    /// The client ID registered on the provider
+    pub client_id: String,
+
+    /// The client secret registered on the provider, if any
+    pub client_secret: Option<String>,
+
+    /// Space-separated list of scopes to request
+    #[serde(default = "default_scope")]
+    pub scope: String,
+
+    /// How to map the upstream claims when provisioning new users
+    #[serde(default)]
+    pub claims: ClaimsMappingConfig,
+}
+
+/// Configuration related to upstream identity providers
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UpstreamOAuth2Config {
+    /// List of upstream OIDC providers
+    #[serde(default)]
+    pub providers: Vec<UpstreamProviderConfig>,
+}
+
+impl UpstreamOAuth2Config {
+    /// Find a provider by its ID
+    #[must_use]
+    pub fn find_provider(&self, id: &str) -> Option<&UpstreamProviderConfig> {
+        self.providers.iter().find(|provider| provider.id == id)
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for UpstreamOAuth2Config {
+    fn path() -> &'static str {
+        "upstream_oauth2"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                  upstream_oauth2:
+                    providers:
+                      - id: google
+                        name: Google
+                        brand: google
+                        issuer: https://accounts.google.com/
+                        client_id: mas
+                        client_secret: secret
+                        scope: openid email
+                      - id: corporate
+                        name: Corporate SSO
+                        icon: mxc://example.com/corporate
+                        issuer: https://sso.example.com/
+                        client_id: mas
+                        claims:
+                          localpart: "{{ user.email | split(pat='@') | first }}"
+                          displayname: ""
+                "#,
+            )?;
+
+            let config = UpstreamOAuth2Config::load_from_file("config.yaml")?;
+
+            assert_eq!(config.providers.len(), 2);
+
+            let google = config.find_provider("google").unwrap();
+            assert_eq!(google.brand.as_deref(), Some("google"));
+            assert_eq!(google.scope, "openid email");
+            assert_eq!(google.claims.localpart, "{{ user.preferred_username }}");
+
+            let corporate = config.find_provider("corporate").unwrap();
+            assert_eq!(
+                corporate.icon.as_deref(),
+                Some("mxc://example.com/corporate")
+            );
+            assert_eq!(corporate.client_secret, None);
+            assert_eq!(corporate.scope, "openid");
+            assert_eq!(
+                corporate.claims.localpart,
+                "{{ user.email | split(pat='@') | first }}"
+            );
+            assert_eq!(corporate.claims.displayname, "");
+            assert_eq!(corporate.claims.email, "{{ user.email }}");
+
+            assert!(config.find_provider("unknown").is_none());
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+
+use super::{ClaimsMappingConfig, ConfigurationSection};
+
+/// The metadata of an identity provider, either embedded in the configuration
+/// or in a file
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataOrPath {
+    /// The XML metadata document
+    Metadata(String),
+
+    /// Path to a file holding the XML metadata document
+    MetadataPath(PathBuf),
+}
+
+/// An upstream SAML 2.0 identity provider users can log in with
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SamlProviderConfig {
+    /// A unique identifier for the provider, used in URLs and advertised to
+    /// Matrix clients. It must not clash with the ID of an upstream OIDC
+    /// provider, as both share the same account links
+    pub id: String,
+
+    /// Human-readable name of the provider
+    pub name: String,
+
+    /// Optional icon of the provider, as an `mxc://` URI
+    pub icon: Option<String>,
+
+    /// Optional brand of the provider, which clients can use to style the
+    /// login button
+    pub brand: Option<String>,
+
+    /// The metadata of the identity provider, which holds its signing
+    /// certificates
+    #[serde(flatten)]
+    pub metadata: MetadataOrPath,
+
+    /// Whether the email addresses released by the provider can be imported
+    /// as verified
+    #[serde(default)]
+    pub trust_email: bool,
+
+    /// How to map the attributes of the assertion when provisioning new
+    /// users. Well-known attributes are also available under their OIDC
+    /// claim names
+    #[serde(default)]
+    pub claims: ClaimsMappingConfig,
+}
+
+impl SamlProviderConfig {
+    /// Read the metadata document of the provider
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when the metadata file could not be read
+    pub async fn load_metadata(&self) -> anyhow::Result<String> {
+        match &self.metadata {
+            MetadataOrPath::Metadata(metadata) => Ok(metadata.clone()),
+            MetadataOrPath::MetadataPath(path) => Ok(tokio::fs::read_to_string(path).await?),
+        }
+    }
+}
+
+/// Configuration related to upstream SAML identity providers
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UpstreamSamlConfig {
+    /// List of upstream SAML identity providers
+    #[serde(default)]
+    pub providers: Vec<SamlProviderConfig>,
+}
+
+impl UpstreamSamlConfig {
+    /// Find a provider by its ID
+    #[must_use]
+    pub fn find_provider(&self, id: &str) -> Option<&SamlProviderConfig> {
+        self.providers.iter().find(|provider| provider.id == id)
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for UpstreamSamlConfig {
+    fn path() -> &'static str {
+        "upstream_saml"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                  upstream_saml:
+                    providers:
+                      - id: corporate
+                        name: Corporate SSO
+                        metadata_path: /etc/mas/corporate.xml
+                        trust_email: true
+                      - id: university
+                        name: University
+                        brand: university
+                        metadata: |
+                          <md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata"/>
+                        claims:
+                          localpart: "{{ user.uid }}"
+                "#,
+            )?;
+
+            let config = UpstreamSamlConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.providers.len(), 2);
+
+            let corporate = config.find_provider("corporate").unwrap();
+            assert!(matches!(
+                &corporate.metadata,
+                MetadataOrPath::MetadataPath(path) if path == &PathBuf::from("/etc/mas/corporate.xml")
+            ));
+            assert!(corporate.trust_email);
+            assert_eq!(corporate.claims.localpart, "{{ user.preferred_username }}");
+
+            let university = config.find_provider("university").unwrap();
+            assert!(matches!(university.metadata, MetadataOrPath::Metadata(_)));
+            assert!(!university.trust_email);
+            assert_eq!(university.brand.as_deref(), Some("university"));
+            assert_eq!(university.claims.localpart, "{{ user.uid }}");
+
+            assert!(config.find_provider("unknown").is_none());
+
+            Ok(())
+        });
+    }
+}
@@ -49,6 +49,14 @@ impl Device {
         format!("urn:matrix:device:{}", self.id).parse().unwrap()
     }
 
+    /// Get the [`Device`] out of a `urn:matrix:device:*` [`ScopeToken`], if it
+    /// is one
+    #[must_use]
+    pub fn from_scope_token(token: &ScopeToken) -> Option<Self> {
+        let id = token.strip_prefix("urn:matrix:device:")?;
+        Self::try_from(id.to_owned()).ok()
+    }
+
     /// Generate a random device ID
     pub fn generate<R: Rng + ?Sized>(rng: &mut R) -> Self {
         let id: String = Alphanumeric.sample_string(rng, DEVICE_ID_LENGTH);
@@ -86,6 +94,7 @@ pub struct CompatSession<T: StorageBackend> {
     pub data: T::CompatSessionData,
     pub user: User<T>,
     pub device: Device,
+    pub initial_device_display_name: Option<String>,
     pub created_at: DateTime<Utc>,
     pub deleted_at: Option<DateTime<Utc>>,
 }
@@ -96,6 +105,7 @@ impl<S: StorageBackendMarker> From<CompatSession<S>> for CompatSession<()> {
             data: (),
             user: t.user.into(),
             device: t.device,
+            initial_device_display_name: t.initial_device_display_name,
             created_at: t.created_at,
             deleted_at: t.deleted_at,
         }
@@ -31,16 +31,18 @@ pub(crate) mod users;
 pub use self::{
     compat::{
         CompatAccessToken, CompatRefreshToken, CompatSession, CompatSsoLogin, CompatSsoLoginState,
-        Device,
+        Device, InvalidDeviceID,
     },
     oauth2::{
-        AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, Client,
-        InvalidRedirectUriError, JwksOrJwksUri, Pkce, Session,
+        AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, CibaRequest,
+        CibaRequestState, Client, InvalidRedirectUriError, InvalidTransitionError, JwksOrJwksUri,
+        Pkce, Session,
     },
     tokens::{AccessToken, RefreshToken, TokenFormatError, TokenType},
     traits::{StorageBackend, StorageBackendMarker},
     users::{
-        Authentication, BrowserSession, User, UserEmail, UserEmailVerification,
-        UserEmailVerificationState,
+        Authentication, AuthenticationMethod, BrowserSession, InvalidAuthenticationMethodError,
+        InvalidSecondFactorError, SecondFactor, SessionExpiration, User, UserEmail,
+        UserEmailVerification, UserEmailVerificationState,
     },
 };
@@ -175,6 +175,7 @@ pub struct AuthorizationGrant<T: StorageBackend> {
     pub response_type_id_token: bool,
     pub created_at: DateTime<Utc>,
     pub requires_consent: bool,
+    pub login_hint: Option<String>,
 }
 
 impl<S: StorageBackendMarker> From<AuthorizationGrant<S>> for AuthorizationGrant<()> {
@@ -195,6 +196,7 @@ impl<S: StorageBackendMarker> From<AuthorizationGrant<S>> for AuthorizationGrant
             response_type_id_token: g.response_type_id_token,
             created_at: g.created_at,
             requires_consent: g.requires_consent,
+            login_hint: g.login_hint,
         }
     }
 }
@@ -0,0 +1,211 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Duration, Utc};
+use oauth2_types::scope::{Scope, OPENID};
+use serde::Serialize;
+
+use super::{authorization_grant::InvalidTransitionError, client::Client, session::Session};
+use crate::{
+    traits::{StorageBackend, StorageBackendMarker},
+    users::User,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(bound = "T: StorageBackend", tag = "state", rename_all = "lowercase")]
+pub enum CibaRequestState<T: StorageBackend> {
+    Pending,
+    Approved {
+        session: Session<T>,
+        approved_at: DateTime<Utc>,
+    },
+    Denied {
+        denied_at: DateTime<Utc>,
+    },
+    Exchanged {
+        session: Session<T>,
+        approved_at: DateTime<Utc>,
+        exchanged_at: DateTime<Utc>,
+    },
+}
+
+impl<T: StorageBackend> Default for CibaRequestState<T> {
+    fn default() -> Self {
+        Self::Pending
+    }
+}
+
+impl<T: StorageBackend> CibaRequestState<T> {
+    pub fn approve(
+        self,
+        approved_at: DateTime<Utc>,
+        session: Session<T>,
+    ) -> Result<Self, InvalidTransitionError> {
+        match self {
+            Self::Pending => Ok(Self::Approved {
+                session,
+                approved_at,
+            }),
+            _ => Err(InvalidTransitionError),
+        }
+    }
+
+    pub fn deny(self, denied_at: DateTime<Utc>) -> Result<Self, InvalidTransitionError> {
+        match self {
+            Self::Pending => Ok(Self::Denied { denied_at }),
+            _ => Err(InvalidTransitionError),
+        }
+    }
+
+    pub fn exchange(self, exchanged_at: DateTime<Utc>) -> Result<Self, InvalidTransitionError> {
+        match self {
+            Self::Approved {
+                session,
+                approved_at,
+            } => Ok(Self::Exchanged {
+                session,
+                approved_at,
+                exchanged_at,
+            }),
+            _ => Err(InvalidTransitionError),
+        }
+    }
+
+    /// Returns `true` if the request is still waiting for the user to act on
+    /// it.
+    #[must_use]
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::Pending)
+    }
+}
+
+impl<S: StorageBackendMarker> From<CibaRequestState<S>> for CibaRequestState<()> {
+    fn from(s: CibaRequestState<S>) -> Self {
+        use CibaRequestState::{Approved, Denied, Exchanged, Pending};
+        match s {
+            Pending => Pending,
+            Approved {
+                session,
+                approved_at,
+            } => Approved {
+                session: session.into(),
+                approved_at,
+            },
+            Denied { denied_at } => Denied { denied_at },
+            Exchanged {
+                session,
+                approved_at,
+                exchanged_at,
+            } => Exchanged {
+                session: session.into(),
+                approved_at,
+                exchanged_at,
+            },
+        }
+    }
+}
+
+/// A Client-Initiated Backchannel Authentication request, waiting for the
+/// user to approve it from one of their browser sessions
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(bound = "T: StorageBackend")]
+pub struct CibaRequest<T: StorageBackend> {
+    pub data: T::CibaRequestData,
+    #[serde(flatten)]
+    pub state: CibaRequestState<T>,
+    pub auth_req_id: String,
+    pub client: Client<T>,
+    pub user: User<T>,
+    pub scope: Scope,
+    pub binding_message: Option<String>,
+    pub client_notification_token: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl<S: StorageBackendMarker> From<CibaRequest<S>> for CibaRequest<()> {
+    fn from(r: CibaRequest<S>) -> Self {
+        CibaRequest {
+            data: (),
+            state: r.state.into(),
+            auth_req_id: r.auth_req_id,
+            client: r.client.into(),
+            user: r.user.into(),
+            scope: r.scope,
+            binding_message: r.binding_message,
+            client_notification_token: r.client_notification_token,
+            created_at: r.created_at,
+            expires_at: r.expires_at,
+        }
+    }
+}
+
+impl<T: StorageBackend> CibaRequest<T> {
+    #[must_use]
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+impl<T: StorageBackend> CibaRequest<T>
+where
+    T::CibaRequestData: Default,
+    T::ClientData: Default,
+    T::UserData: Default,
+{
+    #[must_use]
+    pub fn samples() -> Vec<Self> {
+        User::<T>::samples()
+            .into_iter()
+            .map(|user| CibaRequest {
+                data: Default::default(),
+                state: CibaRequestState::Pending,
+                auth_req_id: "abcdefghijklmnopqrstuvwxyz012345".to_string(),
+                client: Client {
+                    data: Default::default(),
+                    client_id: "support-desk".to_string(),
+                    encrypted_client_secret: None,
+                    redirect_uris: Vec::new(),
+                    response_types: Vec::new(),
+                    grant_types: Vec::new(),
+                    contacts: Vec::new(),
+                    client_name: Some("Support desk".to_string()),
+                    logo_uri: None,
+                    client_uri: None,
+                    policy_uri: None,
+                    tos_uri: None,
+                    jwks: None,
+                    id_token_signed_response_alg: None,
+                    id_token_encrypted_response_alg: None,
+                    id_token_encrypted_response_enc: None,
+                    userinfo_signed_response_alg: None,
+                    userinfo_encrypted_response_alg: None,
+                    userinfo_encrypted_response_enc: None,
+                    token_endpoint_auth_method: None,
+                    token_endpoint_auth_signing_alg: None,
+                    initiate_login_uri: None,
+                    authorization_signed_response_alg: None,
+                    backchannel_token_delivery_mode: None,
+                    backchannel_client_notification_endpoint: None,
+                },
+                user,
+                scope: [OPENID].into_iter().collect(),
+                binding_message: Some("W4SCT".to_string()),
+                client_notification_token: None,
+                created_at: Utc::now(),
+                expires_at: Utc::now() + Duration::minutes(5),
+            })
+            .collect()
+    }
+}
@@ -13,11 +13,11 @@
 // limitations under the License.
 
 use mas_iana::{
-    jose::JsonWebSignatureAlg,
+    jose::{JsonWebEncryptionAlg, JsonWebEncryptionEnc, JsonWebSignatureAlg},
     oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod},
 };
 use mas_jose::JsonWebKeySet;
-use oauth2_types::requests::GrantType;
+use oauth2_types::{oidc::BackchannelTokenDeliveryMode, requests::GrantType};
 use serde::Serialize;
 use thiserror::Error;
 use url::Url;
@@ -82,9 +82,23 @@ pub struct Client<T: StorageBackend> {
     /// Client
     pub id_token_signed_response_alg: Option<JsonWebSignatureAlg>,
 
+    /// JWE alg algorithm REQUIRED for encrypting the ID Token issued to this
+    /// Client
+    pub id_token_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
+
+    /// JWE enc algorithm REQUIRED for encrypting the ID Token issued to this
+    /// Client
+    pub id_token_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
+
     /// JWS alg algorithm REQUIRED for signing UserInfo Responses.
     pub userinfo_signed_response_alg: Option<JsonWebSignatureAlg>,
 
+    /// JWE alg algorithm REQUIRED for encrypting userinfo responses.
+    pub userinfo_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
+
+    /// JWE enc algorithm REQUIRED for encrypting userinfo responses.
+    pub userinfo_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
+
     /// Requested authentication method for the token endpoint
     pub token_endpoint_auth_method: Option<OAuthClientAuthenticationMethod>,
 
@@ -96,6 +110,18 @@ pub struct Client<T: StorageBackend> {
     /// URI using the https scheme that a third party can use to initiate a
     /// login by the RP
     pub initiate_login_uri: Option<Url>,
+
+    /// JWS alg algorithm REQUIRED for signing authorization responses when
+    /// using a JWT-secured response mode
+    pub authorization_signed_response_alg: Option<JsonWebSignatureAlg>,
+
+    /// How the Client expects to get the result of a backchannel
+    /// authentication request
+    pub backchannel_token_delivery_mode: Option<BackchannelTokenDeliveryMode>,
+
+    /// Endpoint to which the OP will post a notification after a successful
+    /// backchannel authentication, when using the ping delivery mode
+    pub backchannel_client_notification_endpoint: Option<Url>,
 }
 
 impl<S: StorageBackendMarker> From<Client<S>> for Client<()> {
@@ -115,10 +141,17 @@ impl<S: StorageBackendMarker> From<Client<S>> for Client<()> {
             tos_uri: c.tos_uri,
             jwks: c.jwks,
             id_token_signed_response_alg: c.id_token_signed_response_alg,
+            id_token_encrypted_response_alg: c.id_token_encrypted_response_alg,
+            id_token_encrypted_response_enc: c.id_token_encrypted_response_enc,
             userinfo_signed_response_alg: c.userinfo_signed_response_alg,
+            userinfo_encrypted_response_alg: c.userinfo_encrypted_response_alg,
+            userinfo_encrypted_response_enc: c.userinfo_encrypted_response_enc,
             token_endpoint_auth_method: c.token_endpoint_auth_method,
             token_endpoint_auth_signing_alg: c.token_endpoint_auth_signing_alg,
             initiate_login_uri: c.initiate_login_uri,
+            authorization_signed_response_alg: c.authorization_signed_response_alg,
+            backchannel_token_delivery_mode: c.backchannel_token_delivery_mode,
+            backchannel_client_notification_endpoint: c.backchannel_client_notification_endpoint,
         }
     }
 }
@@ -13,11 +13,16 @@
 // limitations under the License.
 
 pub(self) mod authorization_grant;
+pub(self) mod ciba;
 pub(self) mod client;
 pub(self) mod session;
 
 pub use self::{
-    authorization_grant::{AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, Pkce},
+    authorization_grant::{
+        AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, InvalidTransitionError,
+        Pkce,
+    },
+    ciba::{CibaRequest, CibaRequestState},
     client::{Client, InvalidRedirectUriError, JwksOrJwksUri},
     session::Session,
 };
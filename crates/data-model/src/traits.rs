@@ -32,6 +32,7 @@ pub trait StorageBackend {
     type ClientData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type SessionData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type AuthorizationGrantData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
+    type CibaRequestData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type AccessTokenData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type RefreshTokenData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type CompatAccessTokenData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
@@ -45,6 +46,7 @@ impl StorageBackend for () {
     type AuthenticationData = ();
     type AuthorizationGrantData = ();
     type BrowserSessionData = ();
+    type CibaRequestData = ();
     type ClientData = ();
     type CompatAccessTokenData = ();
     type CompatRefreshTokenData = ();
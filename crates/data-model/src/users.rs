@@ -12,8 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{net::IpAddr, str::FromStr};
+
 use chrono::{DateTime, Duration, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::traits::{StorageBackend, StorageBackendMarker};
 
@@ -52,12 +55,149 @@ impl<S: StorageBackendMarker> From<User<S>> for User<()> {
     }
 }
 
+/// How the user proved their identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticationMethod {
+    /// The user entered their password on the login page
+    Password,
+
+    /// The user confirmed their password on an already established session
+    Reauth,
+
+    /// The user logged in through an upstream OAuth 2.0 provider
+    UpstreamOAuth2,
+
+    /// The user logged in through an upstream SAML identity provider
+    UpstreamSaml,
+
+    /// The user logged in with a passkey, without a password
+    Webauthn,
+
+    /// The user logged in with a one-time code or link sent to their email
+    /// address
+    Email,
+}
+
+impl AuthenticationMethod {
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::Reauth => "reauth",
+            Self::UpstreamOAuth2 => "upstream_oauth2",
+            Self::UpstreamSaml => "upstream_saml",
+            Self::Webauthn => "webauthn",
+            Self::Email => "email",
+        }
+    }
+
+    /// The value of this method in the `amr` claim, as registered in RFC8176
+    #[must_use]
+    pub const fn amr(&self) -> &'static str {
+        match self {
+            Self::Password | Self::Reauth => "pwd",
+            Self::UpstreamOAuth2 | Self::UpstreamSaml => "fed",
+            Self::Webauthn => "hwk",
+            Self::Email => "otp",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid authentication method {0:?}")]
+pub struct InvalidAuthenticationMethodError(String);
+
+impl FromStr for AuthenticationMethod {
+    type Err = InvalidAuthenticationMethodError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "password" => Ok(Self::Password),
+            "reauth" => Ok(Self::Reauth),
+            "upstream_oauth2" => Ok(Self::UpstreamOAuth2),
+            "upstream_saml" => Ok(Self::UpstreamSaml),
+            "webauthn" => Ok(Self::Webauthn),
+            "email" => Ok(Self::Email),
+            _ => Err(InvalidAuthenticationMethodError(s.to_owned())),
+        }
+    }
+}
+
+/// An additional factor the user proved on top of their authentication method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecondFactor {
+    /// The user entered a code from their TOTP authenticator
+    Totp,
+
+    /// The user entered one of their single-use recovery codes
+    RecoveryCode,
+
+    /// The user used one of their WebAuthn credentials
+    Webauthn,
+}
+
+impl SecondFactor {
+    #[must_use]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Totp => "totp",
+            Self::RecoveryCode => "recovery_code",
+            Self::Webauthn => "webauthn",
+        }
+    }
+
+    /// The value of this factor in the `amr` claim, if RFC8176 registers one
+    #[must_use]
+    pub const fn amr(&self) -> Option<&'static str> {
+        match self {
+            Self::Totp => Some("otp"),
+            Self::RecoveryCode => None,
+            Self::Webauthn => Some("hwk"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("invalid second factor {0:?}")]
+pub struct InvalidSecondFactorError(String);
+
+impl FromStr for SecondFactor {
+    type Err = InvalidSecondFactorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "totp" => Ok(Self::Totp),
+            "recovery_code" => Ok(Self::RecoveryCode),
+            "webauthn" => Ok(Self::Webauthn),
+            _ => Err(InvalidSecondFactorError(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
 pub struct Authentication<T: StorageBackend> {
     #[serde(skip_serializing)]
     pub data: T::AuthenticationData,
     pub created_at: DateTime<Utc>,
+    pub method: AuthenticationMethod,
+    pub second_factor: Option<SecondFactor>,
+}
+
+impl<T: StorageBackend> Authentication<T> {
+    /// The values of the `amr` claim for this authentication, as registered in
+    /// RFC8176
+    #[must_use]
+    pub fn amr(&self) -> Vec<String> {
+        let mut amr = vec![self.method.amr().to_owned()];
+        if let Some(second_factor) = self.second_factor {
+            amr.extend(second_factor.amr().map(ToOwned::to_owned));
+            amr.push("mfa".to_owned());
+        }
+        amr
+    }
 }
 
 impl<S: StorageBackendMarker> From<Authentication<S>> for Authentication<()> {
@@ -65,10 +205,22 @@ impl<S: StorageBackendMarker> From<Authentication<S>> for Authentication<()> {
         Authentication {
             data: (),
             created_at: a.created_at,
+            method: a.method,
+            second_factor: a.second_factor,
         }
     }
 }
 
+/// Limits on how long a [`BrowserSession`] stays usable, fixed when it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SessionExpiration {
+    /// End the session after this long without any activity
+    pub idle_timeout: Option<Duration>,
+
+    /// End the session this long after it started, even if it is still used
+    pub max_lifetime: Option<Duration>,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
 pub struct BrowserSession<T: StorageBackend> {
@@ -76,6 +228,9 @@ pub struct BrowserSession<T: StorageBackend> {
     pub user: User<T>,
     pub created_at: DateTime<Utc>,
     pub last_authentication: Option<Authentication<T>>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<IpAddr>,
+    pub last_active_at: Option<DateTime<Utc>>,
 }
 
 impl<S: StorageBackendMarker> From<BrowserSession<S>> for BrowserSession<()> {
@@ -85,6 +240,9 @@ impl<S: StorageBackendMarker> From<BrowserSession<S>> for BrowserSession<()> {
             user: s.user.into(),
             created_at: s.created_at,
             last_authentication: s.last_authentication.map(Into::into),
+            user_agent: s.user_agent,
+            ip_address: s.ip_address,
+            last_active_at: s.last_active_at,
         }
     }
 }
@@ -113,6 +271,12 @@ where
                 user,
                 created_at: Utc::now(),
                 last_authentication: None,
+                user_agent: Some(
+                    "Mozilla/5.0 (X11; Linux x86_64; rv:101.0) Gecko/20100101 Firefox/101.0"
+                        .to_owned(),
+                ),
+                ip_address: Some(IpAddr::from([192, 0, 2, 1])),
+                last_active_at: Some(Utc::now()),
             })
             .collect()
     }
@@ -18,7 +18,10 @@ use lettre::{
     message::{Mailbox, MessageBuilder, MultiPart},
     AsyncTransport, Message,
 };
-use mas_templates::{EmailVerificationContext, Templates};
+use mas_templates::{
+    CibaRequestEmailContext, EmailLoginEmailContext, EmailVerificationContext,
+    PasswordResetEmailContext, Templates,
+};
 
 use crate::MailTransport;
 
@@ -99,4 +102,125 @@ impl Mailer {
         self.transport.send(message).await?;
         Ok(())
     }
+
+    async fn prepare_ciba_email(
+        &self,
+        to: Mailbox,
+        context: &CibaRequestEmailContext,
+    ) -> anyhow::Result<Message> {
+        let plain = self.templates.render_email_ciba_txt(context).await?;
+
+        let html = self.templates.render_email_ciba_html(context).await?;
+
+        let multipart = MultiPart::alternative_plain_html(plain, html);
+
+        let subject = self.templates.render_email_ciba_subject(context).await?;
+
+        let message = self
+            .base_message()
+            .subject(subject.trim())
+            .to(to)
+            .multipart(multipart)?;
+
+        Ok(message)
+    }
+
+    /// Notify a user that a client is waiting for them to approve a
+    /// backchannel authentication request
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the email failed rendering or failed sending
+    pub async fn send_ciba_email(
+        &self,
+        to: Mailbox,
+        context: &CibaRequestEmailContext,
+    ) -> anyhow::Result<()> {
+        let message = self.prepare_ciba_email(to, context).await?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+
+    async fn prepare_password_reset_email(
+        &self,
+        to: Mailbox,
+        context: &PasswordResetEmailContext,
+    ) -> anyhow::Result<Message> {
+        let plain = self
+            .templates
+            .render_email_password_reset_txt(context)
+            .await?;
+
+        let html = self
+            .templates
+            .render_email_password_reset_html(context)
+            .await?;
+
+        let multipart = MultiPart::alternative_plain_html(plain, html);
+
+        let subject = self
+            .templates
+            .render_email_password_reset_subject(context)
+            .await?;
+
+        let message = self
+            .base_message()
+            .subject(subject.trim())
+            .to(to)
+            .multipart(multipart)?;
+
+        Ok(message)
+    }
+
+    /// Send a password reset link to a user
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the email failed rendering or failed sending
+    pub async fn send_password_reset_email(
+        &self,
+        to: Mailbox,
+        context: &PasswordResetEmailContext,
+    ) -> anyhow::Result<()> {
+        let message = self.prepare_password_reset_email(to, context).await?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+
+    async fn prepare_login_email(
+        &self,
+        to: Mailbox,
+        context: &EmailLoginEmailContext,
+    ) -> anyhow::Result<Message> {
+        let plain = self.templates.render_email_login_txt(context).await?;
+
+        let html = self.templates.render_email_login_html(context).await?;
+
+        let multipart = MultiPart::alternative_plain_html(plain, html);
+
+        let subject = self.templates.render_email_login_subject(context).await?;
+
+        let message = self
+            .base_message()
+            .subject(subject.trim())
+            .to(to)
+            .multipart(multipart)?;
+
+        Ok(message)
+    }
+
+    /// Send a one-time sign-in code and link to a user
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the email failed rendering or failed sending
+    pub async fn send_login_email(
+        &self,
+        to: Mailbox,
+        context: &EmailLoginEmailContext,
+    ) -> anyhow::Result<()> {
+        let message = self.prepare_login_email(to, context).await?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
 }
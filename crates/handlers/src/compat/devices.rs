@@ -0,0 +1,310 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{extract::Path, response::IntoResponse, Extension, Json, TypedHeader};
+use headers::{authorization::Bearer, Authorization};
+use hyper::StatusCode;
+use mas_data_model::{CompatSession, Device, TokenFormatError, TokenType};
+use mas_storage::{
+    compat::{
+        end_compat_sessions_for_device, get_active_compat_sessions,
+        lookup_active_compat_access_token, CompatAccessTokenLookupError,
+    },
+    PostgresqlBackend,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use sqlx::{PgConnection, PgPool};
+use thiserror::Error;
+use tracing::warn;
+
+use super::MatrixError;
+use crate::HomeserverConnection;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("missing access token")]
+    MissingAuthorization,
+
+    #[error("invalid access token")]
+    InvalidAuthorization,
+
+    #[error("device not found")]
+    DeviceNotFound,
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<TokenFormatError> for RouteError {
+    fn from(_e: TokenFormatError) -> Self {
+        Self::InvalidAuthorization
+    }
+}
+
+impl From<CompatAccessTokenLookupError> for RouteError {
+    fn from(e: CompatAccessTokenLookupError) -> Self {
+        if e.not_found() {
+            Self::InvalidAuthorization
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Internal(_) | Self::Anyhow(_) => MatrixError {
+                errcode: "M_UNKNOWN",
+                error: "Internal server error",
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::MissingAuthorization => MatrixError {
+                errcode: "M_MISSING_TOKEN",
+                error: "Missing access token",
+                status: StatusCode::UNAUTHORIZED,
+            },
+            Self::InvalidAuthorization => MatrixError {
+                errcode: "M_UNKNOWN_TOKEN",
+                error: "Invalid access token",
+                status: StatusCode::UNAUTHORIZED,
+            },
+            Self::DeviceNotFound => MatrixError {
+                errcode: "M_NOT_FOUND",
+                error: "Device not found",
+                status: StatusCode::NOT_FOUND,
+            },
+        }
+        .into_response()
+    }
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct DeviceInfo {
+    device_id: Device,
+    display_name: Option<String>,
+}
+
+impl From<CompatSession<PostgresqlBackend>> for DeviceInfo {
+    fn from(session: CompatSession<PostgresqlBackend>) -> Self {
+        Self {
+            device_id: session.device,
+            display_name: session.initial_device_display_name,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DevicesResponse {
+    devices: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteDevicesRequest {
+    devices: Vec<String>,
+}
+
+/// Find the session associated with the access token of the request
+async fn authenticate(
+    conn: &mut PgConnection,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<CompatSession<PostgresqlBackend>, RouteError> {
+    let TypedHeader(authorization) = maybe_authorization.ok_or(RouteError::MissingAuthorization)?;
+
+    let token = authorization.token();
+    if TokenType::check(token)? != TokenType::CompatAccessToken {
+        return Err(RouteError::InvalidAuthorization);
+    }
+
+    let (_, session) = lookup_active_compat_access_token(conn, token).await?;
+    Ok(session)
+}
+
+/// End the session of a device, returning whether there was one
+async fn delete_device(
+    conn: &mut PgConnection,
+    homeserver: &HomeserverConnection,
+    session: &CompatSession<PostgresqlBackend>,
+    device_id: String,
+) -> Result<bool, RouteError> {
+    let device = match Device::try_from(device_id) {
+        Ok(device) => device,
+        // An invalid device ID can't match any existing device
+        Err(_) => return Ok(false),
+    };
+
+    let ended = end_compat_sessions_for_device(conn, &session.user, &device).await?;
+    if ended == 0 {
+        return Ok(false);
+    }
+
+    if let Err(e) = homeserver
+        .delete_device(&session.user.username, &device)
+        .await
+    {
+        warn!(error = %e, "Failed to delete device on the homeserver");
+    }
+
+    Ok(true)
+}
+
+pub(crate) async fn list(
+    Extension(pool): Extension<PgPool>,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    let session = authenticate(&mut conn, maybe_authorization).await?;
+
+    let sessions = get_active_compat_sessions(&mut conn, &session.user).await?;
+    let devices = sessions.into_iter().map(DeviceInfo::from).collect();
+
+    Ok(Json(DevicesResponse { devices }))
+}
+
+pub(crate) async fn delete(
+    Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Path((_version, device_id)): Path<(String, String)>,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    let session = authenticate(&mut conn, maybe_authorization).await?;
+
+    if !delete_device(&mut conn, &homeserver, &session, device_id).await? {
+        return Err(RouteError::DeviceNotFound);
+    }
+
+    Ok(Json(serde_json::json!({})))
+}
+
+pub(crate) async fn delete_many(
+    Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(input): Json<DeleteDevicesRequest>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    let session = authenticate(&mut conn, maybe_authorization).await?;
+
+    // Devices which don't exist are ignored, as per the spec
+    for device_id in input.devices {
+        delete_device(&mut conn, &homeserver, &session, device_id).await?;
+    }
+
+    Ok(Json(serde_json::json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_config::MatrixConfig;
+    use mas_storage::{
+        compat::{add_compat_access_token, start_compat_session},
+        test_utils::{random_name, test_transaction},
+        user::register_passwordless_user,
+    };
+    use rand::thread_rng;
+
+    use super::*;
+
+    fn device(id: &str) -> Device {
+        Device::try_from(id.to_owned()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_delete_device() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        // Without a secret, nothing is sent to the homeserver
+        let homeserver = HomeserverConnection::new(&MatrixConfig::default());
+
+        let alice = register_passwordless_user(&mut txn, &random_name("alice"))
+            .await
+            .unwrap();
+        let bob = register_passwordless_user(&mut txn, &random_name("bob"))
+            .await
+            .unwrap();
+        let phone = start_compat_session(&mut txn, alice.clone(), device("PHONE00001"), None)
+            .await
+            .unwrap();
+        start_compat_session(&mut txn, alice, device("LAPTOP0001"), None)
+            .await
+            .unwrap();
+        start_compat_session(&mut txn, bob.clone(), device("BOBPHONE01"), None)
+            .await
+            .unwrap();
+
+        let token = TokenType::CompatAccessToken.generate(&mut thread_rng());
+        add_compat_access_token(&mut txn, &phone, token.clone(), None)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            authenticate(&mut txn, None).await,
+            Err(RouteError::MissingAuthorization)
+        ));
+        let authorization = Authorization::bearer(&token).unwrap();
+        let session = authenticate(&mut txn, Some(TypedHeader(authorization)))
+            .await
+            .unwrap();
+        assert_eq!(session.data, phone.data);
+
+        // Devices of other users, and invalid device IDs, are not found
+        for device_id in ["BOBPHONE01", "not a device"] {
+            let deleted = delete_device(&mut txn, &homeserver, &session, device_id.to_owned())
+                .await
+                .unwrap();
+            assert!(!deleted);
+        }
+        assert_eq!(
+            get_active_compat_sessions(&mut txn, &bob)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let deleted = delete_device(&mut txn, &homeserver, &session, "LAPTOP0001".to_owned())
+            .await
+            .unwrap();
+        assert!(deleted);
+        let deleted = delete_device(&mut txn, &homeserver, &session, "LAPTOP0001".to_owned())
+            .await
+            .unwrap();
+        assert!(!deleted);
+
+        let sessions = get_active_compat_sessions(&mut txn, &session.user)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].device, device("PHONE00001"));
+    }
+}
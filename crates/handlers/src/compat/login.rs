@@ -12,17 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{response::IntoResponse, Extension, Json};
-use chrono::{Duration, Utc};
+use std::sync::Arc;
+
+use axum::{response::IntoResponse, Extension, Json, TypedHeader};
+use chrono::{DateTime, Duration, Utc};
+use headers::{authorization::Bearer, Authorization};
 use hyper::StatusCode;
-use mas_config::MatrixConfig;
-use mas_data_model::{CompatSession, CompatSsoLoginState, Device, TokenType};
+use mas_axum_utils::SessionClient;
+use mas_config::{
+    AppServicesConfig, MatrixConfig, SamlProviderConfig, UpstreamOAuth2Config,
+    UpstreamProviderConfig, UpstreamSamlConfig,
+};
+use mas_data_model::{CompatSession, CompatSsoLoginState, Device, InvalidDeviceID, TokenType};
+use mas_policy::PolicyFactory;
 use mas_storage::{
     compat::{
         add_compat_access_token, add_compat_refresh_token, compat_login,
-        get_compat_sso_login_by_token, mark_compat_sso_login_as_exchanged,
-        CompatSsoLoginLookupError,
+        end_compat_sessions_for_device, get_compat_sso_login_by_token,
+        mark_compat_sso_login_as_exchanged, start_compat_session, CompatSsoLoginLookupError,
     },
+    login_throttle::{clear_login_failures, login_blocked_until, record_login_failure},
+    password::{PasswordBackends, PasswordSchemes},
+    user::{lookup_user_by_username, UserLookupError},
     PostgresqlBackend,
 };
 use rand::thread_rng;
@@ -30,8 +41,13 @@ use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, skip_serializing_none, DurationMilliSeconds};
 use sqlx::{PgPool, Postgres, Transaction};
 use thiserror::Error;
+use tracing::{info, warn};
 
-use super::MatrixError;
+use super::{LimitExceededError, MatrixError};
+use crate::{
+    views::{login::check_provisioned_user, second_factor::user_has_second_factor},
+    HomeserverConnection,
+};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -51,6 +67,9 @@ enum LoginType {
     #[serde(rename = "m.login.token")]
     Token,
 
+    #[serde(rename = "m.login.application_service")]
+    ApplicationService,
+
     #[serde(rename = "m.login.sso")]
     Sso {
         #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -59,10 +78,35 @@ enum LoginType {
     },
 }
 
+#[skip_serializing_none]
 #[derive(Debug, Serialize)]
 struct SsoIdentityProvider {
-    id: &'static str,
-    name: &'static str,
+    id: String,
+    name: String,
+    icon: Option<String>,
+    brand: Option<String>,
+}
+
+impl From<&UpstreamProviderConfig> for SsoIdentityProvider {
+    fn from(provider: &UpstreamProviderConfig) -> Self {
+        Self {
+            id: provider.id.clone(),
+            name: provider.name.clone(),
+            icon: provider.icon.clone(),
+            brand: provider.brand.clone(),
+        }
+    }
+}
+
+impl From<&SamlProviderConfig> for SsoIdentityProvider {
+    fn from(provider: &SamlProviderConfig) -> Self {
+        Self {
+            id: provider.id.clone(),
+            name: provider.name.clone(),
+            icon: provider.icon.clone(),
+            brand: provider.brand.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -70,17 +114,31 @@ struct LoginTypes {
     flows: Vec<LoginType>,
 }
 
-pub(crate) async fn get() -> impl IntoResponse {
+pub(crate) async fn get(
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+) -> impl IntoResponse {
     let res = LoginTypes {
         flows: vec![
             LoginType::Password {
-                actions: vec![Action::Login],
+                actions: vec![Action::Login, Action::Register],
             },
             LoginType::Sso {
-                identity_providers: vec![],
+                identity_providers: upstream_oauth2
+                    .providers
+                    .iter()
+                    .map(SsoIdentityProvider::from)
+                    .chain(
+                        upstream_saml
+                            .providers
+                            .iter()
+                            .map(SsoIdentityProvider::from),
+                    )
+                    .collect(),
                 actions: vec![Action::Login, Action::Register],
             },
             LoginType::Token,
+            LoginType::ApplicationService,
         ],
     };
 
@@ -94,6 +152,10 @@ pub struct RequestBody {
 
     #[serde(default)]
     refresh_token: bool,
+
+    device_id: Option<String>,
+
+    initial_device_display_name: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,6 +170,9 @@ pub enum Credentials {
     #[serde(rename = "m.login.token")]
     Token { token: String },
 
+    #[serde(rename = "m.login.application_service")]
+    ApplicationService { identifier: Identifier },
+
     #[serde(other)]
     Unsupported,
 }
@@ -148,11 +213,32 @@ pub enum RouteError {
     #[error("login failed")]
     LoginFailed,
 
+    #[error("too many failed attempts")]
+    LimitExceeded { blocked_until: DateTime<Utc> },
+
+    #[error("user has a second factor enabled")]
+    SecondFactorRequired,
+
+    #[error("user from the password backend is not allowed to register")]
+    ProvisioningDenied,
+
     #[error("login took too long")]
     LoginTookTooLong,
 
     #[error("invalid login token")]
     InvalidLoginToken,
+
+    #[error("invalid device ID")]
+    InvalidDeviceId(#[from] InvalidDeviceID),
+
+    #[error("missing access token")]
+    MissingAuthorization,
+
+    #[error("invalid application service token")]
+    InvalidAppServiceToken,
+
+    #[error("user is not in the namespace of the application service")]
+    UserNotInNamespace,
 }
 
 impl From<sqlx::Error> for RouteError {
@@ -161,6 +247,16 @@ impl From<sqlx::Error> for RouteError {
     }
 }
 
+impl From<UserLookupError> for RouteError {
+    fn from(e: UserLookupError) -> Self {
+        if e.not_found() {
+            Self::LoginFailed
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
 impl From<CompatSsoLoginLookupError> for RouteError {
     fn from(e: CompatSsoLoginLookupError) -> Self {
         if e.not_found() {
@@ -174,6 +270,9 @@ impl From<CompatSsoLoginLookupError> for RouteError {
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         match self {
+            Self::LimitExceeded { blocked_until } => {
+                return LimitExceededError::new(blocked_until - Utc::now()).into_response()
+            }
             Self::Internal(_) | Self::Anyhow(_) => MatrixError {
                 errcode: "M_UNKNOWN",
                 error: "Internal server error",
@@ -189,6 +288,16 @@ impl IntoResponse for RouteError {
                 error: "Invalid username/password",
                 status: StatusCode::FORBIDDEN,
             },
+            Self::SecondFactorRequired => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "Two-factor authentication is enabled, please log in through SSO",
+                status: StatusCode::FORBIDDEN,
+            },
+            Self::ProvisioningDenied => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "This user is not allowed to register",
+                status: StatusCode::FORBIDDEN,
+            },
             Self::LoginTookTooLong => MatrixError {
                 errcode: "M_UNAUTHORIZED",
                 error: "Login token expired",
@@ -199,15 +308,43 @@ impl IntoResponse for RouteError {
                 error: "Invalid login token",
                 status: StatusCode::FORBIDDEN,
             },
+            Self::MissingAuthorization => MatrixError {
+                errcode: "M_MISSING_TOKEN",
+                error: "Missing access token",
+                status: StatusCode::UNAUTHORIZED,
+            },
+            Self::InvalidAppServiceToken => MatrixError {
+                errcode: "M_UNKNOWN_TOKEN",
+                error: "Invalid application service token",
+                status: StatusCode::UNAUTHORIZED,
+            },
+            Self::UserNotInNamespace => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "User is not in the namespace of the application service",
+                status: StatusCode::FORBIDDEN,
+            },
+            Self::InvalidDeviceId(_) => MatrixError {
+                errcode: "M_INVALID_PARAM",
+                error: "Invalid device ID",
+                status: StatusCode::BAD_REQUEST,
+            },
         }
         .into_response()
     }
 }
 
 #[tracing::instrument(skip_all, err)]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(config): Extension<MatrixConfig>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(appservices): Extension<AppServicesConfig>,
+    Extension(password_backends): Extension<PasswordBackends>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    client: SessionClient,
     Json(input): Json<RequestBody>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut txn = pool.begin().await?;
@@ -215,10 +352,62 @@ pub(crate) async fn post(
         Credentials::Password {
             identifier: Identifier::User { user },
             password,
-        } => user_password_login(&mut txn, user, password).await?,
+        } => {
+            let ip = client.ip_address;
+
+            // Refuse to check the password while there were too many failed
+            // attempts
+            if let Some(blocked_until) = login_blocked_until(&mut txn, &user, ip).await? {
+                return Err(RouteError::LimitExceeded { blocked_until });
+            }
+
+            let device = requested_device(input.device_id)?;
+            let res = user_password_login(
+                &mut txn,
+                &password_backends,
+                &password_schemes,
+                &policy_factory,
+                &homeserver,
+                &user,
+                &password,
+                device,
+                input.initial_device_display_name,
+            )
+            .await;
+
+            let session = match res {
+                // Failed attempts have to be saved even though the login failed
+                Err(RouteError::LoginFailed) => {
+                    record_login_failure(&mut txn, &user, ip).await?;
+                    txn.commit().await?;
+                    return Err(RouteError::LoginFailed);
+                }
+                res => res?,
+            };
+            clear_login_failures(&mut txn, &user).await?;
+            session
+        }
 
         Credentials::Token { token } => token_login(&mut txn, &token).await?,
 
+        Credentials::ApplicationService {
+            identifier: Identifier::User { user },
+        } => {
+            let TypedHeader(authorization) =
+                maybe_authorization.ok_or(RouteError::MissingAuthorization)?;
+            let device = requested_device(input.device_id)?;
+            appservice_login(
+                &mut txn,
+                &appservices,
+                &config.homeserver,
+                authorization.token(),
+                user,
+                device,
+                input.initial_device_display_name,
+            )
+            .await?
+        }
+
         _ => {
             return Err(RouteError::Unsupported);
         }
@@ -249,6 +438,13 @@ pub(crate) async fn post(
 
     txn.commit().await?;
 
+    if let Err(e) = homeserver
+        .create_device(&session.user.username, &session.device)
+        .await
+    {
+        warn!(error = %e, "Failed to create device on the homeserver");
+    }
+
     Ok(Json(ResponseBody {
         access_token: access_token.token,
         device_id: session.device,
@@ -258,6 +454,14 @@ pub(crate) async fn post(
     }))
 }
 
+/// Re-use the device the client asked for, else generate a new one
+fn requested_device(device_id: Option<String>) -> Result<Device, InvalidDeviceID> {
+    match device_id {
+        Some(device_id) => Device::try_from(device_id),
+        None => Ok(Device::generate(&mut thread_rng())),
+    }
+}
+
 async fn token_login(
     txn: &mut Transaction<'_, Postgres>,
     token: &str,
@@ -299,15 +503,96 @@ async fn token_login(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn user_password_login(
     txn: &mut Transaction<'_, Postgres>,
-    username: String,
-    password: String,
+    password_backends: &PasswordBackends,
+    password_schemes: &PasswordSchemes,
+    policy_factory: &PolicyFactory,
+    homeserver: &HomeserverConnection,
+    username: &str,
+    password: &str,
+    device: Device,
+    initial_device_display_name: Option<String>,
 ) -> Result<CompatSession<PostgresqlBackend>, RouteError> {
-    let device = Device::generate(&mut thread_rng());
-    let session = compat_login(txn, &username, &password, device)
-        .await
-        .map_err(|_| RouteError::LoginFailed)?;
+    let (session, provisioned) = compat_login(
+        &mut *txn,
+        password_backends,
+        password_schemes,
+        username,
+        password,
+        device,
+        initial_device_display_name,
+    )
+    .await
+    .map_err(|_| RouteError::LoginFailed)?;
+
+    // The user was just created from an external password backend, which is
+    // only kept if it would have been allowed to register. Returning an error
+    // rolls the transaction back.
+    if let Some(external) = &provisioned {
+        if !check_provisioned_user(policy_factory, external)
+            .await?
+            .is_empty()
+        {
+            return Err(RouteError::ProvisioningDenied);
+        }
+    }
+
+    // The password alone isn't enough for users who enrolled a second factor.
+    // They can still log in through the SSO flow, which asks for it.
+    if user_has_second_factor(&mut *txn, &session.user).await? {
+        return Err(RouteError::SecondFactorRequired);
+    }
+
+    // The user was just created from an external password backend
+    if let Some(external) = provisioned {
+        if let Err(e) = homeserver
+            .provision_user(&session.user.username, external.displayname.as_deref())
+            .await
+        {
+            warn!(error = %e, "Failed to provision user on the homeserver");
+        }
+    }
+
+    Ok(session)
+}
+
+async fn appservice_login(
+    txn: &mut Transaction<'_, Postgres>,
+    appservices: &AppServicesConfig,
+    server_name: &str,
+    token: &str,
+    user: String,
+    device: Device,
+    initial_device_display_name: Option<String>,
+) -> Result<CompatSession<PostgresqlBackend>, RouteError> {
+    let appservice = appservices
+        .find_by_token(token)
+        .ok_or(RouteError::InvalidAppServiceToken)?;
+
+    // The user can either be given as a localpart or as a full user ID
+    let username = match user.strip_prefix('@') {
+        Some(user_id) => user_id
+            .strip_suffix(&format!(":{}", server_name))
+            .ok_or(RouteError::UserNotInNamespace)?
+            .to_owned(),
+        None => user,
+    };
+
+    let user_id = format!("@{}:{}", username, server_name);
+    if !appservice.is_interested_in_user(&user_id, server_name) {
+        return Err(RouteError::UserNotInNamespace);
+    }
+
+    let user = lookup_user_by_username(&mut *txn, &username).await?;
+
+    // Logging in again with the same device replaces the previous session
+    end_compat_sessions_for_device(&mut *txn, &user, &device).await?;
+    let session =
+        start_compat_session(&mut *txn, user, device, initial_device_display_name).await?;
+
+    info!(appservice.id = %appservice.id, %user_id, "Application service logged in a user");
 
     Ok(session)
 }
@@ -13,10 +13,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+    Extension,
+};
 use hyper::StatusCode;
-use mas_router::{CompatLoginSsoComplete, UrlBuilder};
-use mas_storage::compat::insert_compat_sso_login;
+use mas_config::{UpstreamOAuth2Config, UpstreamSamlConfig};
+use mas_data_model::CompatSsoLogin;
+use mas_router::{
+    CompatLoginSsoComplete, PostAuthAction, UpstreamOAuth2Authorize, UpstreamSamlLogin, UrlBuilder,
+};
+use mas_storage::{compat::insert_compat_sso_login, PostgresqlBackend};
 use rand::{
     distributions::{Alphanumeric, DistString},
     thread_rng,
@@ -45,6 +53,9 @@ pub enum RouteError {
 
     #[error("invalid redirect_url")]
     InvalidRedirectUrl,
+
+    #[error("unknown identity provider")]
+    UnknownIdp,
 }
 
 impl From<sqlx::Error> for RouteError {
@@ -55,16 +66,21 @@ impl From<sqlx::Error> for RouteError {
 
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self)).into_response()
+        let status = match self {
+            Self::Internal(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MissingRedirectUrl | Self::InvalidRedirectUrl => StatusCode::BAD_REQUEST,
+            Self::UnknownIdp => StatusCode::NOT_FOUND,
+        };
+
+        (status, format!("{}", self)).into_response()
     }
 }
 
-#[tracing::instrument(skip(pool, url_builder), err)]
-pub async fn get(
-    Extension(pool): Extension<PgPool>,
-    Extension(url_builder): Extension<UrlBuilder>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, RouteError> {
+/// Validate the `redirectUrl` parameter and start a new compat SSO login
+async fn start_sso_login(
+    pool: &PgPool,
+    params: Params,
+) -> Result<CompatSsoLogin<PostgresqlBackend>, RouteError> {
     // Check the redirectUrl parameter
     let redirect_url = params.redirect_url.ok_or(RouteError::MissingRedirectUrl)?;
     let redirect_url = Url::parse(&redirect_url).map_err(|_| RouteError::InvalidRedirectUrl)?;
@@ -82,6 +98,42 @@ pub async fn get(
     let token = Alphanumeric.sample_string(&mut thread_rng(), 32);
     let mut conn = pool.acquire().await?;
     let login = insert_compat_sso_login(&mut conn, token, redirect_url).await?;
+    Ok(login)
+}
 
+#[tracing::instrument(skip(pool, url_builder), err)]
+pub async fn get(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Query(params): Query<Params>,
+) -> Result<impl IntoResponse, RouteError> {
+    let login = start_sso_login(&pool, params).await?;
     Ok(url_builder.absolute_redirect(&CompatLoginSsoComplete(login.data)))
 }
+
+/// Same as [`get`], but sends the user straight to the chosen upstream
+/// provider instead of showing the login page
+#[tracing::instrument(skip(pool, url_builder, upstream_oauth2, upstream_saml), err)]
+pub async fn get_idp(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Path((_, idp)): Path<(String, String)>,
+    Query(params): Query<Params>,
+) -> Result<impl IntoResponse, RouteError> {
+    let is_oidc = upstream_oauth2.find_provider(&idp).is_some();
+    if !is_oidc && upstream_saml.find_provider(&idp).is_none() {
+        return Err(RouteError::UnknownIdp);
+    }
+
+    let login = start_sso_login(&pool, params).await?;
+    let action = PostAuthAction::continue_compat_sso_login(login.data);
+
+    let destination = if is_oidc {
+        url_builder.absolute_redirect(&UpstreamOAuth2Authorize::new(idp).and_then(action))
+    } else {
+        url_builder.absolute_redirect(&UpstreamSamlLogin::new(idp).and_then(action))
+    };
+    Ok(destination)
+}
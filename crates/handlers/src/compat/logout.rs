@@ -16,10 +16,15 @@ use axum::{response::IntoResponse, Extension, Json, TypedHeader};
 use headers::{authorization::Bearer, Authorization};
 use hyper::StatusCode;
 use mas_data_model::{TokenFormatError, TokenType};
-use mas_storage::compat::compat_logout;
+use mas_storage::{
+    compat::{compat_logout, end_compat_sessions_for_user, lookup_active_compat_access_token},
+    oauth2::end_oauth_sessions_for_user,
+};
 use sqlx::PgPool;
+use tracing::warn;
 
 use super::MatrixError;
+use crate::HomeserverConnection;
 
 pub enum RouteError {
     Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
@@ -57,6 +62,12 @@ impl From<sqlx::Error> for RouteError {
     }
 }
 
+impl From<anyhow::Error> for RouteError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
 impl From<TokenFormatError> for RouteError {
     fn from(_e: TokenFormatError) -> Self {
         Self::InvalidAuthorization
@@ -65,6 +76,7 @@ impl From<TokenFormatError> for RouteError {
 
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
     maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut conn = pool.acquire().await?;
@@ -78,9 +90,63 @@ pub(crate) async fn post(
         return Err(RouteError::InvalidAuthorization);
     }
 
+    let (_, session) = lookup_active_compat_access_token(&mut conn, token)
+        .await
+        .map_err(|_| RouteError::LogoutFailed)?;
+
     compat_logout(&mut conn, token)
         .await
         .map_err(|_| RouteError::LogoutFailed)?;
 
+    if let Err(e) = homeserver
+        .delete_device(&session.user.username, &session.device)
+        .await
+    {
+        warn!(error = %e, "Failed to delete device on the homeserver");
+    }
+
+    Ok(Json(serde_json::json!({})))
+}
+
+/// End every session of the user, both through the compatibility layer and
+/// through OAuth
+pub(crate) async fn post_all(
+    Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut txn = pool.begin().await?;
+
+    let TypedHeader(authorization) = maybe_authorization.ok_or(RouteError::MissingAuthorization)?;
+
+    let token = authorization.token();
+    let token_type = TokenType::check(token)?;
+
+    if token_type != TokenType::CompatAccessToken {
+        return Err(RouteError::InvalidAuthorization);
+    }
+
+    let (_, session) = lookup_active_compat_access_token(&mut txn, token)
+        .await
+        .map_err(|_| RouteError::LogoutFailed)?;
+    let user = session.user;
+
+    let devices = end_compat_sessions_for_user(&mut txn, &user).await?;
+    let scopes = end_oauth_sessions_for_user(&mut txn, &user).await?;
+
+    txn.commit().await?;
+
+    for device in &devices {
+        if let Err(e) = homeserver.delete_device(&user.username, device).await {
+            warn!(error = %e, "Failed to delete device on the homeserver");
+        }
+    }
+
+    for scope in &scopes {
+        homeserver
+            .delete_devices_for_scope(&user.username, scope)
+            .await;
+    }
+
     Ok(Json(serde_json::json!({})))
 }
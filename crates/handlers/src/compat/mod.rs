@@ -13,14 +13,18 @@
 // limitations under the License.
 
 use axum::{response::IntoResponse, Json};
+use chrono::Duration;
 use hyper::StatusCode;
 use serde::Serialize;
+use serde_with::{serde_as, DurationMilliSeconds};
 
+pub(crate) mod devices;
 pub(crate) mod login;
 pub(crate) mod login_sso_complete;
 pub(crate) mod login_sso_redirect;
 pub(crate) mod logout;
 pub(crate) mod refresh;
+pub(crate) mod register;
 
 #[derive(Debug, Serialize)]
 struct MatrixError {
@@ -35,3 +39,30 @@ impl IntoResponse for MatrixError {
         (self.status, Json(self)).into_response()
     }
 }
+
+/// An `M_LIMIT_EXCEEDED` error, telling the client how long to wait before
+/// trying again
+#[serde_as]
+#[derive(Debug, Serialize)]
+struct LimitExceededError {
+    errcode: &'static str,
+    error: &'static str,
+    #[serde_as(as = "DurationMilliSeconds<i64>")]
+    retry_after_ms: Duration,
+}
+
+impl LimitExceededError {
+    fn new(retry_after: Duration) -> Self {
+        Self {
+            errcode: "M_LIMIT_EXCEEDED",
+            error: "Too many failed attempts",
+            retry_after_ms: retry_after.max(Duration::zero()),
+        }
+    }
+}
+
+impl IntoResponse for LimitExceededError {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::TOO_MANY_REQUESTS, Json(self)).into_response()
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use axum::{extract::Query, response::IntoResponse, Extension, Json};
+use chrono::Duration;
+use hyper::StatusCode;
+use mas_config::MatrixConfig;
+use mas_data_model::{Device, TokenType};
+use mas_policy::PolicyFactory;
+use mas_storage::{
+    compat::{add_compat_access_token, add_compat_refresh_token, start_compat_session},
+    password::PasswordSchemes,
+    user::{register_user, username_exists},
+};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, skip_serializing_none, DurationMilliSeconds};
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::MatrixError;
+use crate::{homeserver::is_valid_localpart, HomeserverConnection};
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RegistrationKind {
+    User,
+    Guest,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterParams {
+    kind: Option<RegistrationKind>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestBody {
+    username: Option<String>,
+    password: Option<String>,
+    device_id: Option<String>,
+    initial_device_display_name: Option<String>,
+
+    #[serde(default)]
+    inhibit_login: bool,
+
+    #[serde(default)]
+    refresh_token: bool,
+}
+
+#[skip_serializing_none]
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct ResponseBody {
+    user_id: String,
+    access_token: Option<String>,
+    device_id: Option<Device>,
+    refresh_token: Option<String>,
+    #[serde_as(as = "Option<DurationMilliSeconds<i64>>")]
+    expires_in_ms: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvailableParams {
+    username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AvailableResponse {
+    available: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("missing parameter")]
+    MissingParam,
+
+    #[error("guest registration is not supported")]
+    GuestAccessForbidden,
+
+    #[error("username is already taken")]
+    UserInUse,
+
+    #[error("invalid username")]
+    InvalidUsername,
+
+    #[error("registration denied by policy")]
+    Forbidden,
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Internal(_) | Self::Anyhow(_) => MatrixError {
+                errcode: "M_UNKNOWN",
+                error: "Internal server error",
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Self::MissingParam => MatrixError {
+                errcode: "M_MISSING_PARAM",
+                error: "Missing username or password",
+                status: StatusCode::BAD_REQUEST,
+            },
+            Self::GuestAccessForbidden => MatrixError {
+                errcode: "M_GUEST_ACCESS_FORBIDDEN",
+                error: "Guest access is not supported",
+                status: StatusCode::FORBIDDEN,
+            },
+            Self::UserInUse => MatrixError {
+                errcode: "M_USER_IN_USE",
+                error: "Username is already taken",
+                status: StatusCode::BAD_REQUEST,
+            },
+            Self::InvalidUsername => MatrixError {
+                errcode: "M_INVALID_USERNAME",
+                error: "Invalid username",
+                status: StatusCode::BAD_REQUEST,
+            },
+            Self::Forbidden => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "Registration is not allowed",
+                status: StatusCode::FORBIDDEN,
+            },
+        }
+        .into_response()
+    }
+}
+
+/// Check that a username is valid, not taken yet, and that the policy does not
+/// have anything against it
+async fn check_username(
+    conn: &mut sqlx::PgConnection,
+    policy_factory: &PolicyFactory,
+    username: &str,
+    password: &str,
+) -> Result<(), RouteError> {
+    if !is_valid_localpart(username) {
+        return Err(RouteError::InvalidUsername);
+    }
+
+    if username_exists(&mut *conn, username).await? {
+        return Err(RouteError::UserInUse);
+    }
+
+    let mut policy = policy_factory.instantiate().await?;
+    let res = policy
+        .evaluate_register(username, Some(password), None)
+        .await?;
+
+    for violation in &res.violations {
+        info!(field = ?violation.field, message = %violation.msg, "Registration denied by policy");
+    }
+
+    if res
+        .violations
+        .iter()
+        .any(|v| v.field.as_deref() == Some("username"))
+    {
+        return Err(RouteError::InvalidUsername);
+    }
+
+    if !res.valid() {
+        return Err(RouteError::Forbidden);
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all, err)]
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(config): Extension<MatrixConfig>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Query(params): Query<RegisterParams>,
+    Json(input): Json<RequestBody>,
+) -> Result<impl IntoResponse, RouteError> {
+    if params.kind == Some(RegistrationKind::Guest) {
+        return Err(RouteError::GuestAccessForbidden);
+    }
+
+    let (username, password) = match (input.username, input.password) {
+        (Some(username), Some(password)) => (username, password),
+        _ => return Err(RouteError::MissingParam),
+    };
+
+    let mut txn = pool.begin().await?;
+
+    check_username(&mut txn, &policy_factory, &username, &password).await?;
+
+    let user = register_user(&mut txn, &password_schemes, &username, &password).await?;
+    let user_id = format!("@{}:{}", user.username, config.homeserver);
+
+    if input.inhibit_login {
+        txn.commit().await?;
+
+        if let Err(e) = homeserver.provision_user(&username, None).await {
+            warn!(error = %e, "Failed to provision user on the homeserver");
+        }
+
+        return Ok(Json(ResponseBody {
+            user_id,
+            access_token: None,
+            device_id: None,
+            refresh_token: None,
+            expires_in_ms: None,
+        }));
+    }
+
+    // Use the device ID the client asked for if it is valid, else generate one
+    let device = input
+        .device_id
+        .and_then(|d| Device::try_from(d).ok())
+        .unwrap_or_else(|| Device::generate(&mut thread_rng()));
+
+    let session =
+        start_compat_session(&mut txn, user, device, input.initial_device_display_name).await?;
+
+    // If the client asked for a refreshable token, make it expire
+    let expires_in = if input.refresh_token {
+        // TODO: this should be configurable
+        Some(Duration::minutes(5))
+    } else {
+        None
+    };
+
+    let access_token = TokenType::CompatAccessToken.generate(&mut thread_rng());
+    let access_token =
+        add_compat_access_token(&mut txn, &session, access_token, expires_in).await?;
+
+    let refresh_token = if input.refresh_token {
+        let refresh_token = TokenType::CompatRefreshToken.generate(&mut thread_rng());
+        let refresh_token =
+            add_compat_refresh_token(&mut txn, &session, &access_token, refresh_token).await?;
+        Some(refresh_token.token)
+    } else {
+        None
+    };
+
+    txn.commit().await?;
+
+    if let Err(e) = homeserver.provision_user(&username, None).await {
+        warn!(error = %e, "Failed to provision user on the homeserver");
+    } else if let Err(e) = homeserver.create_device(&username, &session.device).await {
+        warn!(error = %e, "Failed to create device on the homeserver");
+    }
+
+    Ok(Json(ResponseBody {
+        user_id,
+        access_token: Some(access_token.token),
+        device_id: Some(session.device),
+        refresh_token,
+        expires_in_ms: expires_in,
+    }))
+}
+
+#[tracing::instrument(skip_all, err)]
+pub(crate) async fn available(
+    Extension(pool): Extension<PgPool>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Query(params): Query<AvailableParams>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    // There is no password to check here, so only the username violations are
+    // relevant
+    match check_username(&mut conn, &policy_factory, &params.username, "").await {
+        Ok(()) | Err(RouteError::Forbidden) => {}
+        Err(e) => return Err(e),
+    }
+
+    Ok(Json(AvailableResponse { available: true }))
+}
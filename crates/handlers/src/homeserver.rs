@@ -0,0 +1,302 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client for the homeserver admin API, used to provision users and devices
+
+use anyhow::Context;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Method, Request,
+};
+use mas_config::MatrixConfig;
+use mas_data_model::Device;
+use oauth2_types::scope::Scope;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use tracing::warn;
+use url::Url;
+
+/// A connection to the homeserver admin API
+#[derive(Debug, Clone)]
+pub struct HomeserverConnection {
+    server_name: String,
+    endpoint: Url,
+    secret: Option<String>,
+}
+
+impl HomeserverConnection {
+    /// Create a connection from the Matrix configuration
+    #[must_use]
+    pub fn new(config: &MatrixConfig) -> Self {
+        Self {
+            server_name: config.homeserver.clone(),
+            endpoint: config.endpoint.clone(),
+            secret: config.secret.clone(),
+        }
+    }
+
+    /// Get the Matrix ID of a user
+    #[must_use]
+    pub fn mxid(&self, username: &str) -> String {
+        format!("@{}:{}", username, self.server_name)
+    }
+
+    fn user_url(&self, username: &str, rest: &[&str]) -> anyhow::Result<Url> {
+        let mxid = self.mxid(username);
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .map_err(|()| anyhow::anyhow!("homeserver endpoint can't be a base"))?
+            .pop_if_empty()
+            .extend(["_synapse", "admin", "v2", "users", &mxid])
+            .extend(rest);
+        Ok(url)
+    }
+
+    async fn call(
+        &self,
+        operation: &'static str,
+        method: Method,
+        url: Url,
+        body: Option<Value>,
+    ) -> anyhow::Result<()> {
+        let secret = if let Some(secret) = &self.secret {
+            secret
+        } else {
+            // Provisioning is disabled
+            return Ok(());
+        };
+
+        let body = match body {
+            Some(body) => Body::from(serde_json::to_vec(&body)?),
+            None => Body::empty(),
+        };
+
+        let request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(AUTHORIZATION, format!("Bearer {}", secret))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)?;
+
+        let response = mas_http::client(operation)
+            .oneshot(request)
+            .await
+            .context("could not reach the homeserver")?;
+
+        anyhow::ensure!(
+            response.status().is_success(),
+            "homeserver replied with status {}",
+            response.status()
+        );
+
+        Ok(())
+    }
+
+    /// Create the user on the homeserver, or update it if it already exists
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the homeserver could not be reached or rejected
+    /// the request
+    pub async fn provision_user(
+        &self,
+        username: &str,
+        displayname: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let url = self.user_url(username, &[])?;
+        let body = match displayname {
+            Some(displayname) => json!({ "displayname": displayname }),
+            None => json!({}),
+        };
+
+        self.call("homeserver-provision-user", Method::PUT, url, Some(body))
+            .await
+    }
+
+    /// Update the display name of a user on the homeserver
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the homeserver could not be reached or rejected
+    /// the request
+    pub async fn set_displayname(&self, username: &str, displayname: &str) -> anyhow::Result<()> {
+        self.provision_user(username, Some(displayname)).await
+    }
+
+    /// Create a device for a user on the homeserver
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the homeserver could not be reached or rejected
+    /// the request
+    pub async fn create_device(&self, username: &str, device: &Device) -> anyhow::Result<()> {
+        let url = self.user_url(username, &["devices"])?;
+        let body = json!({ "device_id": device.as_str() });
+
+        self.call("homeserver-create-device", Method::POST, url, Some(body))
+            .await
+    }
+
+    /// Delete a device of a user on the homeserver
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the homeserver could not be reached or rejected
+    /// the request
+    pub async fn delete_device(&self, username: &str, device: &Device) -> anyhow::Result<()> {
+        let url = self.user_url(username, &["devices", device.as_str()])?;
+
+        self.call("homeserver-delete-device", Method::DELETE, url, None)
+            .await
+    }
+
+    /// Create the devices for all the `urn:matrix:device:*` tokens in a scope.
+    /// Failures are logged, but don't stop the session from starting.
+    pub async fn create_devices_for_scope(&self, username: &str, scope: &Scope) {
+        for device in scope.iter().filter_map(Device::from_scope_token) {
+            if let Err(e) = self.create_device(username, &device).await {
+                warn!(error = %e, device = device.as_str(), "Failed to create device on the homeserver");
+            }
+        }
+    }
+
+    /// Delete the devices for all the `urn:matrix:device:*` tokens in a scope.
+    /// Failures are logged, but don't stop the session from ending.
+    pub async fn delete_devices_for_scope(&self, username: &str, scope: &Scope) {
+        for device in scope.iter().filter_map(Device::from_scope_token) {
+            if let Err(e) = self.delete_device(username, &device).await {
+                warn!(error = %e, device = device.as_str(), "Failed to delete device on the homeserver");
+            }
+        }
+    }
+}
+
+/// Check that a username only contains the characters allowed in a Matrix
+/// user ID localpart
+#[must_use]
+pub fn is_valid_localpart(username: &str) -> bool {
+    !username.is_empty()
+        && username
+            .chars()
+            .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, TcpListener},
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{http::HeaderMap, routing::any, Router};
+
+    use super::*;
+
+    type Calls = Arc<Mutex<Vec<(Method, String, Option<String>, String)>>>;
+
+    #[test]
+    fn localpart_validation() {
+        assert!(is_valid_localpart("alice"));
+        assert!(is_valid_localpart("alice.bob_42=/-"));
+        assert!(!is_valid_localpart(""));
+        assert!(!is_valid_localpart("Alice"));
+        assert!(!is_valid_localpart("alice:example.com"));
+        assert!(!is_valid_localpart("al ice"));
+    }
+
+    /// Start a fake homeserver which records the calls it receives
+    fn mock_homeserver() -> (SocketAddr, Calls) {
+        let calls: Calls = Arc::default();
+        let recorded = calls.clone();
+        let app = Router::new().fallback(any(
+            move |method: Method, uri: hyper::Uri, headers: HeaderMap, body: String| {
+                let recorded = recorded.clone();
+                async move {
+                    let auth = headers
+                        .get(AUTHORIZATION)
+                        .and_then(|h| h.to_str().ok())
+                        .map(ToOwned::to_owned);
+                    recorded
+                        .lock()
+                        .unwrap()
+                        .push((method, uri.path().to_owned(), auth, body));
+                    "{}"
+                }
+            },
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+
+        (addr, calls)
+    }
+
+    fn connection(addr: SocketAddr, secret: Option<&str>) -> HomeserverConnection {
+        HomeserverConnection::new(&MatrixConfig {
+            homeserver: "example.com".to_owned(),
+            endpoint: Url::parse(&format!("http://{}/", addr)).unwrap(),
+            secret: secret.map(ToOwned::to_owned),
+        })
+    }
+
+    #[tokio::test]
+    async fn provision_user_and_devices() {
+        let (addr, calls) = mock_homeserver();
+        let homeserver = connection(addr, Some("shared-secret"));
+
+        homeserver
+            .provision_user("alice", Some("Alice"))
+            .await
+            .unwrap();
+
+        let scope: Scope = "openid urn:matrix:device:ABCDEFGHIJ".parse().unwrap();
+        homeserver.create_devices_for_scope("alice", &scope).await;
+        homeserver.delete_devices_for_scope("alice", &scope).await;
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 3);
+
+        let (method, path, auth, body) = &calls[0];
+        assert_eq!(method, Method::PUT);
+        assert_eq!(path, "/_synapse/admin/v2/users/@alice:example.com");
+        assert_eq!(auth.as_deref(), Some("Bearer shared-secret"));
+        assert_eq!(body, r#"{"displayname":"Alice"}"#);
+
+        let (method, path, _, body) = &calls[1];
+        assert_eq!(method, Method::POST);
+        assert_eq!(path, "/_synapse/admin/v2/users/@alice:example.com/devices");
+        assert_eq!(body, r#"{"device_id":"ABCDEFGHIJ"}"#);
+
+        let (method, path, _, _) = &calls[2];
+        assert_eq!(method, Method::DELETE);
+        assert_eq!(
+            path,
+            "/_synapse/admin/v2/users/@alice:example.com/devices/ABCDEFGHIJ"
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_without_secret() {
+        let (addr, calls) = mock_homeserver();
+        let homeserver = connection(addr, None);
+
+        homeserver.provision_user("alice", None).await.unwrap();
+
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
@@ -19,23 +19,28 @@
     clippy::unused_async // Some axum handlers need that
 )]
 
-use std::{convert::Infallible, sync::Arc, time::Duration};
+use std::{convert::Infallible, net::IpAddr, sync::Arc, time::Duration};
 
 use axum::{
     body::HttpBody,
     extract::Extension,
     response::{Html, IntoResponse},
-    routing::{get, on, post, MethodFilter},
+    routing::{delete, get, on, post, MethodFilter},
     Router,
 };
 use headers::HeaderName;
 use hyper::header::{ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, CONTENT_TYPE};
-use mas_config::{Encrypter, MatrixConfig};
+use mas_axum_utils::{client_jwks::ClientJwksCache, TrustedProxies};
+use mas_config::{
+    AppServicesConfig, AuthenticationConfig, Encrypter, MatrixConfig, UpstreamOAuth2Config,
+    UpstreamSamlConfig,
+};
 use mas_email::Mailer;
 use mas_http::CorsLayerExt;
 use mas_jose::StaticKeystore;
 use mas_policy::PolicyFactory;
 use mas_router::{Route, UrlBuilder};
+use mas_storage::password::{PasswordBackends, PasswordSchemes};
 use mas_templates::{ErrorContext, Templates};
 use sqlx::PgPool;
 use tower::util::ThenLayer;
@@ -43,8 +48,15 @@ use tower_http::cors::{Any, CorsLayer};
 
 mod compat;
 mod health;
+pub mod homeserver;
 mod oauth2;
+mod totp;
+mod upstream_oauth2;
+mod upstream_saml;
 mod views;
+mod webauthn;
+
+pub use self::homeserver::HomeserverConnection;
 
 #[must_use]
 #[allow(
@@ -60,7 +72,15 @@ pub fn router<B>(
     mailer: &Mailer,
     url_builder: &UrlBuilder,
     matrix_config: &MatrixConfig,
+    homeserver: &HomeserverConnection,
     policy_factory: &Arc<PolicyFactory>,
+    authentication_config: &AuthenticationConfig,
+    appservices_config: &AppServicesConfig,
+    upstream_oauth2_config: &UpstreamOAuth2Config,
+    upstream_saml_config: &UpstreamSamlConfig,
+    password_backends: &PasswordBackends,
+    password_schemes: &PasswordSchemes,
+    trusted_proxies: &[IpAddr],
 ) -> Router<B>
 where
     B: HttpBody + Send + 'static,
@@ -104,6 +124,10 @@ where
             mas_router::OAuth2RegistrationEndpoint::route(),
             post(self::oauth2::registration::post),
         )
+        .route(
+            mas_router::OAuth2BackchannelAuthentication::route(),
+            post(self::oauth2::backchannel::post),
+        )
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -127,10 +151,34 @@ where
             mas_router::CompatLogout::route(),
             post(self::compat::logout::post),
         )
+        .route(
+            mas_router::CompatLogoutAll::route(),
+            post(self::compat::logout::post_all),
+        )
+        .route(
+            mas_router::CompatDevices::route(),
+            get(self::compat::devices::list),
+        )
+        .route(
+            mas_router::CompatDevice::route(),
+            delete(self::compat::devices::delete),
+        )
+        .route(
+            mas_router::CompatDeleteDevices::route(),
+            post(self::compat::devices::delete_many),
+        )
         .route(
             mas_router::CompatRefresh::route(),
             post(self::compat::refresh::post),
         )
+        .route(
+            mas_router::CompatRegister::route(),
+            post(self::compat::register::post),
+        )
+        .route(
+            mas_router::CompatRegisterAvailable::route(),
+            get(self::compat::register::available),
+        )
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
@@ -155,20 +203,73 @@ where
                 mas_router::Login::route(),
                 get(self::views::login::get).post(self::views::login::post),
             )
+            .route(
+                mas_router::EmailLogin::route(),
+                get(self::views::email_login::get).post(self::views::email_login::post),
+            )
+            .route(
+                mas_router::EmailLoginLink::route(),
+                get(self::views::email_login::get_link),
+            )
+            .route(
+                mas_router::WebauthnLogin::route(),
+                get(self::views::login::get_webauthn).post(self::views::login::post_webauthn),
+            )
             .route(mas_router::Logout::route(), post(self::views::logout::post))
             .route(
                 mas_router::Reauth::route(),
                 get(self::views::reauth::get).post(self::views::reauth::post),
             )
+            .route(
+                mas_router::SecondFactor::route(),
+                get(self::views::second_factor::get).post(self::views::second_factor::post),
+            )
+            .route(
+                mas_router::SecondFactorWebauthn::route(),
+                get(self::views::second_factor::get_webauthn)
+                    .post(self::views::second_factor::post_webauthn),
+            )
+            .route(
+                mas_router::SelectAccount::route(),
+                get(self::views::select_account::get).post(self::views::select_account::post),
+            )
             .route(
                 mas_router::Register::route(),
                 get(self::views::register::get).post(self::views::register::post),
             )
+            .route(
+                mas_router::PasswordForgot::route(),
+                get(self::views::password::forgot::get).post(self::views::password::forgot::post),
+            )
+            .route(
+                mas_router::PasswordReset::route(),
+                get(self::views::password::reset::get).post(self::views::password::reset::post),
+            )
             .route(mas_router::Account::route(), get(self::views::account::get))
+            .route(
+                mas_router::AccountBackchannelRequest::route(),
+                post(self::views::account::backchannel::post),
+            )
             .route(
                 mas_router::AccountPassword::route(),
                 get(self::views::account::password::get).post(self::views::account::password::post),
             )
+            .route(
+                mas_router::AccountTotp::route(),
+                get(self::views::account::totp::get).post(self::views::account::totp::post),
+            )
+            .route(
+                mas_router::AccountWebauthn::route(),
+                get(self::views::account::webauthn::get).post(self::views::account::webauthn::post),
+            )
+            .route(
+                mas_router::AccountWebauthnOptions::route(),
+                get(self::views::account::webauthn::get_options),
+            )
+            .route(
+                mas_router::AccountSessions::route(),
+                get(self::views::account::sessions::get).post(self::views::account::sessions::post),
+            )
             .route(
                 mas_router::AccountEmails::route(),
                 get(self::views::account::emails::get).post(self::views::account::emails::post),
@@ -201,7 +302,36 @@ where
             )
             .route(
                 mas_router::CompatLoginSsoRedirectIdp::route(),
-                get(self::compat::login_sso_redirect::get),
+                get(self::compat::login_sso_redirect::get_idp),
+            )
+            .route(
+                mas_router::UpstreamOAuth2Authorize::route(),
+                get(self::upstream_oauth2::authorize::get),
+            )
+            .route(
+                mas_router::UpstreamOAuth2Callback::route(),
+                get(self::upstream_oauth2::callback::get),
+            )
+            .route(
+                mas_router::UpstreamOAuth2Register::route(),
+                get(self::upstream_oauth2::register::get)
+                    .post(self::upstream_oauth2::register::post),
+            )
+            .route(
+                mas_router::UpstreamSamlMetadata::route(),
+                get(self::upstream_saml::metadata::get),
+            )
+            .route(
+                mas_router::UpstreamSamlLogin::route(),
+                get(self::upstream_saml::login::get),
+            )
+            .route(
+                mas_router::UpstreamSamlAcs::route(),
+                post(self::upstream_saml::acs::post),
+            )
+            .route(
+                mas_router::UpstreamSamlComplete::route(),
+                post(self::upstream_saml::acs::complete),
             )
             .route(
                 mas_router::CompatLoginSsoComplete::route(),
@@ -236,8 +366,19 @@ where
         .layer(Extension(templates.clone()))
         .layer(Extension(key_store.clone()))
         .layer(Extension(encrypter.clone()))
+        .layer(Extension(ClientJwksCache::default()))
         .layer(Extension(url_builder.clone()))
         .layer(Extension(mailer.clone()))
+        .layer(Extension(homeserver.clone()))
         .layer(Extension(matrix_config.clone()))
         .layer(Extension(policy_factory.clone()))
+        .layer(Extension(authentication_config.clone()))
+        .layer(Extension(appservices_config.clone()))
+        .layer(Extension(upstream_oauth2_config.clone()))
+        .layer(Extension(upstream_saml_config.clone()))
+        .layer(Extension(password_backends.clone()))
+        .layer(Extension(password_schemes.clone()))
+        .layer(Extension(TrustedProxies::new(
+            trusted_proxies.iter().copied(),
+        )))
 }
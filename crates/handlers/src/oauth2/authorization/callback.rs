@@ -17,10 +17,14 @@
 use std::collections::HashMap;
 
 use axum::response::{Html, IntoResponse, Redirect, Response};
-use mas_data_model::{AuthorizationGrant, StorageBackend};
+use chrono::{DateTime, Duration, Utc};
+use mas_data_model::{AuthorizationGrant, Client, StorageBackend};
+use mas_iana::jose::JsonWebSignatureAlg;
+use mas_jose::{DecodedJsonWebToken, SigningKeystore, StaticKeystore};
 use mas_templates::{FormPostContext, Templates};
 use oauth2_types::requests::ResponseMode;
 use serde::Serialize;
+use serde_with::{serde_as, skip_serializing_none, TimestampSeconds};
 use thiserror::Error;
 use url::Url;
 
@@ -33,11 +37,20 @@ enum CallbackDestinationMode {
     FormPost,
 }
 
+/// Parameters used to wrap the response in a signed JWT, as defined by JARM
+#[derive(Debug, Clone)]
+struct JwtResponse {
+    audience: String,
+    signing_alg: JsonWebSignatureAlg,
+}
+
 #[derive(Debug, Clone)]
 pub struct CallbackDestination {
     mode: CallbackDestinationMode,
     safe_redirect_uri: Url,
     state: Option<String>,
+    issuer: Url,
+    jwt: Option<JwtResponse>,
 }
 
 #[derive(Debug, Error)]
@@ -56,32 +69,45 @@ pub enum CallbackDestinationError {
 
     #[error("Failed to serialize parameters query string")]
     ParamsSerialization(#[from] serde_urlencoded::ser::Error),
-}
 
-impl<S: StorageBackend> TryFrom<&AuthorizationGrant<S>> for CallbackDestination {
-    type Error = InvalidRedirectUriError;
+    #[error("Failed to sign the authorization response")]
+    Signing(#[from] anyhow::Error),
+}
 
-    fn try_from(value: &AuthorizationGrant<S>) -> Result<Self, Self::Error> {
+impl CallbackDestination {
+    pub fn try_from_grant<S: StorageBackend>(
+        grant: &AuthorizationGrant<S>,
+        issuer: Url,
+    ) -> Result<Self, InvalidRedirectUriError> {
         Self::try_new(
-            value.response_mode,
-            value.redirect_uri.clone(),
-            value.state.clone(),
+            &grant.client,
+            grant.response_mode,
+            grant.redirect_uri.clone(),
+            grant.state.clone(),
+            issuer,
         )
     }
-}
 
-impl CallbackDestination {
-    pub fn try_new(
+    pub fn try_new<S: StorageBackend>(
+        client: &Client<S>,
         mode: ResponseMode,
         mut redirect_uri: Url,
         state: Option<String>,
+        issuer: Url,
     ) -> Result<Self, InvalidRedirectUriError> {
         if redirect_uri.fragment().is_some() {
             return Err(InvalidRedirectUriError::FragmentNotAllowed);
         }
 
+        let jwt = mode.is_jwt().then(|| JwtResponse {
+            audience: client.client_id.clone(),
+            signing_alg: client
+                .authorization_signed_response_alg
+                .unwrap_or(JsonWebSignatureAlg::Rs256),
+        });
+
         let mode = match mode {
-            ResponseMode::Query => {
+            ResponseMode::Query | ResponseMode::QueryJwt | ResponseMode::Jwt => {
                 let existing_params = redirect_uri
                     .query()
                     .map(serde_urlencoded::from_str)
@@ -93,20 +119,23 @@ impl CallbackDestination {
 
                 CallbackDestinationMode::Query { existing_params }
             }
-            ResponseMode::Fragment => CallbackDestinationMode::Fragment,
-            ResponseMode::FormPost => CallbackDestinationMode::FormPost,
+            ResponseMode::Fragment | ResponseMode::FragmentJwt => CallbackDestinationMode::Fragment,
+            ResponseMode::FormPost | ResponseMode::FormPostJwt => CallbackDestinationMode::FormPost,
         };
 
         Ok(Self {
             mode,
             safe_redirect_uri: redirect_uri,
             state,
+            issuer,
+            jwt,
         })
     }
 
     pub async fn go<T: Serialize>(
         self,
         templates: &Templates,
+        key_store: &StaticKeystore,
         params: T,
     ) -> Result<Response, CallbackDestinationError> {
         #[derive(Serialize)]
@@ -117,18 +146,65 @@ impl CallbackDestination {
             #[serde(skip_serializing_if = "Option::is_none")]
             state: Option<String>,
 
+            #[serde(skip_serializing_if = "Option::is_none")]
+            iss: Option<Url>,
+
+            #[serde(flatten)]
+            params: T,
+        }
+
+        #[derive(Serialize)]
+        #[serde(untagged)]
+        enum ResponseParams<T> {
+            Plain(T),
+            Jwt { response: String },
+        }
+
+        #[serde_as]
+        #[skip_serializing_none]
+        #[derive(Serialize)]
+        struct JwtClaims<T> {
+            iss: Url,
+            aud: String,
+            #[serde_as(as = "TimestampSeconds<i64>")]
+            exp: DateTime<Utc>,
+            state: Option<String>,
             #[serde(flatten)]
             params: T,
         }
 
         let mut redirect_uri = self.safe_redirect_uri;
-        let state = self.state;
+
+        // With JARM, the whole response, including the state, is wrapped in a signed
+        // JWT which is then sent as the single `response` parameter. Otherwise, the
+        // issuer is sent alongside the other parameters to mitigate mix-up attacks
+        // (RFC 9207).
+        let (state, iss, params) = if let Some(jwt) = self.jwt {
+            let claims = JwtClaims {
+                iss: self.issuer,
+                aud: jwt.audience,
+                exp: Utc::now() + Duration::minutes(10),
+                state: self.state,
+                params,
+            };
+
+            let header = key_store.prepare_header(jwt.signing_alg).await?;
+            let response = DecodedJsonWebToken::new(header, claims)
+                .sign(key_store)
+                .await?
+                .serialize();
+
+            (None, None, ResponseParams::Jwt { response })
+        } else {
+            (self.state, Some(self.issuer), ResponseParams::Plain(params))
+        };
 
         match self.mode {
             CallbackDestinationMode::Query { existing_params } => {
                 let merged = AllParams {
                     existing: Some(&existing_params),
                     state,
+                    iss,
                     params,
                 };
 
@@ -143,6 +219,7 @@ impl CallbackDestination {
                 let merged = AllParams {
                     existing: None,
                     state,
+                    iss,
                     params,
                 };
 
@@ -157,6 +234,7 @@ impl CallbackDestination {
                 let merged = AllParams {
                     existing: None,
                     state,
+                    iss,
                     params,
                 };
                 let ctx = FormPostContext::new(redirect_uri, merged);
@@ -166,3 +244,95 @@ impl CallbackDestination {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::LOCATION;
+    use mas_config::TemplatesConfig;
+    use mas_jose::JsonWebTokenParts;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Params {
+        code: String,
+    }
+
+    fn jarm_destination(signing_alg: JsonWebSignatureAlg) -> CallbackDestination {
+        CallbackDestination {
+            mode: CallbackDestinationMode::Query {
+                existing_params: HashMap::new(),
+            },
+            safe_redirect_uri: "https://client.example.com/callback".parse().unwrap(),
+            state: Some("xyz".to_owned()),
+            issuer: "https://example.com/".parse().unwrap(),
+            jwt: Some(JwtResponse {
+                audience: "client-id".to_owned(),
+                signing_alg,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jwt_response() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_rsa_key().unwrap();
+
+        let params = Params {
+            code: "abcd".to_owned(),
+        };
+        let response = jarm_destination(JsonWebSignatureAlg::Rs256)
+            .go(&templates, &key_store, params)
+            .await
+            .unwrap();
+
+        let location: Url = response.headers()[LOCATION]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let query: HashMap<String, String> = location.query_pairs().into_owned().collect();
+
+        // Everything is in the signed response, nothing is sent in clear
+        assert_eq!(query.len(), 1);
+        let jwt: JsonWebTokenParts = query["response"].parse().unwrap();
+        let decoded = jwt.decode::<serde_json::Value>().unwrap();
+        assert_eq!(decoded.header().alg(), JsonWebSignatureAlg::Rs256);
+        jwt.verify(decoded.header(), &key_store).await.unwrap();
+
+        let claims = decoded.claims();
+        assert_eq!(claims["iss"], "https://example.com/");
+        assert_eq!(claims["aud"], "client-id");
+        assert_eq!(claims["state"], "xyz");
+        assert_eq!(claims["code"], "abcd");
+        // Expires in ten minutes, give or take the rounding to the second
+        let exp = claims["exp"].as_i64().unwrap();
+        let now = Utc::now().timestamp();
+        assert!((now + 599..=now + 601).contains(&exp));
+    }
+
+    #[tokio::test]
+    async fn test_jwt_response_unsupported_alg() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_rsa_key().unwrap();
+
+        // Registration refuses algorithms the key store can't sign with, and
+        // signing with them fails instead of sending an unsigned response
+        let alg = JsonWebSignatureAlg::Es256;
+        assert!(!key_store.supported_algorithms().contains(&alg));
+
+        let params = Params {
+            code: "abcd".to_owned(),
+        };
+        let res = jarm_destination(alg)
+            .go(&templates, &key_store, params)
+            .await;
+        assert!(matches!(res, Err(CallbackDestinationError::Signing(_))));
+    }
+}
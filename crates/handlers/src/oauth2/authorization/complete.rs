@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{collections::HashSet, sync::Arc};
+
 use anyhow::anyhow;
 use axum::{
     extract::Path,
@@ -22,9 +24,10 @@ use axum_extra::extract::PrivateCookieJar;
 use chrono::Duration;
 use hyper::StatusCode;
 use mas_axum_utils::SessionInfoExt;
-use mas_config::Encrypter;
+use mas_config::{AuthenticationConfig, Encrypter};
 use mas_data_model::{AuthorizationGrant, BrowserSession, TokenType};
-use mas_router::{PostAuthAction, Route};
+use mas_jose::StaticKeystore;
+use mas_router::{PostAuthAction, Route, UrlBuilder};
 use mas_storage::{
     oauth2::{
         access_token::add_access_token,
@@ -42,6 +45,7 @@ use sqlx::{PgPool, Postgres, Transaction};
 use thiserror::Error;
 
 use super::callback::{CallbackDestination, CallbackDestinationError, InvalidRedirectUriError};
+use crate::HomeserverConnection;
 
 #[derive(Debug, Error)]
 pub enum RouteError {
@@ -104,9 +108,14 @@ impl From<CallbackDestinationError> for RouteError {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
+    Extension(key_store): Extension<Arc<StaticKeystore>>,
+    Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Extension(homeserver): Extension<HomeserverConnection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Path(grant_id): Path<i64>,
 ) -> Result<Response, RouteError> {
@@ -118,7 +127,8 @@ pub(crate) async fn get(
 
     let grant = get_grant_by_id(&mut txn, grant_id).await?;
 
-    let callback_destination = CallbackDestination::try_from(&grant)?;
+    let callback_destination =
+        CallbackDestination::try_from_grant(&grant, url_builder.oidc_issuer())?;
     let continue_grant = PostAuthAction::continue_grant(grant_id);
 
     let session = if let Some(session) = maybe_session {
@@ -129,9 +139,11 @@ pub(crate) async fn get(
         return Ok((cookie_jar, mas_router::Login::and_then(continue_grant).go()).into_response());
     };
 
-    match complete(grant, session, txn).await {
+    match complete(grant, session, &authentication_config, &homeserver, txn).await {
         Ok(params) => {
-            let res = callback_destination.go(&templates, params).await?;
+            let res = callback_destination
+                .go(&templates, &key_store, params)
+                .await?;
             Ok((cookie_jar, res).into_response())
         }
         Err(GrantCompletionError::RequiresReauth) => Ok((
@@ -182,6 +194,8 @@ impl From<InvalidRedirectUriError> for GrantCompletionError {
 pub(crate) async fn complete(
     grant: AuthorizationGrant<PostgresqlBackend>,
     browser_session: BrowserSession<PostgresqlBackend>,
+    authentication_config: &AuthenticationConfig,
+    homeserver: &HomeserverConnection,
     mut txn: Transaction<'_, Postgres>,
 ) -> Result<AuthorizationResponse<Option<AccessTokenResponse>>, GrantCompletionError> {
     // Verify that the grant is in a pending stage
@@ -195,6 +209,28 @@ pub(crate) async fn complete(
         return Err(GrantCompletionError::RequiresReauth);
     }
 
+    // Check if the authentication satisfies the requested context classes. We
+    // can only step up by asking for the password again, so unsatisfiable
+    // requests are let through and the client will see the actual `acr`.
+    if let Some(acr_values) = &grant.acr_values {
+        let acr_values: HashSet<String> = acr_values
+            .split_whitespace()
+            .map(ToOwned::to_owned)
+            .collect();
+
+        let satisfied = browser_session
+            .last_authentication
+            .as_ref()
+            .map_or(false, |auth| {
+                authentication_config.satisfies(auth.method, &acr_values)
+            });
+
+        if !satisfied && authentication_config.reachable_by_reauth(&acr_values) {
+            txn.commit().await?;
+            return Err(GrantCompletionError::RequiresReauth);
+        }
+    }
+
     let current_consent =
         fetch_client_consent(&mut txn, &browser_session.user, &grant.client).await?;
 
@@ -252,5 +288,10 @@ pub(crate) async fn complete(
     }
 
     txn.commit().await?;
+
+    homeserver
+        .create_devices_for_scope(&session.browser_session.user.username, &session.scope)
+        .await;
+
     Ok(params)
 }
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{anyhow, Context};
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Context};
 use axum::{
     extract::{Extension, Form},
     response::{IntoResponse, Response},
@@ -20,10 +22,11 @@ use axum::{
 use axum_extra::extract::PrivateCookieJar;
 use hyper::StatusCode;
 use mas_axum_utils::SessionInfoExt;
-use mas_config::Encrypter;
-use mas_data_model::{AuthorizationCode, Device, Pkce};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{AuthorizationCode, BrowserSession, Device, Pkce, StorageBackend};
 use mas_iana::oauth::OAuthAuthorizationEndpointResponseType;
-use mas_router::{PostAuthAction, Route};
+use mas_jose::{claims, DecodedJsonWebToken, JsonWebTokenParts, StaticKeystore};
+use mas_router::{PostAuthAction, Route, UrlBuilder};
 use mas_storage::oauth2::{
     authorization_grant::new_authorization_grant,
     client::{lookup_client_by_client_id, ClientFetchError},
@@ -31,9 +34,9 @@ use mas_storage::oauth2::{
 use mas_templates::Templates;
 use oauth2_types::{
     errors::{
-        CONSENT_REQUIRED, INTERACTION_REQUIRED, INVALID_REQUEST, LOGIN_REQUIRED,
-        REGISTRATION_NOT_SUPPORTED, REQUEST_NOT_SUPPORTED, REQUEST_URI_NOT_SUPPORTED, SERVER_ERROR,
-        UNAUTHORIZED_CLIENT,
+        ACCOUNT_SELECTION_REQUIRED, CONSENT_REQUIRED, INTERACTION_REQUIRED, INVALID_REQUEST,
+        LOGIN_REQUIRED, REGISTRATION_NOT_SUPPORTED, REQUEST_NOT_SUPPORTED,
+        REQUEST_URI_NOT_SUPPORTED, SERVER_ERROR, UNAUTHORIZED_CLIENT,
     },
     pkce,
     prelude::*,
@@ -41,10 +44,13 @@ use oauth2_types::{
 };
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
+use serde_json::Value;
 use sqlx::PgPool;
 use thiserror::Error;
+use url::Url;
 
 use self::{callback::CallbackDestination, complete::GrantCompletionError};
+use crate::HomeserverConnection;
 
 mod callback;
 pub mod complete;
@@ -141,24 +147,96 @@ fn resolve_response_mode(
     use ResponseMode as M;
 
     // If the response type includes either "token" or "id_token", the default
-    // response mode is "fragment" and the response mode "query" must not be
-    // used
+    // response mode is "fragment" and the response modes "query" and "query.jwt"
+    // must not be used
     if response_type.has_token() || response_type.has_id_token() {
         match suggested_response_mode {
             None => Ok(M::Fragment),
-            Some(M::Query) => Err(anyhow!("invalid response mode")),
+            Some(M::Query | M::QueryJwt) => Err(anyhow!("invalid response mode")),
+            Some(M::Jwt) => Ok(M::FragmentJwt),
             Some(mode) => Ok(mode),
         }
     } else {
         // In other cases, all response modes are allowed, defaulting to "query"
-        Ok(suggested_response_mode.unwrap_or(M::Query))
+        match suggested_response_mode {
+            None => Ok(M::Query),
+            Some(M::Jwt) => Ok(M::QueryJwt),
+            Some(mode) => Ok(mode),
+        }
+    }
+}
+
+/// Check that an `id_token_hint` is an ID token we issued to this client, and
+/// return the subject it was issued for.
+///
+/// Expired tokens are accepted, as this is only a hint of who the user is.
+async fn validate_id_token_hint(
+    id_token_hint: &str,
+    key_store: &StaticKeystore,
+    issuer: &Url,
+    client_id: &str,
+) -> anyhow::Result<String> {
+    let jwt: JsonWebTokenParts = id_token_hint.parse()?;
+    let decoded: DecodedJsonWebToken<HashMap<String, Value>> = jwt.decode()?;
+    jwt.verify(decoded.header(), key_store).await?;
+    let (_header, mut claims) = decoded.split();
+
+    let iss = claims::ISS.extract_required(&mut claims)?;
+    if iss != issuer.as_str() {
+        bail!("ID token hint was issued by someone else");
+    }
+
+    let aud = claims::AUD.extract_required(&mut claims)?;
+    if !aud.iter().any(|aud| aud == client_id) {
+        bail!("ID token hint was issued to another client");
+    }
+
+    let sub = claims::SUB.extract_required(&mut claims)?;
+    Ok(sub)
+}
+
+/// How the session the client expects, through an `id_token_hint`, relates to
+/// the sessions of this browser
+#[derive(Debug, PartialEq, Eq)]
+enum HintedSession {
+    /// No hint was given, there is no active session, or it is the right one
+    Current,
+
+    /// The active session is for another user, but another session of this
+    /// browser is for the right one
+    Other,
+
+    /// None of the sessions of this browser are for the right user
+    Missing,
+}
+
+impl HintedSession {
+    fn find<S: StorageBackend>(
+        current: Option<&BrowserSession<S>>,
+        sessions: &[BrowserSession<S>],
+        hinted_sub: Option<&str>,
+    ) -> Self {
+        match (current, hinted_sub) {
+            (Some(current), Some(sub)) if current.user.sub != sub => {
+                if sessions.iter().any(|session| session.user.sub == sub) {
+                    Self::Other
+                } else {
+                    Self::Missing
+                }
+            }
+            _ => Self::Current,
+        }
     }
 }
 
 #[allow(clippy::too_many_lines)]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
+    Extension(key_store): Extension<Arc<StaticKeystore>>,
+    Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Extension(homeserver): Extension<HomeserverConnection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(params): Form<Params>,
 ) -> Result<Response, RouteError> {
@@ -176,9 +254,11 @@ pub(crate) async fn get(
 
     // Now we have a proper callback destination to go to on error
     let callback_destination = CallbackDestination::try_new(
+        &client,
         response_mode,
         redirect_uri.clone(),
         params.auth.state.clone(),
+        url_builder.oidc_issuer(),
     )?;
 
     // Get the session info from the cookie
@@ -187,6 +267,7 @@ pub(crate) async fn get(
     // One day, we will have try blocks
     let res: Result<Response, RouteError> = ({
         let templates = templates.clone();
+        let key_store = key_store.clone();
         let callback_destination = callback_destination.clone();
         async move {
             let maybe_session = session_info
@@ -194,36 +275,84 @@ pub(crate) async fn get(
                 .await
                 .context("failed to load browser session")?;
 
+            let sessions = session_info
+                .load_sessions(&mut txn)
+                .await
+                .context("failed to load browser sessions")?;
+
             // Check if the request/request_uri/registration params are used. If so, reply
             // with the right error since we don't support them.
             if params.auth.request.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REQUEST_NOT_SUPPORTED)
+                    .go(&templates, &key_store, REQUEST_NOT_SUPPORTED)
                     .await?);
             }
 
             if params.auth.request_uri.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REQUEST_URI_NOT_SUPPORTED)
+                    .go(&templates, &key_store, REQUEST_URI_NOT_SUPPORTED)
                     .await?);
             }
 
             if params.auth.registration.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REGISTRATION_NOT_SUPPORTED)
+                    .go(&templates, &key_store, REGISTRATION_NOT_SUPPORTED)
                     .await?);
             }
 
             // Check if it is allowed to use this grant type
             if !client.grant_types.contains(&GrantType::AuthorizationCode) {
                 return Ok(callback_destination
-                    .go(&templates, UNAUTHORIZED_CLIENT)
+                    .go(&templates, &key_store, UNAUTHORIZED_CLIENT)
                     .await?);
             }
 
+            // Figure out who the client expects the user to be
+            let hinted_sub = if let Some(id_token_hint) = &params.auth.id_token_hint {
+                match validate_id_token_hint(
+                    id_token_hint,
+                    &key_store,
+                    &url_builder.oidc_issuer(),
+                    &client.client_id,
+                )
+                .await
+                {
+                    Ok(sub) => Some(sub),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Invalid ID token hint");
+                        return Ok(callback_destination
+                            .go(&templates, &key_store, INVALID_REQUEST)
+                            .await?);
+                    }
+                }
+            } else {
+                None
+            };
+
+            // Check if the current session is for another user than the hinted one,
+            // and if one of the other sessions in this browser would match
+            let hinted_session =
+                HintedSession::find(maybe_session.as_ref(), &sessions, hinted_sub.as_deref());
+            let session_mismatch = hinted_session != HintedSession::Current;
+
             // Fail early if prompt=none and there is no active session
             if params.auth.prompt == Some(Prompt::None) && maybe_session.is_none() {
-                return Ok(callback_destination.go(&templates, LOGIN_REQUIRED).await?);
+                return Ok(callback_destination
+                    .go(&templates, &key_store, LOGIN_REQUIRED)
+                    .await?);
+            }
+
+            // Same if the active session is not the one the client expected
+            if params.auth.prompt == Some(Prompt::None) && session_mismatch {
+                let error = if hinted_session == HintedSession::Other {
+                    ACCOUNT_SELECTION_REQUIRED
+                } else {
+                    LOGIN_REQUIRED
+                };
+
+                return Ok(callback_destination
+                    .go(&templates, &key_store, error)
+                    .await?);
             }
 
             let code: Option<AuthorizationCode> = if response_type.has_code() {
@@ -244,7 +373,9 @@ pub(crate) async fn get(
                 // If the request had PKCE params but no code asked, it should get back with an
                 // error
                 if params.pkce.is_some() {
-                    return Ok(callback_destination.go(&templates, INVALID_REQUEST).await?);
+                    return Ok(callback_destination
+                        .go(&templates, &key_store, INVALID_REQUEST)
+                        .await?);
                 }
 
                 None
@@ -261,6 +392,13 @@ pub(crate) async fn get(
             };
 
             let requires_consent = params.auth.prompt == Some(Prompt::Consent);
+            let select_account =
+                params.auth.prompt == Some(Prompt::SelectAccount) || session_mismatch;
+
+            let acr_values = params.auth.acr_values.as_ref().map(|values| {
+                let values: Vec<&str> = values.iter().map(String::as_str).collect();
+                values.join(" ")
+            });
 
             let grant = new_authorization_grant(
                 &mut txn,
@@ -271,11 +409,12 @@ pub(crate) async fn get(
                 params.auth.state.clone(),
                 params.auth.nonce,
                 params.auth.max_age,
-                None,
+                acr_values,
                 response_mode,
                 response_type.has_token(),
                 response_type.has_id_token(),
                 requires_consent,
+                params.auth.login_hint,
             )
             .await?;
             let continue_grant = PostAuthAction::continue_grant(grant.data);
@@ -303,8 +442,8 @@ pub(crate) async fn get(
                         .into_response()
                 }
 
-                // Special case when we already have a sesion but prompt=login|select_account
-                (Some(_), Some(Prompt::Login | Prompt::SelectAccount)) => {
+                // Special case when we already have a sesion but prompt=login
+                (Some(_), Some(Prompt::Login)) => {
                     // TODO: better pages here
                     txn.commit().await?;
 
@@ -313,19 +452,41 @@ pub(crate) async fn get(
                         .into_response()
                 }
 
+                // Let the user pick an account if they asked for it, or if the
+                // current session is not the one the client expected
+                (Some(_), _) if select_account => {
+                    txn.commit().await?;
+
+                    mas_router::SelectAccount::and_then(continue_grant)
+                        .go()
+                        .into_response()
+                }
+
                 // Else, we immediately try to complete the authorization grant
                 (Some(user_session), Some(Prompt::None)) => {
                     // With prompt=none, we should get back to the client immediately
-                    match self::complete::complete(grant, user_session, txn).await {
-                        Ok(params) => callback_destination.go(&templates, params).await?,
+                    match self::complete::complete(
+                        grant,
+                        user_session,
+                        &authentication_config,
+                        &homeserver,
+                        txn,
+                    )
+                    .await
+                    {
+                        Ok(params) => {
+                            callback_destination
+                                .go(&templates, &key_store, params)
+                                .await?
+                        }
                         Err(GrantCompletionError::RequiresConsent) => {
                             callback_destination
-                                .go(&templates, CONSENT_REQUIRED)
+                                .go(&templates, &key_store, CONSENT_REQUIRED)
                                 .await?
                         }
                         Err(GrantCompletionError::RequiresReauth) => {
                             callback_destination
-                                .go(&templates, INTERACTION_REQUIRED)
+                                .go(&templates, &key_store, INTERACTION_REQUIRED)
                                 .await?
                         }
                         Err(GrantCompletionError::Anyhow(a)) => return Err(RouteError::Anyhow(a)),
@@ -341,8 +502,20 @@ pub(crate) async fn get(
                 (Some(user_session), _) => {
                     let grant_id = grant.data;
                     // Else, we show the relevant reauth/consent page if necessary
-                    match self::complete::complete(grant, user_session, txn).await {
-                        Ok(params) => callback_destination.go(&templates, params).await?,
+                    match self::complete::complete(
+                        grant,
+                        user_session,
+                        &authentication_config,
+                        &homeserver,
+                        txn,
+                    )
+                    .await
+                    {
+                        Ok(params) => {
+                            callback_destination
+                                .go(&templates, &key_store, params)
+                                .await?
+                        }
                         Err(GrantCompletionError::RequiresConsent) => {
                             mas_router::Consent(grant_id).go().into_response()
                         }
@@ -372,9 +545,136 @@ pub(crate) async fn get(
         Ok(r) => r,
         Err(err) => {
             tracing::error!(%err);
-            callback_destination.go(&templates, SERVER_ERROR).await?
+            callback_destination
+                .go(&templates, &key_store, SERVER_ERROR)
+                .await?
         }
     };
 
     Ok((cookie_jar, response).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::SessionExpiration;
+    use mas_iana::jose::JsonWebSignatureAlg;
+    use mas_jose::SigningKeystore;
+    use mas_storage::{
+        test_utils::{random_name, test_transaction},
+        user::{register_passwordless_user, start_session},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    async fn sign(key_store: &StaticKeystore, claims: Value) -> String {
+        let header = key_store
+            .prepare_header(JsonWebSignatureAlg::Rs256)
+            .await
+            .unwrap();
+        DecodedJsonWebToken::new(header, claims)
+            .sign(key_store)
+            .await
+            .unwrap()
+            .serialize()
+    }
+
+    #[tokio::test]
+    async fn test_validate_id_token_hint() {
+        let issuer: Url = "https://example.com/".parse().unwrap();
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_rsa_key().unwrap();
+
+        // Expired tokens are still good hints
+        let hint = sign(
+            &key_store,
+            json!({ "iss": "https://example.com/", "aud": "client", "sub": "alice", "exp": 0 }),
+        )
+        .await;
+        let sub = validate_id_token_hint(&hint, &key_store, &issuer, "client")
+            .await
+            .unwrap();
+        assert_eq!(sub, "alice");
+
+        // Issued to another client
+        assert!(
+            validate_id_token_hint(&hint, &key_store, &issuer, "other-client")
+                .await
+                .is_err()
+        );
+
+        // Issued by someone else
+        let hint = sign(
+            &key_store,
+            json!({ "iss": "https://evil.example.com/", "aud": "client", "sub": "alice" }),
+        )
+        .await;
+        assert!(validate_id_token_hint(&hint, &key_store, &issuer, "client")
+            .await
+            .is_err());
+
+        // Not signed with our keys
+        let mut other_key_store = StaticKeystore::new();
+        other_key_store.add_test_ecdsa_key().unwrap();
+        let hint = sign(
+            &key_store,
+            json!({ "iss": "https://example.com/", "aud": "client", "sub": "alice" }),
+        )
+        .await;
+        assert!(
+            validate_id_token_hint(&hint, &other_key_store, &issuer, "client")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hinted_session() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let expiration = SessionExpiration::default();
+        let alice = register_passwordless_user(&mut txn, &random_name("alice"))
+            .await
+            .unwrap();
+        let bob = register_passwordless_user(&mut txn, &random_name("bob"))
+            .await
+            .unwrap();
+        let carol = register_passwordless_user(&mut txn, &random_name("carol"))
+            .await
+            .unwrap();
+        let alice_session = start_session(&mut txn, alice.clone(), None, None, &expiration)
+            .await
+            .unwrap();
+        let bob_session = start_session(&mut txn, bob.clone(), None, None, &expiration)
+            .await
+            .unwrap();
+        let sessions = vec![alice_session.clone(), bob_session];
+
+        // Without a hint or a session, there is nothing to compare
+        assert_eq!(
+            HintedSession::find(Some(&alice_session), &sessions, None),
+            HintedSession::Current
+        );
+        assert_eq!(
+            HintedSession::find(None, &sessions, Some(&bob.sub)),
+            HintedSession::Current
+        );
+
+        assert_eq!(
+            HintedSession::find(Some(&alice_session), &sessions, Some(&alice.sub)),
+            HintedSession::Current
+        );
+        assert_eq!(
+            HintedSession::find(Some(&alice_session), &sessions, Some(&bob.sub)),
+            HintedSession::Other
+        );
+        assert_eq!(
+            HintedSession::find(Some(&alice_session), &sessions, Some(&carol.sub)),
+            HintedSession::Missing
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{extract::Extension, response::IntoResponse, Json};
+use chrono::{Duration, Utc};
+use headers::{CacheControl, HeaderMap, HeaderMapExt, Pragma};
+use hyper::StatusCode;
+use lettre::{message::Mailbox, Address};
+use mas_axum_utils::{
+    client_authorization::{ClientAuthorization, CredentialsVerificationError},
+    client_jwks::ClientJwksCache,
+};
+use mas_config::Encrypter;
+use mas_email::Mailer;
+use mas_router::UrlBuilder;
+use mas_storage::{
+    oauth2::{
+        ciba::{add_ciba_request, count_pending_ciba_requests},
+        client::ClientFetchError,
+    },
+    user::{lookup_user_by_username, UserLookupError},
+};
+use mas_templates::CibaRequestEmailContext;
+use oauth2_types::{
+    errors::{
+        ACCESS_DENIED, INVALID_BINDING_MESSAGE, INVALID_CLIENT, INVALID_REQUEST, INVALID_SCOPE,
+        SERVER_ERROR, UNAUTHORIZED_CLIENT, UNKNOWN_USER_ID,
+    },
+    oidc::BackchannelTokenDeliveryMode,
+    requests::{BackchannelAuthenticationRequest, BackchannelAuthenticationResponse, GrantType},
+    scope,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub(crate) enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("bad request")]
+    BadRequest,
+
+    #[error("client not found")]
+    ClientNotFound,
+
+    #[error("client not allowed")]
+    ClientNotAllowed,
+
+    #[error("could not verify client credentials")]
+    ClientCredentialsVerification(#[from] CredentialsVerificationError),
+
+    #[error("invalid scope")]
+    InvalidScope,
+
+    #[error("missing login_hint")]
+    MissingLoginHint,
+
+    #[error("unsupported hint parameter")]
+    UnsupportedHint,
+
+    #[error("unknown user")]
+    UnknownUser,
+
+    #[error("too many pending requests")]
+    TooManyPendingRequests,
+
+    #[error("invalid binding message")]
+    InvalidBindingMessage,
+}
+
+impl From<ClientFetchError> for RouteError {
+    fn from(e: ClientFetchError) -> Self {
+        if e.not_found() {
+            Self::ClientNotFound
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
+impl From<UserLookupError> for RouteError {
+    fn from(e: UserLookupError) -> Self {
+        if e.not_found() {
+            Self::UnknownUser
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Internal(_) | Self::Anyhow(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(SERVER_ERROR))
+            }
+            Self::BadRequest | Self::MissingLoginHint | Self::UnsupportedHint => {
+                (StatusCode::BAD_REQUEST, Json(INVALID_REQUEST))
+            }
+            Self::ClientNotFound | Self::ClientCredentialsVerification(_) => {
+                (StatusCode::UNAUTHORIZED, Json(INVALID_CLIENT))
+            }
+            Self::ClientNotAllowed => (StatusCode::BAD_REQUEST, Json(UNAUTHORIZED_CLIENT)),
+            Self::InvalidScope => (StatusCode::BAD_REQUEST, Json(INVALID_SCOPE)),
+            Self::UnknownUser => (StatusCode::BAD_REQUEST, Json(UNKNOWN_USER_ID)),
+            Self::TooManyPendingRequests => (StatusCode::BAD_REQUEST, Json(ACCESS_DENIED)),
+            Self::InvalidBindingMessage => (StatusCode::BAD_REQUEST, Json(INVALID_BINDING_MESSAGE)),
+        }
+        .into_response()
+    }
+}
+
+/// How long a request stays valid if the client did not ask for something else
+const DEFAULT_EXPIRY: i64 = 300;
+
+/// Upper bound for the `requested_expiry` parameter
+const MAX_EXPIRY: i64 = 600;
+
+/// Minimum amount of time the client should wait between polls
+const POLLING_INTERVAL: i64 = 5;
+
+/// How many unanswered requests a client can have for a user at once, each of
+/// them sending an email
+const MAX_PENDING_REQUESTS: i64 = 3;
+
+#[tracing::instrument(skip_all, err)]
+pub(crate) async fn post(
+    client_authorization: ClientAuthorization<BackchannelAuthenticationRequest>,
+    Extension(pool): Extension<PgPool>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(jwks_cache): Extension<ClientJwksCache>,
+    Extension(mailer): Extension<Mailer>,
+    Extension(url_builder): Extension<UrlBuilder>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut txn = pool.begin().await?;
+
+    let client = client_authorization.credentials.fetch(&mut txn).await?;
+
+    let method = client
+        .token_endpoint_auth_method
+        .ok_or(RouteError::ClientNotAllowed)?;
+
+    client_authorization
+        .credentials
+        .verify(&encrypter, &jwks_cache, method, &client)
+        .await?;
+
+    let form = client_authorization.form.ok_or(RouteError::BadRequest)?;
+
+    if !client
+        .grant_types
+        .contains(&GrantType::ClientInitiatedBackchannelAuthentication)
+    {
+        return Err(RouteError::ClientNotAllowed);
+    }
+
+    if !form.scope.contains(&scope::OPENID) {
+        return Err(RouteError::InvalidScope);
+    }
+
+    // Clients in ping mode need to give us a token to authenticate the
+    // notification
+    if client.backchannel_token_delivery_mode == Some(BackchannelTokenDeliveryMode::Ping)
+        && form.client_notification_token.is_none()
+    {
+        return Err(RouteError::BadRequest);
+    }
+
+    if let Some(binding_message) = &form.binding_message {
+        if binding_message.is_empty()
+            || binding_message.len() > 64
+            || binding_message.contains(char::is_control)
+        {
+            return Err(RouteError::InvalidBindingMessage);
+        }
+    }
+
+    let expires_in = match form.requested_expiry {
+        Some(expiry) if expiry <= Duration::zero() => return Err(RouteError::BadRequest),
+        Some(expiry) => std::cmp::min(expiry, Duration::seconds(MAX_EXPIRY)),
+        None => Duration::seconds(DEFAULT_EXPIRY),
+    };
+
+    // Users are only identified by their username through login_hint, which
+    // has to be the only hint given
+    if form.login_hint_token.is_some() || form.id_token_hint.is_some() {
+        return Err(RouteError::UnsupportedHint);
+    }
+
+    let login_hint = form.login_hint.ok_or(RouteError::MissingLoginHint)?;
+    let user = lookup_user_by_username(&mut txn, &login_hint).await?;
+
+    if count_pending_ciba_requests(&mut txn, &client, &user).await? >= MAX_PENDING_REQUESTS {
+        return Err(RouteError::TooManyPendingRequests);
+    }
+
+    let auth_req_id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let request = add_ciba_request(
+        &mut txn,
+        client,
+        user,
+        auth_req_id,
+        form.scope,
+        form.binding_message,
+        form.client_notification_token,
+        Utc::now() + expires_in,
+    )
+    .await?;
+
+    txn.commit().await?;
+
+    info!(
+        ciba_request.id = request.data,
+        "Backchannel request created"
+    );
+
+    // Let the user know there is something waiting for them. Failing to do so
+    // is not fatal, since the request also shows up on the account page.
+    if let Some(email) = request
+        .user
+        .primary_email
+        .as_ref()
+        .filter(|e| e.confirmed_at.is_some())
+    {
+        let address: Result<Address, _> = email.email.parse();
+        match address {
+            Ok(address) => {
+                let mailbox = Mailbox::new(Some(request.user.username.clone()), address);
+                let context = CibaRequestEmailContext::new(request.clone(), url_builder.account());
+                if let Err(e) = mailer.send_ciba_email(mailbox, &context).await {
+                    warn!(error = %e, "Failed to send backchannel request email");
+                }
+            }
+            Err(e) => warn!(error = %e, "Invalid primary email address"),
+        }
+    }
+
+    let response = BackchannelAuthenticationResponse {
+        auth_req_id: request.auth_req_id,
+        expires_in,
+        interval: Some(Duration::seconds(POLLING_INTERVAL)),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(CacheControl::new().with_no_store());
+    headers.typed_insert(Pragma::no_cache());
+
+    Ok((headers, Json(response)))
+}
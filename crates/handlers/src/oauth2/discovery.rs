@@ -15,6 +15,7 @@
 use std::sync::Arc;
 
 use axum::{extract::Extension, response::IntoResponse, Json};
+use mas_config::AuthenticationConfig;
 use mas_iana::{
     jose::JsonWebSignatureAlg,
     oauth::{
@@ -25,15 +26,18 @@ use mas_iana::{
 use mas_jose::{SigningKeystore, StaticKeystore};
 use mas_router::UrlBuilder;
 use oauth2_types::{
-    oidc::{ClaimType, Metadata, SubjectType},
+    oidc::{BackchannelTokenDeliveryMode, ClaimType, Metadata, SubjectType},
     requests::{Display, GrantType, Prompt, ResponseMode},
     scope,
 };
 
+use super::encryption::{SUPPORTED_ALGS, SUPPORTED_ENCS};
+
 #[allow(clippy::too_many_lines)]
 pub(crate) async fn get(
     Extension(key_store): Extension<Arc<StaticKeystore>>,
     Extension(url_builder): Extension<UrlBuilder>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
 ) -> impl IntoResponse {
     // This is how clients can authenticate
     let client_auth_methods_supported = Some(vec![
@@ -69,6 +73,8 @@ pub(crate) async fn get(
     let introspection_endpoint = Some(url_builder.oauth_introspection_endpoint());
     let userinfo_endpoint = Some(url_builder.oidc_userinfo_endpoint());
     let registration_endpoint = Some(url_builder.oauth_registration_endpoint());
+    let backchannel_authentication_endpoint =
+        Some(url_builder.oauth_backchannel_authentication_endpoint());
 
     let scopes_supported = Some(vec![scope::OPENID.to_string(), scope::EMAIL.to_string()]);
 
@@ -86,12 +92,17 @@ pub(crate) async fn get(
         ResponseMode::FormPost,
         ResponseMode::Query,
         ResponseMode::Fragment,
+        ResponseMode::FormPostJwt,
+        ResponseMode::QueryJwt,
+        ResponseMode::FragmentJwt,
+        ResponseMode::Jwt,
     ]);
 
     let grant_types_supported = Some(vec![
         GrantType::AuthorizationCode,
         GrantType::Implicit,
         GrantType::RefreshToken,
+        GrantType::ClientInitiatedBackchannelAuthentication,
     ]);
 
     let token_endpoint_auth_methods_supported = client_auth_methods_supported.clone();
@@ -110,7 +121,20 @@ pub(crate) async fn get(
     let subject_types_supported = Some(vec![SubjectType::Public]);
 
     let id_token_signing_alg_values_supported = jwt_signing_alg_values_supported.clone();
-    let userinfo_signing_alg_values_supported = jwt_signing_alg_values_supported;
+    let userinfo_signing_alg_values_supported = jwt_signing_alg_values_supported.clone();
+    let authorization_signing_alg_values_supported = jwt_signing_alg_values_supported;
+
+    let id_token_encryption_alg_values_supported = Some(SUPPORTED_ALGS.to_vec());
+    let id_token_encryption_enc_values_supported = Some(SUPPORTED_ENCS.to_vec());
+    let userinfo_encryption_alg_values_supported = Some(SUPPORTED_ALGS.to_vec());
+    let userinfo_encryption_enc_values_supported = Some(SUPPORTED_ENCS.to_vec());
+
+    let acr_values_supported = Some(
+        authentication_config
+            .supported_values()
+            .map(ToOwned::to_owned)
+            .collect(),
+    );
 
     let display_values_supported = Some(vec![Display::Page]);
 
@@ -124,6 +148,8 @@ pub(crate) async fn get(
         "exp".to_string(),
         "nonce".to_string(),
         "auth_time".to_string(),
+        "acr".to_string(),
+        "amr".to_string(),
         "at_hash".to_string(),
         "c_hash".to_string(),
     ]);
@@ -132,7 +158,20 @@ pub(crate) async fn get(
     let request_parameter_supported = Some(false);
     let request_uri_parameter_supported = Some(false);
 
-    let prompt_values_supported = Some(vec![Prompt::None, Prompt::Login, Prompt::Create]);
+    let prompt_values_supported = Some(vec![
+        Prompt::None,
+        Prompt::Login,
+        Prompt::Create,
+        Prompt::SelectAccount,
+    ]);
+
+    let authorization_response_iss_parameter_supported = Some(true);
+
+    let backchannel_token_delivery_modes_supported = Some(vec![
+        BackchannelTokenDeliveryMode::Poll,
+        BackchannelTokenDeliveryMode::Ping,
+    ]);
+    let backchannel_user_code_parameter_supported = Some(false);
 
     let metadata = Metadata {
         issuer,
@@ -153,7 +192,12 @@ pub(crate) async fn get(
         userinfo_endpoint,
         subject_types_supported,
         id_token_signing_alg_values_supported,
+        id_token_encryption_alg_values_supported,
+        id_token_encryption_enc_values_supported,
         userinfo_signing_alg_values_supported,
+        userinfo_encryption_alg_values_supported,
+        userinfo_encryption_enc_values_supported,
+        acr_values_supported,
         display_values_supported,
         claim_types_supported,
         claims_supported,
@@ -161,6 +205,11 @@ pub(crate) async fn get(
         request_parameter_supported,
         request_uri_parameter_supported,
         prompt_values_supported,
+        authorization_signing_alg_values_supported,
+        authorization_response_iss_parameter_supported,
+        backchannel_authentication_endpoint,
+        backchannel_token_delivery_modes_supported,
+        backchannel_user_code_parameter_supported,
         ..Metadata::default()
     };
 
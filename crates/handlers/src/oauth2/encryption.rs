@@ -0,0 +1,72 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Context;
+use mas_axum_utils::client_jwks::ClientJwksCache;
+use mas_data_model::Client;
+use mas_iana::jose::{JsonWebEncryptionAlg, JsonWebEncryptionEnc};
+use mas_jose::{JsonWebEncryption, JweHeader};
+use mas_storage::PostgresqlBackend;
+
+/// Key management algorithms we can use to encrypt ID tokens and userinfo
+/// responses
+pub(crate) const SUPPORTED_ALGS: [JsonWebEncryptionAlg; 6] = [
+    JsonWebEncryptionAlg::RsaOaep,
+    JsonWebEncryptionAlg::RsaOaep256,
+    JsonWebEncryptionAlg::EcdhEs,
+    JsonWebEncryptionAlg::EcdhEsA128Kw,
+    JsonWebEncryptionAlg::EcdhEsA192Kw,
+    JsonWebEncryptionAlg::EcdhEsA256Kw,
+];
+
+/// Content encryption algorithms we support
+pub(crate) const SUPPORTED_ENCS: [JsonWebEncryptionEnc; 6] = [
+    JsonWebEncryptionEnc::A128CbcHs256,
+    JsonWebEncryptionEnc::A192CbcHs384,
+    JsonWebEncryptionEnc::A256CbcHs512,
+    JsonWebEncryptionEnc::A128Gcm,
+    JsonWebEncryptionEnc::A192Gcm,
+    JsonWebEncryptionEnc::A256Gcm,
+];
+
+/// Encrypt a payload for a client, as a JWE in compact serialization.
+///
+/// If the client did not specify an `enc` algorithm, it defaults to
+/// `A128CBC-HS256` as per the OIDC Dynamic Client Registration spec.
+pub(crate) async fn encrypt_for_client(
+    jwks_cache: &ClientJwksCache,
+    client: &Client<PostgresqlBackend>,
+    alg: JsonWebEncryptionAlg,
+    enc: Option<JsonWebEncryptionEnc>,
+    cty: Option<&str>,
+    payload: &[u8],
+) -> anyhow::Result<String> {
+    let jwks = client
+        .jwks
+        .as_ref()
+        .context("client has no keys to encrypt the response to")?;
+    let jwks = jwks_cache.get(jwks, false).await?;
+
+    let key = jwks
+        .find_encryption_key(alg)
+        .context("could not find a suitable key to encrypt the response")?;
+
+    let mut header = JweHeader::new(alg, enc.unwrap_or(JsonWebEncryptionEnc::A128CbcHs256));
+    if let Some(cty) = cty {
+        header = header.with_cty(cty);
+    }
+
+    let jwe = JsonWebEncryption::encrypt(header, key, payload)?;
+    Ok(jwe.serialize())
+}
@@ -14,9 +14,12 @@
 
 use axum::{extract::Extension, response::IntoResponse, Json};
 use hyper::StatusCode;
-use mas_axum_utils::client_authorization::{ClientAuthorization, CredentialsVerificationError};
-use mas_config::Encrypter;
-use mas_data_model::{TokenFormatError, TokenType};
+use mas_axum_utils::{
+    client_authorization::{ClientAuthorization, CredentialsVerificationError},
+    client_jwks::ClientJwksCache,
+};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{Authentication, TokenFormatError, TokenType};
 use mas_iana::oauth::{OAuthClientAuthenticationMethod, OAuthTokenTypeHint};
 use mas_storage::{
     compat::{
@@ -28,6 +31,7 @@ use mas_storage::{
         client::ClientFetchError,
         refresh_token::{lookup_active_refresh_token, RefreshTokenLookupError},
     },
+    PostgresqlBackend,
 };
 use oauth2_types::requests::{IntrospectionRequest, IntrospectionResponse};
 use sqlx::PgPool;
@@ -150,12 +154,33 @@ const INACTIVE: IntrospectionResponse = IntrospectionResponse {
     aud: None,
     iss: None,
     jti: None,
+    acr: None,
+    amr: None,
 };
 
+/// Get the `acr` and `amr` values to report for the last authentication of a
+/// session
+fn authentication_context(
+    authentication_config: &AuthenticationConfig,
+    last_authentication: Option<&Authentication<PostgresqlBackend>>,
+) -> (Option<String>, Option<Vec<String>>) {
+    match last_authentication {
+        Some(authentication) => (
+            authentication_config
+                .acr_for(authentication.method)
+                .map(ToOwned::to_owned),
+            Some(authentication.amr()),
+        ),
+        None => (None, None),
+    }
+}
+
 #[tracing::instrument(skip_all, err)]
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(encrypter): Extension<Encrypter>,
+    Extension(jwks_cache): Extension<ClientJwksCache>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
     client_authorization: ClientAuthorization<IntrospectionRequest>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut conn = pool.acquire().await?;
@@ -171,7 +196,7 @@ pub(crate) async fn post(
 
     client_authorization
         .credentials
-        .verify(&encrypter, method, &client)
+        .verify(&encrypter, &jwks_cache, method, &client)
         .await?;
 
     let form = if let Some(form) = client_authorization.form {
@@ -192,6 +217,10 @@ pub(crate) async fn post(
         TokenType::AccessToken => {
             let (token, session) = lookup_active_access_token(&mut conn, token).await?;
             let exp = token.exp();
+            let (acr, amr) = authentication_context(
+                &authentication_config,
+                session.browser_session.last_authentication.as_ref(),
+            );
 
             IntrospectionResponse {
                 active: true,
@@ -206,10 +235,16 @@ pub(crate) async fn post(
                 aud: None,
                 iss: None,
                 jti: None,
+                acr,
+                amr,
             }
         }
         TokenType::RefreshToken => {
             let (token, session) = lookup_active_refresh_token(&mut conn, token).await?;
+            let (acr, amr) = authentication_context(
+                &authentication_config,
+                session.browser_session.last_authentication.as_ref(),
+            );
 
             IntrospectionResponse {
                 active: true,
@@ -224,6 +259,8 @@ pub(crate) async fn post(
                 aud: None,
                 iss: None,
                 jti: None,
+                acr,
+                amr,
             }
         }
         TokenType::CompatAccessToken => {
@@ -245,6 +282,8 @@ pub(crate) async fn post(
                 aud: None,
                 iss: None,
                 jti: None,
+                acr: None,
+                amr: None,
             }
         }
         TokenType::CompatRefreshToken => {
@@ -267,6 +306,8 @@ pub(crate) async fn post(
                 aud: None,
                 iss: None,
                 jti: None,
+                acr: None,
+                amr: None,
             }
         }
     };
@@ -13,8 +13,10 @@
 // limitations under the License.
 
 pub mod authorization;
+pub mod backchannel;
 pub mod consent;
 pub mod discovery;
+mod encryption;
 pub mod introspection;
 pub mod keys;
 pub mod registration;
@@ -17,10 +17,12 @@ use std::sync::Arc;
 use axum::{response::IntoResponse, Extension, Json};
 use hyper::StatusCode;
 use mas_iana::oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod};
+use mas_jose::{SigningKeystore, StaticKeystore};
 use mas_policy::PolicyFactory;
 use mas_storage::oauth2::client::insert_client;
 use oauth2_types::{
     errors::{INVALID_CLIENT_METADATA, INVALID_REDIRECT_URI, SERVER_ERROR},
+    oidc::BackchannelTokenDeliveryMode,
     registration::{ClientMetadata, ClientRegistrationResponse},
     requests::GrantType,
 };
@@ -29,6 +31,8 @@ use sqlx::PgPool;
 use thiserror::Error;
 use tracing::info;
 
+use super::encryption::{SUPPORTED_ALGS, SUPPORTED_ENCS};
+
 #[derive(Debug, Error)]
 pub(crate) enum RouteError {
     #[error(transparent)]
@@ -67,10 +71,12 @@ impl IntoResponse for RouteError {
     }
 }
 
+#[allow(clippy::too_many_lines)]
 #[tracing::instrument(skip_all, err)]
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(key_store): Extension<Arc<StaticKeystore>>,
     Json(body): Json<ClientMetadata>,
 ) -> Result<impl IntoResponse, RouteError> {
     info!(?body, "Client registration");
@@ -118,6 +124,47 @@ pub(crate) async fn post(
         return Err(RouteError::InvalidClientMetadata);
     }
 
+    // Clients using the CIBA ping mode need an endpoint where we can notify them
+    if body.backchannel_token_delivery_mode == Some(BackchannelTokenDeliveryMode::Ping)
+        && body.backchannel_client_notification_endpoint.is_none()
+    {
+        return Err(RouteError::InvalidClientMetadata);
+    }
+
+    // JWT-secured authorization responses are signed with our keys, so we need
+    // to have one for the requested algorithm
+    if let Some(alg) = body.authorization_signed_response_alg {
+        if !key_store.supported_algorithms().contains(&alg) {
+            return Err(RouteError::InvalidClientMetadata);
+        }
+    }
+
+    // We need the client keys to encrypt responses, and can only use the
+    // algorithms we support. Setting `enc` without `alg` is not allowed.
+    for (alg, enc) in [
+        (
+            body.id_token_encrypted_response_alg,
+            body.id_token_encrypted_response_enc,
+        ),
+        (
+            body.userinfo_encrypted_response_alg,
+            body.userinfo_encrypted_response_enc,
+        ),
+    ] {
+        match (alg, enc) {
+            (None, None) => {}
+            (None, Some(_)) => return Err(RouteError::InvalidClientMetadata),
+            (Some(alg), enc) => {
+                if !SUPPORTED_ALGS.contains(&alg)
+                    || enc.map_or(false, |enc| !SUPPORTED_ENCS.contains(&enc))
+                    || (body.jwks_uri.is_none() && body.jwks.is_none())
+                {
+                    return Err(RouteError::InvalidClientMetadata);
+                }
+            }
+        }
+    }
+
     let mut policy = policy_factory.instantiate().await?;
     let res = policy.evaluate_client_registration(&body).await?;
     if !res.valid() {
@@ -154,6 +201,13 @@ pub(crate) async fn post(
         body.token_endpoint_auth_method,
         body.token_endpoint_auth_signing_alg,
         body.initiate_login_uri.as_ref(),
+        body.authorization_signed_response_alg,
+        body.backchannel_token_delivery_mode,
+        body.backchannel_client_notification_endpoint.as_ref(),
+        body.id_token_encrypted_response_alg,
+        body.id_token_encrypted_response_enc,
+        body.userinfo_encrypted_response_alg,
+        body.userinfo_encrypted_response_enc,
     )
     .await?;
 
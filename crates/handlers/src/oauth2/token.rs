@@ -20,9 +20,12 @@ use chrono::{DateTime, Duration, Utc};
 use data_encoding::BASE64URL_NOPAD;
 use headers::{CacheControl, HeaderMap, HeaderMapExt, Pragma};
 use hyper::StatusCode;
-use mas_axum_utils::client_authorization::{ClientAuthorization, CredentialsVerificationError};
-use mas_config::Encrypter;
-use mas_data_model::{AuthorizationGrantStage, Client, TokenType};
+use mas_axum_utils::{
+    client_authorization::{ClientAuthorization, CredentialsVerificationError},
+    client_jwks::ClientJwksCache,
+};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{AuthorizationGrantStage, CibaRequestState, Client, TokenType};
 use mas_iana::jose::JsonWebSignatureAlg;
 use mas_jose::{
     claims::{self, ClaimError},
@@ -33,6 +36,7 @@ use mas_storage::{
     oauth2::{
         access_token::{add_access_token, revoke_access_token},
         authorization_grant::{exchange_grant, lookup_grant_by_code},
+        ciba::{exchange_ciba_request, lookup_ciba_request_by_auth_req_id, CibaRequestLookupError},
         client::ClientFetchError,
         end_oauth_session,
         refresh_token::{
@@ -43,9 +47,13 @@ use mas_storage::{
     DatabaseInconsistencyError, PostgresqlBackend,
 };
 use oauth2_types::{
-    errors::{INVALID_CLIENT, INVALID_GRANT, INVALID_REQUEST, SERVER_ERROR, UNAUTHORIZED_CLIENT},
+    errors::{
+        ACCESS_DENIED, AUTHORIZATION_PENDING, EXPIRED_TOKEN, INVALID_CLIENT, INVALID_GRANT,
+        INVALID_REQUEST, SERVER_ERROR, UNAUTHORIZED_CLIENT,
+    },
     requests::{
-        AccessTokenRequest, AccessTokenResponse, AuthorizationCodeGrant, RefreshTokenGrant,
+        AccessTokenRequest, AccessTokenResponse, AuthorizationCodeGrant, CibaGrant, GrantType,
+        RefreshTokenGrant,
     },
     scope,
 };
@@ -58,6 +66,9 @@ use thiserror::Error;
 use tracing::debug;
 use url::Url;
 
+use super::encryption::encrypt_for_client;
+use crate::HomeserverConnection;
+
 #[serde_as]
 #[skip_serializing_none]
 #[derive(Serialize, Debug)]
@@ -100,6 +111,15 @@ pub(crate) enum RouteError {
 
     #[error("unauthorized client")]
     UnauthorizedClient,
+
+    #[error("authorization pending")]
+    AuthorizationPending,
+
+    #[error("expired token")]
+    ExpiredToken,
+
+    #[error("access denied")]
+    AccessDenied,
 }
 
 impl From<ClientFetchError> for RouteError {
@@ -122,6 +142,16 @@ impl From<RefreshTokenLookupError> for RouteError {
     }
 }
 
+impl From<CibaRequestLookupError> for RouteError {
+    fn from(e: CibaRequestLookupError) -> Self {
+        if e.not_found() {
+            Self::InvalidGrant
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         match self {
@@ -136,6 +166,9 @@ impl IntoResponse for RouteError {
                 (StatusCode::UNAUTHORIZED, Json(UNAUTHORIZED_CLIENT))
             }
             Self::InvalidGrant => (StatusCode::BAD_REQUEST, Json(INVALID_GRANT)),
+            Self::AuthorizationPending => (StatusCode::BAD_REQUEST, Json(AUTHORIZATION_PENDING)),
+            Self::ExpiredToken => (StatusCode::BAD_REQUEST, Json(EXPIRED_TOKEN)),
+            Self::AccessDenied => (StatusCode::BAD_REQUEST, Json(ACCESS_DENIED)),
         }
         .into_response()
     }
@@ -154,12 +187,16 @@ impl From<ClaimError> for RouteError {
 }
 
 #[tracing::instrument(skip_all, err)]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
     client_authorization: ClientAuthorization<AccessTokenRequest>,
     Extension(key_store): Extension<Arc<StaticKeystore>>,
     Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
     Extension(encrypter): Extension<Encrypter>,
+    Extension(jwks_cache): Extension<ClientJwksCache>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Extension(homeserver): Extension<HomeserverConnection>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut txn = pool.begin().await?;
 
@@ -171,18 +208,40 @@ pub(crate) async fn post(
 
     client_authorization
         .credentials
-        .verify(&encrypter, method, &client)
+        .verify(&encrypter, &jwks_cache, method, &client)
         .await?;
 
     let form = client_authorization.form.ok_or(RouteError::BadRequest)?;
 
     let reply = match form {
         AccessTokenRequest::AuthorizationCode(grant) => {
-            authorization_code_grant(&grant, &client, &key_store, &url_builder, txn).await?
+            authorization_code_grant(
+                &grant,
+                &client,
+                &key_store,
+                &jwks_cache,
+                &url_builder,
+                &authentication_config,
+                &homeserver,
+                txn,
+            )
+            .await?
         }
         AccessTokenRequest::RefreshToken(grant) => {
             refresh_token_grant(&grant, &client, txn).await?
         }
+        AccessTokenRequest::ClientInitiatedBackchannelAuthentication(grant) => {
+            ciba_grant(
+                &grant,
+                &client,
+                &key_store,
+                &jwks_cache,
+                &url_builder,
+                &authentication_config,
+                txn,
+            )
+            .await?
+        }
         _ => {
             return Err(RouteError::InvalidGrant);
         }
@@ -205,12 +264,15 @@ fn hash<H: Digest>(mut hasher: H, token: &str) -> anyhow::Result<String> {
     Ok(BASE64URL_NOPAD.encode(bits))
 }
 
-#[allow(clippy::too_many_lines)]
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 async fn authorization_code_grant(
     grant: &AuthorizationCodeGrant,
     client: &Client<PostgresqlBackend>,
     key_store: &StaticKeystore,
+    jwks_cache: &ClientJwksCache,
     url_builder: &UrlBuilder,
+    authentication_config: &AuthenticationConfig,
+    homeserver: &HomeserverConnection,
     mut txn: Transaction<'_, Postgres>,
 ) -> Result<AccessTokenResponse, RouteError> {
     // TODO: there is a bunch of unnecessary cloning here
@@ -235,8 +297,12 @@ async fn authorization_code_grant(
             // Ending the session if the token was already exchanged more than 20s ago
             if now - exchanged_at > Duration::seconds(20) {
                 debug!("Ending potentially compromised session");
+                let username = session.browser_session.user.username.clone();
+                let scope = session.scope.clone();
                 end_oauth_session(&mut txn, session).await?;
                 txn.commit().await?;
+
+                homeserver.delete_devices_for_scope(&username, &scope).await;
             }
 
             return Err(RouteError::InvalidGrant);
@@ -310,6 +376,10 @@ async fn authorization_code_grant(
         }
         if let Some(ref last_authentication) = browser_session.last_authentication {
             claims::AUTH_TIME.insert(&mut claims, last_authentication.created_at)?;
+            if let Some(acr) = authentication_config.acr_for(last_authentication.method) {
+                claims::ACR.insert(&mut claims, acr.to_owned())?;
+            }
+            claims::AMR.insert(&mut claims, last_authentication.amr())?;
         }
 
         claims::AT_HASH.insert(&mut claims, hash(Sha256::new(), &access_token_str)?)?;
@@ -323,9 +393,24 @@ async fn authorization_code_grant(
             )
             .await?;
         let id_token = DecodedJsonWebToken::new(header, claims);
-        let id_token = id_token.sign(key_store).await?;
+        let id_token = id_token.sign(key_store).await?.serialize();
+
+        // Wrap the signed ID token in a JWE if the client asked for it
+        let id_token = if let Some(alg) = client.id_token_encrypted_response_alg {
+            encrypt_for_client(
+                jwks_cache,
+                client,
+                alg,
+                client.id_token_encrypted_response_enc,
+                Some("JWT"),
+                id_token.as_bytes(),
+            )
+            .await?
+        } else {
+            id_token
+        };
 
-        Some(id_token.serialize())
+        Some(id_token)
     } else {
         None
     };
@@ -388,3 +473,128 @@ async fn refresh_token_grant(
 
     Ok(params)
 }
+
+async fn ciba_grant(
+    grant: &CibaGrant,
+    client: &Client<PostgresqlBackend>,
+    key_store: &StaticKeystore,
+    jwks_cache: &ClientJwksCache,
+    url_builder: &UrlBuilder,
+    authentication_config: &AuthenticationConfig,
+    mut txn: Transaction<'_, Postgres>,
+) -> Result<AccessTokenResponse, RouteError> {
+    if !client
+        .grant_types
+        .contains(&GrantType::ClientInitiatedBackchannelAuthentication)
+    {
+        return Err(RouteError::UnauthorizedClient);
+    }
+
+    let request = lookup_ciba_request_by_auth_req_id(&mut txn, &grant.auth_req_id).await?;
+
+    if client.client_id != request.client.client_id {
+        return Err(RouteError::InvalidGrant);
+    }
+
+    let session = match &request.state {
+        CibaRequestState::Pending if request.is_expired(Utc::now()) => {
+            return Err(RouteError::ExpiredToken);
+        }
+        CibaRequestState::Pending => return Err(RouteError::AuthorizationPending),
+        CibaRequestState::Denied { denied_at } => {
+            debug!(%denied_at, "Backchannel request was denied");
+            return Err(RouteError::AccessDenied);
+        }
+        CibaRequestState::Exchanged { exchanged_at, .. } => {
+            debug!(%exchanged_at, "Backchannel request was already exchanged");
+            return Err(RouteError::InvalidGrant);
+        }
+        CibaRequestState::Approved { session, .. } => session,
+    };
+
+    let browser_session = &session.browser_session;
+
+    let ttl = Duration::minutes(5);
+    let (access_token_str, refresh_token_str) = {
+        let mut rng = thread_rng();
+        (
+            TokenType::AccessToken.generate(&mut rng),
+            TokenType::RefreshToken.generate(&mut rng),
+        )
+    };
+
+    let access_token = add_access_token(&mut txn, session, &access_token_str, ttl).await?;
+
+    let _refresh_token =
+        add_refresh_token(&mut txn, session, access_token, &refresh_token_str).await?;
+
+    let id_token = if session.scope.contains(&scope::OPENID) {
+        let mut claims = HashMap::new();
+        let now = Utc::now();
+        claims::ISS.insert(&mut claims, url_builder.oidc_issuer().to_string())?;
+        claims::SUB.insert(&mut claims, &browser_session.user.sub)?;
+        claims::AUD.insert(&mut claims, client.client_id.clone())?;
+        claims::IAT.insert(&mut claims, now)?;
+        claims::EXP.insert(&mut claims, now + Duration::hours(1))?;
+
+        if let Some(ref last_authentication) = browser_session.last_authentication {
+            claims::AUTH_TIME.insert(&mut claims, last_authentication.created_at)?;
+            if let Some(acr) = authentication_config.acr_for(last_authentication.method) {
+                claims::ACR.insert(&mut claims, acr.to_owned())?;
+            }
+            claims::AMR.insert(&mut claims, last_authentication.amr())?;
+        }
+
+        claims::AT_HASH.insert(&mut claims, hash(Sha256::new(), &access_token_str)?)?;
+        claims::URN_OPENID_PARAMS_JWT_CLAIM_AUTH_REQ_ID
+            .insert(&mut claims, request.auth_req_id.clone())?;
+
+        let header = key_store
+            .prepare_header(
+                client
+                    .id_token_signed_response_alg
+                    .unwrap_or(JsonWebSignatureAlg::Rs256),
+            )
+            .await?;
+        let id_token = DecodedJsonWebToken::new(header, claims);
+        let id_token = id_token.sign(key_store).await?.serialize();
+
+        // Wrap the signed ID token in a JWE if the client asked for it
+        let id_token = if let Some(alg) = client.id_token_encrypted_response_alg {
+            encrypt_for_client(
+                jwks_cache,
+                client,
+                alg,
+                client.id_token_encrypted_response_enc,
+                Some("JWT"),
+                id_token.as_bytes(),
+            )
+            .await?
+        } else {
+            id_token
+        };
+
+        Some(id_token)
+    } else {
+        None
+    };
+
+    let mut params = AccessTokenResponse::new(access_token_str)
+        .with_expires_in(ttl)
+        .with_refresh_token(refresh_token_str)
+        .with_scope(session.scope.clone());
+
+    if let Some(id_token) = id_token {
+        params = params.with_id_token(id_token);
+    }
+
+    // This fails if another poll exchanged the request concurrently, in which
+    // case the tokens minted above are rolled back with the transaction
+    exchange_ciba_request(&mut txn, request)
+        .await?
+        .ok_or(RouteError::InvalidGrant)?;
+
+    txn.commit().await?;
+
+    Ok(params)
+}
@@ -20,7 +20,9 @@ use axum::{
     Json, TypedHeader,
 };
 use headers::ContentType;
-use mas_axum_utils::{user_authorization::UserAuthorization, FancyError};
+use mas_axum_utils::{
+    client_jwks::ClientJwksCache, user_authorization::UserAuthorization, FancyError,
+};
 use mas_jose::{DecodedJsonWebToken, SigningKeystore, StaticKeystore};
 use mas_router::UrlBuilder;
 use mime::Mime;
@@ -29,6 +31,8 @@ use serde::Serialize;
 use serde_with::skip_serializing_none;
 use sqlx::PgPool;
 
+use super::encryption::encrypt_for_client;
+
 #[skip_serializing_none]
 #[derive(Serialize)]
 struct UserInfo {
@@ -50,6 +54,7 @@ pub async fn get(
     Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
     Extension(key_store): Extension<Arc<StaticKeystore>>,
+    Extension(jwks_cache): Extension<ClientJwksCache>,
     user_authorization: UserAuthorization,
 ) -> Result<Response, FancyError> {
     // TODO: error handling
@@ -72,23 +77,43 @@ pub async fn get(
         }
     }
 
-    if let Some(alg) = session.client.userinfo_signed_response_alg {
+    let client = &session.client;
+
+    // The response is a signed JWT if the client asked for it, and can be
+    // wrapped in a JWE on top of that
+    let (body, cty) = if let Some(alg) = client.userinfo_signed_response_alg {
         let header = key_store.prepare_header(alg).await?;
 
         let user_info = SignedUserInfo {
             iss: url_builder.oidc_issuer().to_string(),
-            aud: session.client.client_id,
+            aud: client.client_id.clone(),
             user_info,
         };
 
         let user_info = DecodedJsonWebToken::new(header, user_info);
         let user_info = user_info.sign(key_store.as_ref()).await?;
+        (user_info.serialize(), Some("JWT"))
+    } else if client.userinfo_encrypted_response_alg.is_some() {
+        (serde_json::to_string(&user_info)?, None)
+    } else {
+        return Ok(Json(user_info).into_response());
+    };
 
-        let token = user_info.serialize();
-        let application_jwt: Mime = "application/jwt".parse().unwrap();
-        let content_type = ContentType::from(application_jwt);
-        Ok((TypedHeader(content_type), token).into_response())
+    let body = if let Some(alg) = client.userinfo_encrypted_response_alg {
+        encrypt_for_client(
+            &jwks_cache,
+            client,
+            alg,
+            client.userinfo_encrypted_response_enc,
+            cty,
+            body.as_bytes(),
+        )
+        .await?
     } else {
-        Ok(Json(user_info).into_response())
-    }
+        body
+    };
+
+    let application_jwt: Mime = "application/jwt".parse().unwrap();
+    let content_type = ContentType::from(application_jwt);
+    Ok((TypedHeader(content_type), body).into_response())
 }
@@ -0,0 +1,199 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Time-based one-time passwords, as described in RFC 6238, and the recovery
+//! codes which can be used in their place
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use url::Url;
+
+/// Length of a time step, in seconds
+const STEP: i64 = 30;
+
+/// How many steps before and after the current one are accepted, to account
+/// for clock drift
+const SKEW: i64 = 1;
+
+const DIGITS: u32 = 6;
+
+const RECOVERY_CODES: usize = 10;
+
+/// Generate a new random TOTP secret
+pub(crate) fn generate_secret(rng: impl Rng) -> Vec<u8> {
+    // 160 bits, as recommended by RFC 4226
+    rng.sample_iter(rand::distributions::Standard)
+        .take(20)
+        .collect()
+}
+
+/// Compute the HOTP value of a secret for a counter
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, see RFC 4226 section 5.4
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    value % 10_u32.pow(DIGITS)
+}
+
+/// Compute the code of a secret for a time step
+fn code_at_step(secret: &[u8], step: i64) -> String {
+    let counter = u64::try_from(step).unwrap_or_default();
+    format!("{:0width$}", hotp(secret, counter), width = DIGITS as usize)
+}
+
+/// The time step a UNIX timestamp falls in
+pub(crate) fn step_at(timestamp: i64) -> i64 {
+    timestamp.div_euclid(STEP)
+}
+
+/// Check a code against a secret at a given time. Returns the time step it
+/// matched, which callers should record to prevent the code from being
+/// replayed.
+pub(crate) fn verify(secret: &[u8], code: &str, timestamp: i64) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = step_at(timestamp);
+    (current - SKEW..=current + SKEW).find(|step| code_at_step(secret, *step) == code)
+}
+
+/// Whether a code looks like a TOTP code rather than a recovery code
+pub(crate) fn is_totp_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Encode a secret in the base32 representation authenticator apps expect
+pub(crate) fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// Build the `otpauth://` URI authenticator apps scan as a QR code
+pub(crate) fn provisioning_uri(secret: &[u8], issuer: &str, account: &str) -> Url {
+    // The label is part of the path and isn't escaped by `Url` the way query
+    // parameters are
+    let label: String =
+        url::form_urlencoded::byte_serialize(format!("{issuer}:{account}").as_bytes())
+            .collect::<String>()
+            .replace('+', "%20");
+    let mut uri = Url::parse(&format!("otpauth://totp/{label}")).expect("valid otpauth URI");
+    uri.query_pairs_mut()
+        .append_pair("secret", &encode_secret(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string());
+    uri
+}
+
+/// Render the provisioning URI as an SVG QR code, suitable for a `data:` URI
+pub(crate) fn qr_code_svg(uri: &Url) -> anyhow::Result<String> {
+    let code = qrcode::QrCode::new(uri.as_str().as_bytes())?;
+    Ok(code
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Generate a fresh set of recovery codes, formatted like `abcde-fghij`
+pub(crate) fn generate_recovery_codes(mut rng: impl Rng) -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc4226_vectors() {
+        let expected = [
+            755_224, 287_082, 359_152, 969_429, 338_314, 254_676, 287_922, 162_583, 399_871,
+            520_489,
+        ];
+        for (counter, expected) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), expected);
+        }
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        // The RFC uses 8 digits, we only keep the last 6
+        assert_eq!(code_at_step(SECRET, step_at(59)), "287082");
+        assert_eq!(code_at_step(SECRET, step_at(1_111_111_109)), "081804");
+        assert_eq!(code_at_step(SECRET, step_at(1_234_567_890)), "005924");
+        assert_eq!(code_at_step(SECRET, step_at(2_000_000_000)), "279037");
+    }
+
+    #[test]
+    fn test_verify_window() {
+        let now = 1_111_111_109;
+        let step = step_at(now);
+
+        assert_eq!(verify(SECRET, "081804", now), Some(step));
+        assert_eq!(verify(SECRET, " 081804 ", now), Some(step));
+
+        let previous = code_at_step(SECRET, step - 1);
+        assert_eq!(verify(SECRET, &previous, now), Some(step - 1));
+
+        let too_old = code_at_step(SECRET, step - 2);
+        assert_eq!(verify(SECRET, &too_old, now), None);
+
+        assert_eq!(verify(SECRET, "81804", now), None);
+        assert_eq!(verify(SECRET, "abcdef", now), None);
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let uri = provisioning_uri(SECRET, "example.com", "john");
+        assert_eq!(
+            uri.as_str(),
+            "otpauth://totp/example.com%3Ajohn?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=example.com&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes(rand::thread_rng());
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(code.find('-'), Some(5));
+        }
+    }
+}
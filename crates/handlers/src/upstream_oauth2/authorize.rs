@@ -0,0 +1,125 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use hyper::StatusCode;
+use mas_config::{Encrypter, UpstreamOAuth2Config};
+use mas_iana::oauth::{OAuthAuthorizationEndpointResponseType, PkceCodeChallengeMethod};
+use mas_router::UrlBuilder;
+use oauth2_types::pkce::{self, CodeChallengeMethodExt};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::Serialize;
+use thiserror::Error;
+use url::Url;
+
+use super::{discover, UpstreamSession};
+use crate::views::shared::OptionalPostAuthAction;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+        };
+
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct AuthorizationParams<'a> {
+    response_type: OAuthAuthorizationEndpointResponseType,
+    client_id: &'a str,
+    redirect_uri: Url,
+    scope: &'a str,
+    state: &'a str,
+    nonce: &'a str,
+    #[serde(flatten)]
+    pkce: pkce::AuthorizationRequest,
+}
+
+#[tracing::instrument(skip(upstream_oauth2, url_builder, cookie_jar), err)]
+pub(crate) async fn get(
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Path(provider_id): Path<String>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<impl IntoResponse, RouteError> {
+    let provider = upstream_oauth2
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let metadata = discover(provider).await?;
+
+    let (state, nonce, code_verifier) = {
+        let mut rng = thread_rng();
+        (
+            Alphanumeric.sample_string(&mut rng, 32),
+            Alphanumeric.sample_string(&mut rng, 32),
+            Alphanumeric.sample_string(&mut rng, 64),
+        )
+    };
+
+    let code_challenge_method = PkceCodeChallengeMethod::S256;
+    let params = AuthorizationParams {
+        response_type: OAuthAuthorizationEndpointResponseType::Code,
+        client_id: &provider.client_id,
+        redirect_uri: url_builder.upstream_oauth2_callback(&provider.id),
+        scope: &provider.scope,
+        state: &state,
+        nonce: &nonce,
+        pkce: pkce::AuthorizationRequest {
+            code_challenge_method,
+            code_challenge: code_challenge_method
+                .compute_challenge(&code_verifier)
+                .into_owned(),
+        },
+    };
+
+    let mut destination = metadata.authorization_endpoint;
+    params
+        .serialize(serde_urlencoded::Serializer::new(
+            &mut destination.query_pairs_mut(),
+        ))
+        .map_err(anyhow::Error::from)?;
+
+    let session = UpstreamSession {
+        provider: provider.id.clone(),
+        state,
+        nonce,
+        code_verifier,
+        action: query,
+    };
+    let cookie_jar = session.save(cookie_jar);
+
+    Ok((cookie_jar, Redirect::to(destination.as_str())))
+}
@@ -0,0 +1,169 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use hyper::StatusCode;
+use mas_axum_utils::SessionClient;
+use mas_config::{AuthenticationConfig, Encrypter, UpstreamOAuth2Config};
+use mas_data_model::AuthenticationMethod;
+use mas_policy::PolicyFactory;
+use mas_router::UrlBuilder;
+use serde::Deserialize;
+use sqlx::PgPool;
+use thiserror::Error;
+
+use super::{
+    discover, exchange_code,
+    login::{login_or_register, UpstreamIdentity},
+    validate_id_token, UpstreamSession,
+};
+use crate::HomeserverConnection;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Params {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+
+    #[error("no upstream login in progress")]
+    MissingSession,
+
+    #[error("state parameter mismatch")]
+    StateMismatch,
+
+    #[error("missing authorization code")]
+    MissingCode,
+
+    #[error("upstream provider returned an error: {error}")]
+    ProviderError {
+        error: String,
+        description: Option<String>,
+    },
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self {
+            Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+            Self::MissingSession
+            | Self::StateMismatch
+            | Self::MissingCode
+            | Self::ProviderError { .. } => StatusCode::BAD_REQUEST,
+        };
+
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+#[tracing::instrument(
+    skip(
+        pool,
+        upstream_oauth2,
+        url_builder,
+        policy_factory,
+        homeserver,
+        cookie_jar
+    ),
+    err
+)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn get(
+    Extension(pool): Extension<PgPool>,
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Path(provider_id): Path<String>,
+    Query(params): Query<Params>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, RouteError> {
+    let (session, cookie_jar) = UpstreamSession::take(cookie_jar);
+    let session = session.ok_or(RouteError::MissingSession)?;
+
+    if session.provider != provider_id || params.state.as_ref() != Some(&session.state) {
+        return Err(RouteError::StateMismatch);
+    }
+
+    if let Some(error) = params.error {
+        return Err(RouteError::ProviderError {
+            error,
+            description: params.error_description,
+        });
+    }
+
+    let code = params.code.ok_or(RouteError::MissingCode)?;
+
+    let provider = upstream_oauth2
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let metadata = discover(provider).await?;
+
+    let response = exchange_code(
+        provider,
+        &metadata,
+        code,
+        url_builder.upstream_oauth2_callback(&provider.id),
+        session.code_verifier,
+    )
+    .await?;
+
+    let id_token = response
+        .id_token()
+        .ok_or_else(|| anyhow::anyhow!("provider did not return an ID token"))?;
+    let (subject, claims) =
+        validate_id_token(id_token, provider, &metadata, &session.nonce).await?;
+
+    let identity = UpstreamIdentity {
+        provider_id: &provider.id,
+        provider_name: &provider.name,
+        claims_mapping: &provider.claims,
+        method: AuthenticationMethod::UpstreamOAuth2,
+        subject,
+        claims,
+    };
+
+    let response = login_or_register(
+        &pool,
+        &policy_factory,
+        &homeserver,
+        identity,
+        session.action,
+        &client,
+        &authentication_config.session_expiration(),
+        cookie_jar,
+    )
+    .await?;
+    Ok(response)
+}
@@ -0,0 +1,128 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What happens once an upstream provider vouched for a user, shared by the
+//! OIDC and SAML providers
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::response::{IntoResponse, Response};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{SessionClient, SessionInfoExt};
+use mas_config::{ClaimsMappingConfig, Encrypter};
+use mas_data_model::{AuthenticationMethod, SessionExpiration};
+use mas_policy::PolicyFactory;
+use mas_router::{Route, UpstreamOAuth2Register};
+use mas_storage::{
+    upstream_oauth2::{add_upstream_oauth_link, lookup_upstream_oauth_link},
+    user::{record_session_authentication, start_session},
+};
+use serde_json::Value;
+use sqlx::PgPool;
+
+use super::register::{register_and_login, validate_username, PendingRegistration};
+use crate::{views::shared::OptionalPostAuthAction, HomeserverConnection};
+
+/// An identity asserted by an upstream provider
+pub(crate) struct UpstreamIdentity<'a> {
+    pub provider_id: &'a str,
+    pub provider_name: &'a str,
+    pub claims_mapping: &'a ClaimsMappingConfig,
+    pub method: AuthenticationMethod,
+    pub subject: String,
+    pub claims: HashMap<String, Value>,
+}
+
+/// Log in the user linked to the upstream identity, link it to the user
+/// currently logged in, or provision a new user for it
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn login_or_register(
+    pool: &PgPool,
+    policy_factory: &Arc<PolicyFactory>,
+    homeserver: &HomeserverConnection,
+    identity: UpstreamIdentity<'_>,
+    action: OptionalPostAuthAction,
+    client: &SessionClient,
+    expiration: &SessionExpiration,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> anyhow::Result<Response> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let linked_user =
+        lookup_upstream_oauth_link(&mut txn, identity.provider_id, &identity.subject).await?;
+    let user = if let Some(user) = linked_user {
+        user
+    } else if let Some(current) = &maybe_session {
+        // Link the upstream account to whoever is currently logged in
+        add_upstream_oauth_link(
+            &mut txn,
+            identity.provider_id,
+            &identity.subject,
+            &current.user,
+        )
+        .await?;
+        current.user.clone()
+    } else {
+        // First time we see this user, provision a local account for them
+        let registration = PendingRegistration::from_identity(identity, action);
+
+        // Use the username derived from the claims straight away if it is
+        // usable, else let the user pick one
+        if let Some(localpart) = registration.localpart() {
+            let localpart = localpart.to_owned();
+            let state =
+                validate_username(&mut txn, policy_factory, &registration, &localpart).await?;
+            if state.is_valid() {
+                return register_and_login(
+                    txn,
+                    homeserver,
+                    registration,
+                    &localpart,
+                    client,
+                    expiration,
+                    cookie_jar,
+                )
+                .await;
+            }
+        }
+
+        let cookie_jar = registration.save(cookie_jar);
+        return Ok((cookie_jar, UpstreamOAuth2Register.go()).into_response());
+    };
+
+    // Reuse the current session if it already belongs to that user
+    let mut browser_session = match maybe_session {
+        Some(browser_session) if browser_session.user.data == user.data => browser_session,
+        _ => {
+            start_session(
+                &mut txn,
+                user,
+                client.user_agent.as_deref(),
+                client.ip_address,
+                expiration,
+            )
+            .await?
+        }
+    };
+
+    record_session_authentication(&mut txn, &mut browser_session, identity.method, None).await?;
+
+    txn.commit().await?;
+
+    let cookie_jar = cookie_jar.set_session(&browser_session);
+    Ok((cookie_jar, action.go_next()).into_response())
+}
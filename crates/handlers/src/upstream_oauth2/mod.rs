@@ -0,0 +1,276 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log in through upstream OIDC providers
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use axum_extra::extract::{cookie::Cookie, PrivateCookieJar};
+use hyper::{header::CONTENT_TYPE, Body, Request};
+use mas_config::{Encrypter, UpstreamProviderConfig};
+use mas_http::HttpServiceExt;
+use mas_jose::{claims, DecodedJsonWebToken, JsonWebKeySet, JsonWebTokenParts, StaticJwksStore};
+use oauth2_types::requests::{AccessTokenRequest, AccessTokenResponse, AuthorizationCodeGrant};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::skip_serializing_none;
+use tower::ServiceExt;
+use url::Url;
+
+use crate::views::shared::OptionalPostAuthAction;
+
+pub(crate) mod authorize;
+pub(crate) mod callback;
+pub(crate) mod login;
+pub(crate) mod register;
+
+static SESSION_COOKIE: &str = "upstream-oauth2-session";
+
+/// The subset of a provider discovery document we need
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: Url,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    jwks_uri: Url,
+}
+
+/// State kept in a cookie between the redirect to the provider and the
+/// callback
+#[derive(Debug, Serialize, Deserialize)]
+struct UpstreamSession {
+    provider: String,
+    state: String,
+    nonce: String,
+    code_verifier: String,
+    #[serde(flatten)]
+    action: OptionalPostAuthAction,
+}
+
+impl UpstreamSession {
+    fn save(&self, cookie_jar: PrivateCookieJar<Encrypter>) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, SESSION_COOKIE, self)
+    }
+
+    fn take(
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> (Option<Self>, PrivateCookieJar<Encrypter>) {
+        let session = load_cookie(&cookie_jar, SESSION_COOKIE);
+        (session, remove_cookie(cookie_jar, SESSION_COOKIE))
+    }
+}
+
+// Those cookies are encoded as a query string rather than with the usual
+// cookie encoding, because the post auth action only roundtrips through that
+pub(crate) fn save_cookie<T: Serialize>(
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    name: &'static str,
+    value: &T,
+) -> PrivateCookieJar<Encrypter> {
+    let value = serde_urlencoded::to_string(value).unwrap();
+    let mut cookie = Cookie::new(name, value);
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie_jar.add(cookie)
+}
+
+pub(crate) fn load_cookie<T: DeserializeOwned>(
+    cookie_jar: &PrivateCookieJar<Encrypter>,
+    name: &'static str,
+) -> Option<T> {
+    cookie_jar
+        .get(name)
+        .and_then(|cookie| serde_urlencoded::from_str(cookie.value()).ok())
+}
+
+pub(crate) fn remove_cookie(
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    name: &'static str,
+) -> PrivateCookieJar<Encrypter> {
+    let mut cookie = Cookie::named(name);
+    cookie.set_path("/");
+    cookie_jar.remove(cookie)
+}
+
+async fn fetch_json<T: DeserializeOwned>(
+    operation: &'static str,
+    request: Request<Body>,
+) -> anyhow::Result<T> {
+    let response = mas_http::client(operation)
+        .json::<T>()
+        .oneshot(request)
+        .await?;
+
+    anyhow::ensure!(
+        response.status().is_success(),
+        "provider replied with status {}",
+        response.status()
+    );
+
+    Ok(response.into_body())
+}
+
+/// Fetch and check the discovery document of a provider
+async fn discover(provider: &UpstreamProviderConfig) -> anyhow::Result<ProviderMetadata> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.as_str().trim_end_matches('/')
+    );
+    let request = Request::builder()
+        .method("GET")
+        .uri(url)
+        .body(Body::empty())?;
+
+    let metadata: ProviderMetadata = fetch_json("upstream-oauth2-discover", request)
+        .await
+        .context("could not fetch the provider discovery document")?;
+
+    if metadata.issuer != provider.issuer {
+        bail!("provider advertised a different issuer");
+    }
+
+    Ok(metadata)
+}
+
+#[skip_serializing_none]
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    #[serde(flatten)]
+    grant: AccessTokenRequest,
+    client_id: &'a str,
+    client_secret: Option<&'a str>,
+}
+
+/// Exchange an authorization code at the token endpoint of the provider
+async fn exchange_code(
+    provider: &UpstreamProviderConfig,
+    metadata: &ProviderMetadata,
+    code: String,
+    redirect_uri: Url,
+    code_verifier: String,
+) -> anyhow::Result<AccessTokenResponse> {
+    let body = TokenRequest {
+        grant: AccessTokenRequest::AuthorizationCode(AuthorizationCodeGrant {
+            code,
+            redirect_uri: Some(redirect_uri),
+            code_verifier: Some(code_verifier),
+        }),
+        client_id: &provider.client_id,
+        client_secret: provider.client_secret.as_deref(),
+    };
+
+    let request = Request::builder()
+        .method("POST")
+        .uri(metadata.token_endpoint.as_str())
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(serde_urlencoded::to_string(&body)?))?;
+
+    fetch_json("upstream-oauth2-token", request)
+        .await
+        .context("could not exchange the authorization code")
+}
+
+/// Validate the ID token returned by the provider, and return its subject
+/// along with all its claims
+async fn validate_id_token(
+    id_token: &str,
+    provider: &UpstreamProviderConfig,
+    metadata: &ProviderMetadata,
+    nonce: &str,
+) -> anyhow::Result<(String, HashMap<String, Value>)> {
+    let request = Request::builder()
+        .method("GET")
+        .uri(metadata.jwks_uri.as_str())
+        .body(Body::empty())?;
+    let jwks: JsonWebKeySet = fetch_json("upstream-oauth2-jwks", request)
+        .await
+        .context("could not fetch the provider keys")?;
+    let key_store = StaticJwksStore::new(jwks);
+
+    let jwt: JsonWebTokenParts = id_token.parse()?;
+    let decoded: DecodedJsonWebToken<HashMap<String, Value>> = jwt.decode()?;
+    jwt.verify(decoded.header(), &key_store).await?;
+    let (_header, mut claims) = decoded.split();
+
+    let iss = claims::ISS.extract_required(&mut claims)?;
+    if iss != metadata.issuer.as_str() {
+        bail!("ID token was issued by someone else");
+    }
+
+    let aud = claims::AUD.extract_required(&mut claims)?;
+    if !aud.iter().any(|aud| aud == &provider.client_id) {
+        bail!("ID token was issued to another client");
+    }
+
+    claims::EXP.extract_required(&mut claims)?;
+
+    let token_nonce = claims::NONCE.extract_required(&mut claims)?;
+    if token_nonce != nonce {
+        bail!("ID token nonce does not match");
+    }
+
+    let sub = claims::SUB.extract_required(&mut claims)?;
+    // Keep it around for the claims mapping templates
+    claims.insert("sub".to_owned(), sub.clone().into());
+
+    Ok((sub, claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_router::PostAuthAction;
+
+    use super::*;
+
+    #[test]
+    fn session_roundtrip() {
+        let session = UpstreamSession {
+            provider: "google".to_owned(),
+            state: "state".to_owned(),
+            nonce: "nonce".to_owned(),
+            code_verifier: "verifier".to_owned(),
+            action: OptionalPostAuthAction {
+                post_auth_action: Some(PostAuthAction::continue_grant(42)),
+            },
+        };
+
+        let encoded = serde_urlencoded::to_string(&session).unwrap();
+        let decoded: UpstreamSession = serde_urlencoded::from_str(&encoded).unwrap();
+        assert_eq!(decoded.provider, "google");
+        assert_eq!(decoded.code_verifier, "verifier");
+        assert!(matches!(
+            decoded.action.post_auth_action,
+            Some(PostAuthAction::ContinueAuthorizationGrant { data: 42 })
+        ));
+    }
+
+    #[test]
+    fn token_request_form() {
+        let body = TokenRequest {
+            grant: AccessTokenRequest::AuthorizationCode(AuthorizationCodeGrant {
+                code: "abcd".to_owned(),
+                redirect_uri: None,
+                code_verifier: Some("verifier".to_owned()),
+            }),
+            client_id: "client",
+            client_secret: None,
+        };
+
+        assert_eq!(
+            serde_urlencoded::to_string(&body).unwrap(),
+            "grant_type=authorization_code&code=abcd&code_verifier=verifier&client_id=client"
+        );
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    FancyError, SessionClient, SessionInfoExt,
+};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{AuthenticationMethod, SessionExpiration, User};
+use mas_policy::PolicyFactory;
+use mas_storage::{
+    upstream_oauth2::add_upstream_oauth_link,
+    user::{
+        add_user_email, mark_user_email_as_verified, record_session_authentication,
+        register_passwordless_user, set_user_email_as_primary, start_session, username_exists,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    render_claims_mapping, FieldError, FormError, FormState, TemplateContext, Templates,
+    ToFormState, UpstreamRegisterContext, UpstreamRegisterFormField,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::skip_serializing_none;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use tracing::warn;
+
+use super::{load_cookie, login::UpstreamIdentity, remove_cookie, save_cookie};
+use crate::{
+    homeserver::is_valid_localpart, views::shared::OptionalPostAuthAction, HomeserverConnection,
+};
+
+static REGISTRATION_COOKIE: &str = "upstream-oauth2-registration";
+
+/// A user who logged in through an upstream provider but has no local account
+/// yet, kept in a cookie while they pick a username
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct PendingRegistration {
+    provider: String,
+    provider_name: String,
+    method: AuthenticationMethod,
+    subject: String,
+    localpart: Option<String>,
+    displayname: Option<String>,
+    email: Option<String>,
+    #[serde(flatten)]
+    action: OptionalPostAuthAction,
+}
+
+impl PendingRegistration {
+    /// Derive the attributes of the new user from the upstream claims
+    pub(super) fn from_identity(
+        identity: UpstreamIdentity<'_>,
+        action: OptionalPostAuthAction,
+    ) -> Self {
+        let claims = &identity.claims;
+        let map = |template: &str| {
+            render_claims_mapping(template, claims).unwrap_or_else(|e| {
+                warn!(error = %e, template, "Could not map upstream claims");
+                None
+            })
+        };
+
+        // Only import emails the provider says it verified
+        let email = if claims.get("email_verified") == Some(&Value::Bool(true)) {
+            map(&identity.claims_mapping.email)
+        } else {
+            None
+        };
+
+        Self {
+            provider: identity.provider_id.to_owned(),
+            provider_name: identity.provider_name.to_owned(),
+            method: identity.method,
+            localpart: map(&identity.claims_mapping.localpart),
+            displayname: map(&identity.claims_mapping.displayname),
+            email,
+            subject: identity.subject,
+            action,
+        }
+    }
+
+    /// The username derived from the claims, if any
+    pub(super) fn localpart(&self) -> Option<&str> {
+        self.localpart.as_deref()
+    }
+
+    pub(super) fn save(
+        &self,
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, REGISTRATION_COOKIE, self)
+    }
+
+    fn load(cookie_jar: &PrivateCookieJar<Encrypter>) -> Option<Self> {
+        load_cookie(cookie_jar, REGISTRATION_COOKIE)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct UpstreamRegisterForm {
+    username: String,
+}
+
+impl ToFormState for UpstreamRegisterForm {
+    type Field = UpstreamRegisterFormField;
+}
+
+/// Check that the username is usable, and that the policy lets this user in
+pub(super) async fn validate_username(
+    conn: &mut PgConnection,
+    policy_factory: &PolicyFactory,
+    registration: &PendingRegistration,
+    username: &str,
+) -> anyhow::Result<FormState<UpstreamRegisterFormField>> {
+    let form = UpstreamRegisterForm {
+        username: username.to_owned(),
+    };
+    let mut state = form.to_form_state();
+
+    if username.is_empty() {
+        state.add_error_on_field(UpstreamRegisterFormField::Username, FieldError::Required);
+        return Ok(state);
+    }
+
+    if !is_valid_localpart(username) {
+        state.add_error_on_field(UpstreamRegisterFormField::Username, FieldError::Invalid);
+        return Ok(state);
+    }
+
+    if username_exists(&mut *conn, username).await? {
+        state.add_error_on_field(UpstreamRegisterFormField::Username, FieldError::Exists);
+    }
+
+    let mut policy = policy_factory.instantiate().await?;
+    let res = policy
+        .evaluate_register(username, None, registration.email.as_deref())
+        .await?;
+
+    for violation in res.violations {
+        match violation.field.as_deref() {
+            Some("username") => state.add_error_on_field(
+                UpstreamRegisterFormField::Username,
+                FieldError::Policy {
+                    message: violation.msg,
+                },
+            ),
+            _ => state.add_error_on_form(FormError::Policy {
+                message: violation.msg,
+            }),
+        }
+    }
+
+    Ok(state)
+}
+
+/// Create the local user, link it to the upstream account and log it in
+pub(super) async fn register_and_login(
+    mut txn: Transaction<'_, Postgres>,
+    homeserver: &HomeserverConnection,
+    registration: PendingRegistration,
+    username: &str,
+    client: &SessionClient,
+    expiration: &SessionExpiration,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> anyhow::Result<Response> {
+    let user = provision_user(&mut txn, &registration, username).await?;
+
+    let mut session = start_session(
+        &mut txn,
+        user,
+        client.user_agent.as_deref(),
+        client.ip_address,
+        expiration,
+    )
+    .await?;
+    record_session_authentication(&mut txn, &mut session, registration.method, None).await?;
+
+    txn.commit().await?;
+
+    // The account exists on our side at this point, so a homeserver failure
+    // should not prevent the user from continuing
+    if let Err(e) = homeserver
+        .provision_user(&session.user.username, registration.displayname.as_deref())
+        .await
+    {
+        warn!(error = %e, "Failed to provision user on the homeserver");
+    }
+
+    let cookie_jar = remove_cookie(cookie_jar, REGISTRATION_COOKIE).set_session(&session);
+    Ok((cookie_jar, registration.action.go_next()).into_response())
+}
+
+async fn provision_user(
+    txn: &mut Transaction<'_, Postgres>,
+    registration: &PendingRegistration,
+    username: &str,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let mut user = register_passwordless_user(&mut *txn, username).await?;
+
+    add_upstream_oauth_link(
+        &mut *txn,
+        &registration.provider,
+        &registration.subject,
+        &user,
+    )
+    .await?;
+
+    if let Some(email) = &registration.email {
+        let user_email = add_user_email(&mut *txn, &user, email).await?;
+        let user_email = mark_user_email_as_verified(&mut *txn, user_email).await?;
+        set_user_email_as_primary(&mut *txn, &user_email).await?;
+        user.primary_email = Some(user_email);
+    }
+
+    Ok(user)
+}
+
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let registration =
+        PendingRegistration::load(&cookie_jar).context("no upstream registration in progress")?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    // Explain why the username we got from the provider can't be used as-is
+    let state = match registration.localpart() {
+        Some(localpart) => {
+            let mut conn = pool.acquire().await?;
+            validate_username(&mut conn, &policy_factory, &registration, localpart).await?
+        }
+        None => FormState::default(),
+    };
+
+    let content = render(&templates, &registration, state, csrf_token).await?;
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<UpstreamRegisterForm>>,
+) -> Result<Response, FancyError> {
+    let form = cookie_jar.verify_form(form)?;
+    let registration =
+        PendingRegistration::load(&cookie_jar).context("no upstream registration in progress")?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let mut txn = pool.begin().await?;
+    let state = validate_username(&mut txn, &policy_factory, &registration, &form.username).await?;
+
+    if !state.is_valid() {
+        let content = render(&templates, &registration, state, csrf_token).await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let response = register_and_login(
+        txn,
+        &homeserver,
+        registration,
+        &form.username,
+        &client,
+        &authentication_config.session_expiration(),
+        cookie_jar,
+    )
+    .await?;
+    Ok(response)
+}
+
+async fn render(
+    templates: &Templates,
+    registration: &PendingRegistration,
+    state: FormState<UpstreamRegisterFormField>,
+    csrf_token: CsrfToken,
+) -> Result<String, FancyError> {
+    let ctx = UpstreamRegisterContext::new(registration.provider_name.clone())
+        .with_form_state(state)
+        .with_displayname(registration.displayname.clone())
+        .with_email(registration.email.clone())
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_upstream_register(&ctx).await?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mas_config::ClaimsMappingConfig;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn map_claims() {
+        let claims_mapping = ClaimsMappingConfig::default();
+        let claims: HashMap<String, Value> = serde_json::from_value(json!({
+            "sub": "abcd",
+            "preferred_username": "john",
+            "email": "john@example.com",
+            "email_verified": true,
+        }))
+        .unwrap();
+        let identity = |claims: &HashMap<String, Value>| UpstreamIdentity {
+            provider_id: "example",
+            provider_name: "Example",
+            claims_mapping: &claims_mapping,
+            method: AuthenticationMethod::UpstreamOAuth2,
+            subject: "abcd".to_owned(),
+            claims: claims.clone(),
+        };
+
+        let registration = PendingRegistration::from_identity(
+            identity(&claims),
+            OptionalPostAuthAction::default(),
+        );
+        assert_eq!(registration.localpart(), Some("john"));
+        // There is no `name` claim to map the display name from
+        assert_eq!(registration.displayname, None);
+        assert_eq!(registration.email.as_deref(), Some("john@example.com"));
+
+        // It should survive being saved in a cookie
+        let encoded = serde_urlencoded::to_string(&registration).unwrap();
+        let decoded: PendingRegistration = serde_urlencoded::from_str(&encoded).unwrap();
+        assert_eq!(decoded.localpart(), Some("john"));
+        assert_eq!(decoded.displayname, None);
+        assert_eq!(decoded.provider_name, "Example");
+        assert_eq!(decoded.method, AuthenticationMethod::UpstreamOAuth2);
+
+        // Unverified emails are not imported
+        let mut claims = claims;
+        claims.insert("email_verified".to_owned(), false.into());
+        let registration = PendingRegistration::from_identity(
+            identity(&claims),
+            OptionalPostAuthAction::default(),
+        );
+        assert_eq!(registration.email, None);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    extract::{Form, Path},
+    response::{Html, IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use hyper::StatusCode;
+use mas_axum_utils::SessionClient;
+use mas_config::{AuthenticationConfig, Encrypter, UpstreamSamlConfig};
+use mas_data_model::AuthenticationMethod;
+use mas_policy::PolicyFactory;
+use mas_router::UrlBuilder;
+use mas_saml::{validate_response, ResponseError, ValidationOptions};
+use mas_storage::upstream_saml::consume_saml_assertion;
+use mas_templates::{FormPostContext, Templates};
+use sqlx::PgPool;
+use thiserror::Error;
+
+use super::{assertion_claims, identity_provider, service_provider, SamlResponseForm, SamlSession};
+use crate::{
+    upstream_oauth2::login::{login_or_register, UpstreamIdentity},
+    HomeserverConnection,
+};
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("failed to render the form_post template")]
+    FormPostRender(#[from] mas_templates::TemplateError),
+
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+
+    #[error("no upstream login in progress")]
+    MissingSession,
+
+    #[error("response was sent for another provider")]
+    ProviderMismatch,
+
+    #[error("invalid SAML response: {0}")]
+    InvalidResponse(#[from] ResponseError),
+
+    #[error("assertion was already used")]
+    ReplayedAssertion,
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::Anyhow(_) | Self::FormPostRender(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+            Self::MissingSession
+            | Self::ProviderMismatch
+            | Self::InvalidResponse(_)
+            | Self::ReplayedAssertion => StatusCode::BAD_REQUEST,
+        };
+
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+/// Assertion consumer service.
+///
+/// The identity provider posts its response from another site, so the
+/// session cookies are not sent along. Post it again from our own origin to
+/// get them.
+#[tracing::instrument(skip(templates, upstream_saml, url_builder, form), err)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Path(provider_id): Path<String>,
+    Form(form): Form<SamlResponseForm>,
+) -> Result<Html<String>, RouteError> {
+    let provider = upstream_saml
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let ctx = FormPostContext::new(url_builder.upstream_saml_complete(&provider.id), form);
+    let content = templates.render_form_post(&ctx).await?;
+    Ok(Html(content))
+}
+
+/// Validate the response of the identity provider and log the user in
+#[tracing::instrument(
+    skip(
+        pool,
+        upstream_saml,
+        url_builder,
+        policy_factory,
+        homeserver,
+        cookie_jar,
+        form
+    ),
+    err
+)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn complete(
+    Extension(pool): Extension<PgPool>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Path(provider_id): Path<String>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<SamlResponseForm>,
+) -> Result<Response, RouteError> {
+    let (session, cookie_jar) = SamlSession::take(cookie_jar);
+    let session = session.ok_or(RouteError::MissingSession)?;
+
+    if session.provider != provider_id {
+        return Err(RouteError::ProviderMismatch);
+    }
+
+    let provider = upstream_saml
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let identity_provider = identity_provider(provider).await?;
+    let service_provider = service_provider(&url_builder, &provider.id);
+
+    let assertion = validate_response(
+        &form.saml_response,
+        &ValidationOptions {
+            identity_provider: &identity_provider,
+            service_provider: &service_provider,
+            request_id: &session.request_id,
+            now: Utc::now(),
+        },
+    )?;
+
+    // Each assertion can only be used once
+    let mut conn = pool
+        .acquire()
+        .await
+        .context("could not acquire a connection")?;
+    let fresh = consume_saml_assertion(
+        &mut conn,
+        &provider.id,
+        &assertion.id,
+        assertion.not_on_or_after,
+    )
+    .await
+    .context("could not record the assertion")?;
+    drop(conn);
+    if !fresh {
+        return Err(RouteError::ReplayedAssertion);
+    }
+
+    let identity = UpstreamIdentity {
+        provider_id: &provider.id,
+        provider_name: &provider.name,
+        claims_mapping: &provider.claims,
+        method: AuthenticationMethod::UpstreamSaml,
+        claims: assertion_claims(&assertion, provider.trust_email),
+        subject: assertion.name_id,
+    };
+
+    let response = login_or_register(
+        &pool,
+        &policy_factory,
+        &homeserver,
+        identity,
+        session.action,
+        &client,
+        &authentication_config.session_expiration(),
+        cookie_jar,
+    )
+    .await?;
+    Ok(response)
+}
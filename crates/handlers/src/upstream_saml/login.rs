@@ -0,0 +1,108 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Path, Query},
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use hyper::StatusCode;
+use mas_config::{Encrypter, UpstreamSamlConfig};
+use mas_router::UrlBuilder;
+use mas_saml::{AuthnRequest, Binding};
+use mas_templates::{FormPostContext, Templates};
+use rand::thread_rng;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{identity_provider, service_provider, SamlSession};
+use crate::views::shared::OptionalPostAuthAction;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("failed to render the form_post template")]
+    FormPostRender(#[from] mas_templates::TemplateError),
+
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::Anyhow(_) | Self::FormPostRender(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+        };
+
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct SamlRequestForm {
+    #[serde(rename = "SAMLRequest")]
+    saml_request: String,
+}
+
+/// Send the user to the identity provider with an authentication request
+#[tracing::instrument(skip(templates, upstream_saml, url_builder, cookie_jar), err)]
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Path(provider_id): Path<String>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, RouteError> {
+    let provider = upstream_saml
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let identity_provider = identity_provider(provider).await?;
+    let service_provider = service_provider(&url_builder, &provider.id);
+    let (binding, destination) = identity_provider.single_sign_on_service();
+
+    let request = AuthnRequest::new(
+        &mut thread_rng(),
+        &service_provider,
+        destination.clone(),
+        Utc::now(),
+    );
+
+    let session = SamlSession {
+        provider: provider.id.clone(),
+        request_id: request.id().to_owned(),
+        action: query,
+    };
+    let cookie_jar = session.save(cookie_jar);
+
+    let response = match binding {
+        Binding::HttpRedirect => Redirect::to(request.redirect_url(None).as_str()).into_response(),
+        Binding::HttpPost => {
+            let params = SamlRequestForm {
+                saml_request: request.post_value(),
+            };
+            let ctx = FormPostContext::new(destination.clone(), params);
+            let content = templates.render_form_post(&ctx).await?;
+            Html(content).into_response()
+        }
+    };
+
+    Ok((cookie_jar, response).into_response())
+}
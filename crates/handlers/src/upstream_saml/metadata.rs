@@ -0,0 +1,56 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use hyper::{header::CONTENT_TYPE, StatusCode};
+use mas_config::UpstreamSamlConfig;
+use mas_router::UrlBuilder;
+use thiserror::Error;
+
+use super::service_provider;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+        };
+
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+/// Metadata of the service provider, to register on the identity provider
+#[tracing::instrument(skip(upstream_saml, url_builder), err)]
+pub(crate) async fn get(
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Path(provider_id): Path<String>,
+) -> Result<impl IntoResponse, RouteError> {
+    let provider = upstream_saml
+        .find_provider(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let metadata = service_provider(&url_builder, &provider.id).metadata();
+    Ok(([(CONTENT_TYPE, "application/samlmetadata+xml")], metadata))
+}
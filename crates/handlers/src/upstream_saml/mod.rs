@@ -0,0 +1,197 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log in through upstream SAML identity providers
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use axum_extra::extract::PrivateCookieJar;
+use mas_config::{Encrypter, SamlProviderConfig};
+use mas_router::UrlBuilder;
+use mas_saml::{Assertion, IdentityProviderMetadata, ServiceProvider};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::skip_serializing_none;
+
+use crate::{
+    upstream_oauth2::{load_cookie, remove_cookie, save_cookie},
+    views::shared::OptionalPostAuthAction,
+};
+
+pub(crate) mod acs;
+pub(crate) mod login;
+pub(crate) mod metadata;
+
+static SESSION_COOKIE: &str = "upstream-saml-session";
+
+/// Well-known attributes, and the OIDC claims they are also exposed as, so
+/// that the default claims mapping works
+const ATTRIBUTE_CLAIMS: &[(&str, &str)] = &[
+    ("uid", "preferred_username"),
+    ("urn:oid:0.9.2342.19200300.100.1.1", "preferred_username"),
+    ("mail", "email"),
+    ("urn:oid:0.9.2342.19200300.100.1.3", "email"),
+    ("displayName", "name"),
+    ("urn:oid:2.16.840.1.113730.3.1.241", "name"),
+];
+
+/// State kept in a cookie between the redirect to the identity provider and
+/// its response
+#[derive(Debug, Serialize, Deserialize)]
+struct SamlSession {
+    provider: String,
+    request_id: String,
+    #[serde(flatten)]
+    action: OptionalPostAuthAction,
+}
+
+impl SamlSession {
+    fn save(&self, cookie_jar: PrivateCookieJar<Encrypter>) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, SESSION_COOKIE, self)
+    }
+
+    fn take(
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> (Option<Self>, PrivateCookieJar<Encrypter>) {
+        let session = load_cookie(&cookie_jar, SESSION_COOKIE);
+        (session, remove_cookie(cookie_jar, SESSION_COOKIE))
+    }
+}
+
+/// The response posted by an identity provider
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SamlResponseForm {
+    #[serde(rename = "SAMLResponse")]
+    saml_response: String,
+
+    #[serde(rename = "RelayState")]
+    relay_state: Option<String>,
+}
+
+/// How we are registered on the identity provider
+fn service_provider(url_builder: &UrlBuilder, provider_id: &str) -> ServiceProvider {
+    ServiceProvider {
+        entity_id: url_builder.upstream_saml_metadata(provider_id).into(),
+        assertion_consumer_service: url_builder.upstream_saml_acs(provider_id),
+    }
+}
+
+async fn identity_provider(
+    provider: &SamlProviderConfig,
+) -> anyhow::Result<IdentityProviderMetadata> {
+    let metadata = provider
+        .load_metadata()
+        .await
+        .context("could not load the identity provider metadata")?;
+    let metadata =
+        IdentityProviderMetadata::parse(&metadata).context("invalid identity provider metadata")?;
+    Ok(metadata)
+}
+
+/// Turn the content of an assertion into claims usable by the claims mapping
+/// templates.
+///
+/// Attributes are available both by name and by friendly name, as a string
+/// if they have a single value or as a list otherwise.
+fn assertion_claims(assertion: &Assertion, trust_email: bool) -> HashMap<String, Value> {
+    let mut claims = HashMap::new();
+    for attribute in &assertion.attributes {
+        let value = match &attribute.values[..] {
+            [value] => Value::String(value.clone()),
+            values => values.iter().cloned().map(Value::String).collect(),
+        };
+
+        for name in std::iter::once(&attribute.name).chain(&attribute.friendly_name) {
+            claims.insert(name.clone(), value.clone());
+        }
+    }
+
+    for (attribute, claim) in ATTRIBUTE_CLAIMS {
+        let value = match claims.get(*attribute) {
+            Some(Value::Array(values)) => values.first().cloned(),
+            Some(value) => Some(value.clone()),
+            None => None,
+        };
+
+        if let Some(value) = value {
+            claims.entry((*claim).to_owned()).or_insert(value);
+        }
+    }
+
+    claims.insert("sub".to_owned(), assertion.name_id.clone().into());
+    claims.insert("email_verified".to_owned(), trust_email.into());
+    claims
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_saml::Attribute;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn map_attributes() {
+        let assertion = Assertion {
+            id: "_assertion".to_owned(),
+            not_on_or_after: chrono::Utc::now(),
+            name_id: "abcd".to_owned(),
+            attributes: vec![
+                Attribute {
+                    name: "urn:oid:0.9.2342.19200300.100.1.1".to_owned(),
+                    friendly_name: Some("uid".to_owned()),
+                    values: vec!["john".to_owned()],
+                },
+                Attribute {
+                    name: "urn:oid:0.9.2342.19200300.100.1.3".to_owned(),
+                    friendly_name: None,
+                    values: vec!["john@example.com".to_owned(), "jd@example.com".to_owned()],
+                },
+            ],
+        };
+
+        let claims = assertion_claims(&assertion, true);
+        assert_eq!(claims["sub"], json!("abcd"));
+        assert_eq!(claims["uid"], json!("john"));
+        assert_eq!(claims["urn:oid:0.9.2342.19200300.100.1.1"], json!("john"));
+        assert_eq!(claims["preferred_username"], json!("john"));
+        assert_eq!(
+            claims["urn:oid:0.9.2342.19200300.100.1.3"],
+            json!(["john@example.com", "jd@example.com"])
+        );
+        assert_eq!(claims["email"], json!("john@example.com"));
+        assert_eq!(claims["email_verified"], json!(true));
+        assert!(!claims.contains_key("name"));
+
+        let claims = assertion_claims(&assertion, false);
+        assert_eq!(claims["email_verified"], json!(false));
+    }
+
+    #[test]
+    fn response_form_roundtrip() {
+        let form: SamlResponseForm =
+            serde_urlencoded::from_str("SAMLResponse=PHNhbWw%2B&RelayState=abc").unwrap();
+        assert_eq!(form.saml_response, "PHNhbWw+");
+        assert_eq!(form.relay_state.as_deref(), Some("abc"));
+
+        // The relay state is not re-posted when it is missing
+        let form: SamlResponseForm = serde_urlencoded::from_str("SAMLResponse=abc").unwrap();
+        assert_eq!(
+            serde_json::to_value(&form).unwrap(),
+            json!({ "SAMLResponse": "abc" })
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form, Path},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Request,
+};
+use mas_axum_utils::{
+    client_url::check_client_url,
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_data_model::CibaRequest;
+use mas_router::Route;
+use mas_storage::{
+    oauth2::ciba::{approve_ciba_request, deny_ciba_request, get_ciba_request_by_id},
+    PostgresqlBackend,
+};
+use oauth2_types::oidc::BackchannelTokenDeliveryMode;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+use crate::HomeserverConnection;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BackchannelForm {
+    Approve,
+    Deny,
+}
+
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Path(id): Path<i64>,
+    Form(form): Form<ProtectedForm<BackchannelForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let request = get_ciba_request_by_id(&mut txn, id).await?;
+
+    // Only the targeted user can act on a request, and only once
+    if request.user.data != session.user.data
+        || !request.state.is_pending()
+        || request.is_expired(Utc::now())
+    {
+        return Err(anyhow::anyhow!("backchannel authentication request is not pending").into());
+    }
+
+    match form {
+        BackchannelForm::Approve => {
+            let request = approve_ciba_request(&mut txn, request, session).await?;
+            txn.commit().await?;
+            info!(
+                ciba_request.id = request.data,
+                "Backchannel request approved"
+            );
+            homeserver
+                .create_devices_for_scope(&request.user.username, &request.scope)
+                .await;
+            notify_client(request);
+        }
+        BackchannelForm::Deny => {
+            let request = deny_ciba_request(&mut txn, request).await?;
+            txn.commit().await?;
+            info!(ciba_request.id = request.data, "Backchannel request denied");
+            notify_client(request);
+        }
+    }
+
+    Ok((cookie_jar, mas_router::Account.go()).into_response())
+}
+
+/// Let clients using the ping delivery mode know that the request was answered,
+/// so that they go fetch the tokens or the denial from the token endpoint
+fn notify_client(request: CibaRequest<PostgresqlBackend>) {
+    if request.client.backchannel_token_delivery_mode != Some(BackchannelTokenDeliveryMode::Ping) {
+        return;
+    }
+
+    let (endpoint, token) = match (
+        request.client.backchannel_client_notification_endpoint,
+        request.client_notification_token,
+    ) {
+        (Some(endpoint), Some(token)) => (endpoint, token),
+        _ => return,
+    };
+
+    // The endpoint is registered by the client, so it should not be used to
+    // reach internal services
+    if let Err(e) = check_client_url(&endpoint) {
+        warn!(error = %e, "Refusing to notify client");
+        return;
+    }
+
+    let body = serde_json::json!({ "auth_req_id": request.auth_req_id }).to_string();
+
+    tokio::spawn(async move {
+        let request = Request::builder()
+            .method("POST")
+            .uri(endpoint.as_str())
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(error = %e, "Invalid client notification request");
+                return;
+            }
+        };
+
+        match mas_http::public_client("ciba-notification")
+            .oneshot(request)
+            .await
+        {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => {
+                warn!(status = %response.status(), "Client notification was rejected");
+            }
+            Err(e) => warn!(error = %e, "Failed to notify client"),
+        }
+    });
+}
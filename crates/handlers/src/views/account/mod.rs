@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod backchannel;
 pub mod emails;
 pub mod password;
+pub mod sessions;
+pub mod totp;
+pub mod webauthn;
 
 use axum::{
     extract::Extension,
@@ -23,7 +27,10 @@ use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{csrf::CsrfExt, FancyError, SessionInfoExt};
 use mas_config::Encrypter;
 use mas_router::Route;
-use mas_storage::user::{count_active_sessions, get_user_emails};
+use mas_storage::{
+    oauth2::ciba::get_pending_ciba_requests_for_user,
+    user::{count_active_sessions, get_user_emails},
+};
 use mas_templates::{AccountContext, TemplateContext, Templates};
 use sqlx::PgPool;
 
@@ -50,7 +57,10 @@ pub(crate) async fn get(
 
     let emails = get_user_emails(&mut conn, &session.user).await?;
 
+    let ciba_requests = get_pending_ciba_requests_for_user(&mut conn, &session.user).await?;
+
     let ctx = AccountContext::new(active_sessions, emails)
+        .with_ciba_requests(ciba_requests)
         .with_session(session)
         .with_csrf(csrf_token.form_value());
 
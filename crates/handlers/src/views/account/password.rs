@@ -12,7 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use argon2::Argon2;
+use std::sync::Arc;
+
 use axum::{
     extract::{Extension, Form},
     response::{Html, IntoResponse, Response},
@@ -23,13 +24,18 @@ use mas_axum_utils::{
     FancyError, SessionInfoExt,
 };
 use mas_config::Encrypter;
-use mas_data_model::BrowserSession;
+use mas_data_model::{AuthenticationMethod, BrowserSession};
+use mas_policy::PolicyFactory;
 use mas_router::Route;
 use mas_storage::{
-    user::{authenticate_session, set_password},
+    password::{PasswordBackends, PasswordSchemes},
+    user::{authenticate_session, set_password, AuthenticationError},
     PostgresqlBackend,
 };
-use mas_templates::{EmptyContext, TemplateContext, Templates};
+use mas_templates::{
+    AccountPasswordContext, AccountPasswordFormField, FieldError, FormError, FormState,
+    TemplateContext, Templates,
+};
 use serde::Deserialize;
 use sqlx::PgPool;
 
@@ -52,7 +58,7 @@ pub(crate) async fn get(
     let maybe_session = session_info.load_session(&mut conn).await?;
 
     if let Some(session) = maybe_session {
-        render(templates, session, cookie_jar).await
+        render(templates, session, FormState::default(), cookie_jar).await
     } else {
         let login = mas_router::Login::and_then(mas_router::PostAuthAction::ChangePassword);
         Ok((cookie_jar, login.go()).into_response())
@@ -62,11 +68,13 @@ pub(crate) async fn get(
 async fn render(
     templates: Templates,
     session: BrowserSession<PostgresqlBackend>,
+    state: FormState<AccountPasswordFormField>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
 
-    let ctx = EmptyContext
+    let ctx = AccountPasswordContext::default()
+        .with_form_state(state)
         .with_session(session)
         .with_csrf(csrf_token.form_value());
 
@@ -78,6 +86,9 @@ async fn render(
 pub(crate) async fn post(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(password_backends): Extension<PasswordBackends>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<ChangeForm>>,
 ) -> Result<Response, FancyError> {
@@ -96,17 +107,93 @@ pub(crate) async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    authenticate_session(&mut txn, &mut session, &form.current_password).await?;
+    match authenticate_session(
+        &mut txn,
+        &password_backends,
+        &password_schemes,
+        &mut session,
+        &form.current_password,
+        AuthenticationMethod::Reauth,
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(AuthenticationError::Password(_)) => {
+            let state = FormState::default().with_error_on_field(
+                AccountPasswordFormField::CurrentPassword,
+                FieldError::Invalid,
+            );
+            return render(templates, session, state, cookie_jar).await;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    // Validate the form
+    let state = {
+        let mut state = FormState::default();
+
+        if form.new_password.is_empty() {
+            state.add_error_on_field(AccountPasswordFormField::NewPassword, FieldError::Required);
+        }
+
+        if form.new_password != form.new_password_confirm {
+            state.add_error_on_form(FormError::PasswordMismatch);
+            state.add_error_on_field(
+                AccountPasswordFormField::NewPassword,
+                FieldError::Unspecified,
+            );
+            state.add_error_on_field(
+                AccountPasswordFormField::NewPasswordConfirm,
+                FieldError::Unspecified,
+            );
+        }
+
+        // New passwords go through the same policy as on registration
+        let mut policy = policy_factory.instantiate().await?;
+        let res = policy
+            .evaluate_register(&session.user.username, Some(&form.new_password), None)
+            .await?;
+
+        for violation in res.violations {
+            match violation.field.as_deref() {
+                Some("password") => state.add_error_on_field(
+                    AccountPasswordFormField::NewPassword,
+                    FieldError::Policy {
+                        message: violation.msg,
+                    },
+                ),
+                _ => state.add_error_on_form(FormError::Policy {
+                    message: violation.msg,
+                }),
+            }
+        }
+
+        if let Some(feedback) = res.password_feedback {
+            state.add_error_on_field(
+                AccountPasswordFormField::NewPassword,
+                FieldError::PasswordFeedback {
+                    warning: feedback.warning,
+                    suggestions: feedback.suggestions,
+                },
+            );
+        }
+
+        state
+    };
 
-    // TODO: display nice form errors
-    if form.new_password != form.new_password_confirm {
-        return Err(anyhow::anyhow!("password mismatch").into());
+    if !state.is_valid() {
+        return render(templates, session, state, cookie_jar).await;
     }
 
-    let phf = Argon2::default();
-    set_password(&mut txn, phf, &session.user, &form.new_password).await?;
+    set_password(
+        &mut txn,
+        &password_schemes,
+        &session.user,
+        &form.new_password,
+    )
+    .await?;
 
-    let reply = render(templates.clone(), session, cookie_jar).await?;
+    let reply = render(templates.clone(), session, FormState::default(), cookie_jar).await?;
 
     txn.commit().await?;
 
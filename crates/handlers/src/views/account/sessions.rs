@@ -0,0 +1,205 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use anyhow::bail;
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_data_model::BrowserSession;
+use mas_router::Route;
+use mas_storage::{
+    user::{end_other_sessions, end_session, get_user_sessions, lookup_active_session},
+    PostgresqlBackend,
+};
+use mas_templates::{AccountSessionsContext, TemplateContext, Templates};
+use oauth2_types::scope::Scope;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+
+use crate::HomeserverConnection;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    End { data: String },
+    EndOthers,
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    render(&templates, session, cookie_jar, &mut conn).await
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let scopes = match form {
+        ManagementForm::End { data } => end_user_session(&mut txn, &session, data.parse()?).await?,
+        ManagementForm::EndOthers => end_other_sessions(&mut txn, &session).await?,
+    };
+
+    let reply = render(&templates, session.clone(), cookie_jar, &mut txn).await?;
+
+    txn.commit().await?;
+
+    for scope in &scopes {
+        homeserver
+            .delete_devices_for_scope(&session.user.username, scope)
+            .await;
+    }
+
+    Ok(reply)
+}
+
+/// End another session of the user owning `current`, returning the scopes of
+/// the OAuth sessions which were ended with it
+async fn end_user_session(
+    conn: &mut PgConnection,
+    current: &BrowserSession<PostgresqlBackend>,
+    id: i64,
+) -> anyhow::Result<Vec<Scope>> {
+    // The current session is ended by logging out
+    if id == current.data {
+        bail!("can't end the current session from here");
+    }
+
+    match lookup_active_session(&mut *conn, id).await {
+        Ok(other) if other.user.data == current.user.data => end_session(conn, &other).await,
+        // It might have ended in the meantime
+        Err(e) if e.not_found() => Ok(Vec::new()),
+        Ok(_) => bail!("session not found"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn render(
+    templates: &Templates,
+    session: BrowserSession<PostgresqlBackend>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    executor: impl PgExecutor<'_>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let sessions = get_user_sessions(executor, &session.user).await?;
+    let ctx = AccountSessionsContext::new(sessions)
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_sessions(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::SessionExpiration;
+    use mas_storage::{
+        test_utils::{random_name, test_transaction},
+        user::{register_passwordless_user, start_session},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_end_user_session() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let expiration = SessionExpiration::default();
+        let alice = register_passwordless_user(&mut txn, &random_name("alice"))
+            .await
+            .unwrap();
+        let bob = register_passwordless_user(&mut txn, &random_name("bob"))
+            .await
+            .unwrap();
+        let current = start_session(&mut txn, alice.clone(), None, None, &expiration)
+            .await
+            .unwrap();
+        let other = start_session(&mut txn, alice, None, None, &expiration)
+            .await
+            .unwrap();
+        let victim = start_session(&mut txn, bob, None, None, &expiration)
+            .await
+            .unwrap();
+
+        // Neither the current session nor the sessions of other users can be
+        // ended from here
+        assert!(end_user_session(&mut txn, &current, current.data)
+            .await
+            .is_err());
+        assert!(end_user_session(&mut txn, &current, victim.data)
+            .await
+            .is_err());
+        lookup_active_session(&mut txn, current.data).await.unwrap();
+        lookup_active_session(&mut txn, victim.data).await.unwrap();
+
+        end_user_session(&mut txn, &current, other.data)
+            .await
+            .unwrap();
+        assert!(lookup_active_session(&mut txn, other.data)
+            .await
+            .unwrap_err()
+            .not_found());
+
+        // Ending it again is a no-op
+        assert!(end_user_session(&mut txn, &current, other.data)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
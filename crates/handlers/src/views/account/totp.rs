@@ -0,0 +1,224 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use data_encoding::BASE64;
+use mas_axum_utils::{
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::{Encrypter, MatrixConfig};
+use mas_data_model::{BrowserSession, User};
+use mas_router::Route;
+use mas_storage::{
+    totp::{
+        add_pending_user_totp, confirm_user_totp, count_unused_user_recovery_codes,
+        lookup_user_totp, remove_user_second_factors, replace_user_recovery_codes,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    AccountTotpContext, FieldError, FormState, SecondFactorFormField, TemplateContext, Templates,
+    TotpEnrollment,
+};
+use rand::thread_rng;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+
+use crate::totp;
+
+/// How recently the user must have typed their password to remove their second
+/// factor or see new recovery codes
+const REAUTH_MAX_AGE_MINUTES: i64 = 5;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    Confirm { code: String },
+    Disable,
+    RegenerateRecoveryCodes,
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(matrix_config): Extension<MatrixConfig>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let ctx = load_context(&mut txn, &encrypter, &matrix_config, &session.user).await?;
+    txn.commit().await?;
+
+    render(&templates, ctx, session, cookie_jar).await
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(matrix_config): Extension<MatrixConfig>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let form = cookie_jar.verify_form(form)?;
+
+    // Holding a session isn't enough to weaken it, the password is asked again
+    let sensitive = matches!(
+        form,
+        ManagementForm::Disable | ManagementForm::RegenerateRecoveryCodes
+    );
+    let max_age = Duration::minutes(REAUTH_MAX_AGE_MINUTES);
+    if sensitive && !session.was_authenticated_after(Utc::now() - max_age) {
+        let reauth = mas_router::Reauth::and_then(mas_router::PostAuthAction::ManageSecondFactor);
+        return Ok((cookie_jar, reauth.go()).into_response());
+    }
+
+    let ctx = match form {
+        ManagementForm::Confirm { code } => {
+            let mut user_totp = lookup_user_totp(&mut txn, &session.user)
+                .await?
+                .filter(|user_totp| !user_totp.is_confirmed())
+                .ok_or_else(|| anyhow::anyhow!("no TOTP authenticator to confirm"))?;
+
+            let secret = encrypter.decrypt_string(&user_totp.encrypted_secret)?;
+            if let Some(step) = totp::verify(&secret, &code, Utc::now().timestamp()) {
+                confirm_user_totp(&mut txn, &mut user_totp, step).await?;
+                let codes = totp::generate_recovery_codes(thread_rng());
+                replace_user_recovery_codes(&mut txn, &session.user, &codes).await?;
+
+                let recovery_codes_left = i64::try_from(codes.len())?;
+                AccountTotpContext::enabled(recovery_codes_left).with_recovery_codes(codes)
+            } else {
+                let state = FormState::default()
+                    .with_error_on_field(SecondFactorFormField::Code, FieldError::Invalid);
+                AccountTotpContext::enrolling(enrollment(&secret, &matrix_config, &session.user)?)
+                    .with_form_state(state)
+            }
+        }
+
+        ManagementForm::Disable => {
+            remove_user_second_factors(&mut txn, &session.user).await?;
+            txn.commit().await?;
+            return Ok((cookie_jar, mas_router::Account.go()).into_response());
+        }
+
+        ManagementForm::RegenerateRecoveryCodes => {
+            let enabled = lookup_user_totp(&mut txn, &session.user)
+                .await?
+                .map_or(false, |user_totp| user_totp.is_confirmed());
+            if !enabled {
+                return Err(anyhow::anyhow!("two-factor authentication is not enabled").into());
+            }
+
+            let codes = totp::generate_recovery_codes(thread_rng());
+            replace_user_recovery_codes(&mut txn, &session.user, &codes).await?;
+
+            let recovery_codes_left = i64::try_from(codes.len())?;
+            AccountTotpContext::enabled(recovery_codes_left).with_recovery_codes(codes)
+        }
+    };
+
+    txn.commit().await?;
+
+    render(&templates, ctx, session, cookie_jar).await
+}
+
+/// Load the TOTP authenticator of the user, starting a new enrollment if
+/// they don't have one yet
+async fn load_context(
+    conn: &mut PgConnection,
+    encrypter: &Encrypter,
+    matrix_config: &MatrixConfig,
+    user: &User<PostgresqlBackend>,
+) -> Result<AccountTotpContext, FancyError> {
+    let secret = match lookup_user_totp(&mut *conn, user).await? {
+        Some(user_totp) if user_totp.is_confirmed() => {
+            let recovery_codes_left = count_unused_user_recovery_codes(conn, user).await?;
+            return Ok(AccountTotpContext::enabled(recovery_codes_left));
+        }
+        Some(user_totp) => encrypter.decrypt_string(&user_totp.encrypted_secret)?,
+        None => {
+            let secret = totp::generate_secret(thread_rng());
+            let encrypted_secret = encrypter.encryt_to_string(&secret)?;
+            add_pending_user_totp(conn, user, &encrypted_secret).await?;
+            secret
+        }
+    };
+
+    Ok(AccountTotpContext::enrolling(enrollment(
+        &secret,
+        matrix_config,
+        user,
+    )?))
+}
+
+fn enrollment(
+    secret: &[u8],
+    matrix_config: &MatrixConfig,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<TotpEnrollment> {
+    let uri = totp::provisioning_uri(secret, &matrix_config.homeserver, &user.username);
+    let svg = totp::qr_code_svg(&uri)?;
+    let qr_code = format!(
+        "data:image/svg+xml;base64,{}",
+        BASE64.encode(svg.as_bytes())
+    );
+    Ok(TotpEnrollment::new(totp::encode_secret(secret), qr_code))
+}
+
+async fn render(
+    templates: &Templates,
+    ctx: AccountTotpContext,
+    session: BrowserSession<PostgresqlBackend>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let ctx = ctx.with_session(session).with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_totp(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
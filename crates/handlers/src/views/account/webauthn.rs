@@ -0,0 +1,238 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_data_model::{BrowserSession, User};
+use mas_router::{Route, UrlBuilder};
+use mas_storage::{
+    webauthn::{
+        add_user_webauthn_credential, get_user_webauthn_credentials,
+        remove_user_webauthn_credential,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    AccountWebauthnContext, AccountWebauthnFormField, FieldError, FormError, FormState,
+    TemplateContext, Templates, WebauthnCredentialInfo,
+};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use tracing::warn;
+
+use crate::webauthn::{self, Challenge, RelyingParty};
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    Register {
+        name: String,
+        credential_id: String,
+        client_data_json: String,
+        attestation_object: String,
+    },
+    Remove {
+        data: String,
+    },
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    render(
+        &templates,
+        FormState::default(),
+        session,
+        cookie_jar,
+        &mut conn,
+    )
+    .await
+}
+
+/// Start a WebAuthn registration ceremony
+pub(crate) async fn get_options(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let rp = RelyingParty::new(&url_builder)?;
+
+    // Don't let users register the same authenticator twice
+    let existing = get_user_webauthn_credentials(&mut conn, &session.user).await?;
+    let challenge = Challenge::generate(thread_rng());
+    let options = webauthn::creation_options(&rp, &challenge, &session.user, &existing);
+    let cookie_jar = challenge.save(cookie_jar);
+
+    Ok((cookie_jar, Json(options)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (state, cookie_jar) = match form {
+        ManagementForm::Register {
+            name,
+            credential_id,
+            client_data_json,
+            attestation_object,
+        } => {
+            let rp = RelyingParty::new(&url_builder)?;
+            let (challenge, cookie_jar) = Challenge::take(cookie_jar);
+
+            let state = if name.trim().is_empty() {
+                FormState::default()
+                    .with_error_on_field(AccountWebauthnFormField::Name, FieldError::Required)
+            } else {
+                let registered = challenge.and_then(|challenge| {
+                    webauthn::verify_registration(
+                        &rp,
+                        &challenge,
+                        &client_data_json,
+                        &attestation_object,
+                    )
+                });
+
+                match registered {
+                    Ok(registered) if registered.credential_id == credential_id => {
+                        add_user_webauthn_credential(
+                            &mut txn,
+                            &session.user,
+                            &registered.credential_id,
+                            &registered.public_key,
+                            registered.sign_count,
+                            name.trim(),
+                        )
+                        .await?;
+                        FormState::default()
+                    }
+                    Ok(_) => FormState::default().with_error_on_form(FormError::WebauthnFailed),
+                    Err(e) => {
+                        warn!(error = %e, "Failed to verify WebAuthn registration");
+                        FormState::default().with_error_on_form(FormError::WebauthnFailed)
+                    }
+                }
+            };
+
+            (state, cookie_jar)
+        }
+
+        ManagementForm::Remove { data } => {
+            let id = data.parse()?;
+            remove_user_webauthn_credential(&mut txn, &session.user, id).await?;
+            (FormState::default(), cookie_jar)
+        }
+    };
+
+    let reply = render(&templates, state, session, cookie_jar, &mut txn).await?;
+
+    txn.commit().await?;
+
+    Ok(reply)
+}
+
+async fn render(
+    templates: &Templates,
+    state: FormState<AccountWebauthnFormField>,
+    session: BrowserSession<PostgresqlBackend>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    executor: impl PgExecutor<'_>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let credentials = load_credentials(executor, &session.user).await?;
+    let options_link = mas_router::AccountWebauthnOptions.relative_url();
+    let ctx = AccountWebauthnContext::new(credentials, options_link.to_string())
+        .with_form_state(state)
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_webauthn(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+async fn load_credentials(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Vec<WebauthnCredentialInfo>> {
+    let credentials = get_user_webauthn_credentials(executor, user).await?;
+    Ok(credentials
+        .into_iter()
+        .map(|credential| {
+            WebauthnCredentialInfo::new(
+                credential.id,
+                credential.name,
+                credential.created_at,
+                credential.last_used_at,
+            )
+        })
+        .collect())
+}
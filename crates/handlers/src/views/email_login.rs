@@ -0,0 +1,531 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passwordless login with a one-time code or link sent to the verified
+//! primary email address of the user
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Extension, Form, Query},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use lettre::{message::Mailbox, Address};
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    FancyError, SessionClient, SessionInfoExt,
+};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{
+    AuthenticationMethod, SessionExpiration, User, UserEmail, UserEmailVerification,
+    UserEmailVerificationState,
+};
+use mas_email::Mailer;
+use mas_router::{EmailLoginLink, Route, UrlBuilder};
+use mas_storage::{
+    email_request::record_email_request,
+    login_throttle::{clear_login_failures, login_blocked_until, record_login_failure},
+    user::{
+        add_user_email_login_code, consume_email_verification, lookup_user_by_username,
+        lookup_user_email_login_link, lookup_user_email_verification_code,
+        record_email_verification_failure, record_session_authentication, start_session,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    EmailLoginContext, EmailLoginEmailContext, EmailLoginFormField, FieldError, FormError,
+    FormState, TemplateContext, Templates,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString, Uniform},
+    thread_rng, Rng,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use tracing::warn;
+
+use super::{
+    second_factor::{user_has_second_factor, PendingSecondFactor},
+    shared::OptionalPostAuthAction,
+};
+use crate::upstream_oauth2::{load_cookie, remove_cookie, save_cookie};
+
+static PENDING_COOKIE: &str = "pending-email-login";
+
+/// How long codes and links can be used for, in minutes
+const CODE_TTL_MINUTES: i64 = 10;
+
+/// How many wrong codes can be entered before having to ask for a new one
+const MAX_ATTEMPTS: u8 = 5;
+
+/// A login waiting for the code sent by email. It lives in an encrypted
+/// cookie, which is set even for unknown users so that the response doesn't
+/// tell whether an account exists.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingEmailLogin {
+    username: String,
+
+    /// The verification holding the code, if one was sent
+    verification_id: Option<i64>,
+
+    attempts: u8,
+    expires_at: i64,
+}
+
+impl PendingEmailLogin {
+    fn new(username: String, verification_id: Option<i64>) -> Self {
+        Self {
+            username,
+            verification_id,
+            attempts: 0,
+            expires_at: (Utc::now() + Duration::minutes(CODE_TTL_MINUTES)).timestamp(),
+        }
+    }
+
+    fn save(&self, cookie_jar: PrivateCookieJar<Encrypter>) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, PENDING_COOKIE, self)
+    }
+
+    fn load(cookie_jar: &PrivateCookieJar<Encrypter>) -> Option<Self> {
+        load_cookie::<Self>(cookie_jar, PENDING_COOKIE)
+            .filter(|pending| pending.expires_at > Utc::now().timestamp())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum EmailLoginForm {
+    Send { username: String },
+    Verify { code: String },
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    if !authentication_config.email_login {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    }
+
+    let mut conn = pool.acquire().await?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    if maybe_session.is_some() {
+        let reply = query.go_next();
+        return Ok((cookie_jar, reply).into_response());
+    }
+
+    let ctx = if PendingEmailLogin::load(&cookie_jar).is_some() {
+        EmailLoginContext::default().with_code_sent()
+    } else {
+        EmailLoginContext::default()
+    };
+
+    let content = render(ctx, query, csrf_token, &mut conn, &templates).await?;
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<Mailer>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<EmailLoginForm>>,
+) -> Result<Response, FancyError> {
+    if !authentication_config.email_login {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    }
+
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    match form {
+        EmailLoginForm::Send { username } => {
+            if username.is_empty() {
+                let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+                let state = FormState::default()
+                    .with_error_on_field(EmailLoginFormField::Username, FieldError::Required);
+                let ctx = EmailLoginContext::default().with_form_state(state);
+                let content = render(ctx, query, csrf_token, &mut txn, &templates).await?;
+                return Ok((cookie_jar, Html(content)).into_response());
+            }
+
+            let verification_id = send_code(
+                &mut txn,
+                &mailer,
+                &url_builder,
+                &username,
+                client.ip_address,
+                &query,
+            )
+            .await?;
+            txn.commit().await?;
+
+            let cookie_jar = PendingEmailLogin::new(username, verification_id).save(cookie_jar);
+            let next = mas_router::EmailLogin::from(query.post_auth_action);
+            Ok((cookie_jar, next.go()).into_response())
+        }
+
+        EmailLoginForm::Verify { code } => {
+            let mut pending = if let Some(pending) = PendingEmailLogin::load(&cookie_jar) {
+                pending
+            } else {
+                // Start over if no code was sent or it took too long
+                let next = mas_router::EmailLogin::from(query.post_auth_action);
+                return Ok((cookie_jar, next.go()).into_response());
+            };
+
+            // Refuse to check the code while there were too many failed attempts
+            let ip = client.ip_address;
+            if login_blocked_until(&mut txn, &pending.username, ip)
+                .await?
+                .is_some()
+            {
+                let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+                let state = FormState::default().with_error_on_form(FormError::TooManyAttempts);
+                let ctx = EmailLoginContext::default()
+                    .with_code_sent()
+                    .with_form_state(state);
+                let content = render(ctx, query, csrf_token, &mut txn, &templates).await?;
+                return Ok((cookie_jar, Html(content)).into_response());
+            }
+
+            let verified = lookup_verification(&mut txn, &pending.username, code.trim()).await?;
+
+            // Only the code sent along with the cookie is accepted
+            let verified = verified
+                .filter(|(_, verification)| pending.verification_id == Some(verification.data));
+
+            let user = if let Some((user, verification)) = verified {
+                consume_email_verification(&mut txn, verification).await?;
+                user
+            } else {
+                record_login_failure(&mut txn, &pending.username, ip).await?;
+
+                // Wrong codes are counted against the code itself as well, so
+                // that replaying an older cookie doesn't give more attempts
+                let usable = match pending.verification_id {
+                    Some(id) => {
+                        record_email_verification_failure(&mut txn, id, MAX_ATTEMPTS.into()).await?
+                    }
+                    None => true,
+                };
+
+                pending.attempts += 1;
+                let exhausted = !usable || pending.attempts >= MAX_ATTEMPTS;
+                let cookie_jar = if exhausted {
+                    remove_cookie(cookie_jar, PENDING_COOKIE)
+                } else {
+                    pending.save(cookie_jar)
+                };
+
+                let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+                let state = FormState::default()
+                    .with_error_on_field(EmailLoginFormField::Code, FieldError::Invalid);
+                let ctx = if exhausted {
+                    EmailLoginContext::default().with_form_state(state)
+                } else {
+                    EmailLoginContext::default()
+                        .with_code_sent()
+                        .with_form_state(state)
+                };
+                let content = render(ctx, query, csrf_token, &mut txn, &templates).await?;
+                txn.commit().await?;
+                return Ok((cookie_jar, Html(content)).into_response());
+            };
+
+            let cookie_jar = remove_cookie(cookie_jar, PENDING_COOKIE);
+            complete(
+                txn,
+                cookie_jar,
+                user,
+                &query,
+                &client,
+                &authentication_config.session_expiration(),
+            )
+            .await
+        }
+    }
+}
+
+/// Log in from the link in the email. It works from any browser, since the
+/// token is too long to be guessed. Only the token of the link is accepted
+/// here, not the short code.
+pub(crate) async fn get_link(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(link): Query<EmailLoginLink>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let query = OptionalPostAuthAction {
+        post_auth_action: link.post_auth_action,
+    };
+
+    if !authentication_config.email_login {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    }
+
+    let mut txn = pool.begin().await?;
+
+    // Links are guessable the same way codes are, so they share the throttle
+    let ip = client.ip_address;
+    if login_blocked_until(&mut txn, &link.username, ip)
+        .await?
+        .is_some()
+    {
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let state = FormState::default().with_error_on_form(FormError::TooManyAttempts);
+        let ctx = EmailLoginContext::default().with_form_state(state);
+        let content = render(ctx, query, csrf_token, &mut txn, &templates).await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let verified = lookup_link(&mut txn, &link.username, &link.token).await?;
+
+    let user = if let Some((user, verification)) = verified {
+        consume_email_verification(&mut txn, verification).await?;
+        user
+    } else {
+        record_login_failure(&mut txn, &link.username, ip).await?;
+
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let state = FormState::default().with_error_on_form(FormError::InvalidLink);
+        let ctx = EmailLoginContext::default().with_form_state(state);
+        let content = render(ctx, query, csrf_token, &mut txn, &templates).await?;
+        txn.commit().await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    };
+
+    let cookie_jar = remove_cookie(cookie_jar, PENDING_COOKIE);
+    complete(
+        txn,
+        cookie_jar,
+        user,
+        &query,
+        &client,
+        &authentication_config.session_expiration(),
+    )
+    .await
+}
+
+/// Send a code and a link to the verified primary email of the user, if they
+/// have one, returning the verification holding the code. Nothing is sent when
+/// there were too many requests for this address or from this IP.
+async fn send_code(
+    conn: &mut PgConnection,
+    mailer: &Mailer,
+    url_builder: &UrlBuilder,
+    username: &str,
+    ip: Option<IpAddr>,
+    query: &OptionalPostAuthAction,
+) -> Result<Option<i64>, FancyError> {
+    let user = match lookup_user_by_username(&mut *conn, username).await {
+        Ok(user) => user,
+        Err(e) if e.not_found() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let email = match user.primary_email.clone() {
+        Some(email) if email.confirmed_at.is_some() => email,
+        _ => return Ok(None),
+    };
+
+    if !record_email_request(&mut *conn, &email.email, ip).await? {
+        return Ok(None);
+    }
+
+    // The short code is typed in the browser which asked for it, and the long
+    // token is part of the link. Using either of them consumes both.
+    let code = format!(
+        "{:06}",
+        thread_rng().sample(Uniform::<u32>::from(0..1_000_000))
+    );
+    let token = Alphanumeric.sample_string(&mut thread_rng(), 32);
+
+    let verification =
+        add_user_email_login_code(&mut *conn, email.clone(), code.clone(), &token).await?;
+
+    let link = url_builder.email_login_link(&EmailLoginLink {
+        username: user.username.clone(),
+        token,
+        post_auth_action: query.post_auth_action.clone(),
+    });
+
+    let address: Address = email.email.parse()?;
+    let mailbox = Mailbox::new(Some(user.username.clone()), address);
+    let context = EmailLoginEmailContext::new(user.into(), code, link);
+
+    // Sent in the background, so that the response doesn't tell whether the
+    // account exists
+    let mailer = mailer.clone();
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send_login_email(mailbox, &context).await {
+            warn!(error = %e, "Failed to send login email");
+        }
+    });
+
+    Ok(Some(verification.data))
+}
+
+/// The user behind a username, along with their verified primary email
+async fn lookup_user_email(
+    conn: &mut PgConnection,
+    username: &str,
+) -> Result<Option<(User<PostgresqlBackend>, UserEmail<PostgresqlBackend>)>, FancyError> {
+    let user = match lookup_user_by_username(&mut *conn, username).await {
+        Ok(user) => user,
+        Err(e) if e.not_found() => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    match user.primary_email.clone() {
+        Some(email) if email.confirmed_at.is_some() => Ok(Some((user, email))),
+        _ => Ok(None),
+    }
+}
+
+/// Find a valid code sent to the primary email of the user
+async fn lookup_verification(
+    conn: &mut PgConnection,
+    username: &str,
+    code: &str,
+) -> Result<
+    Option<(
+        User<PostgresqlBackend>,
+        UserEmailVerification<PostgresqlBackend>,
+    )>,
+    FancyError,
+> {
+    let (user, email) = if let Some(found) = lookup_user_email(&mut *conn, username).await? {
+        found
+    } else {
+        return Ok(None);
+    };
+
+    let max_age = Duration::minutes(CODE_TTL_MINUTES);
+    match lookup_user_email_verification_code(conn, email, code, max_age).await {
+        Ok(verification) if matches!(verification.state, UserEmailVerificationState::Valid) => {
+            Ok(Some((user, verification)))
+        }
+        Ok(_) => Ok(None),
+        Err(e) if e.not_found() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Find a valid link sent to the primary email of the user
+async fn lookup_link(
+    conn: &mut PgConnection,
+    username: &str,
+    token: &str,
+) -> Result<
+    Option<(
+        User<PostgresqlBackend>,
+        UserEmailVerification<PostgresqlBackend>,
+    )>,
+    FancyError,
+> {
+    let (user, email) = if let Some(found) = lookup_user_email(&mut *conn, username).await? {
+        found
+    } else {
+        return Ok(None);
+    };
+
+    let verification =
+        lookup_user_email_login_link(conn, email, token, Duration::minutes(CODE_TTL_MINUTES))
+            .await?;
+
+    match verification {
+        Some(verification) if matches!(verification.state, UserEmailVerificationState::Valid) => {
+            Ok(Some((user, verification)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Give the user their session, or ask for their second factor if they have
+/// one
+async fn complete(
+    mut txn: Transaction<'_, Postgres>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    user: User<PostgresqlBackend>,
+    query: &OptionalPostAuthAction,
+    client: &SessionClient,
+    expiration: &SessionExpiration,
+) -> Result<Response, FancyError> {
+    // Failures are only forgotten once the second factor is given as well
+    if user_has_second_factor(&mut txn, &user).await? {
+        txn.commit().await?;
+        let cookie_jar =
+            PendingSecondFactor::login(&user, AuthenticationMethod::Email).save(cookie_jar);
+        let next = mas_router::SecondFactor::from(query.post_auth_action.clone());
+        return Ok((cookie_jar, next.go()).into_response());
+    }
+
+    clear_login_failures(&mut txn, &user.username).await?;
+    let mut session = start_session(
+        &mut txn,
+        user,
+        client.user_agent.as_deref(),
+        client.ip_address,
+        expiration,
+    )
+    .await?;
+    record_session_authentication(&mut txn, &mut session, AuthenticationMethod::Email, None)
+        .await?;
+    txn.commit().await?;
+
+    let cookie_jar = cookie_jar.set_session(&session);
+    let reply = query.go_next();
+    Ok((cookie_jar, reply).into_response())
+}
+
+async fn render(
+    ctx: EmailLoginContext,
+    action: OptionalPostAuthAction,
+    csrf_token: CsrfToken,
+    conn: &mut PgConnection,
+    templates: &Templates,
+) -> Result<String, FancyError> {
+    let next = action.load_context(conn).await?;
+    let ctx = if let Some(next) = next {
+        ctx.with_post_action(next)
+    } else {
+        ctx
+    };
+    let ctx = ctx.with_csrf(csrf_token.form_value());
+
+    let content = templates.render_email_login(&ctx).await?;
+    Ok(content)
+}
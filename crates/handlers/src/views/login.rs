@@ -12,25 +12,46 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use axum::{
     extract::{Extension, Form, Query},
     response::{Html, IntoResponse, Response},
+    Json,
 };
 use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{
     csrf::{CsrfExt, CsrfToken, ProtectedForm},
-    FancyError, SessionInfoExt,
+    FancyError, SessionClient, SessionInfoExt,
+};
+use mas_config::{AuthenticationConfig, Encrypter, UpstreamOAuth2Config, UpstreamSamlConfig};
+use mas_data_model::AuthenticationMethod;
+use mas_policy::PolicyFactory;
+use mas_router::{Route, UpstreamOAuth2Authorize, UpstreamSamlLogin, UrlBuilder};
+use mas_storage::{
+    login_throttle::{clear_login_failures, login_blocked_until, record_login_failure},
+    password::{authenticate_user, ExternalUser, PasswordBackends, PasswordSchemes},
+    user::{record_session_authentication, start_session, LoginError},
+    webauthn::{lookup_webauthn_credential, use_webauthn_credential},
 };
-use mas_config::Encrypter;
-use mas_router::Route;
-use mas_storage::user::{login, LoginError};
 use mas_templates::{
     FieldError, FormError, LoginContext, LoginFormField, TemplateContext, Templates, ToFormState,
+    UpstreamProviderLink,
 };
+use rand::thread_rng;
 use serde::{Deserialize, Serialize};
-use sqlx::{PgConnection, PgPool};
+use sqlx::{Connection, PgConnection, PgPool};
+use tracing::{info, warn};
 
-use super::shared::OptionalPostAuthAction;
+use super::{
+    second_factor::{user_has_second_factor, PendingSecondFactor},
+    shared::OptionalPostAuthAction,
+};
+use crate::{
+    homeserver::is_valid_localpart,
+    webauthn::{self, AssertionResponse, Challenge, RelyingParty},
+    HomeserverConnection,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct LoginForm {
@@ -42,10 +63,38 @@ impl ToFormState for LoginForm {
     type Field = LoginFormField;
 }
 
-#[tracing::instrument(skip(templates, pool, cookie_jar))]
+/// Check a user just provisioned from an external password backend like a
+/// registration, returning why it should be refused if it should
+pub(crate) async fn check_provisioned_user(
+    policy_factory: &PolicyFactory,
+    external: &ExternalUser,
+) -> anyhow::Result<Vec<String>> {
+    if !is_valid_localpart(&external.username) {
+        info!(username = %external.username, "Provisioned username is not a valid localpart");
+        return Ok(vec![
+            "The username from the directory is not a valid Matrix username".to_owned(),
+        ]);
+    }
+
+    let mut policy = policy_factory.instantiate().await?;
+    let res = policy
+        .evaluate_register(&external.username, None, external.email.as_deref())
+        .await?;
+
+    for violation in &res.violations {
+        info!(field = ?violation.field, message = %violation.msg, "Provisioning denied by policy");
+    }
+
+    Ok(res.violations.into_iter().map(|v| v.msg).collect())
+}
+
+#[tracing::instrument(skip(templates, pool, upstream_oauth2, upstream_saml, cookie_jar))]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
     Query(query): Query<OptionalPostAuthAction>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
@@ -60,12 +109,27 @@ pub(crate) async fn get(
         let reply = query.go_next();
         Ok((cookie_jar, reply).into_response())
     } else {
+        // Prefill the username if the client gave us a hint
+        let ctx = match query.load_login_hint(&mut conn).await? {
+            Some(username) => {
+                let form = LoginForm {
+                    username,
+                    password: String::new(),
+                };
+                LoginContext::default().with_form_state(form.to_form_state())
+            }
+            None => LoginContext::default(),
+        };
+
         let content = render(
-            LoginContext::default(),
+            ctx,
             query,
             csrf_token,
             &mut conn,
             &templates,
+            &upstream_oauth2,
+            &upstream_saml,
+            &authentication_config,
         )
         .await?;
 
@@ -73,14 +137,24 @@ pub(crate) async fn get(
     }
 }
 
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 pub(crate) async fn post(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(password_backends): Extension<PasswordBackends>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
     Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<LoginForm>>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
+    let ip = client.ip_address;
 
     let form = cookie_jar.verify_form(form)?;
 
@@ -108,21 +182,124 @@ pub(crate) async fn post(
             csrf_token,
             &mut conn,
             &templates,
+            &upstream_oauth2,
+            &upstream_saml,
+            &authentication_config,
         )
         .await?;
 
         return Ok((cookie_jar, Html(content)).into_response());
     }
 
-    match login(&mut conn, &form.username, &form.password).await {
-        Ok(session_info) => {
+    let mut txn = conn.begin().await?;
+
+    // Refuse to check the password while there were too many failed attempts
+    if login_blocked_until(&mut txn, &form.username, ip)
+        .await?
+        .is_some()
+    {
+        let content = render(
+            LoginContext::default()
+                .with_form_state(state.with_error_on_form(FormError::TooManyAttempts)),
+            query,
+            csrf_token,
+            &mut txn,
+            &templates,
+            &upstream_oauth2,
+            &upstream_saml,
+            &authentication_config,
+        )
+        .await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    match authenticate_user(
+        &mut txn,
+        &password_backends,
+        &password_schemes,
+        &form.username,
+        &form.password,
+    )
+    .await
+    {
+        Ok((user, provisioned)) => {
+            // The user was just created from an external password backend, which
+            // is only kept if it would have been allowed to register
+            if let Some(external) = &provisioned {
+                let violations = check_provisioned_user(&policy_factory, external).await?;
+                if !violations.is_empty() {
+                    txn.rollback().await?;
+
+                    let mut state = state;
+                    for message in violations {
+                        state.add_error_on_form(FormError::Policy { message });
+                    }
+                    let content = render(
+                        LoginContext::default().with_form_state(state),
+                        query,
+                        csrf_token,
+                        &mut conn,
+                        &templates,
+                        &upstream_oauth2,
+                        &upstream_saml,
+                        &authentication_config,
+                    )
+                    .await?;
+
+                    return Ok((cookie_jar, Html(content)).into_response());
+                }
+            }
+
+            if let Some(external) = provisioned {
+                if let Err(e) = homeserver
+                    .provision_user(&user.username, external.displayname.as_deref())
+                    .await
+                {
+                    warn!(error = %e, "Failed to provision user on the homeserver");
+                }
+            }
+
+            // Users who enrolled a second factor have to provide it before
+            // getting a session. Failures are only forgotten once they did, so
+            // that going through the password again doesn't give more attempts.
+            if user_has_second_factor(&mut txn, &user).await? {
+                txn.commit().await?;
+                let cookie_jar = PendingSecondFactor::login(&user, AuthenticationMethod::Password)
+                    .save(cookie_jar);
+                let next = mas_router::SecondFactor::from(query.post_auth_action);
+                return Ok((cookie_jar, next.go()).into_response());
+            }
+
+            clear_login_failures(&mut txn, &form.username).await?;
+
+            let mut session_info = start_session(
+                &mut txn,
+                user,
+                client.user_agent.as_deref(),
+                client.ip_address,
+                &authentication_config.session_expiration(),
+            )
+            .await?;
+            record_session_authentication(
+                &mut txn,
+                &mut session_info,
+                AuthenticationMethod::Password,
+                None,
+            )
+            .await?;
+            txn.commit().await?;
+
             let cookie_jar = cookie_jar.set_session(&session_info);
             let reply = query.go_next();
             Ok((cookie_jar, reply).into_response())
         }
         Err(e) => {
             let state = match e {
-                LoginError::NotFound { .. } | LoginError::Authentication { .. } => {
+                LoginError::NotFound { .. }
+                | LoginError::Authentication { .. }
+                | LoginError::NotExternal { .. } => {
+                    record_login_failure(&mut txn, &form.username, ip).await?;
                     state.with_error_on_form(FormError::InvalidCredentials)
                 }
                 LoginError::Other(_) => state.with_error_on_form(FormError::Internal),
@@ -132,22 +309,133 @@ pub(crate) async fn post(
                 LoginContext::default().with_form_state(state),
                 query,
                 csrf_token,
-                &mut conn,
+                &mut txn,
                 &templates,
+                &upstream_oauth2,
+                &upstream_saml,
+                &authentication_config,
             )
             .await?;
+            txn.commit().await?;
 
             Ok((cookie_jar, Html(content)).into_response())
         }
     }
 }
 
+/// Start a passwordless WebAuthn assertion ceremony. The user picks one of
+/// the discoverable credentials they registered for this service.
+pub(crate) async fn get_webauthn(
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let rp = RelyingParty::new(&url_builder)?;
+    let challenge = Challenge::generate(thread_rng());
+    let options = webauthn::request_options(&rp, &challenge, &[], true);
+    let cookie_jar = challenge.save(cookie_jar);
+
+    Ok((cookie_jar, Json(options)).into_response())
+}
+
+/// Finish the passwordless WebAuthn assertion ceremony. Since the
+/// authenticator verified the user, it counts as a multi-factor
+/// authentication on its own.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post_webauthn(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(upstream_oauth2): Extension<UpstreamOAuth2Config>,
+    Extension(upstream_saml): Extension<UpstreamSamlConfig>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<AssertionResponse>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let rp = RelyingParty::new(&url_builder)?;
+    let (challenge, cookie_jar) = Challenge::take(cookie_jar);
+
+    let user = match (
+        challenge,
+        lookup_webauthn_credential(&mut txn, &form.credential_id).await?,
+    ) {
+        (Ok(challenge), Some((user, credential))) => {
+            match form.verify(&rp, &challenge, &credential, true).await {
+                Ok(sign_count) => use_webauthn_credential(&mut txn, &credential, sign_count)
+                    .await?
+                    .then(|| user),
+                Err(e) => {
+                    warn!(error = %e, "Failed to verify WebAuthn assertion");
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let user = if let Some(user) = user {
+        user
+    } else {
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let state = LoginForm {
+            username: String::new(),
+            password: String::new(),
+        }
+        .to_form_state()
+        .with_error_on_form(FormError::WebauthnFailed);
+
+        let content = render(
+            LoginContext::default().with_form_state(state),
+            query,
+            csrf_token,
+            &mut txn,
+            &templates,
+            &upstream_oauth2,
+            &upstream_saml,
+            &authentication_config,
+        )
+        .await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    };
+
+    let mut session_info = start_session(
+        &mut txn,
+        user,
+        client.user_agent.as_deref(),
+        client.ip_address,
+        &authentication_config.session_expiration(),
+    )
+    .await?;
+    record_session_authentication(
+        &mut txn,
+        &mut session_info,
+        AuthenticationMethod::Webauthn,
+        None,
+    )
+    .await?;
+    txn.commit().await?;
+
+    let cookie_jar = cookie_jar.set_session(&session_info);
+    let reply = query.go_next();
+    Ok((cookie_jar, reply).into_response())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn render(
     ctx: LoginContext,
     action: OptionalPostAuthAction,
     csrf_token: CsrfToken,
     conn: &mut PgConnection,
     templates: &Templates,
+    upstream_oauth2: &UpstreamOAuth2Config,
+    upstream_saml: &UpstreamSamlConfig,
+    authentication_config: &AuthenticationConfig,
 ) -> Result<String, FancyError> {
     let next = action.load_context(conn).await?;
     let ctx = if let Some(next) = next {
@@ -155,9 +443,33 @@ async fn render(
     } else {
         ctx
     };
+    let oidc_providers = upstream_oauth2.providers.iter().map(|provider| {
+        let mut route = UpstreamOAuth2Authorize::new(&provider.id);
+        if let Some(action) = &action.post_auth_action {
+            route = route.and_then(action.clone());
+        }
+        UpstreamProviderLink::new(provider.name.clone(), route.relative_url().to_string())
+    });
+    let saml_providers = upstream_saml.providers.iter().map(|provider| {
+        let mut route = UpstreamSamlLogin::new(&provider.id);
+        if let Some(action) = &action.post_auth_action {
+            route = route.and_then(action.clone());
+        }
+        UpstreamProviderLink::new(provider.name.clone(), route.relative_url().to_string())
+    });
+    let providers = oidc_providers.chain(saml_providers).collect();
+    let ctx = if authentication_config.email_login {
+        let link = mas_router::EmailLogin::from(action.post_auth_action.clone());
+        ctx.with_email_login_link(link.relative_url().to_string())
+    } else {
+        ctx
+    };
+    let webauthn_link = mas_router::WebauthnLogin::from(action.post_auth_action.clone());
     let register_link = mas_router::Register::from(action.post_auth_action).relative_url();
     let ctx = ctx
         .with_register_link(register_link.to_string())
+        .with_webauthn_link(webauthn_link.relative_url().to_string())
+        .with_upstream_providers(providers)
         .with_csrf(csrf_token.form_value());
 
     let content = templates.render_login(&ctx).await?;
@@ -26,8 +26,11 @@ use mas_router::{PostAuthAction, Route};
 use mas_storage::user::end_session;
 use sqlx::PgPool;
 
+use crate::HomeserverConnection;
+
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
+    Extension(homeserver): Extension<HomeserverConnection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<Option<PostAuthAction>>>,
 ) -> Result<impl IntoResponse, FancyError> {
@@ -39,13 +42,22 @@ pub(crate) async fn post(
 
     let maybe_session = session_info.load_session(&mut txn).await?;
 
+    let mut ended = None;
     if let Some(session) = maybe_session {
-        end_session(&mut txn, &session).await?;
+        let scopes = end_session(&mut txn, &session).await?;
         cookie_jar = cookie_jar.update_session_info(&session_info.mark_session_ended());
+        ended = Some((session.user.username, scopes));
     }
 
     txn.commit().await?;
 
+    // Also remove the devices of the OAuth sessions started from this session
+    if let Some((username, scopes)) = ended {
+        for scope in &scopes {
+            homeserver.delete_devices_for_scope(&username, scope).await;
+        }
+    }
+
     let destination = if let Some(action) = form {
         mas_router::Login::and_then(action)
     } else {
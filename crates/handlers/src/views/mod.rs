@@ -13,9 +13,13 @@
 // limitations under the License.
 
 pub mod account;
+pub mod email_login;
 pub mod index;
 pub mod login;
 pub mod logout;
+pub mod password;
 pub mod reauth;
 pub mod register;
+pub mod second_factor;
+pub mod select_account;
 pub mod shared;
@@ -0,0 +1,212 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{net::IpAddr, str::FromStr};
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use lettre::{message::Mailbox, Address};
+use mas_axum_utils::{
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionClient,
+};
+use mas_config::Encrypter;
+use mas_email::Mailer;
+use mas_router::UrlBuilder;
+use mas_storage::{
+    email_request::record_email_request,
+    password_reset::{add_password_reset, lookup_users_by_verified_email},
+};
+use mas_templates::{
+    FieldError, PasswordForgotContext, PasswordForgotFormField, PasswordResetEmailContext,
+    TemplateContext, Templates, ToFormState,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use tracing::warn;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ForgotForm {
+    email: String,
+}
+
+impl ToFormState for ForgotForm {
+    type Field = PasswordForgotFormField;
+}
+
+/// Create a reset for each account which verified the address, and prepare
+/// the emails to send. Nothing is sent when there were too many requests for
+/// this address or from this IP.
+async fn request_reset(
+    conn: &mut PgConnection,
+    url_builder: &UrlBuilder,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> anyhow::Result<Vec<(Mailbox, PasswordResetEmailContext)>> {
+    if !record_email_request(&mut *conn, email, ip).await? {
+        return Ok(Vec::new());
+    }
+
+    let mut messages = Vec::new();
+    for (user, email) in lookup_users_by_verified_email(&mut *conn, email).await? {
+        let token = Alphanumeric.sample_string(&mut thread_rng(), 32);
+        add_password_reset(&mut *conn, &email, &token).await?;
+
+        let address: Address = email.email.parse()?;
+        let mailbox = Mailbox::new(Some(user.username.clone()), address);
+        let context =
+            PasswordResetEmailContext::new(user.into(), url_builder.password_reset(&token));
+        messages.push((mailbox, context));
+    }
+
+    Ok(messages)
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let ctx = PasswordForgotContext::default().with_csrf(csrf_token.form_value());
+    let content = templates.render_password_forgot(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(mailer): Extension<Mailer>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ForgotForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let state = {
+        let mut state = form.to_form_state();
+
+        if form.email.is_empty() {
+            state.add_error_on_field(PasswordForgotFormField::Email, FieldError::Required);
+        } else if Address::from_str(&form.email).is_err() {
+            state.add_error_on_field(PasswordForgotFormField::Email, FieldError::Invalid);
+        }
+
+        state
+    };
+
+    if !state.is_valid() {
+        let ctx = PasswordForgotContext::default()
+            .with_form_state(state)
+            .with_csrf(csrf_token.form_value());
+        let content = templates.render_password_forgot(&ctx).await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let messages = request_reset(&mut txn, &url_builder, &form.email, client.ip_address).await?;
+    txn.commit().await?;
+
+    // Send the emails in the background, so that the response doesn't tell
+    // whether the address belongs to an account
+    tokio::spawn(async move {
+        for (mailbox, context) in messages {
+            if let Err(e) = mailer.send_password_reset_email(mailbox, &context).await {
+                warn!(error = %e, "Failed to send password reset email");
+            }
+        }
+    });
+
+    let ctx = PasswordForgotContext::default()
+        .sent()
+        .with_csrf(csrf_token.form_value());
+    let content = templates.render_password_forgot(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_storage::{
+        password::PasswordSchemes,
+        test_utils::{random_name, test_transaction},
+        user::{add_user_email, mark_user_email_as_verified, register_user},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_reset() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let url_builder = UrlBuilder::new("https://example.com/".parse().unwrap());
+        let schemes = PasswordSchemes::default();
+        let user = register_user(&mut txn, &schemes, &random_name("forgot"), "hunter2")
+            .await
+            .unwrap();
+
+        let verified = format!("{}@example.com", random_name("verified"));
+        let email = add_user_email(&mut txn, &user, &verified).await.unwrap();
+        mark_user_email_as_verified(&mut txn, email).await.unwrap();
+
+        let unverified = format!("{}@example.com", random_name("unverified"));
+        add_user_email(&mut txn, &user, &unverified).await.unwrap();
+
+        let unknown = format!("{}@example.com", random_name("unknown"));
+
+        // The handler answers the same way as long as this doesn't fail, so only
+        // the emails tell those addresses apart
+        for email in [&unknown, &unverified] {
+            let messages = request_reset(&mut txn, &url_builder, email, None)
+                .await
+                .unwrap();
+            assert!(messages.is_empty());
+        }
+
+        let messages = request_reset(&mut txn, &url_builder, &verified, None)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0.email.to_string(), verified);
+
+        // Sending stops after a few requests for the same address
+        request_reset(&mut txn, &url_builder, &verified, None)
+            .await
+            .unwrap();
+        request_reset(&mut txn, &url_builder, &verified, None)
+            .await
+            .unwrap();
+        let messages = request_reset(&mut txn, &url_builder, &verified, None)
+            .await
+            .unwrap();
+        assert!(messages.is_empty());
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Form, Path},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Duration;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    FancyError,
+};
+use mas_config::Encrypter;
+use mas_data_model::Device;
+use mas_policy::PolicyFactory;
+use mas_router::Route;
+use mas_storage::{
+    compat::end_compat_sessions_for_user,
+    oauth2::end_oauth_sessions_for_user,
+    password::PasswordSchemes,
+    password_reset::{consume_password_reset, lookup_password_reset, PasswordReset},
+    user::{end_user_sessions, set_password},
+};
+use mas_templates::{
+    FieldError, FormError, FormState, PasswordResetContext, PasswordResetFormField,
+    TemplateContext, Templates,
+};
+use oauth2_types::scope::Scope;
+use serde::Deserialize;
+use sqlx::{PgConnection, PgPool};
+use tracing::warn;
+
+use super::RESET_TTL_HOURS;
+use crate::HomeserverConnection;
+
+#[derive(Deserialize)]
+pub(crate) struct ResetForm {
+    password: String,
+    password_confirm: String,
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Path(token): Path<String>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let reset = lookup_password_reset(&mut conn, &token, Duration::hours(RESET_TTL_HOURS)).await?;
+    let ctx = match reset {
+        Some(reset) => PasswordResetContext::new(reset.user.username),
+        None => PasswordResetContext::invalid(),
+    };
+
+    render(&templates, ctx, csrf_token, cookie_jar).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(homeserver): Extension<HomeserverConnection>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Path(token): Path<String>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ResetForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let reset = lookup_password_reset(&mut txn, &token, Duration::hours(RESET_TTL_HOURS)).await?;
+    let reset = if let Some(reset) = reset {
+        reset
+    } else {
+        return render(
+            &templates,
+            PasswordResetContext::invalid(),
+            csrf_token,
+            cookie_jar,
+        )
+        .await;
+    };
+
+    // Validate the form
+    let state = {
+        let mut state = FormState::default();
+
+        if form.password.is_empty() {
+            state.add_error_on_field(PasswordResetFormField::Password, FieldError::Required);
+        }
+
+        if form.password_confirm.is_empty() {
+            state.add_error_on_field(
+                PasswordResetFormField::PasswordConfirm,
+                FieldError::Required,
+            );
+        }
+
+        if form.password != form.password_confirm {
+            state.add_error_on_form(FormError::PasswordMismatch);
+            state.add_error_on_field(PasswordResetFormField::Password, FieldError::Unspecified);
+            state.add_error_on_field(
+                PasswordResetFormField::PasswordConfirm,
+                FieldError::Unspecified,
+            );
+        }
+
+        // New passwords go through the same policy as on registration
+        let mut policy = policy_factory.instantiate().await?;
+        let res = policy
+            .evaluate_register(&reset.user.username, Some(&form.password), None)
+            .await?;
+
+        for violation in res.violations {
+            match violation.field.as_deref() {
+                Some("password") => state.add_error_on_field(
+                    PasswordResetFormField::Password,
+                    FieldError::Policy {
+                        message: violation.msg,
+                    },
+                ),
+                _ => state.add_error_on_form(FormError::Policy {
+                    message: violation.msg,
+                }),
+            }
+        }
+
+        if let Some(feedback) = res.password_feedback {
+            state.add_error_on_field(
+                PasswordResetFormField::Password,
+                FieldError::PasswordFeedback {
+                    warning: feedback.warning,
+                    suggestions: feedback.suggestions,
+                },
+            );
+        }
+
+        state
+    };
+
+    if !state.is_valid() {
+        let ctx = PasswordResetContext::new(reset.user.username).with_form_state(state);
+        return render(&templates, ctx, csrf_token, cookie_jar).await;
+    }
+
+    let user = reset.user.clone();
+    let ended = reset_password(&mut txn, &password_schemes, &reset, &form.password).await?;
+    let (devices, scopes) = if let Some(ended) = ended {
+        ended
+    } else {
+        return render(
+            &templates,
+            PasswordResetContext::invalid(),
+            csrf_token,
+            cookie_jar,
+        )
+        .await;
+    };
+
+    txn.commit().await?;
+
+    for device in &devices {
+        if let Err(e) = homeserver.delete_device(&user.username, device).await {
+            warn!(error = %e, "Failed to delete device on the homeserver");
+        }
+    }
+
+    for scope in &scopes {
+        homeserver
+            .delete_devices_for_scope(&user.username, scope)
+            .await;
+    }
+
+    Ok((cookie_jar, mas_router::Login::default().go()).into_response())
+}
+
+/// Consume the reset and set the new password. Returns the devices and scopes
+/// of the sessions which were ended, or `None` if someone else used the link
+/// in the meantime.
+async fn reset_password(
+    conn: &mut PgConnection,
+    password_schemes: &PasswordSchemes,
+    reset: &PasswordReset,
+    password: &str,
+) -> anyhow::Result<Option<(Vec<Device>, Vec<Scope>)>> {
+    if !consume_password_reset(&mut *conn, reset).await? {
+        return Ok(None);
+    }
+
+    let user = &reset.user;
+    set_password(&mut *conn, password_schemes, user, password).await?;
+
+    // Whoever knew the old password shouldn't stay logged in
+    end_user_sessions(&mut *conn, user).await?;
+    let devices = end_compat_sessions_for_user(&mut *conn, user).await?;
+    let scopes = end_oauth_sessions_for_user(&mut *conn, user).await?;
+
+    Ok(Some((devices, scopes)))
+}
+
+async fn render(
+    templates: &Templates,
+    ctx: PasswordResetContext,
+    csrf_token: CsrfToken,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let ctx = ctx.with_csrf(csrf_token.form_value());
+    let content = templates.render_password_reset(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::SessionExpiration;
+    use mas_storage::{
+        compat::start_compat_session,
+        password::{verify_password, PasswordBackends},
+        password_reset::add_password_reset,
+        test_utils::{random_name, test_transaction},
+        user::{
+            add_user_email, lookup_active_session, mark_user_email_as_verified, register_user,
+            start_session,
+        },
+    };
+    use rand::thread_rng;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reset_password() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let schemes = PasswordSchemes::default();
+        let user = register_user(&mut txn, &schemes, &random_name("reset"), "hunter2")
+            .await
+            .unwrap();
+        let email = add_user_email(&mut txn, &user, "reset@example.com")
+            .await
+            .unwrap();
+        let email = mark_user_email_as_verified(&mut txn, email).await.unwrap();
+
+        let session = start_session(
+            &mut txn,
+            user.clone(),
+            None,
+            None,
+            &SessionExpiration::default(),
+        )
+        .await
+        .unwrap();
+        let device = Device::generate(&mut thread_rng());
+        start_compat_session(&mut txn, user.clone(), device.clone(), None)
+            .await
+            .unwrap();
+
+        let token = random_name("token");
+        add_password_reset(&mut txn, &email, &token).await.unwrap();
+        let reset = lookup_password_reset(&mut txn, &token, Duration::hours(RESET_TTL_HOURS))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let (devices, _) = reset_password(&mut txn, &schemes, &reset, "correct horse")
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Every session of the user is over
+        assert_eq!(devices, vec![device]);
+        assert!(lookup_active_session(&mut txn, session.data)
+            .await
+            .unwrap_err()
+            .not_found());
+
+        let backends = PasswordBackends::default();
+        verify_password(&mut txn, &backends, &schemes, &user, "correct horse")
+            .await
+            .unwrap();
+        assert!(
+            verify_password(&mut txn, &backends, &schemes, &user, "hunter2")
+                .await
+                .is_err()
+        );
+
+        // The link only works once
+        assert!(reset_password(&mut txn, &schemes, &reset, "hunter3")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -18,17 +18,28 @@ use axum::{
 };
 use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
-    FancyError, SessionInfoExt,
+    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    FancyError, SessionClient, SessionInfoExt,
 };
 use mas_config::Encrypter;
+use mas_data_model::{AuthenticationMethod, BrowserSession};
 use mas_router::Route;
-use mas_storage::user::authenticate_session;
-use mas_templates::{ReauthContext, TemplateContext, Templates};
+use mas_storage::{
+    login_throttle::{clear_login_failures, login_blocked_until, record_login_failure},
+    password::{verify_password, PasswordBackends, PasswordSchemes},
+    user::{record_session_authentication, AuthenticationError},
+    PostgresqlBackend,
+};
+use mas_templates::{
+    FormError, FormState, ReauthContext, ReauthFormField, TemplateContext, Templates,
+};
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 
-use super::shared::OptionalPostAuthAction;
+use super::{
+    second_factor::{user_has_second_factor, PendingSecondFactor},
+    shared::OptionalPostAuthAction,
+};
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct ReauthForm {
@@ -57,26 +68,27 @@ pub(crate) async fn get(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    let ctx = ReauthContext::default();
-    let next = query.load_context(&mut conn).await?;
-    let ctx = if let Some(next) = next {
-        // SAFETY: we should have an action only if we have a "next" context
-        // TODO: make that cleaner
-        let action = query.post_auth_action.unwrap();
-        ctx.with_post_action(next, action)
-    } else {
-        ctx
-    };
-    let ctx = ctx.with_session(session).with_csrf(csrf_token.form_value());
-
-    let content = templates.render_reauth(&ctx).await?;
+    let content = render(
+        FormState::default(),
+        query,
+        csrf_token,
+        session,
+        &mut conn,
+        &templates,
+    )
+    .await?;
 
     Ok((cookie_jar, Html(content)).into_response())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(password_backends): Extension<PasswordBackends>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
     Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<ReauthForm>>,
 ) -> Result<Response, FancyError> {
@@ -97,11 +109,90 @@ pub(crate) async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    // TODO: recover from errors here
-    authenticate_session(&mut txn, &mut session, &form.password).await?;
+    let ip = client.ip_address;
+    let username = session.user.username.clone();
+
+    // Refuse to check the password while there were too many failed attempts
+    let error = if login_blocked_until(&mut txn, &username, ip)
+        .await?
+        .is_some()
+    {
+        Some(FormError::TooManyAttempts)
+    } else {
+        match verify_password(
+            &mut txn,
+            &password_backends,
+            &password_schemes,
+            &session.user,
+            &form.password,
+        )
+        .await
+        {
+            Ok(()) => None,
+            Err(AuthenticationError::Password(_)) => {
+                record_login_failure(&mut txn, &username, ip).await?;
+                Some(FormError::InvalidCredentials)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    if let Some(error) = error {
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let content = render(
+            FormState::default().with_error_on_form(error),
+            query,
+            csrf_token,
+            session,
+            &mut txn,
+            &templates,
+        )
+        .await?;
+        txn.commit().await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    // Users who enrolled a second factor have to provide it as well, and their
+    // failures are only forgotten once they did
+    if user_has_second_factor(&mut txn, &session.user).await? {
+        txn.commit().await?;
+
+        let cookie_jar = PendingSecondFactor::reauth(&session).save(cookie_jar);
+        let next = mas_router::SecondFactor::from(query.post_auth_action);
+        return Ok((cookie_jar, next.go()).into_response());
+    }
+
+    clear_login_failures(&mut txn, &username).await?;
+    record_session_authentication(&mut txn, &mut session, AuthenticationMethod::Reauth, None)
+        .await?;
     let cookie_jar = cookie_jar.set_session(&session);
     txn.commit().await?;
 
     let reply = query.go_next();
     Ok((cookie_jar, reply).into_response())
 }
+
+async fn render(
+    form: FormState<ReauthFormField>,
+    action: OptionalPostAuthAction,
+    csrf_token: CsrfToken,
+    session: BrowserSession<PostgresqlBackend>,
+    conn: &mut PgConnection,
+    templates: &Templates,
+) -> Result<String, FancyError> {
+    let ctx = ReauthContext::default().with_form_state(form);
+    let next = action.load_context(conn).await?;
+    let ctx = if let Some(next) = next {
+        // SAFETY: we should have an action only if we have a "next" context
+        // TODO: make that cleaner
+        let action = action.post_auth_action.unwrap();
+        ctx.with_post_action(next, action)
+    } else {
+        ctx
+    };
+    let ctx = ctx.with_session(session).with_csrf(csrf_token.form_value());
+
+    let content = templates.render_reauth(&ctx).await?;
+    Ok(content)
+}
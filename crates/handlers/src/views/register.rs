@@ -16,7 +16,6 @@
 
 use std::{str::FromStr, sync::Arc};
 
-use argon2::Argon2;
 use axum::{
     extract::{Extension, Form, Query},
     response::{Html, IntoResponse, Response},
@@ -25,14 +24,18 @@ use axum_extra::extract::PrivateCookieJar;
 use lettre::{message::Mailbox, Address};
 use mas_axum_utils::{
     csrf::{CsrfExt, CsrfToken, ProtectedForm},
-    FancyError, SessionInfoExt,
+    FancyError, SessionClient, SessionInfoExt,
 };
-use mas_config::Encrypter;
+use mas_config::{AuthenticationConfig, Encrypter};
 use mas_email::Mailer;
 use mas_policy::PolicyFactory;
 use mas_router::Route;
-use mas_storage::user::{
-    add_user_email, add_user_email_verification_code, register_user, start_session, username_exists,
+use mas_storage::{
+    password::PasswordSchemes,
+    user::{
+        add_user_email, add_user_email_verification_code, register_user, start_session,
+        username_exists,
+    },
 };
 use mas_templates::{
     EmailVerificationContext, FieldError, FormError, RegisterContext, RegisterFormField,
@@ -41,8 +44,10 @@ use mas_templates::{
 use rand::{distributions::Uniform, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use sqlx::{PgConnection, PgPool};
+use tracing::warn;
 
 use super::shared::OptionalPostAuthAction;
+use crate::HomeserverConnection;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct RegisterForm {
@@ -86,12 +91,17 @@ pub(crate) async fn get(
     }
 }
 
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 pub(crate) async fn post(
     Extension(mailer): Extension<Mailer>,
+    Extension(homeserver): Extension<HomeserverConnection>,
     Extension(policy_factory): Extension<Arc<PolicyFactory>>,
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(password_schemes): Extension<PasswordSchemes>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
     Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<RegisterForm>>,
 ) -> Result<Response, FancyError> {
@@ -133,7 +143,7 @@ pub(crate) async fn post(
 
         let mut policy = policy_factory.instantiate().await?;
         let res = policy
-            .evaluate_register(&form.username, &form.password, &form.email)
+            .evaluate_register(&form.username, Some(&form.password), Some(&form.email))
             .await?;
 
         for violation in res.violations {
@@ -162,6 +172,16 @@ pub(crate) async fn post(
             }
         }
 
+        if let Some(feedback) = res.password_feedback {
+            state.add_error_on_field(
+                RegisterFormField::Password,
+                FieldError::PasswordFeedback {
+                    warning: feedback.warning,
+                    suggestions: feedback.suggestions,
+                },
+            );
+        }
+
         state
     };
 
@@ -178,8 +198,7 @@ pub(crate) async fn post(
         return Ok((cookie_jar, Html(content)).into_response());
     }
 
-    let pfh = Argon2::default();
-    let user = register_user(&mut txn, pfh, &form.username, &form.password).await?;
+    let user = register_user(&mut txn, &password_schemes, &form.username, &form.password).await?;
 
     let user_email = add_user_email(&mut txn, &user, &form.email).await?;
 
@@ -201,10 +220,26 @@ pub(crate) async fn post(
     let next =
         mas_router::AccountVerifyEmail::new(verification.data).and_maybe(query.post_auth_action);
 
-    let session = start_session(&mut txn, user).await?;
+    let session = start_session(
+        &mut txn,
+        user,
+        client.user_agent.as_deref(),
+        client.ip_address,
+        &authentication_config.session_expiration(),
+    )
+    .await?;
 
     txn.commit().await?;
 
+    // The account exists on our side at this point, so a homeserver failure
+    // should not prevent the user from continuing
+    if let Err(e) = homeserver
+        .provision_user(&session.user.username, None)
+        .await
+    {
+        warn!(error = %e, "Failed to provision user on the homeserver");
+    }
+
     let cookie_jar = cookie_jar.set_session(&session);
     Ok((cookie_jar, next.go()).into_response())
 }
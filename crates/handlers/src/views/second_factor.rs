@@ -0,0 +1,571 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Second factor step of the login and reauthentication flows
+
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Extension, Form, Query},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    FancyError, SessionClient, SessionInfoExt,
+};
+use mas_config::{AuthenticationConfig, Encrypter};
+use mas_data_model::{AuthenticationMethod, BrowserSession, SecondFactor, SessionExpiration, User};
+use mas_router::{Route, UrlBuilder};
+use mas_storage::{
+    login_throttle::{clear_login_failures, login_blocked_until, record_login_failure},
+    totp::{lookup_user_totp, use_user_recovery_code, use_user_totp_step},
+    user::{lookup_user_by_username, record_session_authentication, start_session},
+    webauthn::{
+        get_user_webauthn_credentials, has_user_webauthn_credentials, lookup_webauthn_credential,
+        use_webauthn_credential,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    FieldError, FormError, FormState, SecondFactorContext, SecondFactorFormField, TemplateContext,
+    Templates, ToFormState,
+};
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use tracing::warn;
+
+use super::shared::OptionalPostAuthAction;
+use crate::{
+    totp,
+    upstream_oauth2::{load_cookie, remove_cookie, save_cookie},
+    webauthn::{self, AssertionResponse, Challenge, RelyingParty},
+};
+
+static PENDING_COOKIE: &str = "pending-second-factor";
+
+/// Users have this long to enter their second factor after their password
+const PENDING_TTL: i64 = 10 * 60;
+
+/// How many wrong second factors can be given before having to start over
+const MAX_ATTEMPTS: u8 = 5;
+
+/// A user who entered their password and still has to provide their second
+/// factor. It lives in an encrypted cookie.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PendingSecondFactor {
+    username: String,
+
+    /// The session being reauthenticated, if this isn't a fresh login
+    session_id: Option<i64>,
+
+    /// How the user passed the first step
+    method: AuthenticationMethod,
+
+    #[serde(default)]
+    attempts: u8,
+    expires_at: i64,
+}
+
+impl PendingSecondFactor {
+    fn new(username: String, session_id: Option<i64>, method: AuthenticationMethod) -> Self {
+        Self {
+            username,
+            session_id,
+            method,
+            attempts: 0,
+            expires_at: (Utc::now() + Duration::seconds(PENDING_TTL)).timestamp(),
+        }
+    }
+
+    /// A login which passed its first step, with the given method
+    pub(crate) fn login(user: &User<PostgresqlBackend>, method: AuthenticationMethod) -> Self {
+        Self::new(user.username.clone(), None, method)
+    }
+
+    /// A reauthentication which passed the password check
+    pub(crate) fn reauth(session: &BrowserSession<PostgresqlBackend>) -> Self {
+        Self::new(
+            session.user.username.clone(),
+            Some(session.data),
+            AuthenticationMethod::Reauth,
+        )
+    }
+
+    pub(crate) fn save(
+        &self,
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, PENDING_COOKIE, self)
+    }
+
+    fn load(cookie_jar: &PrivateCookieJar<Encrypter>) -> Option<Self> {
+        load_cookie::<Self>(cookie_jar, PENDING_COOKIE)
+            .filter(|pending| pending.expires_at > Utc::now().timestamp())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct SecondFactorForm {
+    code: String,
+}
+
+impl ToFormState for SecondFactorForm {
+    type Field = SecondFactorFormField;
+}
+
+/// Whether the user has to provide a second factor after their password
+pub(crate) async fn user_has_second_factor(
+    conn: &mut PgConnection,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<bool> {
+    let totp = lookup_user_totp(&mut *conn, user).await?;
+    if totp.map_or(false, |totp| totp.is_confirmed()) {
+        return Ok(true);
+    }
+
+    Ok(has_user_webauthn_credentials(conn, user).await?)
+}
+
+/// The user behind the pending second factor step, and the session being
+/// reauthenticated if any
+struct PendingUser {
+    user: User<PostgresqlBackend>,
+    cookie: PendingSecondFactor,
+    reauth_session: Option<BrowserSession<PostgresqlBackend>>,
+}
+
+async fn load_pending_user(
+    conn: &mut PgConnection,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<(Option<PendingUser>, PrivateCookieJar<Encrypter>), FancyError> {
+    // Start over if the password step was skipped or took too long
+    let pending = if let Some(pending) = PendingSecondFactor::load(&cookie_jar) {
+        pending
+    } else {
+        return Ok((None, cookie_jar));
+    };
+
+    let user = lookup_user_by_username(&mut *conn, &pending.username).await?;
+
+    // For reauthentications, the session must still be the one which
+    // passed the password check
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+    let reauth_session = match pending.session_id {
+        Some(session_id) => match session_info.load_session(conn).await? {
+            Some(session) if session.data == session_id => Some(session),
+            _ => return Ok((None, remove_cookie(cookie_jar, PENDING_COOKIE))),
+        },
+        None => None,
+    };
+
+    Ok((
+        Some(PendingUser {
+            user,
+            cookie: pending,
+            reauth_session,
+        }),
+        cookie_jar,
+    ))
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (pending, cookie_jar) = load_pending_user(&mut conn, cookie_jar).await?;
+    let pending = if let Some(pending) = pending {
+        pending
+    } else {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+
+    let ctx = SecondFactorContext::new(pending.user.username.clone());
+    let content = render(ctx, query, csrf_token, &mut conn, &templates, &pending.user).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<SecondFactorForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (pending, cookie_jar) = load_pending_user(&mut txn, cookie_jar).await?;
+    let mut pending = if let Some(pending) = pending {
+        pending
+    } else {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    // Refuse to check the code while there were too many failed attempts
+    let ip = client.ip_address;
+    let blocked = login_blocked_until(&mut txn, &pending.user.username, ip)
+        .await?
+        .is_some();
+    let factor = if blocked {
+        None
+    } else {
+        verify_code(&mut txn, &encrypter, &pending.user, &form.code).await?
+    };
+
+    let factor = if let Some(factor) = factor {
+        factor
+    } else {
+        let state = form.to_form_state();
+        let (state, cookie_jar) = if blocked {
+            (
+                state.with_error_on_form(FormError::TooManyAttempts),
+                cookie_jar,
+            )
+        } else {
+            let (cookie_jar, dropped) =
+                record_failure(&mut txn, cookie_jar, &mut pending, ip).await?;
+            let state = if dropped {
+                state.with_error_on_form(FormError::TooManyAttempts)
+            } else {
+                state.with_error_on_field(SecondFactorFormField::Code, FieldError::Invalid)
+            };
+            (state, cookie_jar)
+        };
+
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let ctx = SecondFactorContext::new(pending.user.username.clone()).with_form_state(state);
+        let content = render(ctx, query, csrf_token, &mut txn, &templates, &pending.user).await?;
+
+        // Still commit, so that the failure is counted and a used recovery
+        // code can't be replayed
+        txn.commit().await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    };
+
+    complete(
+        txn,
+        cookie_jar,
+        pending,
+        factor,
+        &query,
+        &client,
+        &authentication_config.session_expiration(),
+    )
+    .await
+}
+
+/// Start a WebAuthn assertion ceremony with the credentials of the user
+pub(crate) async fn get_webauthn(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (pending, cookie_jar) = load_pending_user(&mut conn, cookie_jar).await?;
+    let pending = if let Some(pending) = pending {
+        pending
+    } else {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let rp = RelyingParty::new(&url_builder)?;
+    let credentials = get_user_webauthn_credentials(&mut conn, &pending.user).await?;
+    let challenge = Challenge::generate(thread_rng());
+    let options = webauthn::request_options(&rp, &challenge, &credentials, false);
+    let cookie_jar = challenge.save(cookie_jar);
+
+    Ok((cookie_jar, Json(options)).into_response())
+}
+
+/// Finish the WebAuthn assertion ceremony
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post_webauthn(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(authentication_config): Extension<AuthenticationConfig>,
+    Query(query): Query<OptionalPostAuthAction>,
+    client: SessionClient,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<AssertionResponse>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (pending, cookie_jar) = load_pending_user(&mut txn, cookie_jar).await?;
+    let mut pending = if let Some(pending) = pending {
+        pending
+    } else {
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let rp = RelyingParty::new(&url_builder)?;
+    let (challenge, cookie_jar) = Challenge::take(cookie_jar);
+
+    // Refuse to check the assertion while there were too many failed attempts
+    let ip = client.ip_address;
+    if login_blocked_until(&mut txn, &pending.user.username, ip)
+        .await?
+        .is_some()
+    {
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let state = FormState::default().with_error_on_form(FormError::TooManyAttempts);
+        let ctx = SecondFactorContext::new(pending.user.username.clone()).with_form_state(state);
+        let content = render(ctx, query, csrf_token, &mut txn, &templates, &pending.user).await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let verified = match (
+        challenge,
+        lookup_webauthn_credential(&mut txn, &form.credential_id).await?,
+    ) {
+        (Ok(challenge), Some((owner, credential))) if owner.data == pending.user.data => match form
+            .verify(&rp, &challenge, &credential, false)
+            .await
+        {
+            Ok(sign_count) => use_webauthn_credential(&mut txn, &credential, sign_count).await?,
+            Err(e) => {
+                warn!(error = %e, "Failed to verify WebAuthn assertion");
+                false
+            }
+        },
+        _ => false,
+    };
+
+    if !verified {
+        let (cookie_jar, dropped) = record_failure(&mut txn, cookie_jar, &mut pending, ip).await?;
+        let error = if dropped {
+            FormError::TooManyAttempts
+        } else {
+            FormError::WebauthnFailed
+        };
+
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let state = FormState::default().with_error_on_form(error);
+        let ctx = SecondFactorContext::new(pending.user.username.clone()).with_form_state(state);
+        let content = render(ctx, query, csrf_token, &mut txn, &templates, &pending.user).await?;
+        txn.commit().await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    complete(
+        txn,
+        cookie_jar,
+        pending,
+        SecondFactor::Webauthn,
+        &query,
+        &client,
+        &authentication_config.session_expiration(),
+    )
+    .await
+}
+
+/// Count a wrong second factor against the login throttle of the user. After
+/// too many of them, the pending login is dropped and the user has to start
+/// over from the first step. Returns whether it was dropped.
+async fn record_failure(
+    conn: &mut PgConnection,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    pending: &mut PendingUser,
+    ip: Option<IpAddr>,
+) -> Result<(PrivateCookieJar<Encrypter>, bool), FancyError> {
+    let failure = record_login_failure(conn, &pending.user.username, ip).await?;
+
+    pending.cookie.attempts += 1;
+    if failure.locked || pending.cookie.attempts >= MAX_ATTEMPTS {
+        Ok((remove_cookie(cookie_jar, PENDING_COOKIE), true))
+    } else {
+        Ok((pending.cookie.save(cookie_jar), false))
+    }
+}
+
+/// Record the authentication with its second factor, and give the user
+/// their session
+async fn complete(
+    mut txn: Transaction<'_, Postgres>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    pending: PendingUser,
+    factor: SecondFactor,
+    query: &OptionalPostAuthAction,
+    client: &SessionClient,
+    expiration: &SessionExpiration,
+) -> Result<Response, FancyError> {
+    clear_login_failures(&mut txn, &pending.user.username).await?;
+
+    let session = if let Some(mut session) = pending.reauth_session {
+        record_session_authentication(
+            &mut txn,
+            &mut session,
+            AuthenticationMethod::Reauth,
+            Some(factor),
+        )
+        .await?;
+        session
+    } else {
+        let mut session = start_session(
+            &mut txn,
+            pending.user,
+            client.user_agent.as_deref(),
+            client.ip_address,
+            expiration,
+        )
+        .await?;
+        record_session_authentication(&mut txn, &mut session, pending.cookie.method, Some(factor))
+            .await?;
+        session
+    };
+
+    txn.commit().await?;
+
+    let cookie_jar = remove_cookie(cookie_jar, PENDING_COOKIE).set_session(&session);
+    let reply = query.go_next();
+    Ok((cookie_jar, reply).into_response())
+}
+
+/// Check a code from the form, either against the TOTP authenticator of the
+/// user or against their recovery codes, and consume it
+async fn verify_code(
+    conn: &mut PgConnection,
+    encrypter: &Encrypter,
+    user: &User<PostgresqlBackend>,
+    code: &str,
+) -> Result<Option<SecondFactor>, FancyError> {
+    if totp::is_totp_code(code) {
+        let user_totp = match lookup_user_totp(&mut *conn, user).await? {
+            Some(user_totp) if user_totp.is_confirmed() => user_totp,
+            _ => return Ok(None),
+        };
+
+        let secret = encrypter.decrypt_string(&user_totp.encrypted_secret)?;
+        let step = match totp::verify(&secret, code, Utc::now().timestamp()) {
+            Some(step) => step,
+            None => return Ok(None),
+        };
+
+        if use_user_totp_step(&mut *conn, &user_totp, step).await? {
+            return Ok(Some(SecondFactor::Totp));
+        }
+
+        Ok(None)
+    } else {
+        let consumed = use_user_recovery_code(conn, user, code).await?;
+        Ok(consumed.then(|| SecondFactor::RecoveryCode))
+    }
+}
+
+async fn render(
+    ctx: SecondFactorContext,
+    action: OptionalPostAuthAction,
+    csrf_token: CsrfToken,
+    conn: &mut PgConnection,
+    templates: &Templates,
+    user: &User<PostgresqlBackend>,
+) -> Result<String, FancyError> {
+    // Only offer the factors the user actually has
+    let totp = lookup_user_totp(&mut *conn, user).await?;
+    let ctx = if totp.map_or(false, |totp| totp.is_confirmed()) {
+        ctx.with_totp()
+    } else {
+        ctx
+    };
+    let ctx = if has_user_webauthn_credentials(&mut *conn, user).await? {
+        let link = mas_router::SecondFactorWebauthn::from(action.post_auth_action.clone());
+        ctx.with_webauthn_link(link.relative_url().to_string())
+    } else {
+        ctx
+    };
+
+    let next = action.load_context(conn).await?;
+    let ctx = match (next, action.post_auth_action) {
+        (Some(next), Some(action)) => ctx.with_post_action(next, action),
+        _ => ctx,
+    };
+    let ctx = ctx.with_csrf(csrf_token.form_value());
+
+    let content = templates.render_second_factor(&ctx).await?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_storage::{
+        test_utils::{random_name, test_transaction},
+        totp::replace_user_recovery_codes,
+        user::register_passwordless_user,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recovery_codes() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let encrypter = Encrypter::new(&[0x42; 32]);
+        let user = register_passwordless_user(&mut txn, &random_name("recovery"))
+            .await
+            .unwrap();
+        let codes = totp::generate_recovery_codes(thread_rng());
+        replace_user_recovery_codes(&mut txn, &user, &codes)
+            .await
+            .unwrap();
+
+        // Codes work as displayed, and however they are typed, but only once
+        assert!(matches!(
+            verify_code(&mut txn, &encrypter, &user, &codes[0]).await,
+            Ok(Some(SecondFactor::RecoveryCode))
+        ));
+        assert!(matches!(
+            verify_code(&mut txn, &encrypter, &user, &codes[0]).await,
+            Ok(None)
+        ));
+
+        let typed = format!(" {} ", codes[1].replace('-', "").to_uppercase());
+        assert!(matches!(
+            verify_code(&mut txn, &encrypter, &user, &typed).await,
+            Ok(Some(SecondFactor::RecoveryCode))
+        ));
+
+        assert!(matches!(
+            verify_code(&mut txn, &encrypter, &user, "abcde-fghij").await,
+            Ok(None)
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form, Query},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_router::Route;
+use mas_templates::{SelectAccountContext, TemplateContext, Templates};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use super::shared::OptionalPostAuthAction;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum SelectAccountForm {
+    Select { data: String },
+    Other,
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let sessions = session_info.load_sessions(&mut conn).await?;
+
+    if sessions.is_empty() {
+        // Nothing to pick from, go straight to the login screen, keeping the
+        // PostAuthAction
+        let login = mas_router::Login::from(query.post_auth_action);
+        return Ok((cookie_jar, login.go()).into_response());
+    }
+
+    let ctx = SelectAccountContext::new(sessions);
+    let next = query.load_context(&mut conn).await?;
+    let ctx = if let Some(next) = next {
+        ctx.with_post_action(next)
+    } else {
+        ctx
+    };
+    let ctx = ctx.with_csrf(csrf_token.form_value());
+
+    let content = templates.render_select_account(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Query(query): Query<OptionalPostAuthAction>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<SelectAccountForm>>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let form = cookie_jar.verify_form(form)?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    match form {
+        SelectAccountForm::Select { data } => {
+            let id: i64 = data.parse()?;
+
+            // Only sessions known to this browser can be picked
+            let sessions = session_info.load_sessions(&mut conn).await?;
+            let session = sessions
+                .into_iter()
+                .find(|session| session.data == id)
+                .ok_or_else(|| anyhow::anyhow!("unknown browser session"))?;
+
+            let cookie_jar = cookie_jar.set_session(&session);
+            Ok((cookie_jar, query.go_next()).into_response())
+        }
+        SelectAccountForm::Other => {
+            // Keep the current session around, but let the user log in with
+            // another account
+            let cookie_jar = cookie_jar.update_session_info(&session_info.detach_current());
+            let login = mas_router::Login::from(query.post_auth_action);
+            Ok((cookie_jar, login.go()).into_response())
+        }
+    }
+}
@@ -53,7 +53,110 @@ impl OptionalPostAuthAction {
                 Ok(Some(PostAuthContext::ContinueCompatSsoLogin { login }))
             }
             Some(PostAuthAction::ChangePassword) => Ok(Some(PostAuthContext::ChangePassword)),
+            Some(PostAuthAction::ManageSecondFactor) => {
+                Ok(Some(PostAuthContext::ManageSecondFactor))
+            }
             None => Ok(None),
         }
     }
+
+    /// Get the username the client suggested when starting the authorization
+    /// grant, if any
+    pub async fn load_login_hint(&self, conn: &mut PgConnection) -> anyhow::Result<Option<String>> {
+        match &self.post_auth_action {
+            Some(PostAuthAction::ContinueAuthorizationGrant { data }) => {
+                let grant = get_grant_by_id(conn, *data).await?;
+                Ok(grant.login_hint)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_iana::oauth::OAuthClientAuthenticationMethod;
+    use mas_storage::{
+        oauth2::{
+            authorization_grant::new_authorization_grant,
+            client::{insert_client_from_config, lookup_client_by_client_id},
+        },
+        test_utils::{random_name, test_transaction},
+    };
+    use oauth2_types::requests::ResponseMode;
+    use url::Url;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_login_hint() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let client_id = random_name("client");
+        let redirect_uri: Url = "https://example.com/callback".parse().unwrap();
+        insert_client_from_config(
+            &mut txn,
+            &client_id,
+            OAuthClientAuthenticationMethod::None,
+            None,
+            None,
+            None,
+            std::slice::from_ref(&redirect_uri),
+        )
+        .await
+        .unwrap();
+
+        let mut grants = Vec::new();
+        for login_hint in [Some("alice".to_owned()), None] {
+            let client = lookup_client_by_client_id(&mut txn, &client_id)
+                .await
+                .unwrap();
+            let grant = new_authorization_grant(
+                &mut txn,
+                client,
+                redirect_uri.clone(),
+                "openid".parse().unwrap(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                ResponseMode::Query,
+                false,
+                false,
+                false,
+                login_hint,
+            )
+            .await
+            .unwrap();
+            grants.push(grant);
+        }
+
+        let with_hint = OptionalPostAuthAction {
+            post_auth_action: Some(PostAuthAction::continue_grant(grants[0].data)),
+        };
+        assert_eq!(
+            with_hint
+                .load_login_hint(&mut txn)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("alice")
+        );
+
+        let without_hint = OptionalPostAuthAction {
+            post_auth_action: Some(PostAuthAction::continue_grant(grants[1].data)),
+        };
+        assert_eq!(without_hint.load_login_hint(&mut txn).await.unwrap(), None);
+
+        // Only authorization grants carry a hint
+        let other = OptionalPostAuthAction {
+            post_auth_action: Some(PostAuthAction::ChangePassword),
+        };
+        assert_eq!(other.load_login_hint(&mut txn).await.unwrap(), None);
+    }
 }
@@ -0,0 +1,685 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebAuthn registration and assertion ceremonies.
+//!
+//! Only the `none` attestation conveyance is requested, so credentials are
+//! trusted on first use and attestation statements are ignored.
+
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use ciborium::value::Value;
+use data_encoding::BASE64URL_NOPAD;
+use mas_config::Encrypter;
+use mas_data_model::User;
+use mas_iana::jose::{JsonWebKeyEcEllipticCurve, JsonWebSignatureAlg};
+use mas_jose::{
+    JsonWebKey, JsonWebKeyParameters, JsonWebKeySet, JwtHeader, StaticJwksStore, VerifyingKeystore,
+};
+use mas_router::UrlBuilder;
+use mas_storage::{webauthn::WebauthnCredential, PostgresqlBackend};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::upstream_oauth2::{load_cookie, remove_cookie, save_cookie};
+
+static CHALLENGE_COOKIE: &str = "webauthn-challenge";
+
+/// How long users have to complete a ceremony, in seconds
+const TIMEOUT: i64 = 5 * 60;
+
+// COSE key parameters and algorithms, as registered by RFC 8152
+const COSE_KTY: i64 = 1;
+const COSE_ALG: i64 = 3;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_KTY_RSA: i64 = 3;
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_RS256: i64 = -257;
+const COSE_EC2_CRV: i64 = -1;
+const COSE_EC2_X: i64 = -2;
+const COSE_EC2_Y: i64 = -3;
+const COSE_CRV_P256: i64 = 1;
+const COSE_RSA_N: i64 = -1;
+const COSE_RSA_E: i64 = -2;
+
+// Flags of the authenticator data
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Debug, Error)]
+pub(crate) enum WebauthnError {
+    #[error("the public base URL has no host to use as relying party ID")]
+    InvalidRelyingParty,
+
+    #[error("no WebAuthn ceremony in progress")]
+    NoCeremony,
+
+    #[error("malformed {0}")]
+    Malformed(&'static str),
+
+    #[error("unexpected client data type")]
+    UnexpectedType,
+
+    #[error("challenge mismatch")]
+    ChallengeMismatch,
+
+    #[error("origin mismatch")]
+    OriginMismatch,
+
+    #[error("relying party ID mismatch")]
+    RpIdMismatch,
+
+    #[error("the user was not present")]
+    UserNotPresent,
+
+    #[error("the user was not verified")]
+    UserNotVerified,
+
+    #[error("unsupported credential public key")]
+    UnsupportedKey,
+
+    #[error("invalid signature")]
+    InvalidSignature,
+}
+
+/// The service, as WebAuthn authenticators see it. It is identified by the
+/// host of the public base URL.
+#[derive(Debug, Clone)]
+pub(crate) struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    pub(crate) fn new(url_builder: &UrlBuilder) -> Result<Self, WebauthnError> {
+        let id = url_builder
+            .webauthn_rp_id()
+            .ok_or(WebauthnError::InvalidRelyingParty)?;
+
+        Ok(Self {
+            id: id.to_owned(),
+            origin: url_builder.webauthn_origin(),
+        })
+    }
+}
+
+/// A challenge sent to the browser. It is kept in an encrypted cookie until
+/// the response of the authenticator comes back.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Challenge {
+    challenge: String,
+    expires_at: i64,
+}
+
+impl Challenge {
+    pub(crate) fn generate(rng: impl Rng) -> Self {
+        let bytes: Vec<u8> = rng
+            .sample_iter(rand::distributions::Standard)
+            .take(32)
+            .collect();
+
+        Self {
+            challenge: BASE64URL_NOPAD.encode(&bytes),
+            expires_at: (Utc::now() + Duration::seconds(TIMEOUT)).timestamp(),
+        }
+    }
+
+    pub(crate) fn save(
+        &self,
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> PrivateCookieJar<Encrypter> {
+        save_cookie(cookie_jar, CHALLENGE_COOKIE, self)
+    }
+
+    /// Get the pending challenge, making sure it can't be used twice
+    pub(crate) fn take(
+        cookie_jar: PrivateCookieJar<Encrypter>,
+    ) -> (Result<Self, WebauthnError>, PrivateCookieJar<Encrypter>) {
+        let challenge = load_cookie::<Self>(&cookie_jar, CHALLENGE_COOKIE)
+            .filter(|challenge| challenge.expires_at > Utc::now().timestamp())
+            .ok_or(WebauthnError::NoCeremony);
+        (challenge, remove_cookie(cookie_jar, CHALLENGE_COOKIE))
+    }
+}
+
+fn descriptors(credentials: &[WebauthnCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|credential| json!({ "type": "public-key", "id": credential.credential_id }))
+        .collect()
+}
+
+/// Options for `navigator.credentials.create()`, with binary fields encoded
+/// as base64url
+pub(crate) fn creation_options(
+    rp: &RelyingParty,
+    challenge: &Challenge,
+    user: &User<PostgresqlBackend>,
+    existing: &[WebauthnCredential],
+) -> serde_json::Value {
+    json!({
+        "rp": { "id": rp.id, "name": rp.id },
+        "user": {
+            "id": BASE64URL_NOPAD.encode(user.sub.as_bytes()),
+            "name": user.username,
+            "displayName": user.username,
+        },
+        "challenge": challenge.challenge,
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": COSE_ALG_ES256 },
+            { "type": "public-key", "alg": COSE_ALG_RS256 },
+        ],
+        "timeout": TIMEOUT * 1000,
+        "excludeCredentials": descriptors(existing),
+        "authenticatorSelection": {
+            "residentKey": "preferred",
+            "userVerification": "preferred",
+        },
+        "attestation": "none",
+    })
+}
+
+/// Options for `navigator.credentials.get()`. Leaving `allowed` empty lets
+/// the user pick any discoverable credential, for passwordless logins.
+pub(crate) fn request_options(
+    rp: &RelyingParty,
+    challenge: &Challenge,
+    allowed: &[WebauthnCredential],
+    require_user_verification: bool,
+) -> serde_json::Value {
+    let user_verification = if require_user_verification {
+        "required"
+    } else {
+        "discouraged"
+    };
+
+    json!({
+        "challenge": challenge.challenge,
+        "rpId": rp.id,
+        "timeout": TIMEOUT * 1000,
+        "allowCredentials": descriptors(allowed),
+        "userVerification": user_verification,
+    })
+}
+
+fn decode(value: &str, what: &'static str) -> Result<Vec<u8>, WebauthnError> {
+    BASE64URL_NOPAD
+        .decode(value.as_bytes())
+        .map_err(|_| WebauthnError::Malformed(what))
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn verify_client_data(
+    rp: &RelyingParty,
+    challenge: &Challenge,
+    client_data_json: &[u8],
+    kind: &str,
+) -> Result<(), WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| WebauthnError::Malformed("client data"))?;
+
+    if client_data.kind != kind {
+        return Err(WebauthnError::UnexpectedType);
+    }
+
+    if client_data.challenge != challenge.challenge {
+        return Err(WebauthnError::ChallengeMismatch);
+    }
+
+    if client_data.origin != rp.origin {
+        return Err(WebauthnError::OriginMismatch);
+    }
+
+    Ok(())
+}
+
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<(Vec<u8>, Value)>,
+}
+
+fn parse_authenticator_data(
+    rp: &RelyingParty,
+    data: &[u8],
+) -> Result<AuthenticatorData, WebauthnError> {
+    let malformed = WebauthnError::Malformed("authenticator data");
+    if data.len() < 37 {
+        return Err(malformed);
+    }
+
+    let (rp_id_hash, rest) = data.split_at(32);
+    if rp_id_hash != Sha256::digest(rp.id.as_bytes()).as_slice() {
+        return Err(WebauthnError::RpIdMismatch);
+    }
+
+    let flags = rest[0];
+    let sign_count = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebauthnError::UserNotPresent);
+    }
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        None
+    } else {
+        // The AAGUID of the authenticator, then the length of the credential ID
+        let rest = &rest[5..];
+        if rest.len() < 18 {
+            return Err(malformed);
+        }
+        let length = usize::from(u16::from_be_bytes([rest[16], rest[17]]));
+        let rest = &rest[18..];
+        if rest.len() < length {
+            return Err(malformed);
+        }
+
+        // The public key is followed by the extensions, if any
+        let (credential_id, mut rest) = rest.split_at(length);
+        let public_key: Value = ciborium::de::from_reader(&mut rest).map_err(|_| malformed)?;
+
+        Some((credential_id.to_vec(), public_key))
+    };
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+/// Convert a COSE public key to the equivalent JWK
+fn cose_to_jwk(key: &Value) -> Result<JsonWebKey, WebauthnError> {
+    let entries = match key {
+        Value::Map(entries) => entries,
+        _ => return Err(WebauthnError::UnsupportedKey),
+    };
+
+    let get = |label: i64| {
+        entries.iter().find_map(|(key, value)| match key {
+            Value::Integer(key) if i128::from(*key) == i128::from(label) => Some(value),
+            _ => None,
+        })
+    };
+    let integer = |label: i64| match get(label) {
+        Some(Value::Integer(value)) => i64::try_from(i128::from(*value)).ok(),
+        _ => None,
+    };
+    let bytes = |label: i64| match get(label) {
+        Some(Value::Bytes(value)) => Ok(value.clone()),
+        _ => Err(WebauthnError::UnsupportedKey),
+    };
+
+    match (integer(COSE_KTY), integer(COSE_ALG)) {
+        (Some(COSE_KTY_EC2), Some(COSE_ALG_ES256)) => {
+            if integer(COSE_EC2_CRV) != Some(COSE_CRV_P256) {
+                return Err(WebauthnError::UnsupportedKey);
+            }
+
+            let parameters = JsonWebKeyParameters::Ec {
+                crv: JsonWebKeyEcEllipticCurve::P256,
+                x: bytes(COSE_EC2_X)?,
+                y: bytes(COSE_EC2_Y)?,
+            };
+
+            // Make sure the point is on the curve
+            ecdsa::VerifyingKey::<p256::NistP256>::try_from(parameters.clone())
+                .map_err(|_| WebauthnError::UnsupportedKey)?;
+
+            Ok(JsonWebKey::new(parameters).with_alg(JsonWebSignatureAlg::Es256))
+        }
+
+        (Some(COSE_KTY_RSA), Some(COSE_ALG_RS256)) => {
+            let parameters = JsonWebKeyParameters::Rsa {
+                n: bytes(COSE_RSA_N)?,
+                e: bytes(COSE_RSA_E)?,
+            };
+
+            Ok(JsonWebKey::new(parameters).with_alg(JsonWebSignatureAlg::Rs256))
+        }
+
+        _ => Err(WebauthnError::UnsupportedKey),
+    }
+}
+
+async fn verify_signature(
+    key: &JsonWebKey,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), WebauthnError> {
+    let (alg, signature) = match key.params() {
+        JsonWebKeyParameters::Ec { .. } => {
+            // WebAuthn ECDSA signatures are DER-encoded, unlike JOSE ones
+            let signature = ecdsa::Signature::<p256::NistP256>::from_der(signature)
+                .map_err(|_| WebauthnError::InvalidSignature)?;
+            (JsonWebSignatureAlg::Es256, signature.as_ref().to_vec())
+        }
+        JsonWebKeyParameters::Rsa { .. } => (JsonWebSignatureAlg::Rs256, signature.to_vec()),
+        JsonWebKeyParameters::Okp { .. } => return Err(WebauthnError::UnsupportedKey),
+    };
+
+    let store = StaticJwksStore::new(JsonWebKeySet::new(vec![key.clone()]));
+    store
+        .verify(&JwtHeader::new(alg), message, &signature)
+        .await
+        .map_err(|_| WebauthnError::InvalidSignature)
+}
+
+/// A credential which went through the registration ceremony
+#[derive(Debug)]
+pub(crate) struct RegisteredCredential {
+    pub credential_id: String,
+    pub public_key: JsonWebKey,
+    pub sign_count: u32,
+}
+
+/// Check the response of the authenticator to `navigator.credentials.create()`
+pub(crate) fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &Challenge,
+    client_data_json: &str,
+    attestation_object: &str,
+) -> Result<RegisteredCredential, WebauthnError> {
+    let client_data_json = decode(client_data_json, "client data")?;
+    verify_client_data(rp, challenge, &client_data_json, "webauthn.create")?;
+
+    let attestation_object = decode(attestation_object, "attestation object")?;
+    let attestation_object: Value = ciborium::de::from_reader(attestation_object.as_slice())
+        .map_err(|_| WebauthnError::Malformed("attestation object"))?;
+
+    let auth_data = match &attestation_object {
+        Value::Map(entries) => entries.iter().find_map(|(key, value)| match (key, value) {
+            (Value::Text(key), Value::Bytes(value)) if key == "authData" => Some(value),
+            _ => None,
+        }),
+        _ => None,
+    }
+    .ok_or(WebauthnError::Malformed("attestation object"))?;
+
+    let auth_data = parse_authenticator_data(rp, auth_data)?;
+    let (credential_id, public_key) = auth_data
+        .attested_credential
+        .ok_or(WebauthnError::Malformed("authenticator data"))?;
+
+    Ok(RegisteredCredential {
+        credential_id: BASE64URL_NOPAD.encode(&credential_id),
+        public_key: cose_to_jwk(&public_key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// The response of the authenticator to `navigator.credentials.get()`, as
+/// submitted by the browser
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AssertionResponse {
+    pub credential_id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+}
+
+impl AssertionResponse {
+    /// Check this assertion against the stored credential, and return the
+    /// new signature counter
+    pub(crate) async fn verify(
+        &self,
+        rp: &RelyingParty,
+        challenge: &Challenge,
+        credential: &WebauthnCredential,
+        require_user_verification: bool,
+    ) -> Result<u32, WebauthnError> {
+        let client_data_json = decode(&self.client_data_json, "client data")?;
+        verify_client_data(rp, challenge, &client_data_json, "webauthn.get")?;
+
+        let authenticator_data = decode(&self.authenticator_data, "authenticator data")?;
+        let auth_data = parse_authenticator_data(rp, &authenticator_data)?;
+        if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebauthnError::UserNotVerified);
+        }
+
+        // The signature covers the authenticator data and the hash of the
+        // client data
+        let mut message = authenticator_data;
+        message.extend_from_slice(&Sha256::digest(&client_data_json));
+
+        let signature = decode(&self.signature, "signature")?;
+        verify_signature(&credential.public_key, &message, &signature).await?;
+
+        Ok(auth_data.sign_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "auth.example.com".to_owned(),
+            origin: "https://auth.example.com".to_owned(),
+        }
+    }
+
+    fn challenge() -> Challenge {
+        Challenge::generate(rand::thread_rng())
+    }
+
+    fn client_data(kind: &str, challenge: &Challenge, origin: &str) -> String {
+        let client_data = json!({
+            "type": kind,
+            "challenge": challenge.challenge,
+            "origin": origin,
+        });
+        BASE64URL_NOPAD.encode(client_data.to_string().as_bytes())
+    }
+
+    fn authenticator_data(
+        flags: u8,
+        sign_count: u32,
+        attested: Option<(&[u8], &Value)>,
+    ) -> Vec<u8> {
+        let mut data = Sha256::digest(b"auth.example.com").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((credential_id, public_key)) = attested {
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&u16::try_from(credential_id.len()).unwrap().to_be_bytes());
+            data.extend_from_slice(credential_id);
+            ciborium::ser::into_writer(public_key, &mut data).unwrap();
+        }
+        data
+    }
+
+    fn cose_key(key: &SigningKey) -> Value {
+        let point = key.verifying_key().to_encoded_point(false);
+        Value::Map(vec![
+            (COSE_KTY.into(), COSE_KTY_EC2.into()),
+            (COSE_ALG.into(), COSE_ALG_ES256.into()),
+            (COSE_EC2_CRV.into(), COSE_CRV_P256.into()),
+            (COSE_EC2_X.into(), Value::Bytes(point.x().unwrap().to_vec())),
+            (COSE_EC2_Y.into(), Value::Bytes(point.y().unwrap().to_vec())),
+        ])
+    }
+
+    fn register(
+        key: &SigningKey,
+        challenge: &Challenge,
+    ) -> Result<RegisteredCredential, WebauthnError> {
+        let public_key = cose_key(key);
+        let auth_data = authenticator_data(
+            FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA,
+            0,
+            Some((b"credential", &public_key)),
+        );
+        let attestation_object = Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), Value::Map(Vec::new())),
+            ("authData".into(), Value::Bytes(auth_data)),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(&attestation_object, &mut encoded).unwrap();
+
+        verify_registration(
+            &rp(),
+            challenge,
+            &client_data("webauthn.create", challenge, "https://auth.example.com"),
+            &BASE64URL_NOPAD.encode(&encoded),
+        )
+    }
+
+    fn assertion(
+        key: &SigningKey,
+        challenge: &Challenge,
+        flags: u8,
+        sign_count: u32,
+    ) -> AssertionResponse {
+        let client_data_json = client_data("webauthn.get", challenge, "https://auth.example.com");
+        let auth_data = authenticator_data(flags, sign_count, None);
+
+        let mut message = auth_data.clone();
+        message.extend_from_slice(&Sha256::digest(
+            BASE64URL_NOPAD.decode(client_data_json.as_bytes()).unwrap(),
+        ));
+        let signature: p256::ecdsa::Signature = key.sign(&message);
+
+        AssertionResponse {
+            credential_id: BASE64URL_NOPAD.encode(b"credential"),
+            client_data_json,
+            authenticator_data: BASE64URL_NOPAD.encode(&auth_data),
+            signature: BASE64URL_NOPAD.encode(signature.to_der().as_bytes()),
+        }
+    }
+
+    fn stored(registered: RegisteredCredential) -> WebauthnCredential {
+        WebauthnCredential {
+            id: 1,
+            credential_id: registered.credential_id,
+            public_key: registered.public_key,
+            sign_count: registered.sign_count.into(),
+            name: "Test".to_owned(),
+            created_at: Utc::now(),
+            last_used_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_assert() {
+        let key = SigningKey::random(&mut rand::thread_rng());
+
+        let challenge = challenge();
+        let registered = register(&key, &challenge).unwrap();
+        assert_eq!(
+            registered.credential_id,
+            BASE64URL_NOPAD.encode(b"credential")
+        );
+        assert_eq!(
+            registered.public_key.alg(),
+            Some(JsonWebSignatureAlg::Es256)
+        );
+        let credential = stored(registered);
+
+        let challenge = self::challenge();
+        let response = assertion(&key, &challenge, FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 3);
+        let sign_count = response
+            .verify(&rp(), &challenge, &credential, true)
+            .await
+            .unwrap();
+        assert_eq!(sign_count, 3);
+
+        // Passwordless logins need user verification
+        let response = assertion(&key, &challenge, FLAG_USER_PRESENT, 4);
+        assert!(matches!(
+            response.verify(&rp(), &challenge, &credential, true).await,
+            Err(WebauthnError::UserNotVerified)
+        ));
+        response
+            .verify(&rp(), &challenge, &credential, false)
+            .await
+            .unwrap();
+
+        // Another key can't sign for this credential
+        let other = SigningKey::random(&mut rand::thread_rng());
+        let response = assertion(&other, &challenge, FLAG_USER_PRESENT, 5);
+        assert!(matches!(
+            response.verify(&rp(), &challenge, &credential, false).await,
+            Err(WebauthnError::InvalidSignature)
+        ));
+
+        // Neither can an old challenge be replayed
+        let response = assertion(&key, &challenge, FLAG_USER_PRESENT, 6);
+        let other_challenge = self::challenge();
+        assert!(matches!(
+            response
+                .verify(&rp(), &other_challenge, &credential, false)
+                .await,
+            Err(WebauthnError::ChallengeMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_client_data_checks() {
+        let challenge = challenge();
+
+        let data = client_data("webauthn.get", &challenge, "https://auth.example.com");
+        let data = BASE64URL_NOPAD.decode(data.as_bytes()).unwrap();
+        assert!(matches!(
+            verify_client_data(&rp(), &challenge, &data, "webauthn.create"),
+            Err(WebauthnError::UnexpectedType)
+        ));
+
+        let data = client_data("webauthn.get", &challenge, "https://evil.example.com");
+        let data = BASE64URL_NOPAD.decode(data.as_bytes()).unwrap();
+        assert!(matches!(
+            verify_client_data(&rp(), &challenge, &data, "webauthn.get"),
+            Err(WebauthnError::OriginMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_authenticator_data_checks() {
+        let data = authenticator_data(0, 0, None);
+        assert!(matches!(
+            parse_authenticator_data(&rp(), &data),
+            Err(WebauthnError::UserNotPresent)
+        ));
+
+        let other_rp = RelyingParty {
+            id: "example.com".to_owned(),
+            origin: "https://example.com".to_owned(),
+        };
+        let data = authenticator_data(FLAG_USER_PRESENT, 0, None);
+        assert!(matches!(
+            parse_authenticator_data(&other_rp, &data),
+            Err(WebauthnError::RpIdMismatch)
+        ));
+
+        assert!(matches!(
+            parse_authenticator_data(&rp(), &data[..36]),
+            Err(WebauthnError::Malformed(_))
+        ));
+    }
+}
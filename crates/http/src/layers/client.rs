@@ -21,7 +21,10 @@ use tower::{
 };
 use tower_http::{
     decompression::{DecompressionBody, DecompressionLayer},
-    follow_redirect::FollowRedirectLayer,
+    follow_redirect::{
+        policy::{Action, Attempt, Policy, SameOrigin, Standard},
+        FollowRedirectLayer,
+    },
     set_header::SetRequestHeaderLayer,
 };
 
@@ -34,6 +37,7 @@ static MAS_USER_AGENT: HeaderValue =
 #[derive(Debug, Clone)]
 pub struct ClientLayer<ReqBody> {
     operation: &'static str,
+    same_origin_redirects: bool,
     _t: PhantomData<ReqBody>,
 }
 
@@ -42,9 +46,44 @@ impl<B> ClientLayer<B> {
     pub fn new(operation: &'static str) -> Self {
         Self {
             operation,
+            same_origin_redirects: false,
             _t: PhantomData,
         }
     }
+
+    /// Only follow redirects which stay on the origin of the original request
+    #[must_use]
+    pub fn same_origin_redirects(mut self) -> Self {
+        self.same_origin_redirects = true;
+        self
+    }
+}
+
+/// The standard redirect policy, optionally restricted to same-origin
+/// redirects
+#[derive(Debug, Clone, Default)]
+struct RedirectPolicy {
+    same_origin: bool,
+    inner: Standard,
+}
+
+impl<B, E> Policy<B, E> for RedirectPolicy {
+    fn redirect(&mut self, attempt: &Attempt<'_>) -> Result<Action, E> {
+        if self.same_origin && Policy::<B, E>::redirect(&mut SameOrigin::new(), attempt)?.is_stop()
+        {
+            return Ok(Action::Stop);
+        }
+
+        Policy::<B, E>::redirect(&mut self.inner, attempt)
+    }
+
+    fn on_request(&mut self, request: &mut Request<B>) {
+        Policy::<B, E>::on_request(&mut self.inner, request);
+    }
+
+    fn clone_body(&self, body: &B) -> Option<B> {
+        Policy::<B, E>::clone_body(&self.inner, body)
+    }
 }
 
 pub type ClientResponse<B> = Response<DecompressionBody<B>>;
@@ -75,7 +114,10 @@ where
             // limits in it
             .layer(TraceLayer::http_client(self.operation))
             .layer(ConcurrencyLimitLayer::new(10))
-            .layer(FollowRedirectLayer::new())
+            .layer(FollowRedirectLayer::with_policy(RedirectPolicy {
+                same_origin: self.same_origin_redirects,
+                ..RedirectPolicy::default()
+            }))
             // A trace for each "real" http request
             .layer(TraceLayer::inner_http_client())
             .layer(TimeoutLayer::new(Duration::from_secs(10)))
@@ -23,14 +23,17 @@
 #![warn(clippy::pedantic)]
 #![allow(clippy::module_name_repetitions)]
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use bytes::Bytes;
 use futures_util::{FutureExt, TryFutureExt};
 use http::{Request, Response};
 use http_body::{combinators::BoxBody, Body};
 use hyper::{
-    client::{connect::dns::GaiResolver, HttpConnector},
+    client::{
+        connect::dns::{GaiResolver, Name},
+        HttpConnector,
+    },
     Client,
 };
 use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
@@ -40,16 +43,18 @@ use layers::{
 };
 use thiserror::Error;
 use tokio::{sync::OnceCell, task::JoinError};
-use tower::{util::BoxCloneService, ServiceBuilder, ServiceExt};
+use tower::{util::BoxCloneService, Service, ServiceBuilder, ServiceExt};
 
 mod ext;
 mod future_service;
 mod layers;
+mod public_resolver;
 
 pub use self::{
     ext::{set_propagator, CorsLayerExt, ServiceExt as HttpServiceExt},
     future_service::FutureService,
     layers::{client::ClientLayer, json::JsonResponseLayer, otel, server::ServerLayer},
+    public_resolver::{is_public_ip, NonPublicAddressError, PublicResolver},
 };
 
 pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
@@ -76,17 +81,24 @@ pub enum ClientInitError {
 
 static TLS_CONFIG: OnceCell<rustls::ClientConfig> = OnceCell::const_new();
 
-async fn make_base_client<B, E>(
-) -> Result<hyper::Client<HttpsConnector<HttpConnector<TraceDns<GaiResolver>>>, B>, ClientInitError>
+/// Trace DNS requests
+fn make_resolver() -> TraceDns<GaiResolver> {
+    ServiceBuilder::new()
+        .layer(TraceLayer::dns())
+        .service(GaiResolver::new())
+}
+
+async fn make_base_client<B, E, R>(
+    resolver: R,
+) -> Result<hyper::Client<HttpsConnector<HttpConnector<R>>, B>, ClientInitError>
 where
     B: http_body::Body<Data = Bytes, Error = E> + Send + 'static,
     E: Into<BoxError>,
+    R: Service<Name> + Clone + Send + Sync + 'static,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+    R::Future: Send,
 {
-    // Trace DNS requests
-    let resolver = ServiceBuilder::new()
-        .layer(TraceLayer::dns())
-        .service(GaiResolver::new());
-
     let mut http = HttpConnector::new_with_resolver(resolver);
     http.enforce_http(false);
 
@@ -128,7 +140,58 @@ where
     B: http_body::Body<Data = Bytes, Error = E> + Default + Send + 'static,
     E: Into<BoxError>,
 {
-    let fut = make_base_client()
+    client_with_layer(make_resolver(), ClientLayer::new(operation))
+}
+
+/// Like [`client`], but only follows redirects which stay on the origin of
+/// the request
+#[must_use]
+pub fn same_origin_client<B, E>(
+    operation: &'static str,
+) -> BoxCloneService<Request<B>, Response<BoxBody<bytes::Bytes, ClientError>>, ClientError>
+where
+    B: http_body::Body<Data = Bytes, Error = E> + Default + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    client_with_layer(
+        make_resolver(),
+        ClientLayer::new(operation).same_origin_redirects(),
+    )
+}
+
+/// Like [`same_origin_client`], but only connects to public addresses. This is
+/// meant for URLs given by clients, which could otherwise be used to reach
+/// internal services.
+///
+/// Hosts which are IP literals are not resolved, so they should be checked
+/// with [`is_public_ip`] beforehand.
+#[must_use]
+pub fn public_client<B, E>(
+    operation: &'static str,
+) -> BoxCloneService<Request<B>, Response<BoxBody<bytes::Bytes, ClientError>>, ClientError>
+where
+    B: http_body::Body<Data = Bytes, Error = E> + Default + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    client_with_layer(
+        PublicResolver::new(make_resolver()),
+        ClientLayer::new(operation).same_origin_redirects(),
+    )
+}
+
+fn client_with_layer<B, E, R>(
+    resolver: R,
+    layer: ClientLayer<B>,
+) -> BoxCloneService<Request<B>, Response<BoxBody<bytes::Bytes, ClientError>>, ClientError>
+where
+    B: http_body::Body<Data = Bytes, Error = E> + Default + Send + 'static,
+    E: Into<BoxError> + 'static,
+    R: Service<Name> + Clone + Send + Sync + 'static,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+    R::Future: Send,
+{
+    let fut = make_base_client(resolver)
         // Map the error to a ClientError
         .map_ok(|s| s.map_err(|e| ClientError::from(BoxError::from(e))))
         // Wrap it in an Shared (Arc) to be able to Clone it
@@ -142,7 +205,7 @@ where
         .map_response(|r: ClientResponse<hyper::Body>| {
             r.map(|body| body.map_err(ClientError::from).boxed())
         })
-        .layer(ClientLayer::new(operation))
+        .layer(layer)
         .service(client);
 
     client.boxed_clone()
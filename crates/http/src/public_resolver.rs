@@ -0,0 +1,177 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A DNS resolver which refuses names resolving to non-public addresses.
+//!
+//! Checking the addresses of a URL before handing it to the client isn't
+//! enough, as the name is resolved again when connecting and could then point
+//! somewhere else. Doing the check in the resolver of the connector pins the
+//! connection to the addresses which were checked.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    task::{Context, Poll},
+};
+
+use futures_util::future::BoxFuture;
+use hyper::client::connect::dns::Name;
+use thiserror::Error;
+use tower::Service;
+
+use crate::BoxError;
+
+#[derive(Debug, Error)]
+#[error("{0} resolves to a non-public address")]
+pub struct NonPublicAddressError(String);
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // Shared address space, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // "This network", 0.0.0.0/8
+        || a == 0)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4() {
+        return is_public_v4(ip);
+    }
+
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Whether an address is reachable on the public internet, as opposed to
+/// loopback, private or link-local networks
+#[must_use]
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+/// Wraps a resolver, failing the resolution if any of the addresses isn't
+/// public
+#[derive(Debug, Clone)]
+pub struct PublicResolver<R> {
+    inner: R,
+}
+
+impl<R> PublicResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R> Service<Name> for PublicResolver<R>
+where
+    R: Service<Name>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_owned();
+        let fut = self.inner.call(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = fut.await.map_err(Into::into)?.collect();
+            if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+                return Err(NonPublicAddressError(host).into());
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(
+                !is_public_ip(ip.parse().unwrap()),
+                "{} should not be public",
+                ip
+            );
+        }
+
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_public_resolver() {
+        let resolve = |addrs: &'static [&'static str]| {
+            PublicResolver::new(tower::service_fn(move |_name: Name| async move {
+                Ok::<_, Infallible>(
+                    addrs
+                        .iter()
+                        .map(|addr| addr.parse::<SocketAddr>().unwrap())
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                )
+            }))
+        };
+        let name: Name = "example.com".parse().unwrap();
+
+        let mut resolver = resolve(&["93.184.216.34:0"]);
+        let addrs: Vec<_> = resolver.call(name.clone()).await.unwrap().collect();
+        assert_eq!(addrs.len(), 1);
+
+        // A single non-public address is enough to refuse the name
+        let mut resolver = resolve(&["93.184.216.34:0", "127.0.0.1:0"]);
+        assert!(resolver.call(name).await.is_err());
+    }
+}
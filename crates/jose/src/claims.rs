@@ -311,6 +311,8 @@ mod oidc_core {
     use super::{Claim, Timestamp};
 
     pub const AUTH_TIME: Claim<Timestamp> = Claim::new("auth_time");
+    pub const ACR: Claim<String> = Claim::new("acr");
+    pub const AMR: Claim<Vec<String>> = Claim::new("amr");
     pub const NONCE: Claim<String> = Claim::new("nonce");
     pub const AT_HASH: Claim<String> = Claim::new("at_hash");
     pub const C_HASH: Claim<String> = Claim::new("c_hash");
@@ -341,7 +343,16 @@ mod oidc_core {
     pub const UPDATED_AT: Claim<Timestamp> = Claim::new("updated_at");
 }
 
-pub use self::{oidc_core::*, rfc7519::*};
+/// Claims defined in OIDC.CIBA sec. 7.4
+/// <https://openid.net/specs/openid-client-initiated-backchannel-authentication-core-1_0.html#rfc.section.7.4>
+mod ciba {
+    use super::Claim;
+
+    pub const URN_OPENID_PARAMS_JWT_CLAIM_AUTH_REQ_ID: Claim<String> =
+        Claim::new("urn:openid:params:jwt:claim:auth_req_id");
+}
+
+pub use self::{ciba::*, oidc_core::*, rfc7519::*};
 
 #[cfg(test)]
 mod tests {
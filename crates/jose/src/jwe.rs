@@ -0,0 +1,910 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JSON Web Encryption, with the compact serialization
+//!
+//! Ref: <https://www.rfc-editor.org/rfc/rfc7516.html>
+
+use std::str::FromStr;
+
+use aes::{
+    cipher::{generic_array::GenericArray, typenum::U12, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128, Aes192, Aes256,
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    AesGcm,
+};
+use anyhow::{bail, Context};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use digest::Digest;
+use elliptic_curve::ecdh::EphemeralSecret;
+use hmac::{Hmac, Mac};
+use mas_iana::jose::{JsonWebEncryptionAlg, JsonWebEncryptionEnc};
+use p256::NistP256;
+use rand::{thread_rng, RngCore};
+use rsa::{PaddingScheme, PublicKey};
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use sha2::{Sha256, Sha384, Sha512};
+
+use crate::jwk::{JsonWebKey, JsonWebKeyParameters};
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct JweHeader {
+    alg: JsonWebEncryptionAlg,
+
+    enc: JsonWebEncryptionEnc,
+
+    #[serde(default)]
+    kid: Option<String>,
+
+    #[serde(default)]
+    typ: Option<String>,
+
+    #[serde(default)]
+    cty: Option<String>,
+
+    #[serde(default)]
+    epk: Option<JsonWebKeyParameters>,
+}
+
+impl JweHeader {
+    #[must_use]
+    pub fn new(alg: JsonWebEncryptionAlg, enc: JsonWebEncryptionEnc) -> Self {
+        Self {
+            alg,
+            enc,
+            kid: None,
+            typ: None,
+            cty: None,
+            epk: None,
+        }
+    }
+
+    #[must_use]
+    pub fn alg(&self) -> JsonWebEncryptionAlg {
+        self.alg
+    }
+
+    #[must_use]
+    pub fn enc(&self) -> JsonWebEncryptionEnc {
+        self.enc
+    }
+
+    #[must_use]
+    pub fn kid(&self) -> Option<&str> {
+        self.kid.as_deref()
+    }
+
+    #[must_use]
+    pub fn with_kid(mut self, kid: impl Into<String>) -> Self {
+        self.kid = Some(kid.into());
+        self
+    }
+
+    #[must_use]
+    pub fn cty(&self) -> Option<&str> {
+        self.cty.as_deref()
+    }
+
+    /// Set the content type of the payload. This should be set to `JWT` when
+    /// encrypting a signed JWT.
+    #[must_use]
+    pub fn with_cty(mut self, cty: impl Into<String>) -> Self {
+        self.cty = Some(cty.into());
+        self
+    }
+}
+
+/// A private key used to decrypt a [`JsonWebEncryption`]
+pub enum JweDecryptionKey {
+    Rsa(Box<rsa::RsaPrivateKey>),
+    Ec(p256::SecretKey),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonWebEncryption {
+    header: JweHeader,
+
+    /// The base64-encoded header, kept around because it is used as the
+    /// additional authenticated data
+    encoded_header: String,
+    encrypted_key: Vec<u8>,
+    iv: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+impl JsonWebEncryption {
+    /// Encrypt a payload for the given public key.
+    ///
+    /// The `kid` of the key is copied in the header if it has one.
+    pub fn encrypt(header: JweHeader, key: &JsonWebKey, plaintext: &[u8]) -> anyhow::Result<Self> {
+        if !key.can_encrypt_with(header.alg) {
+            bail!("key can't be used with the {} algorithm", header.alg);
+        }
+
+        let mut header = header;
+        if let Some(kid) = key.kid() {
+            header.kid = Some(kid.to_owned());
+        }
+
+        let mut rng = thread_rng();
+
+        let (cek, encrypted_key) = match header.alg {
+            JsonWebEncryptionAlg::RsaOaep | JsonWebEncryptionAlg::RsaOaep256 => {
+                let public_key: rsa::RsaPublicKey = key.params().clone().try_into()?;
+                let mut cek = vec![0; cek_len(header.enc)];
+                rng.fill_bytes(&mut cek);
+                let padding = oaep_padding(header.alg)?;
+                let encrypted_key = public_key.encrypt(&mut rng, padding, &cek)?;
+                (cek, encrypted_key)
+            }
+
+            JsonWebEncryptionAlg::EcdhEs
+            | JsonWebEncryptionAlg::EcdhEsA128Kw
+            | JsonWebEncryptionAlg::EcdhEsA192Kw
+            | JsonWebEncryptionAlg::EcdhEsA256Kw => {
+                let public_key: p256::PublicKey = key.params().clone().try_into()?;
+                let ephemeral = EphemeralSecret::<NistP256>::random(&mut rng);
+                header.epk = Some(ephemeral.public_key().into());
+                let shared = ephemeral.diffie_hellman(&public_key);
+                let z = shared.raw_secret_bytes();
+
+                match kw_key_len(header.alg) {
+                    // Direct key agreement: the derived key is the CEK
+                    None => {
+                        let cek =
+                            concat_kdf(z, &header.enc.to_string(), &[], &[], cek_len(header.enc));
+                        (cek, Vec::new())
+                    }
+                    Some(len) => {
+                        let kek = concat_kdf(z, &header.alg.to_string(), &[], &[], len);
+                        let mut cek = vec![0; cek_len(header.enc)];
+                        rng.fill_bytes(&mut cek);
+                        let encrypted_key = aes_key_wrap(&kek, &cek)?;
+                        (cek, encrypted_key)
+                    }
+                }
+            }
+
+            alg => bail!("unsupported key management algorithm {}", alg),
+        };
+
+        let encoded_header = Base64UrlUnpadded::encode_string(&serde_json::to_vec(&header)?);
+
+        let (iv, ciphertext, tag) =
+            encrypt_content(header.enc, &cek, encoded_header.as_bytes(), plaintext)?;
+
+        Ok(Self {
+            header,
+            encoded_header,
+            encrypted_key,
+            iv,
+            ciphertext,
+            tag,
+        })
+    }
+
+    #[must_use]
+    pub fn header(&self) -> &JweHeader {
+        &self.header
+    }
+
+    /// Decrypt the payload with the given private key
+    pub fn decrypt(&self, key: &JweDecryptionKey) -> anyhow::Result<Vec<u8>> {
+        let header = &self.header;
+        let cek = match (header.alg, key) {
+            (
+                JsonWebEncryptionAlg::RsaOaep | JsonWebEncryptionAlg::RsaOaep256,
+                JweDecryptionKey::Rsa(key),
+            ) => key.decrypt(oaep_padding(header.alg)?, &self.encrypted_key)?,
+
+            (
+                JsonWebEncryptionAlg::EcdhEs
+                | JsonWebEncryptionAlg::EcdhEsA128Kw
+                | JsonWebEncryptionAlg::EcdhEsA192Kw
+                | JsonWebEncryptionAlg::EcdhEsA256Kw,
+                JweDecryptionKey::Ec(key),
+            ) => {
+                let epk = header.epk.clone().context("missing epk header")?;
+                let epk: p256::PublicKey = epk.try_into()?;
+                let shared =
+                    elliptic_curve::ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine());
+                let z = shared.raw_secret_bytes();
+
+                match kw_key_len(header.alg) {
+                    None => concat_kdf(z, &header.enc.to_string(), &[], &[], cek_len(header.enc)),
+                    Some(len) => {
+                        let kek = concat_kdf(z, &header.alg.to_string(), &[], &[], len);
+                        aes_key_unwrap(&kek, &self.encrypted_key)?
+                    }
+                }
+            }
+
+            (alg, _) => bail!("can't decrypt {} with this key", alg),
+        };
+
+        if cek.len() != cek_len(header.enc) {
+            bail!("invalid content encryption key length");
+        }
+
+        decrypt_content(
+            header.enc,
+            &cek,
+            self.encoded_header.as_bytes(),
+            &self.iv,
+            &self.ciphertext,
+            &self.tag,
+        )
+    }
+
+    #[must_use]
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}",
+            self.encoded_header,
+            Base64UrlUnpadded::encode_string(&self.encrypted_key),
+            Base64UrlUnpadded::encode_string(&self.iv),
+            Base64UrlUnpadded::encode_string(&self.ciphertext),
+            Base64UrlUnpadded::encode_string(&self.tag),
+        )
+    }
+}
+
+impl FromStr for JsonWebEncryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('.').collect();
+        let (encoded_header, encrypted_key, iv, ciphertext, tag) = match parts[..] {
+            [encoded_header, encrypted_key, iv, ciphertext, tag] => {
+                (encoded_header, encrypted_key, iv, ciphertext, tag)
+            }
+            _ => bail!("invalid number of parts in JWE"),
+        };
+
+        let header = Base64UrlUnpadded::decode_vec(encoded_header)?;
+        let header = serde_json::from_slice(&header)?;
+
+        Ok(Self {
+            header,
+            encoded_header: encoded_header.to_owned(),
+            encrypted_key: Base64UrlUnpadded::decode_vec(encrypted_key)?,
+            iv: Base64UrlUnpadded::decode_vec(iv)?,
+            ciphertext: Base64UrlUnpadded::decode_vec(ciphertext)?,
+            tag: Base64UrlUnpadded::decode_vec(tag)?,
+        })
+    }
+}
+
+fn oaep_padding(alg: JsonWebEncryptionAlg) -> anyhow::Result<PaddingScheme> {
+    match alg {
+        JsonWebEncryptionAlg::RsaOaep => Ok(PaddingScheme::new_oaep::<sha1::Sha1>()),
+        JsonWebEncryptionAlg::RsaOaep256 => Ok(PaddingScheme::new_oaep::<Sha256>()),
+        alg => bail!("{} is not an RSA-OAEP algorithm", alg),
+    }
+}
+
+/// Length in bytes of the content encryption key
+const fn cek_len(enc: JsonWebEncryptionEnc) -> usize {
+    match enc {
+        JsonWebEncryptionEnc::A128Gcm => 16,
+        JsonWebEncryptionEnc::A192Gcm => 24,
+        JsonWebEncryptionEnc::A256Gcm | JsonWebEncryptionEnc::A128CbcHs256 => 32,
+        JsonWebEncryptionEnc::A192CbcHs384 => 48,
+        JsonWebEncryptionEnc::A256CbcHs512 => 64,
+    }
+}
+
+/// Length in bytes of the key encryption key for the ECDH-ES+A*KW algorithms,
+/// or `None` if the ECDH-ES key agreement is used directly
+const fn kw_key_len(alg: JsonWebEncryptionAlg) -> Option<usize> {
+    match alg {
+        JsonWebEncryptionAlg::EcdhEsA128Kw => Some(16),
+        JsonWebEncryptionAlg::EcdhEsA192Kw => Some(24),
+        JsonWebEncryptionAlg::EcdhEsA256Kw => Some(32),
+        _ => None,
+    }
+}
+
+/// Concat KDF, as used by ECDH-ES. We never set `apu` and `apv` ourselves, so
+/// they are empty outside of tests.
+///
+/// Ref: <https://www.rfc-editor.org/rfc/rfc7518.html#section-4.6.2>
+fn concat_kdf(z: &[u8], algorithm_id: &str, apu: &[u8], apv: &[u8], len: usize) -> Vec<u8> {
+    let mut other_info = Vec::new();
+    #[allow(clippy::cast_possible_truncation)]
+    let id_len = algorithm_id.len() as u32;
+    other_info.extend_from_slice(&id_len.to_be_bytes());
+    other_info.extend_from_slice(algorithm_id.as_bytes());
+    for info in [apu, apv] {
+        #[allow(clippy::cast_possible_truncation)]
+        let info_len = info.len() as u32;
+        other_info.extend_from_slice(&info_len.to_be_bytes());
+        other_info.extend_from_slice(info);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let key_bits = (len * 8) as u32;
+    other_info.extend_from_slice(&key_bits.to_be_bytes());
+
+    let mut out = Vec::with_capacity(len);
+    let mut counter: u32 = 1;
+    while out.len() < len {
+        let mut hasher = Sha256::new();
+        hasher.update(counter.to_be_bytes());
+        hasher.update(z);
+        hasher.update(&other_info);
+        out.extend_from_slice(&hasher.finalize());
+        counter += 1;
+    }
+
+    out.truncate(len);
+    out
+}
+
+const KEY_WRAP_IV: [u8; 8] = [0xA6; 8];
+
+/// AES Key Wrap
+///
+/// Ref: <https://www.rfc-editor.org/rfc/rfc3394.html#section-2.2.1>
+fn aes_key_wrap(kek: &[u8], key: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match kek.len() {
+        16 => key_wrap(&Aes128::new_from_slice(kek)?, key),
+        24 => key_wrap(&Aes192::new_from_slice(kek)?, key),
+        32 => key_wrap(&Aes256::new_from_slice(kek)?, key),
+        _ => bail!("invalid key encryption key length"),
+    }
+}
+
+fn aes_key_unwrap(kek: &[u8], wrapped: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match kek.len() {
+        16 => key_unwrap(&Aes128::new_from_slice(kek)?, wrapped),
+        24 => key_unwrap(&Aes192::new_from_slice(kek)?, wrapped),
+        32 => key_unwrap(&Aes256::new_from_slice(kek)?, wrapped),
+        _ => bail!("invalid key encryption key length"),
+    }
+}
+
+fn key_wrap<C: BlockEncrypt>(cipher: &C, key: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if key.len() % 8 != 0 || key.len() < 16 {
+        bail!("invalid key length for key wrapping");
+    }
+
+    let n = key.len() / 8;
+    let mut a = KEY_WRAP_IV;
+    let mut r: Vec<[u8; 8]> = key.chunks_exact(8).map(|c| c.try_into().unwrap()).collect();
+
+    for j in 0..6 {
+        for (i, r_i) in r.iter_mut().enumerate() {
+            let mut block = GenericArray::clone_from_slice(&[a, *r_i].concat());
+            cipher.encrypt_block(&mut block);
+            let t = (n * j + i + 1) as u64;
+            a = xor_counter(&block[..8], t);
+            r_i.copy_from_slice(&block[8..]);
+        }
+    }
+
+    let mut out = a.to_vec();
+    for r_i in r {
+        out.extend_from_slice(&r_i);
+    }
+    Ok(out)
+}
+
+fn key_unwrap<C: BlockDecrypt>(cipher: &C, wrapped: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if wrapped.len() % 8 != 0 || wrapped.len() < 24 {
+        bail!("invalid wrapped key length");
+    }
+
+    let n = wrapped.len() / 8 - 1;
+    let mut a: [u8; 8] = wrapped[..8].try_into()?;
+    let mut r: Vec<[u8; 8]> = wrapped[8..]
+        .chunks_exact(8)
+        .map(|c| c.try_into().unwrap())
+        .collect();
+
+    for j in (0..6).rev() {
+        for (i, r_i) in r.iter_mut().enumerate().rev() {
+            let t = (n * j + i + 1) as u64;
+            let a_t = xor_counter(&a, t);
+            let mut block = GenericArray::clone_from_slice(&[a_t, *r_i].concat());
+            cipher.decrypt_block(&mut block);
+            a.copy_from_slice(&block[..8]);
+            r_i.copy_from_slice(&block[8..]);
+        }
+    }
+
+    if a != KEY_WRAP_IV {
+        bail!("key unwrap integrity check failed");
+    }
+
+    Ok(r.concat())
+}
+
+fn xor_counter(a: &[u8], t: u64) -> [u8; 8] {
+    let mut out: [u8; 8] = a.try_into().unwrap();
+    for (o, t) in out.iter_mut().zip(t.to_be_bytes()) {
+        *o ^= t;
+    }
+    out
+}
+
+type Aes192Gcm = AesGcm<Aes192, U12>;
+
+/// Encrypt the plaintext, returning the IV, the ciphertext and the
+/// authentication tag
+fn encrypt_content(
+    enc: JsonWebEncryptionEnc,
+    cek: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> anyhow::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let mut rng = thread_rng();
+    match enc {
+        JsonWebEncryptionEnc::A128Gcm
+        | JsonWebEncryptionEnc::A192Gcm
+        | JsonWebEncryptionEnc::A256Gcm => {
+            let mut iv = vec![0; 12];
+            rng.fill_bytes(&mut iv);
+            let payload = Payload {
+                msg: plaintext,
+                aad,
+            };
+            let nonce = GenericArray::from_slice(&iv);
+            let mut ciphertext = match enc {
+                JsonWebEncryptionEnc::A128Gcm => aes_gcm::Aes128Gcm::new_from_slice(cek)?
+                    .encrypt(nonce, payload)
+                    .map_err(|_| anyhow::anyhow!("encryption failed"))?,
+                JsonWebEncryptionEnc::A192Gcm => Aes192Gcm::new_from_slice(cek)?
+                    .encrypt(nonce, payload)
+                    .map_err(|_| anyhow::anyhow!("encryption failed"))?,
+                _ => aes_gcm::Aes256Gcm::new_from_slice(cek)?
+                    .encrypt(nonce, payload)
+                    .map_err(|_| anyhow::anyhow!("encryption failed"))?,
+            };
+            // The tag is appended at the end of the ciphertext
+            let tag = ciphertext.split_off(ciphertext.len() - 16);
+            Ok((iv, ciphertext, tag))
+        }
+
+        JsonWebEncryptionEnc::A128CbcHs256
+        | JsonWebEncryptionEnc::A192CbcHs384
+        | JsonWebEncryptionEnc::A256CbcHs512 => {
+            let mut iv = vec![0; 16];
+            rng.fill_bytes(&mut iv);
+            let (mac_key, enc_key) = cek.split_at(cek.len() / 2);
+            let ciphertext = match enc_key.len() {
+                16 => cbc_encrypt(&Aes128::new_from_slice(enc_key)?, &iv, plaintext),
+                24 => cbc_encrypt(&Aes192::new_from_slice(enc_key)?, &iv, plaintext),
+                _ => cbc_encrypt(&Aes256::new_from_slice(enc_key)?, &iv, plaintext),
+            };
+            let tag = cbc_hmac_tag(enc, mac_key, aad, &iv, &ciphertext)?;
+            Ok((iv, ciphertext, tag))
+        }
+    }
+}
+
+fn decrypt_content(
+    enc: JsonWebEncryptionEnc,
+    cek: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    match enc {
+        JsonWebEncryptionEnc::A128Gcm
+        | JsonWebEncryptionEnc::A192Gcm
+        | JsonWebEncryptionEnc::A256Gcm => {
+            if iv.len() != 12 {
+                bail!("invalid IV length");
+            }
+
+            let msg = [ciphertext, tag].concat();
+            let payload = Payload { msg: &msg, aad };
+            let nonce = GenericArray::from_slice(iv);
+            let plaintext = match enc {
+                JsonWebEncryptionEnc::A128Gcm => {
+                    aes_gcm::Aes128Gcm::new_from_slice(cek)?.decrypt(nonce, payload)
+                }
+                JsonWebEncryptionEnc::A192Gcm => {
+                    Aes192Gcm::new_from_slice(cek)?.decrypt(nonce, payload)
+                }
+                _ => aes_gcm::Aes256Gcm::new_from_slice(cek)?.decrypt(nonce, payload),
+            };
+            plaintext.map_err(|_| anyhow::anyhow!("decryption failed"))
+        }
+
+        JsonWebEncryptionEnc::A128CbcHs256
+        | JsonWebEncryptionEnc::A192CbcHs384
+        | JsonWebEncryptionEnc::A256CbcHs512 => {
+            if iv.len() != 16 {
+                bail!("invalid IV length");
+            }
+
+            let (mac_key, enc_key) = cek.split_at(cek.len() / 2);
+            cbc_hmac_verify(enc, mac_key, aad, iv, ciphertext, tag)?;
+
+            match enc_key.len() {
+                16 => cbc_decrypt(&Aes128::new_from_slice(enc_key)?, iv, ciphertext),
+                24 => cbc_decrypt(&Aes192::new_from_slice(enc_key)?, iv, ciphertext),
+                _ => cbc_decrypt(&Aes256::new_from_slice(enc_key)?, iv, ciphertext),
+            }
+        }
+    }
+}
+
+/// Input of the MAC for the `AES_CBC_HMAC_SHA2` algorithms
+///
+/// Ref: <https://www.rfc-editor.org/rfc/rfc7518.html#section-5.2.2.1>
+fn cbc_hmac_input(aad: &[u8], iv: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let al = (aad.len() as u64) * 8;
+    [aad, iv, ciphertext, &al.to_be_bytes()].concat()
+}
+
+fn cbc_hmac_tag(
+    enc: JsonWebEncryptionEnc,
+    mac_key: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let input = cbc_hmac_input(aad, iv, ciphertext);
+    let mut tag = match enc {
+        JsonWebEncryptionEnc::A128CbcHs256 => <Hmac<Sha256> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        JsonWebEncryptionEnc::A192CbcHs384 => <Hmac<Sha384> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        JsonWebEncryptionEnc::A256CbcHs512 => <Hmac<Sha512> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .finalize()
+            .into_bytes()
+            .to_vec(),
+        enc => bail!("{} is not a CBC-HMAC algorithm", enc),
+    };
+
+    // The tag is truncated to the length of the MAC key
+    tag.truncate(mac_key.len());
+    Ok(tag)
+}
+
+fn cbc_hmac_verify(
+    enc: JsonWebEncryptionEnc,
+    mac_key: &[u8],
+    aad: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> anyhow::Result<()> {
+    if tag.len() != mac_key.len() {
+        bail!("invalid authentication tag length");
+    }
+
+    let input = cbc_hmac_input(aad, iv, ciphertext);
+    let res = match enc {
+        JsonWebEncryptionEnc::A128CbcHs256 => <Hmac<Sha256> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .verify_truncated_left(tag),
+        JsonWebEncryptionEnc::A192CbcHs384 => <Hmac<Sha384> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .verify_truncated_left(tag),
+        JsonWebEncryptionEnc::A256CbcHs512 => <Hmac<Sha512> as Mac>::new_from_slice(mac_key)?
+            .chain_update(&input)
+            .verify_truncated_left(tag),
+        enc => bail!("{} is not a CBC-HMAC algorithm", enc),
+    };
+
+    res.map_err(|_| anyhow::anyhow!("invalid authentication tag"))
+}
+
+/// AES-CBC encryption with PKCS#7 padding
+fn cbc_encrypt<C: BlockEncrypt>(cipher: &C, iv: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let block_size = C::block_size();
+    let padding = block_size - plaintext.len() % block_size;
+    let mut data = plaintext.to_vec();
+    #[allow(clippy::cast_possible_truncation)]
+    data.resize(plaintext.len() + padding, padding as u8);
+
+    let mut previous = GenericArray::clone_from_slice(iv);
+    for chunk in data.chunks_exact_mut(block_size) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        for (b, p) in block.iter_mut().zip(previous.iter()) {
+            *b ^= p;
+        }
+        cipher.encrypt_block(&mut block);
+        chunk.copy_from_slice(&block);
+        previous = block;
+    }
+
+    data
+}
+
+fn cbc_decrypt<C: BlockDecrypt>(
+    cipher: &C,
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let block_size = C::block_size();
+    if ciphertext.is_empty() || ciphertext.len() % block_size != 0 {
+        bail!("invalid ciphertext length");
+    }
+
+    let mut data = ciphertext.to_vec();
+    let mut previous: GenericArray<u8, C::BlockSize> = GenericArray::clone_from_slice(iv);
+    for chunk in data.chunks_exact_mut(block_size) {
+        let encrypted = GenericArray::clone_from_slice(chunk);
+        let mut block = encrypted.clone();
+        cipher.decrypt_block(&mut block);
+        for (b, p) in block.iter_mut().zip(previous.iter()) {
+            *b ^= p;
+        }
+        chunk.copy_from_slice(&block);
+        previous = encrypted;
+    }
+
+    let padding = usize::from(*data.last().unwrap_or(&0));
+    if padding == 0
+        || padding > block_size
+        || !data[data.len() - padding..]
+            .iter()
+            .all(|&b| usize::from(b) == padding)
+    {
+        bail!("invalid padding");
+    }
+
+    data.truncate(data.len() - padding);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_wrap_test_vector() {
+        // From RFC3394 section 4.1
+        let kek = hex("000102030405060708090A0B0C0D0E0F");
+        let key = hex("00112233445566778899AABBCCDDEEFF");
+        let wrapped = aes_key_wrap(&kek, &key).unwrap();
+        assert_eq!(
+            wrapped,
+            hex("1FA68B0A8112B447AEF34BD8FB5A7B829D3E862371D2CFE5")
+        );
+        assert_eq!(aes_key_unwrap(&kek, &wrapped).unwrap(), key);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = thread_rng();
+        let rsa_key = rsa::RsaPrivateKey::new(&mut rng, 2048).unwrap();
+        let rsa_jwk = JsonWebKey::new(rsa_key.to_public_key().into());
+        let ec_key = p256::SecretKey::random(&mut rng);
+        let ec_jwk = JsonWebKey::new(ec_key.public_key().into());
+
+        let rsa_key = JweDecryptionKey::Rsa(Box::new(rsa_key));
+        let ec_key = JweDecryptionKey::Ec(ec_key);
+
+        let cases = [
+            (JsonWebEncryptionAlg::RsaOaep, &rsa_jwk, &rsa_key),
+            (JsonWebEncryptionAlg::RsaOaep256, &rsa_jwk, &rsa_key),
+            (JsonWebEncryptionAlg::EcdhEs, &ec_jwk, &ec_key),
+            (JsonWebEncryptionAlg::EcdhEsA128Kw, &ec_jwk, &ec_key),
+            (JsonWebEncryptionAlg::EcdhEsA192Kw, &ec_jwk, &ec_key),
+            (JsonWebEncryptionAlg::EcdhEsA256Kw, &ec_jwk, &ec_key),
+        ];
+
+        let encs = [
+            JsonWebEncryptionEnc::A128Gcm,
+            JsonWebEncryptionEnc::A192Gcm,
+            JsonWebEncryptionEnc::A256Gcm,
+            JsonWebEncryptionEnc::A128CbcHs256,
+            JsonWebEncryptionEnc::A192CbcHs384,
+            JsonWebEncryptionEnc::A256CbcHs512,
+        ];
+
+        let plaintext = b"The true sign of intelligence is not knowledge but imagination.";
+
+        for (alg, jwk, key) in cases {
+            for enc in encs {
+                let header = JweHeader::new(alg, enc);
+                let jwe = JsonWebEncryption::encrypt(header, jwk, plaintext).unwrap();
+                let jwe: JsonWebEncryption = jwe.serialize().parse().unwrap();
+                assert_eq!(jwe.header().alg(), alg);
+                assert_eq!(jwe.header().enc(), enc);
+                assert_eq!(jwe.decrypt(key).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn rfc7518_b_aes_cbc_hmac_sha2() {
+        // From RFC7518 appendix B
+        let plaintext = b"A cipher system must not be required to be secret, and it must be \
+            able to fall into the hands of the enemy without inconvenience";
+        let iv = hex("1AF38C2DC2B96FFDD86694092341BC04");
+        let aad = b"The second principle of Auguste Kerckhoffs";
+
+        let cases = [
+            (
+                JsonWebEncryptionEnc::A128CbcHs256,
+                "C80EDFA32DDF39D5EF00C0B468834279A2E46A1B8049F792F76BFE54B903A9C9\
+                 A94AC9B47AD2655C5F10F9AEF71427E2FC6F9B3F399A221489F16362C7032336\
+                 09D45AC69864E3321CF82935AC4096C86E133314C54019E8CA7980DFA4B9CF1B\
+                 384C486F3A54C51078158EE5D79DE59FBD34D848B3D69550A67646344427ADE5\
+                 4B8851FFB598F7F80074B9473C82E2DB",
+                "652C3FA36B0A7C5B3219FAB3A30BC1C4",
+            ),
+            (
+                JsonWebEncryptionEnc::A192CbcHs384,
+                "EA65DA6B59E61EDB419BE62D19712AE5D303EEB50052D0DFD6697F77224C8EDB\
+                 000D279BDC14C1072654BD30944230C657BED4CA0C9F4A8466F22B226D174621\
+                 4BF8CFC2400ADD9F5126E479663FC90B3BED787A2F0FFCBF3904BE2A641D5C21\
+                 05BFE591BAE23B1D7449E532EEF60A9AC8BB6C6B01D35D49787BCD57EF484927\
+                 F280ADC91AC0C4E79C7B11EFC60054E3",
+                "8490AC0E58949BFE51875D733F93AC2075168039CCC733D7",
+            ),
+            (
+                JsonWebEncryptionEnc::A256CbcHs512,
+                "4AFFAAADB78C31C5DA4B1B590D10FFBD3DD8D5D302423526912DA037ECBCC7BD\
+                 822C301DD67C373BCCB584AD3E9279C2E6D12A1374B77F077553DF829410446B\
+                 36EBD97066296AE6427EA75C2E0846A11A09CCF5370DC80BFECBAD28C73F09B3\
+                 A3B75E662A2594410AE496B2E2E6609E31E6E02CC837F053D21F37FF4F51950B\
+                 BE2638D09DD7A4930930806D0703B1F6",
+                "4DD3B4C088A7F45C216839645B2012BF2E6269A8C56A816DBC1B267761955BC5",
+            ),
+        ];
+
+        for (enc, ciphertext, tag) in cases {
+            let ciphertext = hex(&ciphertext.replace(' ', ""));
+            let tag = hex(tag);
+            // The key is 00 01 02 .. of the length of the algorithm
+            #[allow(clippy::cast_possible_truncation)]
+            let cek: Vec<u8> = (0..cek_len(enc)).map(|i| i as u8).collect();
+            let (mac_key, enc_key) = cek.split_at(cek.len() / 2);
+
+            let encrypted = match enc_key.len() {
+                16 => cbc_encrypt(&Aes128::new_from_slice(enc_key).unwrap(), &iv, plaintext),
+                24 => cbc_encrypt(&Aes192::new_from_slice(enc_key).unwrap(), &iv, plaintext),
+                _ => cbc_encrypt(&Aes256::new_from_slice(enc_key).unwrap(), &iv, plaintext),
+            };
+            assert_eq!(encrypted, ciphertext);
+            assert_eq!(
+                cbc_hmac_tag(enc, mac_key, aad, &iv, &ciphertext).unwrap(),
+                tag
+            );
+
+            let decrypted = decrypt_content(enc, &cek, aad, &iv, &ciphertext, &tag).unwrap();
+            assert_eq!(decrypted, plaintext);
+
+            // Tampering with the AAD or the tag must fail
+            assert!(decrypt_content(enc, &cek, b"tampered", &iv, &ciphertext, &tag).is_err());
+            let mut bad_tag = tag.clone();
+            bad_tag[0] ^= 1;
+            assert!(decrypt_content(enc, &cek, aad, &iv, &ciphertext, &bad_tag).is_err());
+        }
+    }
+
+    #[test]
+    fn rfc7516_a1_content_decryption() {
+        // From RFC7516 appendix A.1, using the CEK from section A.1.2 instead of
+        // the RSA key, so the encrypted key is left out
+        let cek = [
+            177, 161, 244, 128, 84, 143, 225, 115, 63, 180, 3, 255, 107, 154, 212, 246, 138, 7,
+            110, 91, 112, 46, 34, 105, 47, 130, 203, 46, 122, 234, 64, 252,
+        ];
+        let jwe: JsonWebEncryption = "eyJhbGciOiJSU0EtT0FFUCIsImVuYyI6IkEyNTZHQ00ifQ..\
+            48V1_ALb6US04U3b.\
+            5eym8TW_c8SuK0ltJ3rpYIzOeDQz7TALvtu6UG9oMo4vpzs9tX_EFShS8iB7j6jiSdiwkIr3ajwQzaBtQD_A.\
+            XFBoMYUZodetZdvTiFvSkQ"
+            .parse()
+            .unwrap();
+
+        assert_eq!(jwe.header().alg(), JsonWebEncryptionAlg::RsaOaep);
+        assert_eq!(jwe.header().enc(), JsonWebEncryptionEnc::A256Gcm);
+
+        let plaintext = decrypt_content(
+            jwe.header().enc(),
+            &cek,
+            jwe.encoded_header.as_bytes(),
+            &jwe.iv,
+            &jwe.ciphertext,
+            &jwe.tag,
+        )
+        .unwrap();
+        assert_eq!(
+            plaintext,
+            b"The true sign of intelligence is not knowledge but imagination."
+        );
+    }
+
+    #[test]
+    fn rfc7516_a3_key_wrap_and_cbc_hmac() {
+        // From RFC7516 appendix A.3
+        let kek = Base64UrlUnpadded::decode_vec("GawgguFyGrWKav7AX4VKUg").unwrap();
+        let cek = [
+            4, 211, 31, 197, 84, 157, 252, 254, 11, 100, 157, 250, 63, 170, 106, 206, 107, 124,
+            212, 45, 111, 107, 9, 219, 200, 177, 0, 240, 143, 156, 44, 207,
+        ];
+        let jwe: JsonWebEncryption = "eyJhbGciOiJBMTI4S1ciLCJlbmMiOiJBMTI4Q0JDLUhTMjU2In0.\
+            6KB707dM9YTIgHtLvtgWQ8mKwboJW3of9locizkDTHzBC2IlrT1oOQ.\
+            AxY8DCtDaGlsbGljb3RoZQ.\
+            KDlTtXchhZTGufMYmOYGS4HffxPSUrfmqCHXaI9wOGY.\
+            U0m_YmjN04DJvceFICbCVQ"
+            .parse()
+            .unwrap();
+
+        assert_eq!(jwe.header().enc(), JsonWebEncryptionEnc::A128CbcHs256);
+        assert_eq!(aes_key_wrap(&kek, &cek).unwrap(), jwe.encrypted_key);
+        assert_eq!(aes_key_unwrap(&kek, &jwe.encrypted_key).unwrap(), cek);
+
+        let plaintext = decrypt_content(
+            jwe.header().enc(),
+            &cek,
+            jwe.encoded_header.as_bytes(),
+            &jwe.iv,
+            &jwe.ciphertext,
+            &jwe.tag,
+        )
+        .unwrap();
+        assert_eq!(plaintext, b"Live long and prosper.");
+    }
+
+    #[test]
+    fn rfc7518_c_ecdh_es_key_agreement() {
+        // From RFC7518 appendix C
+        let b64 = |s| Base64UrlUnpadded::decode_vec(s).unwrap();
+        let alice =
+            p256::SecretKey::from_be_bytes(&b64("0_NxaRPUMQoAJt50Gz8YiTr8gRTwyEaCumd-MToTmIo"))
+                .unwrap();
+        let bob_public = p256::PublicKey::from_sec1_bytes(
+            &[
+                &[0x04][..],
+                &b64("weNJy2HscCSM6AEDTDg04biOvhFhyyWvOHQfeF_PxMQ"),
+                &b64("e8lnCO-AlStT-NJVX-crhB7QRYhiix03illJOVAOyck"),
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        let shared =
+            elliptic_curve::ecdh::diffie_hellman(alice.to_nonzero_scalar(), bob_public.as_affine());
+        let z = shared.raw_secret_bytes();
+        assert_eq!(
+            z.as_slice(),
+            [
+                158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+                110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196
+            ]
+        );
+
+        let key = concat_kdf(z, "A128GCM", b"Alice", b"Bob", 16);
+        assert_eq!(key, b64("VqqN6vgjbSBcIijNcacQGg"));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
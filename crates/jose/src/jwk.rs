@@ -16,8 +16,8 @@
 
 use anyhow::bail;
 use mas_iana::jose::{
-    JsonWebKeyEcEllipticCurve, JsonWebKeyOkpEllipticCurve, JsonWebKeyOperation, JsonWebKeyType,
-    JsonWebKeyUse, JsonWebSignatureAlg,
+    JsonWebEncryptionAlg, JsonWebKeyEcEllipticCurve, JsonWebKeyOkpEllipticCurve,
+    JsonWebKeyOperation, JsonWebKeyType, JsonWebKeyUse, JsonWebSignatureAlg,
 };
 use p256::NistP256;
 use rsa::{BigUint, PublicKeyParts};
@@ -30,6 +30,15 @@ use serde_with::{
 };
 use url::Url;
 
+/// The algorithm a key is intended to be used with, which can either be a
+/// signature or a key management algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum JsonWebKeyAlg {
+    Signature(JsonWebSignatureAlg),
+    Encryption(JsonWebEncryptionAlg),
+}
+
 #[serde_as]
 #[skip_serializing_none]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
@@ -44,7 +53,7 @@ pub struct JsonWebKey {
     key_ops: Option<Vec<JsonWebKeyOperation>>,
 
     #[serde(default)]
-    alg: Option<JsonWebSignatureAlg>,
+    alg: Option<JsonWebKeyAlg>,
 
     #[serde(default)]
     kid: Option<String>,
@@ -99,7 +108,13 @@ impl JsonWebKey {
 
     #[must_use]
     pub const fn with_alg(mut self, alg: JsonWebSignatureAlg) -> Self {
-        self.alg = Some(alg);
+        self.alg = Some(JsonWebKeyAlg::Signature(alg));
+        self
+    }
+
+    #[must_use]
+    pub const fn with_encryption_alg(mut self, alg: JsonWebEncryptionAlg) -> Self {
+        self.alg = Some(JsonWebKeyAlg::Encryption(alg));
         self
     }
 
@@ -125,7 +140,50 @@ impl JsonWebKey {
 
     #[must_use]
     pub const fn alg(&self) -> Option<JsonWebSignatureAlg> {
-        self.alg
+        match self.alg {
+            Some(JsonWebKeyAlg::Signature(alg)) => Some(alg),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn encryption_alg(&self) -> Option<JsonWebEncryptionAlg> {
+        match self.alg {
+            Some(JsonWebKeyAlg::Encryption(alg)) => Some(alg),
+            _ => None,
+        }
+    }
+
+    /// Check if this key can be used to encrypt content with the given key
+    /// management algorithm
+    #[must_use]
+    pub fn can_encrypt_with(&self, alg: JsonWebEncryptionAlg) -> bool {
+        if self.r#use == Some(JsonWebKeyUse::Sig) {
+            return false;
+        }
+
+        match self.alg {
+            Some(JsonWebKeyAlg::Encryption(key_alg)) if key_alg != alg => return false,
+            Some(JsonWebKeyAlg::Signature(_)) => return false,
+            _ => {}
+        }
+
+        matches!(
+            (&self.parameters, alg),
+            (
+                JsonWebKeyParameters::Rsa { .. },
+                JsonWebEncryptionAlg::RsaOaep | JsonWebEncryptionAlg::RsaOaep256,
+            ) | (
+                JsonWebKeyParameters::Ec {
+                    crv: JsonWebKeyEcEllipticCurve::P256,
+                    ..
+                },
+                JsonWebEncryptionAlg::EcdhEs
+                    | JsonWebEncryptionAlg::EcdhEsA128Kw
+                    | JsonWebEncryptionAlg::EcdhEsA192Kw
+                    | JsonWebEncryptionAlg::EcdhEsA256Kw,
+            )
+        )
     }
 
     #[must_use]
@@ -152,6 +210,13 @@ impl JsonWebKeySet {
     pub fn new(keys: Vec<JsonWebKey>) -> Self {
         Self { keys }
     }
+
+    /// Find the first key which can be used to encrypt content with the given
+    /// key management algorithm
+    #[must_use]
+    pub fn find_encryption_key(&self, alg: JsonWebEncryptionAlg) -> Option<&JsonWebKey> {
+        self.keys.iter().find(|key| key.can_encrypt_with(alg))
+    }
 }
 
 #[serde_as]
@@ -225,6 +290,21 @@ impl From<ecdsa::VerifyingKey<NistP256>> for JsonWebKeyParameters {
     }
 }
 
+impl TryFrom<JsonWebKeyParameters> for p256::PublicKey {
+    type Error = anyhow::Error;
+
+    fn try_from(params: JsonWebKeyParameters) -> Result<Self, Self::Error> {
+        let key: ecdsa::VerifyingKey<NistP256> = params.try_into()?;
+        Ok(key.into())
+    }
+}
+
+impl From<p256::PublicKey> for JsonWebKeyParameters {
+    fn from(key: p256::PublicKey) -> Self {
+        ecdsa::VerifyingKey::from(key).into()
+    }
+}
+
 impl TryFrom<JsonWebKeyParameters> for rsa::RsaPublicKey {
     type Error = anyhow::Error;
 
@@ -18,6 +18,7 @@
 #![allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
 
 pub mod claims;
+pub(crate) mod jwe;
 pub(crate) mod jwk;
 pub(crate) mod jwt;
 mod keystore;
@@ -25,7 +26,8 @@ mod keystore;
 pub use futures_util::future::Either;
 
 pub use self::{
-    jwk::{JsonWebKey, JsonWebKeySet},
+    jwe::{JsonWebEncryption, JweDecryptionKey, JweHeader},
+    jwk::{JsonWebKey, JsonWebKeyAlg, JsonWebKeyParameters, JsonWebKeySet},
     jwt::{DecodedJsonWebToken, JsonWebTokenParts, JwtHeader},
     keystore::{
         DynamicJwksStore, SharedSecret, SigningKeystore, StaticJwksStore, StaticKeystore,
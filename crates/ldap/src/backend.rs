@@ -0,0 +1,590 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use mas_config::{LdapBindConfig, LdapServerConfig};
+use mas_storage::password::{ExternalUser, PasswordBackend, PasswordVerification};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+
+use crate::{
+    client::{Connection, LdapError},
+    filter::{self, escape_dn_value, escape_value},
+    protocol::{Scope, SearchEntry, INVALID_CREDENTIALS},
+};
+
+/// Checks passwords by binding to an LDAP directory
+pub struct LdapBackend {
+    config: LdapServerConfig,
+    tls: Arc<ClientConfig>,
+}
+
+impl std::fmt::Debug for LdapBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The config holds the service account password, don't leak it in logs
+        f.debug_struct("LdapBackend")
+            .field("url", &self.config.url.as_str())
+            .finish_non_exhaustive()
+    }
+}
+
+fn load_tls_config(ca_certificate: Option<&Path>) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+
+    let native = rustls_native_certs::load_native_certs()
+        .context("could not load the system certificates")?;
+    let native: Vec<_> = native.into_iter().map(|cert| cert.0).collect();
+    roots.add_parsable_certificates(&native);
+
+    if let Some(path) = ca_certificate {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("could not read CA certificate {}", path.display()))?;
+        let certs = rustls_pemfile::certs(&mut &pem[..])
+            .with_context(|| format!("invalid CA certificate {}", path.display()))?;
+        for cert in certs {
+            roots.add(&Certificate(cert))?;
+        }
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+impl LdapBackend {
+    /// Create a backend from its configuration, checking it on the way
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration is invalid or if the
+    /// certificates could not be loaded
+    pub async fn from_config(config: &LdapServerConfig) -> anyhow::Result<Self> {
+        match (config.url.scheme(), config.starttls) {
+            ("ldap", _) | ("ldaps", false) => {}
+            ("ldaps", true) => anyhow::bail!("StartTLS can't be used with an ldaps:// URL"),
+            (scheme, _) => anyhow::bail!("unsupported LDAP URL scheme {:?}", scheme),
+        }
+
+        if let LdapBindConfig::Search { filter, .. } = &config.bind {
+            filter::parse(&filter.replace("{username}", "test"))
+                .with_context(|| format!("invalid LDAP search filter {:?}", filter))?;
+        }
+
+        // Loading the system certificates can take a while, do it in a blocking task
+        let ca_certificate = config.ca_certificate.clone();
+        let tls = tokio::task::spawn_blocking(move || load_tls_config(ca_certificate.as_deref()))
+            .await??;
+
+        Ok(Self {
+            config: config.clone(),
+            tls: Arc::new(tls),
+        })
+    }
+
+    fn attributes(&self) -> Vec<&str> {
+        let attributes = &self.config.attributes;
+        std::iter::once(attributes.username.as_str())
+            .chain(
+                [&attributes.email, &attributes.displayname]
+                    .into_iter()
+                    .filter_map(Option::as_deref),
+            )
+            .collect()
+    }
+
+    fn external_user(&self, entry: &SearchEntry) -> Result<ExternalUser, LdapError> {
+        let attributes = &self.config.attributes;
+        let get = |attribute: &Option<String>| {
+            attribute
+                .as_deref()
+                .and_then(|attribute| entry.first_value(attribute))
+                .map(ToOwned::to_owned)
+        };
+
+        // The username typed by the user may differ from the canonical one, which
+        // is the one used for the local account
+        let username = entry
+            .first_value(&attributes.username)
+            .ok_or_else(|| LdapError::MissingAttribute(attributes.username.clone()))?
+            .to_owned();
+
+        Ok(ExternalUser {
+            username,
+            email: get(&attributes.email),
+            displayname: get(&attributes.displayname),
+        })
+    }
+
+    async fn verify(
+        &self,
+        connection: &mut Connection,
+        username: &str,
+        password: &str,
+    ) -> Result<PasswordVerification, LdapError> {
+        let attributes = self.attributes();
+
+        match &self.config.bind {
+            LdapBindConfig::Search {
+                bind_dn,
+                bind_password,
+                base_dn,
+                filter,
+            } => {
+                if let Some(bind_dn) = bind_dn {
+                    let result = connection
+                        .simple_bind(bind_dn, bind_password.as_deref().unwrap_or_default())
+                        .await?;
+                    if !result.is_success() {
+                        return Err(result.into());
+                    }
+                }
+
+                let filter = filter::parse(&filter.replace("{username}", &escape_value(username)))?;
+                let mut entries = connection
+                    .search(base_dn, Scope::WholeSubtree, &filter, &attributes)
+                    .await?;
+
+                let entry = match entries.len() {
+                    0 => return Ok(PasswordVerification::UnknownUser),
+                    1 => entries.remove(0),
+                    _ => {
+                        tracing::warn!(%username, "Several LDAP entries match this user, ignoring them");
+                        return Ok(PasswordVerification::UnknownUser);
+                    }
+                };
+
+                let result = connection.simple_bind(&entry.dn, password).await?;
+                match result.code {
+                    _ if result.is_success() => {
+                        Ok(PasswordVerification::Valid(self.external_user(&entry)?))
+                    }
+                    INVALID_CREDENTIALS => Ok(PasswordVerification::Invalid),
+                    _ => Err(result.into()),
+                }
+            }
+
+            LdapBindConfig::Direct { dn_template } => {
+                let dn = dn_template.replace("{username}", &escape_dn_value(username));
+                let result = connection.simple_bind(&dn, password).await?;
+                match result.code {
+                    _ if result.is_success() => {}
+                    // Servers don't tell apart unknown users from wrong passwords
+                    INVALID_CREDENTIALS => return Ok(PasswordVerification::UnknownUser),
+                    _ => return Err(result.into()),
+                }
+
+                // Now that we are bound as the user, read their own entry
+                let filter = filter::parse("(objectClass=*)")?;
+                let entries = connection
+                    .search(&dn, Scope::BaseObject, &filter, &attributes)
+                    .await?;
+                let entry = entries.first().ok_or(LdapError::UnexpectedResponse)?;
+
+                Ok(PasswordVerification::Valid(self.external_user(entry)?))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PasswordBackend for LdapBackend {
+    #[tracing::instrument(skip(password), fields(url = %self.config.url))]
+    async fn verify_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<PasswordVerification> {
+        // Servers treat a bind with an empty password as an anonymous bind, which
+        // succeeds
+        if password.is_empty() {
+            return Ok(PasswordVerification::UnknownUser);
+        }
+
+        let verification = tokio::time::timeout(self.config.timeout, async {
+            let mut connection =
+                Connection::connect(&self.config.url, self.config.starttls, &self.tls).await?;
+            let verification = self.verify(&mut connection, username, password).await;
+            connection.unbind().await;
+            verification
+        })
+        .await
+        .context("LDAP server timed out")?
+        .context("could not check password against LDAP server")?;
+
+        Ok(verification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        ber::{self, Reader, ENUMERATED, INTEGER, OCTET_STRING, SEQUENCE, SET},
+        client::read_message,
+    };
+
+    const SERVICE_DN: &str = "cn=mas,dc=example,dc=org";
+    const SERVICE_PASSWORD: &str = "service-password";
+
+    struct Entry {
+        dn: &'static str,
+        uid: &'static str,
+        password: &'static str,
+        mail: &'static str,
+        display_name: &'static str,
+    }
+
+    const DIRECTORY: &[Entry] = &[
+        Entry {
+            dn: "uid=alice,ou=people,dc=example,dc=org",
+            uid: "alice",
+            password: "alice-password",
+            mail: "alice@example.org",
+            display_name: "Alice",
+        },
+        Entry {
+            dn: "uid=bob,ou=people,dc=example,dc=org",
+            uid: "bob",
+            password: "bob-password",
+            mail: "bob@example.org",
+            display_name: "Bob",
+        },
+    ];
+
+    fn response(id: i64, tag: u8, parts: &[Vec<u8>]) -> Vec<u8> {
+        ber::constructed(
+            SEQUENCE,
+            &[ber::integer(INTEGER, id), ber::constructed(tag, parts)],
+        )
+    }
+
+    fn result(code: i64) -> [Vec<u8>; 3] {
+        [
+            ber::integer(ENUMERATED, code),
+            ber::octet_string(OCTET_STRING, b""),
+            ber::octet_string(OCTET_STRING, b""),
+        ]
+    }
+
+    fn search_entry(id: i64, entry: &Entry, attributes: &[String]) -> Vec<u8> {
+        let attributes: Vec<_> = [
+            ("uid", entry.uid),
+            ("mail", entry.mail),
+            ("displayName", entry.display_name),
+        ]
+        .into_iter()
+        .filter(|(name, _)| attributes.iter().any(|a| a.eq_ignore_ascii_case(name)))
+        .map(|(name, value)| {
+            ber::constructed(
+                SEQUENCE,
+                &[
+                    ber::octet_string(OCTET_STRING, name.as_bytes()),
+                    ber::constructed(SET, &[ber::octet_string(OCTET_STRING, value.as_bytes())]),
+                ],
+            )
+        })
+        .collect();
+
+        response(
+            id,
+            0x64,
+            &[
+                ber::octet_string(OCTET_STRING, entry.dn.as_bytes()),
+                ber::constructed(SEQUENCE, &attributes),
+            ],
+        )
+    }
+
+    /// Evaluate the filters used by the backend against an entry
+    fn filter_matches(entry: &Entry, tag: u8, filter: &[u8]) -> bool {
+        let mut filter = Reader::new(filter);
+        match tag {
+            // And
+            0xa0 => {
+                while !filter.is_empty() {
+                    let (tag, content) = filter.read_any().unwrap();
+                    if !filter_matches(entry, tag, content) {
+                        return false;
+                    }
+                }
+                true
+            }
+            // Equality match, every entry is an inetOrgPerson
+            0xa3 => {
+                let attribute = filter.read_string(OCTET_STRING).unwrap();
+                let value = filter.read_string(OCTET_STRING).unwrap();
+                match attribute.as_str() {
+                    "uid" => value.eq_ignore_ascii_case(entry.uid),
+                    "objectClass" => value == "inetOrgPerson",
+                    _ => false,
+                }
+            }
+            // Presence
+            0x87 => true,
+            _ => false,
+        }
+    }
+
+    /// A tiny LDAP server, which understands just what the backend sends
+    async fn handle(mut stream: TcpStream) {
+        while let Ok(message) = read_message(&mut stream).await {
+            let mut reader = Reader::new(&message);
+            let mut message = reader.read_constructed(SEQUENCE).unwrap();
+            let id = message.read_integer(INTEGER).unwrap();
+            let (tag, content) = message.read_any().unwrap();
+            let mut op = Reader::new(content);
+
+            let replies = match tag {
+                // Bind request
+                0x60 => {
+                    op.read_integer(INTEGER).unwrap();
+                    let dn = op.read_string(OCTET_STRING).unwrap();
+                    let password = op.read_string(0x80).unwrap();
+                    let valid = (dn == SERVICE_DN && password == SERVICE_PASSWORD)
+                        || DIRECTORY
+                            .iter()
+                            .any(|entry| entry.dn == dn && entry.password == password);
+                    vec![response(id, 0x61, &result(if valid { 0 } else { 49 }))]
+                }
+
+                // Search request
+                0x63 => {
+                    let base = op.read_string(OCTET_STRING).unwrap();
+                    let scope = op.read_integer(ENUMERATED).unwrap();
+                    for _ in 0..4 {
+                        op.read_any().unwrap();
+                    }
+                    let (filter_tag, filter) = op.read_any().unwrap();
+                    let mut list = op.read_constructed(SEQUENCE).unwrap();
+                    let mut attributes = Vec::new();
+                    while !list.is_empty() {
+                        attributes.push(list.read_string(OCTET_STRING).unwrap());
+                    }
+
+                    let matches = |entry: &&Entry| {
+                        entry.dn.ends_with(&base)
+                            && (scope != 0 || entry.dn == base)
+                            && filter_matches(entry, filter_tag, filter)
+                    };
+
+                    let mut replies: Vec<_> = DIRECTORY
+                        .iter()
+                        .filter(matches)
+                        .map(|entry| search_entry(id, entry, &attributes))
+                        .collect();
+                    replies.push(response(id, 0x65, &result(0)));
+                    replies
+                }
+
+                // Unbind request
+                0x42 => return,
+                _ => panic!("unexpected request {:#04x}", tag),
+            };
+
+            for reply in replies {
+                stream.write_all(&reply).await.unwrap();
+            }
+        }
+    }
+
+    async fn server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle(stream));
+            }
+        });
+
+        Url::parse(&format!("ldap://{}", addr)).unwrap()
+    }
+
+    async fn backend(url: Url, bind: LdapBindConfig) -> LdapBackend {
+        LdapBackend::from_config(&LdapServerConfig {
+            url,
+            starttls: false,
+            ca_certificate: None,
+            bind,
+            attributes: mas_config::LdapAttributesConfig::default(),
+            timeout: Duration::from_secs(5),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn search_config(bind_password: &str) -> LdapBindConfig {
+        LdapBindConfig::Search {
+            bind_dn: Some(SERVICE_DN.to_string()),
+            bind_password: Some(bind_password.to_string()),
+            base_dn: "ou=people,dc=example,dc=org".to_string(),
+            filter: "(&(objectClass=inetOrgPerson)(uid={username}))".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn search_and_bind() {
+        let backend = backend(server().await, search_config(SERVICE_PASSWORD)).await;
+
+        assert_eq!(
+            backend
+                .verify_password("alice", "alice-password")
+                .await
+                .unwrap(),
+            PasswordVerification::Valid(ExternalUser {
+                username: "alice".to_string(),
+                email: Some("alice@example.org".to_string()),
+                displayname: Some("Alice".to_string()),
+            })
+        );
+
+        // The canonical username is the one from the directory
+        assert!(matches!(
+            backend
+                .verify_password("ALICE", "alice-password")
+                .await
+                .unwrap(),
+            PasswordVerification::Valid(ExternalUser { username, .. }) if username == "alice"
+        ));
+
+        assert_eq!(
+            backend
+                .verify_password("alice", "bob-password")
+                .await
+                .unwrap(),
+            PasswordVerification::Invalid
+        );
+
+        assert_eq!(
+            backend
+                .verify_password("carol", "carol-password")
+                .await
+                .unwrap(),
+            PasswordVerification::UnknownUser
+        );
+
+        // The username is escaped, so this doesn't match everyone
+        assert_eq!(
+            backend
+                .verify_password("*", "alice-password")
+                .await
+                .unwrap(),
+            PasswordVerification::UnknownUser
+        );
+
+        assert_eq!(
+            backend.verify_password("alice", "").await.unwrap(),
+            PasswordVerification::UnknownUser
+        );
+    }
+
+    #[tokio::test]
+    async fn search_with_wrong_service_account() {
+        let backend = backend(server().await, search_config("wrong")).await;
+        assert!(backend
+            .verify_password("alice", "alice-password")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn direct_bind() {
+        let backend = backend(
+            server().await,
+            LdapBindConfig::Direct {
+                dn_template: "uid={username},ou=people,dc=example,dc=org".to_string(),
+            },
+        )
+        .await;
+
+        assert_eq!(
+            backend
+                .verify_password("bob", "bob-password")
+                .await
+                .unwrap(),
+            PasswordVerification::Valid(ExternalUser {
+                username: "bob".to_string(),
+                email: Some("bob@example.org".to_string()),
+                displayname: Some("Bob".to_string()),
+            })
+        );
+
+        assert_eq!(
+            backend
+                .verify_password("bob", "alice-password")
+                .await
+                .unwrap(),
+            PasswordVerification::UnknownUser
+        );
+    }
+
+    #[tokio::test]
+    async fn unresponsive_server() {
+        // Accept connections, but never answer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ldap://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let mut backend = backend(url, search_config(SERVICE_PASSWORD)).await;
+        backend.config.timeout = Duration::from_millis(100);
+
+        assert!(backend
+            .verify_password("alice", "alice-password")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_config() {
+        let config = LdapServerConfig {
+            url: Url::parse("ldaps://ldap.example.org").unwrap(),
+            starttls: true,
+            ca_certificate: None,
+            bind: search_config(SERVICE_PASSWORD),
+            attributes: mas_config::LdapAttributesConfig::default(),
+            timeout: Duration::from_secs(5),
+        };
+        assert!(LdapBackend::from_config(&config).await.is_err());
+
+        let config = LdapServerConfig {
+            url: Url::parse("ldap://ldap.example.org").unwrap(),
+            starttls: false,
+            bind: LdapBindConfig::Search {
+                bind_dn: None,
+                bind_password: None,
+                base_dn: "dc=example,dc=org".to_string(),
+                filter: "uid={username}".to_string(),
+            },
+            ..config
+        };
+        assert!(LdapBackend::from_config(&config).await.is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Just enough of the Basic Encoding Rules to speak LDAP, as defined in
+//! X.690 and restricted by RFC 4511 section 5.1
+
+use thiserror::Error;
+
+pub(crate) const BOOLEAN: u8 = 0x01;
+pub(crate) const INTEGER: u8 = 0x02;
+pub(crate) const OCTET_STRING: u8 = 0x04;
+pub(crate) const ENUMERATED: u8 = 0x0a;
+pub(crate) const SEQUENCE: u8 = 0x30;
+pub(crate) const SET: u8 = 0x31;
+
+/// The largest message we accept from a server
+pub(crate) const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BerError {
+    #[error("unexpected end of data")]
+    Truncated,
+
+    #[error("unexpected tag {found:#04x}, expected {expected:#04x}")]
+    UnexpectedTag { expected: u8, found: u8 },
+
+    #[error("unsupported length encoding")]
+    InvalidLength,
+
+    #[error("integer out of range")]
+    InvalidInteger,
+}
+
+/// Encode a TLV with the given tag
+pub(crate) fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 6);
+    out.push(tag);
+
+    let len = content.len();
+    if len < 0x80 {
+        // Lengths are bounded by the size of the buffer, this can't truncate
+        #[allow(clippy::cast_possible_truncation)]
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        let bytes = &bytes[skip..];
+        #[allow(clippy::cast_possible_truncation)]
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+
+    out.extend_from_slice(content);
+    out
+}
+
+/// Encode a constructed value from its already encoded parts
+pub(crate) fn constructed(tag: u8, parts: &[Vec<u8>]) -> Vec<u8> {
+    encode(tag, &parts.concat())
+}
+
+/// Encode an integer in its shortest two's complement form
+pub(crate) fn integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+
+    encode(tag, &bytes[start..])
+}
+
+pub(crate) fn boolean(value: bool) -> Vec<u8> {
+    encode(BOOLEAN, &[if value { 0xff } else { 0x00 }])
+}
+
+pub(crate) fn octet_string(tag: u8, value: &[u8]) -> Vec<u8> {
+    encode(tag, value)
+}
+
+/// Figure out the total size of the TLV starting this buffer, once enough of
+/// its header is available
+pub(crate) fn header_length(header: &[u8]) -> Result<Option<(usize, usize)>, BerError> {
+    let first = match header.get(1) {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+
+    if first & 0x80 == 0 {
+        return Ok(Some((2, usize::from(first))));
+    }
+
+    let count = usize::from(first & 0x7f);
+    if count == 0 || count > 4 {
+        // Indefinite lengths are forbidden in LDAP
+        return Err(BerError::InvalidLength);
+    }
+
+    match header.get(2..2 + count) {
+        Some(bytes) => {
+            let len = bytes
+                .iter()
+                .fold(0_usize, |acc, b| (acc << 8) | usize::from(*b));
+            Ok(Some((2 + count, len)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads TLVs one after the other out of a buffer
+#[derive(Debug, Clone)]
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Read the next TLV, returning its tag and content
+    pub fn read_any(&mut self) -> Result<(u8, &'a [u8]), BerError> {
+        let (header, len) = header_length(self.data)?.ok_or(BerError::Truncated)?;
+        let end = header.checked_add(len).ok_or(BerError::InvalidLength)?;
+        let content = self.data.get(header..end).ok_or(BerError::Truncated)?;
+        let tag = self.data[0];
+        self.data = &self.data[end..];
+        Ok((tag, content))
+    }
+
+    /// Read the next TLV, checking its tag
+    pub fn read(&mut self, expected: u8) -> Result<&'a [u8], BerError> {
+        let (found, content) = self.read_any()?;
+        if found == expected {
+            Ok(content)
+        } else {
+            Err(BerError::UnexpectedTag { expected, found })
+        }
+    }
+
+    /// Read a constructed value, returning a reader over its content
+    pub fn read_constructed(&mut self, tag: u8) -> Result<Reader<'a>, BerError> {
+        self.read(tag).map(Reader::new)
+    }
+
+    pub fn read_integer(&mut self, tag: u8) -> Result<i64, BerError> {
+        let content = self.read(tag)?;
+        if content.is_empty() || content.len() > 8 {
+            return Err(BerError::InvalidInteger);
+        }
+
+        let init = if content[0] & 0x80 == 0 { 0 } else { -1 };
+        Ok(content
+            .iter()
+            .fold(init, |acc: i64, b| (acc << 8) | i64::from(*b)))
+    }
+
+    pub fn read_string(&mut self, tag: u8) -> Result<String, BerError> {
+        let content = self.read(tag)?;
+        // Servers are supposed to send UTF-8, but better be lenient here
+        Ok(String::from_utf8_lossy(content).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_lengths() {
+        assert_eq!(encode(OCTET_STRING, b"abc"), b"\x04\x03abc");
+
+        let long = encode(OCTET_STRING, &[0x42; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+
+        assert_eq!(header_length(&long[..1]), Ok(None));
+        assert_eq!(header_length(&long[..3]), Ok(None));
+        assert_eq!(header_length(&long[..4]), Ok(Some((4, 300))));
+        assert_eq!(header_length(&[0x30, 0x80]), Err(BerError::InvalidLength));
+    }
+
+    #[test]
+    fn integers_roundtrip() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x00, 0x80]),
+            (256, &[0x01, 0x00]),
+            (-1, &[0xff]),
+            (-128, &[0x80]),
+            (-129, &[0xff, 0x7f]),
+        ] {
+            let tlv = integer(INTEGER, value);
+            assert_eq!(&tlv[2..], encoded, "encoding {}", value);
+            assert_eq!(Reader::new(&tlv).read_integer(INTEGER), Ok(value));
+        }
+    }
+
+    #[test]
+    fn read_nested() {
+        let data = constructed(
+            SEQUENCE,
+            &[integer(INTEGER, 3), octet_string(OCTET_STRING, b"dn")],
+        );
+
+        let mut reader = Reader::new(&data);
+        let mut seq = reader.read_constructed(SEQUENCE).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(seq.read_integer(INTEGER), Ok(3));
+        assert_eq!(
+            seq.read_integer(INTEGER),
+            Err(BerError::UnexpectedTag {
+                expected: INTEGER,
+                found: OCTET_STRING
+            })
+        );
+
+        let mut truncated = Reader::new(&data[..data.len() - 1]);
+        assert_eq!(truncated.read_any(), Err(BerError::Truncated));
+    }
+}
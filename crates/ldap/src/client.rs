@@ -0,0 +1,291 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use rustls::{client::InvalidDnsNameError, ClientConfig, ServerName};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use url::Url;
+
+use crate::{
+    ber::{self, BerError, MAX_MESSAGE_SIZE},
+    filter::FilterError,
+    protocol::{
+        decode_response, LdapResult, Request, Response, Scope, SearchEntry, NO_SUCH_OBJECT,
+        START_TLS_OID,
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum LdapError {
+    #[error("unsupported LDAP URL {0}")]
+    InvalidUrl(Url),
+
+    #[error("could not talk to the LDAP server")]
+    Io(#[from] io::Error),
+
+    #[error("invalid LDAP server name")]
+    InvalidServerName(#[from] InvalidDnsNameError),
+
+    #[error("malformed message from the LDAP server")]
+    Ber(#[from] BerError),
+
+    #[error("invalid search filter")]
+    Filter(#[from] FilterError),
+
+    #[error("unexpected response from the LDAP server")]
+    UnexpectedResponse,
+
+    #[error("the user entry has no {0} attribute")]
+    MissingAttribute(String),
+
+    #[error("LDAP operation failed with code {code}: {message}")]
+    Operation { code: i64, message: String },
+}
+
+impl From<LdapResult> for LdapError {
+    fn from(result: LdapResult) -> Self {
+        Self::Operation {
+            code: result.code,
+            message: result.message,
+        }
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Read a whole BER-encoded message from a stream
+pub(crate) async fn read_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Vec<u8>, LdapError> {
+    let mut message = vec![0; 2];
+    stream.read_exact(&mut message).await?;
+
+    let (header, len) = loop {
+        if let Some(lengths) = ber::header_length(&message)? {
+            break lengths;
+        }
+
+        let mut byte = [0];
+        stream.read_exact(&mut byte).await?;
+        message.push(byte[0]);
+    };
+
+    if len > MAX_MESSAGE_SIZE {
+        return Err(BerError::InvalidLength.into());
+    }
+
+    message.resize(header + len, 0);
+    stream.read_exact(&mut message[header..]).await?;
+    Ok(message)
+}
+
+/// A connection to an LDAP server
+pub(crate) struct Connection {
+    stream: Stream,
+    next_id: i32,
+}
+
+impl Connection {
+    /// Connect to the server, upgrading the connection to TLS if needed
+    pub async fn connect(
+        url: &Url,
+        starttls: bool,
+        tls: &Arc<ClientConfig>,
+    ) -> Result<Self, LdapError> {
+        let (secure, default_port) = match url.scheme() {
+            "ldap" => (false, 389),
+            "ldaps" => (true, 636),
+            _ => return Err(LdapError::InvalidUrl(url.clone())),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| LdapError::InvalidUrl(url.clone()))?;
+        let port = url.port().unwrap_or(default_port);
+
+        let stream = TcpStream::connect((host, port)).await?;
+        let mut connection = Self {
+            stream: Stream::Plain(stream),
+            next_id: 1,
+        };
+
+        if secure {
+            connection = connection.upgrade(tls, host).await?;
+        } else if starttls {
+            connection
+                .send(&Request::Extended { oid: START_TLS_OID })
+                .await?;
+            let response = connection.receive().await?;
+            connection.done();
+            match response {
+                Response::Extended(result) if result.is_success() => {}
+                Response::Extended(result) => return Err(result.into()),
+                _ => return Err(LdapError::UnexpectedResponse),
+            }
+
+            connection = connection.upgrade(tls, host).await?;
+        }
+
+        Ok(connection)
+    }
+
+    async fn upgrade(self, tls: &Arc<ClientConfig>, host: &str) -> Result<Self, LdapError> {
+        let stream = match self.stream {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(_) => return Ok(self),
+        };
+
+        let name = ServerName::try_from(host)?;
+        let stream = TlsConnector::from(tls.clone())
+            .connect(name, stream)
+            .await?;
+
+        Ok(Self {
+            stream: Stream::Tls(Box::new(stream)),
+            next_id: self.next_id,
+        })
+    }
+
+    async fn send(&mut self, request: &Request<'_>) -> Result<(), LdapError> {
+        let message = request.encode(self.next_id);
+        self.stream.write_all(&message).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Receive the response to the last request sent
+    async fn receive(&mut self) -> Result<Response, LdapError> {
+        let message = read_message(&mut self.stream).await?;
+        let (id, response) = decode_response(&message)?;
+
+        // This also catches unsolicited notifications, which have the ID 0 and
+        // usually mean the server is about to close the connection
+        if id == self.next_id {
+            Ok(response)
+        } else {
+            Err(LdapError::UnexpectedResponse)
+        }
+    }
+
+    fn done(&mut self) {
+        self.next_id += 1;
+    }
+
+    /// Do a simple bind, returning the result for the caller to interpret
+    pub async fn simple_bind(&mut self, dn: &str, password: &str) -> Result<LdapResult, LdapError> {
+        self.send(&Request::Bind { dn, password }).await?;
+        let response = self.receive().await?;
+        self.done();
+
+        match response {
+            Response::Bind(result) => Ok(result),
+            _ => Err(LdapError::UnexpectedResponse),
+        }
+    }
+
+    /// Search the directory. A missing base DN gives no entries
+    pub async fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &[u8],
+        attributes: &[&str],
+    ) -> Result<Vec<SearchEntry>, LdapError> {
+        self.send(&Request::Search {
+            base,
+            scope,
+            filter,
+            attributes,
+        })
+        .await?;
+
+        let mut entries = Vec::new();
+        loop {
+            match self.receive().await? {
+                Response::SearchEntry(entry) => entries.push(entry),
+                // We don't follow referrals
+                Response::SearchReference => {}
+                Response::SearchDone(result) => {
+                    self.done();
+                    return if result.is_success() || result.code == NO_SUCH_OBJECT {
+                        Ok(entries)
+                    } else {
+                        Err(result.into())
+                    };
+                }
+                _ => return Err(LdapError::UnexpectedResponse),
+            }
+        }
+    }
+
+    /// Politely close the connection
+    pub async fn unbind(mut self) {
+        // The server closes the connection on its side anyway
+        let _ = self.send(&Request::Unbind).await;
+        let _ = self.stream.shutdown().await;
+    }
+}
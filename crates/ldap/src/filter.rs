@@ -0,0 +1,353 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! LDAP search filters, from their string representation (RFC 4515) to BER
+
+use thiserror::Error;
+
+use crate::ber::{self, OCTET_STRING, SEQUENCE};
+
+const AND: u8 = 0xa0;
+const OR: u8 = 0xa1;
+const NOT: u8 = 0xa2;
+const EQUALITY_MATCH: u8 = 0xa3;
+const SUBSTRINGS: u8 = 0xa4;
+const GREATER_OR_EQUAL: u8 = 0xa5;
+const LESS_OR_EQUAL: u8 = 0xa6;
+const PRESENT: u8 = 0x87;
+const APPROX_MATCH: u8 = 0xa8;
+
+const SUBSTRING_INITIAL: u8 = 0x80;
+const SUBSTRING_ANY: u8 = 0x81;
+const SUBSTRING_FINAL: u8 = 0x82;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FilterError {
+    #[error("unexpected end of filter")]
+    UnexpectedEnd,
+
+    #[error("unexpected character {0:?} at offset {1}")]
+    UnexpectedCharacter(char, usize),
+
+    #[error("invalid escape sequence at offset {0}")]
+    InvalidEscape(usize),
+
+    #[error("missing attribute description at offset {0}")]
+    MissingAttribute(usize),
+
+    #[error("trailing data after filter at offset {0}")]
+    TrailingData(usize),
+}
+
+/// Escape a value so that it can be inserted in a filter, as per RFC 4515
+/// section 3
+#[must_use]
+pub fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => out.push_str("\\2a"),
+            '(' => out.push_str("\\28"),
+            ')' => out.push_str("\\29"),
+            '\\' => out.push_str("\\5c"),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escape a value so that it can be used as an attribute value in a DN, as
+/// per RFC 4514 section 2.4
+#[must_use]
+pub fn escape_dn_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if i == 0 || i == last => out.push_str("\\ "),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parse a filter in its string representation and encode it
+pub(crate) fn parse(filter: &str) -> Result<Vec<u8>, FilterError> {
+    let mut parser = Parser {
+        input: filter.as_bytes(),
+        pos: 0,
+    };
+
+    let encoded = parser.filter()?;
+    if parser.pos == parser.input.len() {
+        Ok(encoded)
+    } else {
+        Err(FilterError::TrailingData(parser.pos))
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Result<u8, FilterError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or(FilterError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), FilterError> {
+        let found = self.peek()?;
+        if found == expected {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(FilterError::UnexpectedCharacter(
+                char::from(found),
+                self.pos,
+            ))
+        }
+    }
+
+    fn filter(&mut self) -> Result<Vec<u8>, FilterError> {
+        self.expect(b'(')?;
+
+        let encoded = match self.peek()? {
+            b'&' => {
+                self.pos += 1;
+                self.filter_list(AND)?
+            }
+            b'|' => {
+                self.pos += 1;
+                self.filter_list(OR)?
+            }
+            b'!' => {
+                self.pos += 1;
+                ber::encode(NOT, &self.filter()?)
+            }
+            _ => self.item()?,
+        };
+
+        self.expect(b')')?;
+        Ok(encoded)
+    }
+
+    fn filter_list(&mut self, tag: u8) -> Result<Vec<u8>, FilterError> {
+        let mut filters = Vec::new();
+        while self.peek()? == b'(' {
+            filters.push(self.filter()?);
+        }
+        Ok(ber::constructed(tag, &filters))
+    }
+
+    fn item(&mut self) -> Result<Vec<u8>, FilterError> {
+        let start = self.pos;
+        while !matches!(self.peek()?, b'=' | b'~' | b'>' | b'<') {
+            let c = self.peek()?;
+            if !(c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b';') {
+                return Err(FilterError::UnexpectedCharacter(char::from(c), self.pos));
+            }
+            self.pos += 1;
+        }
+
+        let attribute = &self.input[start..self.pos];
+        if attribute.is_empty() {
+            return Err(FilterError::MissingAttribute(start));
+        }
+
+        let tag = match self.peek()? {
+            b'~' => Some(APPROX_MATCH),
+            b'>' => Some(GREATER_OR_EQUAL),
+            b'<' => Some(LESS_OR_EQUAL),
+            _ => None,
+        };
+        if tag.is_some() {
+            self.pos += 1;
+        }
+        self.expect(b'=')?;
+
+        let value_start = self.pos;
+        let parts = self.value()?;
+        let description = ber::octet_string(OCTET_STRING, attribute);
+
+        let encoded = match (tag, parts.as_slice()) {
+            (Some(tag), [value]) => {
+                ber::constructed(tag, &[description, ber::octet_string(OCTET_STRING, value)])
+            }
+            (Some(_), _) => {
+                return Err(FilterError::UnexpectedCharacter('*', value_start));
+            }
+            (None, [value]) => ber::constructed(
+                EQUALITY_MATCH,
+                &[description, ber::octet_string(OCTET_STRING, value)],
+            ),
+            (None, [initial, last]) if initial.is_empty() && last.is_empty() => {
+                ber::octet_string(PRESENT, attribute)
+            }
+            (None, [initial, any @ .., last]) => {
+                let mut substrings = Vec::new();
+                if !initial.is_empty() {
+                    substrings.push(ber::octet_string(SUBSTRING_INITIAL, initial));
+                }
+                for value in any {
+                    if value.is_empty() {
+                        // Two consecutive stars
+                        return Err(FilterError::UnexpectedCharacter('*', value_start));
+                    }
+                    substrings.push(ber::octet_string(SUBSTRING_ANY, value));
+                }
+                if !last.is_empty() {
+                    substrings.push(ber::octet_string(SUBSTRING_FINAL, last));
+                }
+                ber::constructed(
+                    SUBSTRINGS,
+                    &[description, ber::constructed(SEQUENCE, &substrings)],
+                )
+            }
+            (None, []) => unreachable!("a value always has at least one part"),
+        };
+
+        Ok(encoded)
+    }
+
+    /// Read an assertion value, split on the unescaped stars
+    fn value(&mut self) -> Result<Vec<Vec<u8>>, FilterError> {
+        let mut parts = vec![Vec::new()];
+        loop {
+            match self.peek()? {
+                b')' => return Ok(parts),
+                b'(' => {
+                    return Err(FilterError::UnexpectedCharacter('(', self.pos));
+                }
+                b'*' => {
+                    self.pos += 1;
+                    parts.push(Vec::new());
+                }
+                b'\\' => {
+                    let escape = self
+                        .input
+                        .get(self.pos + 1..self.pos + 3)
+                        .and_then(|hex| std::str::from_utf8(hex).ok())
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or(FilterError::InvalidEscape(self.pos))?;
+                    parts.last_mut().unwrap().push(escape);
+                    self.pos += 3;
+                }
+                c => {
+                    parts.last_mut().unwrap().push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_equality() {
+        assert_eq!(
+            parse("(uid=alice)").unwrap(),
+            b"\xa3\x0c\x04\x03uid\x04\x05alice"
+        );
+    }
+
+    #[test]
+    fn encode_compound() {
+        let filter = parse("(&(objectClass=*)(|(cn=al*ce)(!(mail>=a\\2ab))))").unwrap();
+
+        let present = ber::octet_string(PRESENT, b"objectClass");
+        let substrings = ber::constructed(
+            SUBSTRINGS,
+            &[
+                ber::octet_string(OCTET_STRING, b"cn"),
+                ber::constructed(
+                    SEQUENCE,
+                    &[
+                        ber::octet_string(SUBSTRING_INITIAL, b"al"),
+                        ber::octet_string(SUBSTRING_FINAL, b"ce"),
+                    ],
+                ),
+            ],
+        );
+        let greater = ber::constructed(
+            GREATER_OR_EQUAL,
+            &[
+                ber::octet_string(OCTET_STRING, b"mail"),
+                ber::octet_string(OCTET_STRING, b"a*b"),
+            ],
+        );
+        let expected = ber::constructed(
+            AND,
+            &[
+                present,
+                ber::constructed(OR, &[substrings, ber::encode(NOT, &greater)]),
+            ],
+        );
+
+        assert_eq!(filter, expected);
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert_eq!(
+            parse("uid=alice"),
+            Err(FilterError::UnexpectedCharacter('u', 0))
+        );
+        assert_eq!(parse("(uid=alice"), Err(FilterError::UnexpectedEnd));
+        assert_eq!(parse("(=alice)"), Err(FilterError::MissingAttribute(1)));
+        assert_eq!(parse("(uid=a\\zz)"), Err(FilterError::InvalidEscape(6)));
+        assert_eq!(
+            parse("(uid>=a*)"),
+            Err(FilterError::UnexpectedCharacter('*', 6))
+        );
+        assert_eq!(parse("(uid=a)(cn=b)"), Err(FilterError::TrailingData(7)));
+    }
+
+    #[test]
+    fn escaped_values_stay_literal() {
+        let username = "*)(uid=*";
+        let filter = format!("(uid={})", escape_value(username));
+        assert_eq!(filter, "(uid=\\2a\\29\\28uid=\\2a)");
+
+        let expected = ber::constructed(
+            EQUALITY_MATCH,
+            &[
+                ber::octet_string(OCTET_STRING, b"uid"),
+                ber::octet_string(OCTET_STRING, username.as_bytes()),
+            ],
+        );
+        assert_eq!(parse(&filter).unwrap(), expected);
+    }
+
+    #[test]
+    fn escape_dn() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("a,b+c"), "a\\,b\\+c");
+        assert_eq!(escape_dn_value("#a b "), "\\#a b\\ ");
+        assert_eq!(escape_dn_value(" "), "\\ ");
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Check user passwords against an LDAP directory
+//!
+//! This implements just enough of LDAP version 3 to look up users and bind as them,
+//! optionally over TLS or `StartTLS`.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all, rustdoc::broken_intra_doc_links)]
+#![warn(clippy::pedantic)]
+#![allow(clippy::module_name_repetitions)]
+
+mod backend;
+mod ber;
+mod client;
+mod filter;
+mod protocol;
+
+pub use self::{
+    backend::LdapBackend,
+    ber::BerError,
+    client::LdapError,
+    filter::{escape_dn_value, escape_value, FilterError},
+};
@@ -0,0 +1,288 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The subset of LDAP version 3 messages needed to check passwords, as defined in RFC
+//! 4511 section 4
+
+use crate::ber::{self, BerError, Reader, ENUMERATED, INTEGER, OCTET_STRING, SEQUENCE, SET};
+
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const EXTENDED_REQUEST: u8 = 0x77;
+const EXTENDED_RESPONSE: u8 = 0x78;
+
+const SIMPLE_AUTHENTICATION: u8 = 0x80;
+const EXTENDED_REQUEST_NAME: u8 = 0x80;
+
+/// The OID of the `StartTLS` extended operation, from RFC 4511 section 4.14
+pub(crate) const START_TLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+
+/// The result codes we care about, from RFC 4511 appendix A
+pub(crate) const SUCCESS: i64 = 0;
+pub(crate) const NO_SUCH_OBJECT: i64 = 32;
+pub(crate) const INVALID_CREDENTIALS: i64 = 49;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Scope {
+    BaseObject = 0,
+    WholeSubtree = 2,
+}
+
+pub(crate) enum Request<'a> {
+    Bind {
+        dn: &'a str,
+        password: &'a str,
+    },
+    Search {
+        base: &'a str,
+        scope: Scope,
+        filter: &'a [u8],
+        attributes: &'a [&'a str],
+    },
+    Extended {
+        oid: &'a str,
+    },
+    Unbind,
+}
+
+impl Request<'_> {
+    /// Wrap the request in an `LDAPMessage` and encode it
+    pub fn encode(&self, message_id: i32) -> Vec<u8> {
+        let op = match self {
+            Request::Bind { dn, password } => ber::constructed(
+                BIND_REQUEST,
+                &[
+                    ber::integer(INTEGER, 3),
+                    ber::octet_string(OCTET_STRING, dn.as_bytes()),
+                    ber::octet_string(SIMPLE_AUTHENTICATION, password.as_bytes()),
+                ],
+            ),
+            Request::Search {
+                base,
+                scope,
+                filter,
+                attributes,
+            } => {
+                let attributes: Vec<_> = attributes
+                    .iter()
+                    .map(|a| ber::octet_string(OCTET_STRING, a.as_bytes()))
+                    .collect();
+                ber::constructed(
+                    SEARCH_REQUEST,
+                    &[
+                        ber::octet_string(OCTET_STRING, base.as_bytes()),
+                        ber::integer(ENUMERATED, *scope as i64),
+                        // Never dereference aliases
+                        ber::integer(ENUMERATED, 0),
+                        // No size or time limit, the server enforces its own
+                        ber::integer(INTEGER, 0),
+                        ber::integer(INTEGER, 0),
+                        ber::boolean(false),
+                        filter.to_vec(),
+                        ber::constructed(SEQUENCE, &attributes),
+                    ],
+                )
+            }
+            Request::Extended { oid } => ber::constructed(
+                EXTENDED_REQUEST,
+                &[ber::octet_string(EXTENDED_REQUEST_NAME, oid.as_bytes())],
+            ),
+            Request::Unbind => ber::encode(UNBIND_REQUEST, &[]),
+        };
+
+        ber::constructed(SEQUENCE, &[ber::integer(INTEGER, message_id.into()), op])
+    }
+}
+
+/// The outcome of an operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LdapResult {
+    pub code: i64,
+    pub message: String,
+}
+
+impl LdapResult {
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, BerError> {
+        let code = reader.read_integer(ENUMERATED)?;
+        let _matched_dn = reader.read(OCTET_STRING)?;
+        let message = reader.read_string(OCTET_STRING)?;
+        // Ignore the referrals and operation-specific fields which may follow
+        Ok(Self { code, message })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code == SUCCESS
+    }
+}
+
+/// An entry returned by a search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SearchEntry {
+    pub dn: String,
+    pub attributes: Vec<(String, Vec<String>)>,
+}
+
+impl SearchEntry {
+    /// Get the first value of an attribute, matching its name
+    /// case-insensitively like LDAP does
+    pub fn first_value(&self, attribute: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+            .and_then(|(_, values)| values.first())
+            .map(String::as_str)
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, BerError> {
+        let dn = reader.read_string(OCTET_STRING)?;
+        let mut list = reader.read_constructed(SEQUENCE)?;
+        let mut attributes = Vec::new();
+        while !list.is_empty() {
+            let mut attribute = list.read_constructed(SEQUENCE)?;
+            let name = attribute.read_string(OCTET_STRING)?;
+            let mut set = attribute.read_constructed(SET)?;
+            let mut values = Vec::new();
+            while !set.is_empty() {
+                values.push(set.read_string(OCTET_STRING)?);
+            }
+            attributes.push((name, values));
+        }
+
+        Ok(Self { dn, attributes })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Response {
+    Bind(LdapResult),
+    SearchEntry(SearchEntry),
+    SearchReference,
+    SearchDone(LdapResult),
+    Extended(LdapResult),
+    /// A response we do not know how to handle, with its tag
+    Other(u8),
+}
+
+/// Decode an `LDAPMessage`, returning its ID and the response it holds
+pub(crate) fn decode_response(message: &[u8]) -> Result<(i32, Response), BerError> {
+    let mut reader = Reader::new(message);
+    let mut message = reader.read_constructed(SEQUENCE)?;
+    let id = message.read_integer(INTEGER)?;
+    let id = i32::try_from(id).map_err(|_| BerError::InvalidInteger)?;
+
+    let (tag, content) = message.read_any()?;
+    let mut op = Reader::new(content);
+    let response = match tag {
+        BIND_RESPONSE => Response::Bind(LdapResult::decode(&mut op)?),
+        SEARCH_RESULT_ENTRY => Response::SearchEntry(SearchEntry::decode(&mut op)?),
+        SEARCH_RESULT_REFERENCE => Response::SearchReference,
+        SEARCH_RESULT_DONE => Response::SearchDone(LdapResult::decode(&mut op)?),
+        EXTENDED_RESPONSE => Response::Extended(LdapResult::decode(&mut op)?),
+        other => Response::Other(other),
+    };
+
+    Ok((id, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_bind() {
+        let message = Request::Bind {
+            dn: "cn=admin",
+            password: "pw",
+        }
+        .encode(1);
+
+        assert_eq!(
+            message,
+            b"\x30\x16\x02\x01\x01\x60\x11\x02\x01\x03\x04\x08cn=admin\x80\x02pw"
+        );
+    }
+
+    #[test]
+    fn decode_search_entry() {
+        let message = ber::constructed(
+            SEQUENCE,
+            &[
+                ber::integer(INTEGER, 2),
+                ber::constructed(
+                    SEARCH_RESULT_ENTRY,
+                    &[
+                        ber::octet_string(OCTET_STRING, b"uid=alice,dc=example,dc=org"),
+                        ber::constructed(
+                            SEQUENCE,
+                            &[ber::constructed(
+                                SEQUENCE,
+                                &[
+                                    ber::octet_string(OCTET_STRING, b"mail"),
+                                    ber::constructed(
+                                        SET,
+                                        &[ber::octet_string(OCTET_STRING, b"alice@example.org")],
+                                    ),
+                                ],
+                            )],
+                        ),
+                    ],
+                ),
+            ],
+        );
+
+        let (id, response) = decode_response(&message).unwrap();
+        assert_eq!(id, 2);
+        let entry = match response {
+            Response::SearchEntry(entry) => entry,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(entry.dn, "uid=alice,dc=example,dc=org");
+        assert_eq!(entry.first_value("MAIL"), Some("alice@example.org"));
+        assert_eq!(entry.first_value("cn"), None);
+    }
+
+    #[test]
+    fn decode_bind_response() {
+        let message = ber::constructed(
+            SEQUENCE,
+            &[
+                ber::integer(INTEGER, 1),
+                ber::constructed(
+                    BIND_RESPONSE,
+                    &[
+                        ber::integer(ENUMERATED, INVALID_CREDENTIALS),
+                        ber::octet_string(OCTET_STRING, b""),
+                        ber::octet_string(OCTET_STRING, b"bad password"),
+                    ],
+                ),
+            ],
+        );
+
+        assert_eq!(
+            decode_response(&message),
+            Ok((
+                1,
+                Response::Bind(LdapResult {
+                    code: INVALID_CREDENTIALS,
+                    message: "bad password".to_string(),
+                })
+            ))
+        );
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests against a real OpenLDAP server, see `fixtures/README.md` to set it up
+
+use std::time::Duration;
+
+use mas_config::{LdapAttributesConfig, LdapBindConfig, LdapServerConfig};
+use mas_ldap::LdapBackend;
+use mas_storage::password::{ExternalUser, PasswordBackend, PasswordVerification};
+use url::Url;
+
+async fn backend(bind: LdapBindConfig) -> LdapBackend {
+    let url = std::env::var("MAS_LDAP_TEST_URL").expect("MAS_LDAP_TEST_URL is not set");
+
+    LdapBackend::from_config(&LdapServerConfig {
+        url: Url::parse(&url).unwrap(),
+        starttls: false,
+        ca_certificate: None,
+        bind,
+        attributes: LdapAttributesConfig::default(),
+        timeout: Duration::from_secs(5),
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "needs a local slapd"]
+async fn search_and_bind() {
+    let backend = backend(LdapBindConfig::Search {
+        bind_dn: Some("cn=mas,dc=example,dc=org".to_string()),
+        bind_password: Some("service-password".to_string()),
+        base_dn: "ou=people,dc=example,dc=org".to_string(),
+        filter: "(&(objectClass=inetOrgPerson)(uid={username}))".to_string(),
+    })
+    .await;
+
+    assert_eq!(
+        backend
+            .verify_password("alice", "alice-password")
+            .await
+            .unwrap(),
+        PasswordVerification::Valid(ExternalUser {
+            username: "alice".to_string(),
+            email: Some("alice@example.org".to_string()),
+            displayname: Some("Alice".to_string()),
+        })
+    );
+    assert_eq!(
+        backend.verify_password("alice", "wrong").await.unwrap(),
+        PasswordVerification::Invalid
+    );
+    assert_eq!(
+        backend.verify_password("carol", "wrong").await.unwrap(),
+        PasswordVerification::UnknownUser
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs a local slapd"]
+async fn direct_bind() {
+    let backend = backend(LdapBindConfig::Direct {
+        dn_template: "uid={username},ou=people,dc=example,dc=org".to_string(),
+    })
+    .await;
+
+    assert_eq!(
+        backend
+            .verify_password("bob", "bob-password")
+            .await
+            .unwrap(),
+        PasswordVerification::Valid(ExternalUser {
+            username: "bob".to_string(),
+            email: None,
+            displayname: Some("Bob".to_string()),
+        })
+    );
+    assert_eq!(
+        backend.verify_password("bob", "wrong").await.unwrap(),
+        PasswordVerification::UnknownUser
+    );
+}
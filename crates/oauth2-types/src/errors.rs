@@ -148,6 +148,35 @@ mod rfc7591 {
     );
 }
 
+pub mod ciba {
+    use super::ClientError;
+
+    pub const AUTHORIZATION_PENDING: ClientError = ClientError::new(
+        "authorization_pending",
+        "The authorization request is still pending as the end-user hasn't yet been authenticated.",
+    );
+
+    pub const SLOW_DOWN: ClientError = ClientError::new(
+        "slow_down",
+        "The authorization request is still pending and polling should continue, \
+         but the interval must be increased.",
+    );
+
+    pub const EXPIRED_TOKEN: ClientError =
+        ClientError::new("expired_token", "The auth_req_id has expired.");
+
+    pub const UNKNOWN_USER_ID: ClientError = ClientError::new(
+        "unknown_user_id",
+        "The OpenID Provider is not able to identify which end-user the Client wishes to be authenticated by means of the hint provided in the request.",
+    );
+
+    pub const INVALID_BINDING_MESSAGE: ClientError = ClientError::new(
+        "invalid_binding_message",
+        "The binding message is invalid or unacceptable for use in the context of the given request.",
+    );
+}
+
+pub use ciba::*;
 pub use oidc_core::*;
 pub use rfc6749::*;
 pub use rfc7591::*;
@@ -39,6 +39,13 @@ pub enum SubjectType {
     Pairwise,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BackchannelTokenDeliveryMode {
+    Poll,
+    Ping,
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum ClaimType {
@@ -232,4 +239,23 @@ pub struct Metadata {
 
     /// Array containing the list of prompt values that this OP supports.
     pub prompt_values_supported: Option<Vec<Prompt>>,
+
+    /// JSON array containing a list of the JWS signing algorithms supported for
+    /// signing JWT-secured authorization responses.
+    pub authorization_signing_alg_values_supported: Option<Vec<JsonWebSignatureAlg>>,
+
+    /// Boolean value indicating whether the authorization server provides the
+    /// "iss" parameter in the authorization response.
+    pub authorization_response_iss_parameter_supported: Option<bool>,
+
+    /// URL of the OP's Backchannel Authentication Endpoint.
+    pub backchannel_authentication_endpoint: Option<Url>,
+
+    /// JSON array containing one or more of the token delivery modes supported
+    /// by the OP for CIBA.
+    pub backchannel_token_delivery_modes_supported: Option<Vec<BackchannelTokenDeliveryMode>>,
+
+    /// Boolean value specifying whether the OP supports the use of the
+    /// `user_code` parameter in CIBA requests.
+    pub backchannel_user_code_parameter_supported: Option<bool>,
 }
@@ -14,7 +14,7 @@
 
 use chrono::{DateTime, Duration, Utc};
 use mas_iana::{
-    jose::{JsonWebEncryptionAlg, JsonWebSignatureAlg},
+    jose::{JsonWebEncryptionAlg, JsonWebEncryptionEnc, JsonWebSignatureAlg},
     oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod},
 };
 use mas_jose::JsonWebKeySet;
@@ -23,7 +23,7 @@ use serde_with::{serde_as, skip_serializing_none, DurationSeconds, TimestampSeco
 use url::Url;
 
 use crate::{
-    oidc::{ApplicationType, SubjectType},
+    oidc::{ApplicationType, BackchannelTokenDeliveryMode, SubjectType},
     requests::GrantType,
 };
 
@@ -97,7 +97,7 @@ pub struct ClientMetadata {
     pub id_token_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
 
     #[serde(default)]
-    pub id_token_encrypted_response_enc: Option<JsonWebEncryptionAlg>,
+    pub id_token_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
 
     #[serde(default)]
     pub userinfo_signed_response_alg: Option<JsonWebSignatureAlg>,
@@ -106,7 +106,7 @@ pub struct ClientMetadata {
     pub userinfo_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
 
     #[serde(default)]
-    pub userinfo_encrypted_response_enc: Option<JsonWebEncryptionAlg>,
+    pub userinfo_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
 
     #[serde(default)]
     pub request_object_signing_alg: Option<JsonWebSignatureAlg>,
@@ -115,7 +115,7 @@ pub struct ClientMetadata {
     pub request_object_encryption_alg: Option<JsonWebEncryptionAlg>,
 
     #[serde(default)]
-    pub request_object_encryption_enc: Option<JsonWebEncryptionAlg>,
+    pub request_object_encryption_enc: Option<JsonWebEncryptionEnc>,
 
     #[serde(default)]
     #[serde_as(as = "Option<DurationSeconds<i64>>")]
@@ -146,7 +146,16 @@ pub struct ClientMetadata {
     pub introspection_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
 
     #[serde(default)]
-    pub introspection_encrypted_response_enc: Option<JsonWebEncryptionAlg>,
+    pub introspection_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
+
+    #[serde(default)]
+    pub authorization_signed_response_alg: Option<JsonWebSignatureAlg>,
+
+    #[serde(default)]
+    pub backchannel_token_delivery_mode: Option<BackchannelTokenDeliveryMode>,
+
+    #[serde(default)]
+    pub backchannel_client_notification_endpoint: Option<Url>,
 }
 
 #[serde_as]
@@ -50,6 +50,33 @@ pub enum ResponseMode {
     Query,
     Fragment,
     FormPost,
+
+    /// JWT Secured Authorization Response Mode, as defined by [JARM]
+    ///
+    /// [JARM]: https://openid.net/specs/oauth-v2-jarm.html
+    #[serde(rename = "query.jwt")]
+    QueryJwt,
+
+    #[serde(rename = "fragment.jwt")]
+    FragmentJwt,
+
+    #[serde(rename = "form_post.jwt")]
+    FormPostJwt,
+
+    /// Use the default JWT-secured response mode for the response type, either
+    /// `query.jwt` or `fragment.jwt`
+    Jwt,
+}
+
+impl ResponseMode {
+    /// Whether the response is wrapped in a signed JWT
+    #[must_use]
+    pub const fn is_jwt(self) -> bool {
+        matches!(
+            self,
+            Self::QueryJwt | Self::FragmentJwt | Self::FormPostJwt | Self::Jwt
+        )
+    }
 }
 
 #[derive(
@@ -127,13 +154,13 @@ pub struct AuthorizationRequest {
     #[serde(default)]
     ui_locales: Option<Vec<LanguageTag>>,
 
-    id_token_hint: Option<String>,
+    pub id_token_hint: Option<String>,
 
-    login_hint: Option<String>,
+    pub login_hint: Option<String>,
 
     #[serde_as(as = "Option<StringWithSeparator::<SpaceSeparator, String>>")]
     #[serde(default)]
-    acr_values: Option<HashSet<String>>,
+    pub acr_values: Option<HashSet<String>>,
 
     pub request: Option<String>,
 
@@ -195,6 +222,13 @@ pub enum GrantType {
     RefreshToken,
     Implicit,
     ClientCredentials,
+    #[serde(rename = "urn:openid:params:grant-type:ciba")]
+    ClientInitiatedBackchannelAuthentication,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CibaGrant {
+    pub auth_req_id: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -203,6 +237,8 @@ pub enum AccessTokenRequest {
     AuthorizationCode(AuthorizationCodeGrant),
     RefreshToken(RefreshTokenGrant),
     ClientCredentials(ClientCredentialsGrant),
+    #[serde(rename = "urn:openid:params:grant-type:ciba")]
+    ClientInitiatedBackchannelAuthentication(CibaGrant),
     #[serde(skip_deserializing, other)]
     Unsupported,
 }
@@ -260,6 +296,59 @@ impl AccessTokenResponse {
         self.expires_in = Some(expires_in);
         self
     }
+
+    #[must_use]
+    pub fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    #[must_use]
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_deref()
+    }
+}
+
+/// Request sent by a client to the backchannel authentication endpoint, as
+/// defined by [OpenID Connect CIBA]
+///
+/// [OpenID Connect CIBA]: https://openid.net/specs/openid-client-initiated-backchannel-authentication-core-1_0.html
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BackchannelAuthenticationRequest {
+    pub scope: Scope,
+
+    #[serde(default)]
+    pub client_notification_token: Option<String>,
+
+    #[serde(default)]
+    pub login_hint: Option<String>,
+
+    #[serde(default)]
+    pub login_hint_token: Option<String>,
+
+    #[serde(default)]
+    pub id_token_hint: Option<String>,
+
+    #[serde(default)]
+    pub binding_message: Option<String>,
+
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationSeconds<i64>>")]
+    pub requested_expiry: Option<Duration>,
+}
+
+#[serde_as]
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BackchannelAuthenticationResponse {
+    pub auth_req_id: String,
+
+    #[serde_as(as = "DurationSeconds<i64>")]
+    pub expires_in: Duration,
+
+    #[serde_as(as = "Option<DurationSeconds<i64>>")]
+    pub interval: Option<Duration>,
 }
 
 #[skip_serializing_none]
@@ -301,6 +390,10 @@ pub struct IntrospectionResponse {
     pub iss: Option<String>,
 
     pub jti: Option<String>,
+
+    pub acr: Option<String>,
+
+    pub amr: Option<Vec<String>>,
 }
 
 #[cfg(test)]
@@ -347,4 +440,26 @@ mod tests {
 
         assert_serde_json(&req, expected);
     }
+
+    #[test]
+    fn serde_ciba_grant() {
+        let expected = json!({
+            "grant_type": "urn:openid:params:grant-type:ciba",
+            "auth_req_id": "abcd",
+        });
+
+        let req = AccessTokenRequest::ClientInitiatedBackchannelAuthentication(CibaGrant {
+            auth_req_id: "abcd".into(),
+        });
+
+        assert_serde_json(&req, expected);
+    }
+
+    #[test]
+    fn serde_jwt_response_modes() {
+        assert_serde_json(&ResponseMode::QueryJwt, json!("query.jwt"));
+        assert_serde_json(&ResponseMode::FragmentJwt, json!("fragment.jwt"));
+        assert_serde_json(&ResponseMode::FormPostJwt, json!("form_post.jwt"));
+        assert_serde_json(&ResponseMode::Jwt, json!("jwt"));
+    }
 }
@@ -16,6 +16,12 @@ violation[{"field": "username", "msg": "username too long"}] {
 	count(input.user.username) >= 15
 }
 
+# Users registering through an upstream provider don't have a password, so the
+# password rules don't apply to them
+has_password {
+	input.user.password
+}
+
 violation[{"field": "password", "msg": msg}] {
 	count(input.user.password) < data.passwords.min_length
 	msg := sprintf("needs to be at least %d characters", [data.passwords.min_length])
@@ -23,24 +29,43 @@ violation[{"field": "password", "msg": msg}] {
 
 violation[{"field": "password", "msg": "requires at least one number"}] {
 	data.passwords.require_number
+	has_password
 	not regex.match("[0-9]", input.user.password)
 }
 
 violation[{"field": "password", "msg": "requires at least one lowercase letter"}] {
 	data.passwords.require_lowercase
+	has_password
 	not regex.match("[a-z]", input.user.password)
 }
 
 violation[{"field": "password", "msg": "requires at least one uppercase letter"}] {
 	data.passwords.require_uppercase
+	has_password
 	not regex.match("[A-Z]", input.user.password)
 }
 
+# The strength score goes from 0 (too guessable) to 4 (very unguessable)
+violation[{"field": "password", "msg": "is too easy to guess"}] {
+	input.user.password_strength < data.passwords.min_strength
+}
+
+# Only set when a breached passwords dataset is configured
+violation[{"field": "password", "msg": "this password appeared in a data breach"}] {
+	input.user.password_breached
+}
+
 # Allow any domains if the data.allowed_domains array is not set
 email_domain_allowed {
 	not data.allowed_domains
 }
 
+# Registrations without an email, like the ones from the compatibility layer,
+# are not subject to the domain checks
+email_domain_allowed {
+	not input.user.email
+}
+
 # Allow an email only if its domain is in the list of allowed domains
 email_domain_allowed {
 	[_, domain] := split(input.user.email, "@")
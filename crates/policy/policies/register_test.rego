@@ -27,6 +27,19 @@ test_banned_subdomain {
 		with data.banned_domains as ["staging.element.io"]
 }
 
+test_no_email {
+	allow with input.user as {"username": "hello", "password": "Hunter2"}
+		with data.allowed_domains as ["*.element.io"]
+}
+
+test_no_password {
+	allow with input.user as {"username": "hello", "email": "hello@element.io"}
+		with data.passwords.min_length as 6
+		with data.passwords.require_number as true
+		with data.passwords.require_lowercase as true
+		with data.passwords.require_uppercase as true
+}
+
 test_short_username {
 	not allow with input.user as {"username": "a", "email": "hello@element.io"}
 }
@@ -70,3 +83,21 @@ test_password_min_length {
 		with input.user.password as "short"
 		with data.passwords.min_length as 6
 }
+
+test_password_breached {
+	allow with input.user as mock_user
+		with input.user.password_breached as false
+
+	not allow with input.user as mock_user
+		with input.user.password_breached as true
+}
+
+test_password_min_strength {
+	allow with input.user as mock_user
+		with input.user.password_strength as 3
+		with data.passwords.min_strength as 3
+
+	not allow with input.user as mock_user
+		with input.user.password_strength as 2
+		with data.passwords.min_strength as 3
+}
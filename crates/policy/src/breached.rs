@@ -0,0 +1,215 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Offline lookup of passwords which appeared in known data breaches.
+//!
+//! Two layouts of the [Pwned Passwords] dataset are supported:
+//!
+//!  - a directory of range files, as produced by the official downloader. Each
+//!    file is named after the first 5 hex characters of the SHA-1 hash (e.g.
+//!    `21BD1.txt`) and contains one `SUFFIX:COUNT` line per hash. Only the file
+//!    matching the hash prefix is read.
+//!  - a single file with one `HASH:COUNT` line per hash, ordered by hash, which
+//!    is binary-searched
+//!
+//! [Pwned Passwords]: https://haveibeenpwned.com/Passwords
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+/// Length of the hash prefix used to name the range files
+const PREFIX_LEN: usize = 5;
+
+#[derive(Debug, Clone)]
+enum Layout {
+    Ranges,
+    Ordered,
+}
+
+#[derive(Debug, Clone)]
+pub struct BreachedPasswords {
+    path: PathBuf,
+    layout: Layout,
+}
+
+impl BreachedPasswords {
+    /// Use the dataset at the given path, which can either be a directory of
+    /// range files or a single file ordered by hash
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the path can't be accessed
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let layout = if std::fs::metadata(&path)?.is_dir() {
+            Layout::Ranges
+        } else {
+            Layout::Ordered
+        };
+
+        Ok(Self { path, layout })
+    }
+
+    /// Check whether the password is in the dataset
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dataset could not be read
+    pub async fn contains(&self, password: &str) -> anyhow::Result<bool> {
+        let hash = sha1_hex(password);
+        let this = self.clone();
+        let found = tokio::task::spawn_blocking(move || match this.layout {
+            Layout::Ranges => search_range(&this.path, &hash),
+            Layout::Ordered => search_ordered(&this.path, &hash),
+        })
+        .await??;
+
+        Ok(found)
+    }
+}
+
+fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+/// Split a `HASH:COUNT` line, returning the uppercased hash part
+fn line_hash(line: &[u8]) -> Vec<u8> {
+    line.iter()
+        .take_while(|&&c| c != b':' && c != b'\r' && c != b'\n')
+        .map(u8::to_ascii_uppercase)
+        .collect()
+}
+
+fn search_range(directory: &Path, hash: &str) -> std::io::Result<bool> {
+    let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+    let file = match File::open(directory.join(format!("{}.txt", prefix))) {
+        Ok(file) => file,
+        // No range file means no breached password with this prefix
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    for line in BufReader::new(file).split(b'\n') {
+        if line_hash(&line?) == suffix.as_bytes() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn search_ordered(path: &Path, hash: &str) -> std::io::Result<bool> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let target = hash.as_bytes();
+    let mut line = Vec::new();
+
+    // Invariant: if the hash is in the file, its line starts in [lo, hi)
+    let mut lo = 0;
+    let mut hi = reader.get_ref().metadata()?.len();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        // Find the first line starting at or after `mid`
+        let start = if mid == 0 {
+            reader.seek(SeekFrom::Start(0))?;
+            0
+        } else {
+            reader.seek(SeekFrom::Start(mid - 1))?;
+            line.clear();
+            let skipped = reader.read_until(b'\n', &mut line)?;
+            mid - 1 + skipped as u64
+        };
+
+        if start >= hi {
+            hi = mid;
+            continue;
+        }
+
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        match line_hash(&line).as_slice().cmp(target) {
+            std::cmp::Ordering::Equal => return Ok(true),
+            std::cmp::Ordering::Less => lo = start + read as u64,
+            std::cmp::Ordering::Greater => hi = mid,
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ordered_file() {
+        let mut hashes: Vec<String> = ["hunter2", "password", "123456", "letmein", "qwerty"]
+            .into_iter()
+            .map(sha1_hex)
+            .collect();
+        hashes.sort();
+
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        for (count, hash) in hashes.iter().enumerate() {
+            write!(file, "{}:{}\r\n", hash, count + 1).unwrap();
+        }
+        drop(file);
+
+        let breached = BreachedPasswords::open(&path).unwrap();
+        for password in ["hunter2", "password", "123456", "letmein", "qwerty"] {
+            assert!(breached.contains(password).await.unwrap());
+        }
+        assert!(!breached
+            .contains("correct horse battery staple")
+            .await
+            .unwrap());
+        assert!(!breached.contains("").await.unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_range_directory() {
+        let hash = sha1_hex("hunter2");
+        let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+
+        let path = std::env::temp_dir().join(format!("breached-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(
+            path.join(format!("{}.txt", prefix)),
+            format!("0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n{}:17\r\n", suffix),
+        )
+        .unwrap();
+
+        let breached = BreachedPasswords::open(&path).unwrap();
+        assert!(breached.contains("hunter2").await.unwrap());
+        assert!(!breached
+            .contains("correct horse battery staple")
+            .await
+            .unwrap());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
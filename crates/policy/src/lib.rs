@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod breached;
+pub mod strength;
+
 use std::io::Cursor;
 
 use anyhow::bail;
@@ -22,6 +25,8 @@ use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use wasmtime::{Config, Engine, Module, Store};
 
+pub use self::{breached::BreachedPasswords, strength::Feedback as PasswordFeedback};
+
 const DEFAULT_POLICY: &[u8] = include_bytes!("../policies/policy.wasm");
 
 pub fn default_wasm_policy() -> impl AsyncRead + std::marker::Unpin {
@@ -52,6 +57,7 @@ pub struct PolicyFactory {
     data: serde_json::Value,
     register_entrypoint: String,
     client_registration_entrypoint: String,
+    breached_passwords: Option<BreachedPasswords>,
 }
 
 impl PolicyFactory {
@@ -84,6 +90,7 @@ impl PolicyFactory {
             data,
             register_entrypoint,
             client_registration_entrypoint,
+            breached_passwords: None,
         };
 
         // Try to instanciate
@@ -95,6 +102,14 @@ impl PolicyFactory {
         Ok(factory)
     }
 
+    /// Check passwords against a dataset of breached passwords, and expose the
+    /// result to the policy as `input.user.password_breached`
+    #[must_use]
+    pub fn with_breached_passwords(mut self, breached_passwords: BreachedPasswords) -> Self {
+        self.breached_passwords = Some(breached_passwords);
+        self
+    }
+
     pub async fn instantiate(&self) -> Result<Policy, anyhow::Error> {
         let mut store = Store::new(&self.engine, ());
         let runtime = Runtime::new(&mut store, &self.module).await?;
@@ -118,6 +133,7 @@ impl PolicyFactory {
             instance,
             register_entrypoint: self.register_entrypoint.clone(),
             client_registration_entrypoint: self.client_registration_entrypoint.clone(),
+            breached_passwords: self.breached_passwords.clone(),
         })
     }
 }
@@ -132,6 +148,10 @@ pub struct Violation {
 pub struct EvaluationResult {
     #[serde(rename = "result")]
     pub violations: Vec<Violation>,
+
+    /// How to make the password stronger, if it was rejected
+    #[serde(skip)]
+    pub password_feedback: Option<PasswordFeedback>,
 }
 
 impl EvaluationResult {
@@ -146,29 +166,63 @@ pub struct Policy {
     instance: opa_wasm::Policy,
     register_entrypoint: String,
     client_registration_entrypoint: String,
+    breached_passwords: Option<BreachedPasswords>,
 }
 
 impl Policy {
-    #[tracing::instrument]
+    #[tracing::instrument(skip(password))]
     pub async fn evaluate_register(
         &mut self,
         username: &str,
-        password: &str,
-        email: &str,
+        password: Option<&str>,
+        email: Option<&str>,
     ) -> Result<EvaluationResult, anyhow::Error> {
-        let input = serde_json::json!({
-            "user": {
-                "username": username,
-                "password": password,
-                "email": email
-            }
+        let mut user = serde_json::json!({
+            "username": username,
         });
 
-        let [res]: [EvaluationResult; 1] = self
+        // Registrations through an upstream provider don't have a password
+        let mut password_feedback = None;
+        if let Some(password) = password {
+            user["password"] = password.into();
+
+            // Passwords should not contain the username or parts of the email
+            let user_inputs: Vec<&str> = std::iter::once(username)
+                .chain(
+                    email
+                        .into_iter()
+                        .flat_map(|email| email.split(&['@', '.'][..])),
+                )
+                .collect();
+            let strength = strength::estimate(password, &user_inputs);
+            user["password_strength"] = strength.score.into();
+            password_feedback = Some(strength.feedback);
+
+            if let Some(breached_passwords) = &self.breached_passwords {
+                user["password_breached"] = breached_passwords.contains(password).await?.into();
+            }
+        }
+
+        // Registrations through the compatibility layer don't have an email
+        if let Some(email) = email {
+            user["email"] = email.into();
+        }
+
+        let input = serde_json::json!({ "user": user });
+
+        let [mut res]: [EvaluationResult; 1] = self
             .instance
             .evaluate(&mut self.store, &self.register_entrypoint, &input)
             .await?;
 
+        let password_rejected = res
+            .violations
+            .iter()
+            .any(|v| v.field.as_deref() == Some("password"));
+        if password_rejected {
+            res.password_feedback = password_feedback.filter(|feedback| !feedback.is_empty());
+        }
+
         Ok(res)
     }
 
@@ -216,21 +270,33 @@ mod tests {
         let mut policy = factory.instantiate().await.unwrap();
 
         let res = policy
-            .evaluate_register("hello", "hunter2", "hello@example.com")
+            .evaluate_register("hello", Some("hunter2"), Some("hello@example.com"))
             .await
             .unwrap();
         assert!(!res.valid());
 
         let res = policy
-            .evaluate_register("hello", "hunter2", "hello@foo.element.io")
+            .evaluate_register("hello", Some("hunter2"), Some("hello@foo.element.io"))
             .await
             .unwrap();
         assert!(res.valid());
 
         let res = policy
-            .evaluate_register("hello", "hunter2", "hello@staging.element.io")
+            .evaluate_register("hello", Some("hunter2"), Some("hello@staging.element.io"))
             .await
             .unwrap();
         assert!(!res.valid());
+
+        let res = policy
+            .evaluate_register("hello", Some("hunter2"), None)
+            .await
+            .unwrap();
+        assert!(res.valid());
+
+        let res = policy
+            .evaluate_register("hello", None, Some("hello@element.io"))
+            .await
+            .unwrap();
+        assert!(res.valid());
     }
 }
@@ -0,0 +1,384 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Password strength estimation, loosely modeled after [zxcvbn].
+//!
+//! The password is split into the patterns an attacker would try first
+//! (common passwords, the user's own inputs, repeats, sequences and keyboard
+//! rows), and the rest is considered brute-forced. The number of guesses needed
+//! for each part is multiplied to get an estimate for the whole password, which
+//! is then bucketed into a score from 0 (too guessable) to 4 (very
+//! unguessable).
+//!
+//! [zxcvbn]: https://github.com/dropbox/zxcvbn
+
+use std::cmp::Ordering;
+
+use serde::Serialize;
+
+/// Common passwords and words, most common first
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "123456", "qwerty", "letmein", "admin", "welcome", "monkey", "dragon", "iloveyou",
+    "football", "baseball", "master", "sunshine", "princess", "shadow", "superman", "trustno1",
+    "hello", "freedom", "whatever", "login", "starwars", "secret", "changeme", "default", "guest",
+    "access", "flower", "hunter", "soccer", "hockey", "killer", "batman", "pokemon", "ninja",
+    "mustang", "michael", "jennifer", "charlie", "thomas", "jordan", "daniel", "pepper", "cheese",
+    "summer", "winter", "spring", "autumn", "computer", "internet", "matrix", "banana", "orange",
+    "purple", "cookie", "silver", "golden", "diamond", "money", "love", "test", "user", "root",
+    "pass",
+];
+
+/// Keyboard rows, and the digits row
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+/// Minimum length for repeats and sequences to be considered patterns
+const MIN_PATTERN_LEN: usize = 3;
+
+/// Minimum length for a keyboard row to be considered a pattern
+const MIN_KEYBOARD_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+enum Pattern {
+    Dictionary { rank: usize, whole: bool },
+    UserInput,
+    Repeat,
+    Sequence,
+    Keyboard,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Match {
+    len: usize,
+    guesses_log10: f64,
+    pattern: Pattern,
+}
+
+/// Feedback on how to make a password stronger
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Feedback {
+    /// What makes the password weak
+    pub warning: Option<String>,
+
+    /// How to make it stronger
+    pub suggestions: Vec<String>,
+}
+
+impl Feedback {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.warning.is_none() && self.suggestions.is_empty()
+    }
+}
+
+/// Estimated strength of a password
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordStrength {
+    /// From 0 (too guessable) to 4 (very unguessable)
+    pub score: u8,
+
+    /// Estimated number of guesses needed, as a power of 10
+    pub guesses_log10: f64,
+
+    /// Feedback for passwords with a score lower than 3
+    pub feedback: Feedback,
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '5' | '$' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+fn cardinality(password: &[char]) -> f64 {
+    let mut cardinality = 0;
+    if password.iter().any(char::is_ascii_lowercase) {
+        cardinality += 26;
+    }
+    if password.iter().any(char::is_ascii_uppercase) {
+        cardinality += 26;
+    }
+    if password.iter().any(char::is_ascii_digit) {
+        cardinality += 10;
+    }
+    if password.iter().any(char::is_ascii_punctuation) || password.contains(&' ') {
+        cardinality += 33;
+    }
+    if password.iter().any(|c| !c.is_ascii()) {
+        cardinality += 100;
+    }
+    f64::from(cardinality.max(1))
+}
+
+fn starts_with(haystack: &[char], needle: &str) -> bool {
+    let mut haystack = haystack.iter();
+    needle.chars().all(|c| haystack.next() == Some(&c))
+}
+
+/// Find the dictionary words and user inputs starting at the beginning of
+/// `password`
+fn dictionary_matches(password: &[char], lower: &[char], user_inputs: &[String]) -> Vec<Match> {
+    let unleeted: Vec<char> = lower.iter().copied().map(unleet).collect();
+    let mut matches = Vec::new();
+
+    let words = COMMON_PASSWORDS
+        .iter()
+        .enumerate()
+        .map(|(rank, word)| {
+            (
+                *word,
+                Pattern::Dictionary {
+                    rank: rank + 1,
+                    whole: false,
+                },
+            )
+        })
+        .chain(
+            user_inputs
+                .iter()
+                .map(|input| (input.as_str(), Pattern::UserInput)),
+        );
+
+    for (word, pattern) in words {
+        let len = word.chars().count();
+        let leet = if starts_with(lower, word) {
+            false
+        } else if starts_with(&unleeted, word) {
+            true
+        } else {
+            continue;
+        };
+
+        let mut guesses = match pattern {
+            Pattern::Dictionary { rank, .. } => rank as f64,
+            _ => 1.,
+        };
+        if password[..len].iter().any(char::is_ascii_uppercase) {
+            guesses *= 2.;
+        }
+        if leet {
+            guesses *= 2.;
+        }
+
+        matches.push(Match {
+            len,
+            guesses_log10: guesses.log10(),
+            pattern,
+        });
+    }
+
+    matches
+}
+
+/// Find a run of the same character at the beginning of `lower`
+fn repeat_match(lower: &[char], cardinality: f64) -> Option<Match> {
+    let len = lower.iter().take_while(|&&c| c == lower[0]).count();
+    (len >= MIN_PATTERN_LEN).then(|| Match {
+        len,
+        guesses_log10: (cardinality * len as f64).log10(),
+        pattern: Pattern::Repeat,
+    })
+}
+
+/// Find a sequence like `abcd` or `9876` at the beginning of `lower`
+fn sequence_match(lower: &[char]) -> Option<Match> {
+    let delta = |a: char, b: char| i64::from(u32::from(b)) - i64::from(u32::from(a));
+    let first = *lower.first()?;
+    let step = delta(first, *lower.get(1)?);
+    if step.abs() != 1 || !first.is_ascii_alphanumeric() {
+        return None;
+    }
+
+    let len = 1 + lower
+        .windows(2)
+        .take_while(|w| delta(w[0], w[1]) == step && w[1].is_ascii_alphanumeric())
+        .count();
+    if len < MIN_PATTERN_LEN {
+        return None;
+    }
+
+    let base: f64 = if first.is_ascii_digit() { 10. } else { 26. };
+    let direction = if step < 0 { 2. } else { 1. };
+    Some(Match {
+        len,
+        guesses_log10: (base * direction * len as f64).log10(),
+        pattern: Pattern::Sequence,
+    })
+}
+
+/// Find a straight run of keys on a keyboard row at the beginning of `lower`
+fn keyboard_match(lower: &[char]) -> Option<Match> {
+    KEYBOARD_ROWS
+        .iter()
+        .flat_map(|row| [row.chars().collect::<Vec<_>>(), row.chars().rev().collect()])
+        .filter_map(|row| {
+            let start = row.iter().position(|c| Some(c) == lower.first())?;
+            let len = row[start..]
+                .iter()
+                .zip(lower)
+                .take_while(|(a, b)| a == b)
+                .count();
+            (len >= MIN_KEYBOARD_LEN).then(|| Match {
+                len,
+                guesses_log10: (KEYBOARD_ROWS.len() as f64 * 20. * len as f64).log10(),
+                pattern: Pattern::Keyboard,
+            })
+        })
+        .max_by_key(|m| m.len)
+}
+
+fn feedback(pattern: Option<Pattern>) -> Feedback {
+    let warning = match pattern {
+        Some(Pattern::Dictionary { rank, whole: true }) if rank <= 10 => {
+            Some("This is a top-10 common password")
+        }
+        Some(Pattern::Dictionary { whole: true, .. }) => Some("This is a very common password"),
+        Some(Pattern::Dictionary { whole: false, .. }) => {
+            Some("This is similar to a commonly used password")
+        }
+        Some(Pattern::UserInput) => Some("Avoid using your username or email address"),
+        Some(Pattern::Repeat) => Some("Repeats like \"aaa\" are easy to guess"),
+        Some(Pattern::Sequence) => Some("Sequences like abc or 6543 are easy to guess"),
+        Some(Pattern::Keyboard) => Some("Straight rows of keys are easy to guess"),
+        None => None,
+    };
+
+    let mut suggestions = vec!["Add another word or two. Uncommon words are better.".to_owned()];
+    match pattern {
+        Some(Pattern::Repeat) => suggestions.push("Avoid repeated words and characters".to_owned()),
+        Some(Pattern::Sequence) => suggestions.push("Avoid sequences".to_owned()),
+        Some(Pattern::Keyboard) => {
+            suggestions.push("Use a longer keyboard pattern with more turns".to_owned());
+        }
+        _ => {}
+    }
+
+    Feedback {
+        warning: warning.map(ToOwned::to_owned),
+        suggestions,
+    }
+}
+
+/// Estimate the strength of a password
+///
+/// `user_inputs` are values known about the user, like their username, which
+/// should not be used in the password.
+#[must_use]
+pub fn estimate(password: &str, user_inputs: &[&str]) -> PasswordStrength {
+    let password: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = password.iter().flat_map(|c| c.to_lowercase()).collect();
+    // Lowercasing might change the length, in which case we give up on patterns
+    let lower = if lower.len() == password.len() {
+        lower
+    } else {
+        password.clone()
+    };
+    let user_inputs: Vec<String> = user_inputs
+        .iter()
+        .map(|input| input.to_lowercase())
+        .filter(|input| input.chars().count() >= MIN_PATTERN_LEN)
+        .collect();
+
+    let cardinality = cardinality(&password);
+    let mut guesses_log10 = 0.;
+    let mut longest: Option<Match> = None;
+
+    let mut i = 0;
+    while i < password.len() {
+        let rest = &lower[i..];
+        let best = dictionary_matches(&password[i..], rest, &user_inputs)
+            .into_iter()
+            .chain(repeat_match(rest, cardinality))
+            .chain(sequence_match(rest))
+            .chain(keyboard_match(rest))
+            // Prefer the longest match, then the easiest to guess
+            .max_by(|a, b| {
+                a.len.cmp(&b.len).then(
+                    b.guesses_log10
+                        .partial_cmp(&a.guesses_log10)
+                        .unwrap_or(Ordering::Equal),
+                )
+            });
+
+        if let Some(mut m) = best {
+            if let Pattern::Dictionary { ref mut whole, .. } = m.pattern {
+                *whole = i == 0 && m.len == password.len();
+            }
+
+            guesses_log10 += m.guesses_log10;
+            if longest.map_or(true, |l| m.len > l.len) {
+                longest = Some(m);
+            }
+            i += m.len;
+        } else {
+            guesses_log10 += cardinality.log10();
+            i += 1;
+        }
+    }
+
+    let score = match guesses_log10 {
+        x if x < 3. => 0,
+        x if x < 6. => 1,
+        x if x < 8. => 2,
+        x if x < 10. => 3,
+        _ => 4,
+    };
+
+    let feedback = if score < 3 {
+        feedback(longest.map(|m| m.pattern))
+    } else {
+        Feedback::default()
+    };
+
+    PasswordStrength {
+        score,
+        guesses_log10,
+        feedback,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        for password in [
+            "password",
+            "P@ssw0rd",
+            "123456789",
+            "qwertyuiop",
+            "aaaaaaaaaaaa",
+        ] {
+            let strength = estimate(password, &[]);
+            assert_eq!(strength.score, 0, "{}", password);
+            assert!(strength.feedback.warning.is_some(), "{}", password);
+        }
+
+        let strength = estimate("johndoe1990", &["johndoe"]);
+        assert!(strength.score < 3);
+        assert_eq!(
+            strength.feedback.warning.as_deref(),
+            Some("Avoid using your username or email address")
+        );
+
+        let strength = estimate("correct horse battery staple", &["johndoe"]);
+        assert_eq!(strength.score, 4);
+        assert!(strength.feedback.is_empty());
+    }
+}
@@ -28,6 +28,7 @@ pub enum PostAuthAction {
         data: i64,
     },
     ChangePassword,
+    ManageSecondFactor,
 }
 
 impl PostAuthAction {
@@ -47,6 +48,7 @@ impl PostAuthAction {
             Self::ContinueAuthorizationGrant { data } => ContinueAuthorizationGrant(*data).go(),
             Self::ContinueCompatSsoLogin { data } => CompatLoginSsoComplete(*data).go(),
             Self::ChangePassword => AccountPassword.go(),
+            Self::ManageSecondFactor => AccountTotp.go(),
         }
     }
 }
@@ -114,6 +116,14 @@ impl SimpleRoute for OAuth2RegistrationEndpoint {
     const PATH: &'static str = "/oauth2/registration";
 }
 
+/// `POST /oauth2/backchannel-authentication`
+#[derive(Default, Debug, Clone)]
+pub struct OAuth2BackchannelAuthentication;
+
+impl SimpleRoute for OAuth2BackchannelAuthentication {
+    const PATH: &'static str = "/oauth2/backchannel-authentication";
+}
+
 /// `GET /authorize`
 #[derive(Default, Debug, Clone)]
 pub struct OAuth2AuthorizationEndpoint;
@@ -199,6 +209,76 @@ impl From<Option<PostAuthAction>> for Login {
     }
 }
 
+/// `GET|POST /login/webauthn`
+#[derive(Default, Debug, Clone)]
+pub struct WebauthnLogin {
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl Route for WebauthnLogin {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/login/webauthn"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+impl From<Option<PostAuthAction>> for WebauthnLogin {
+    fn from(post_auth_action: Option<PostAuthAction>) -> Self {
+        Self { post_auth_action }
+    }
+}
+
+/// `GET|POST /login/email`
+#[derive(Default, Debug, Clone)]
+pub struct EmailLogin {
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl Route for EmailLogin {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/login/email"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+impl From<Option<PostAuthAction>> for EmailLogin {
+    fn from(post_auth_action: Option<PostAuthAction>) -> Self {
+        Self { post_auth_action }
+    }
+}
+
+/// `GET /login/email/link`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EmailLoginLink {
+    pub username: String,
+    pub token: String,
+
+    #[serde(flatten)]
+    pub post_auth_action: Option<PostAuthAction>,
+}
+
+impl Route for EmailLoginLink {
+    type Query = Self;
+
+    fn route() -> &'static str {
+        "/login/email/link"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        Some(self)
+    }
+}
+
 /// `POST /logout`
 #[derive(Default, Debug, Clone)]
 pub struct Logout;
@@ -261,6 +341,117 @@ impl From<Option<PostAuthAction>> for Reauth {
     }
 }
 
+/// `GET|POST /second-factor`
+#[derive(Default, Debug, Clone)]
+pub struct SecondFactor {
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl SecondFactor {
+    /// Get a reference to the second factor step's post auth action.
+    #[must_use]
+    pub fn post_auth_action(&self) -> Option<&PostAuthAction> {
+        self.post_auth_action.as_ref()
+    }
+
+    #[must_use]
+    pub fn go_next(&self) -> axum::response::Redirect {
+        match &self.post_auth_action {
+            Some(action) => action.go_next(),
+            None => Index.go(),
+        }
+    }
+}
+
+impl Route for SecondFactor {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/second-factor"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+impl From<Option<PostAuthAction>> for SecondFactor {
+    fn from(post_auth_action: Option<PostAuthAction>) -> Self {
+        Self { post_auth_action }
+    }
+}
+
+/// `GET|POST /second-factor/webauthn`
+#[derive(Default, Debug, Clone)]
+pub struct SecondFactorWebauthn {
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl Route for SecondFactorWebauthn {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/second-factor/webauthn"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+impl From<Option<PostAuthAction>> for SecondFactorWebauthn {
+    fn from(post_auth_action: Option<PostAuthAction>) -> Self {
+        Self { post_auth_action }
+    }
+}
+
+/// `GET|POST /select_account`
+#[derive(Default, Debug, Clone)]
+pub struct SelectAccount {
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl SelectAccount {
+    #[must_use]
+    pub fn and_then(action: PostAuthAction) -> Self {
+        Self {
+            post_auth_action: Some(action),
+        }
+    }
+
+    /// Get a reference to the account selection's post auth action.
+    #[must_use]
+    pub fn post_auth_action(&self) -> Option<&PostAuthAction> {
+        self.post_auth_action.as_ref()
+    }
+
+    #[must_use]
+    pub fn go_next(&self) -> axum::response::Redirect {
+        match &self.post_auth_action {
+            Some(action) => action.go_next(),
+            None => Index.go(),
+        }
+    }
+}
+
+impl Route for SelectAccount {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/select_account"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+impl From<Option<PostAuthAction>> for SelectAccount {
+    fn from(post_auth_action: Option<PostAuthAction>) -> Self {
+        Self { post_auth_action }
+    }
+}
+
 /// `GET|POST /register`
 #[derive(Default, Debug, Clone)]
 pub struct Register {
@@ -315,6 +506,30 @@ impl From<Option<PostAuthAction>> for Register {
     }
 }
 
+/// `GET|POST /password/forgot`
+#[derive(Default, Debug, Clone)]
+pub struct PasswordForgot;
+
+impl SimpleRoute for PasswordForgot {
+    const PATH: &'static str = "/password/forgot";
+}
+
+/// `GET|POST /password/reset/:token`
+#[derive(Debug, Clone)]
+pub struct PasswordReset(pub String);
+
+impl Route for PasswordReset {
+    type Query = ();
+
+    fn route() -> &'static str {
+        "/password/reset/:token"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/password/reset/{}", self.0).into()
+    }
+}
+
 /// `GET|POST /account/emails/verify/:id`
 #[derive(Debug, Clone)]
 pub struct AccountVerifyEmail {
@@ -400,6 +615,38 @@ impl SimpleRoute for AccountPassword {
     const PATH: &'static str = "/account/password";
 }
 
+/// `GET|POST /account/totp`
+#[derive(Default, Debug, Clone)]
+pub struct AccountTotp;
+
+impl SimpleRoute for AccountTotp {
+    const PATH: &'static str = "/account/totp";
+}
+
+/// `GET|POST /account/webauthn`
+#[derive(Default, Debug, Clone)]
+pub struct AccountWebauthn;
+
+impl SimpleRoute for AccountWebauthn {
+    const PATH: &'static str = "/account/webauthn";
+}
+
+/// `GET /account/webauthn/options`
+#[derive(Default, Debug, Clone)]
+pub struct AccountWebauthnOptions;
+
+impl SimpleRoute for AccountWebauthnOptions {
+    const PATH: &'static str = "/account/webauthn/options";
+}
+
+/// `GET|POST /account/sessions`
+#[derive(Default, Debug, Clone)]
+pub struct AccountSessions;
+
+impl SimpleRoute for AccountSessions {
+    const PATH: &'static str = "/account/sessions";
+}
+
 /// `GET|POST /account/emails`
 #[derive(Default, Debug, Clone)]
 pub struct AccountEmails;
@@ -408,6 +655,21 @@ impl SimpleRoute for AccountEmails {
     const PATH: &'static str = "/account/emails";
 }
 
+/// `POST /account/backchannel/:id`
+#[derive(Debug, Clone)]
+pub struct AccountBackchannelRequest(pub i64);
+
+impl Route for AccountBackchannelRequest {
+    type Query = ();
+    fn route() -> &'static str {
+        "/account/backchannel/:id"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/account/backchannel/{}", self.0).into()
+    }
+}
+
 /// `GET /authorize/:grant_id`
 #[derive(Debug, Clone)]
 pub struct ContinueAuthorizationGrant(pub i64);
@@ -452,6 +714,34 @@ impl SimpleRoute for CompatLogout {
     const PATH: &'static str = "/_matrix/client/:version/logout";
 }
 
+/// `POST /_matrix/client/v3/logout/all`
+pub struct CompatLogoutAll;
+
+impl SimpleRoute for CompatLogoutAll {
+    const PATH: &'static str = "/_matrix/client/:version/logout/all";
+}
+
+/// `GET /_matrix/client/v3/devices`
+pub struct CompatDevices;
+
+impl SimpleRoute for CompatDevices {
+    const PATH: &'static str = "/_matrix/client/:version/devices";
+}
+
+/// `DELETE /_matrix/client/v3/devices/:device_id`
+pub struct CompatDevice;
+
+impl SimpleRoute for CompatDevice {
+    const PATH: &'static str = "/_matrix/client/:version/devices/:device_id";
+}
+
+/// `POST /_matrix/client/v3/delete_devices`
+pub struct CompatDeleteDevices;
+
+impl SimpleRoute for CompatDeleteDevices {
+    const PATH: &'static str = "/_matrix/client/:version/delete_devices";
+}
+
 /// `POST /_matrix/client/v3/refresh`
 pub struct CompatRefresh;
 
@@ -459,6 +749,20 @@ impl SimpleRoute for CompatRefresh {
     const PATH: &'static str = "/_matrix/client/:version/refresh";
 }
 
+/// `POST /_matrix/client/v3/register`
+pub struct CompatRegister;
+
+impl SimpleRoute for CompatRegister {
+    const PATH: &'static str = "/_matrix/client/:version/register";
+}
+
+/// `GET /_matrix/client/v3/register/available`
+pub struct CompatRegisterAvailable;
+
+impl SimpleRoute for CompatRegisterAvailable {
+    const PATH: &'static str = "/_matrix/client/:version/register/available";
+}
+
 /// `POST /_matrix/client/v3/login/sso/redirect`
 pub struct CompatLoginSsoRedirect;
 
@@ -486,3 +790,153 @@ impl Route for CompatLoginSsoComplete {
         format!("/complete-compat-sso/{}", self.0).into()
     }
 }
+
+/// `GET /upstream/authorize/:provider`
+#[derive(Debug, Clone)]
+pub struct UpstreamOAuth2Authorize {
+    provider: String,
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl UpstreamOAuth2Authorize {
+    #[must_use]
+    pub fn new(provider: impl Into<String>) -> Self {
+        Self {
+            provider: provider.into(),
+            post_auth_action: None,
+        }
+    }
+
+    #[must_use]
+    pub fn and_then(mut self, action: PostAuthAction) -> Self {
+        self.post_auth_action = Some(action);
+        self
+    }
+}
+
+impl Route for UpstreamOAuth2Authorize {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/upstream/authorize/:provider"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/authorize/{}", self.provider).into()
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+/// `GET /upstream/callback/:provider`
+#[derive(Debug, Clone)]
+pub struct UpstreamOAuth2Callback(pub String);
+
+impl Route for UpstreamOAuth2Callback {
+    type Query = ();
+
+    fn route() -> &'static str {
+        "/upstream/callback/:provider"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/callback/{}", self.0).into()
+    }
+}
+
+/// `GET|POST /upstream/register`
+#[derive(Default, Debug, Clone)]
+pub struct UpstreamOAuth2Register;
+
+impl SimpleRoute for UpstreamOAuth2Register {
+    const PATH: &'static str = "/upstream/register";
+}
+
+/// `GET /upstream/saml/:provider/metadata`
+#[derive(Debug, Clone)]
+pub struct UpstreamSamlMetadata(pub String);
+
+impl Route for UpstreamSamlMetadata {
+    type Query = ();
+
+    fn route() -> &'static str {
+        "/upstream/saml/:provider/metadata"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/saml/{}/metadata", self.0).into()
+    }
+}
+
+/// `GET /upstream/saml/:provider/login`
+#[derive(Debug, Clone)]
+pub struct UpstreamSamlLogin {
+    provider: String,
+    post_auth_action: Option<PostAuthAction>,
+}
+
+impl UpstreamSamlLogin {
+    #[must_use]
+    pub fn new(provider: impl Into<String>) -> Self {
+        Self {
+            provider: provider.into(),
+            post_auth_action: None,
+        }
+    }
+
+    #[must_use]
+    pub fn and_then(mut self, action: PostAuthAction) -> Self {
+        self.post_auth_action = Some(action);
+        self
+    }
+}
+
+impl Route for UpstreamSamlLogin {
+    type Query = PostAuthAction;
+
+    fn route() -> &'static str {
+        "/upstream/saml/:provider/login"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/saml/{}/login", self.provider).into()
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        self.post_auth_action.as_ref()
+    }
+}
+
+/// `POST /upstream/saml/:provider/acs`
+#[derive(Debug, Clone)]
+pub struct UpstreamSamlAcs(pub String);
+
+impl Route for UpstreamSamlAcs {
+    type Query = ();
+
+    fn route() -> &'static str {
+        "/upstream/saml/:provider/acs"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/saml/{}/acs", self.0).into()
+    }
+}
+
+/// `POST /upstream/saml/:provider/complete`
+#[derive(Debug, Clone)]
+pub struct UpstreamSamlComplete(pub String);
+
+impl Route for UpstreamSamlComplete {
+    type Query = ();
+
+    fn route() -> &'static str {
+        "/upstream/saml/:provider/complete"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/saml/{}/complete", self.0).into()
+    }
+}
@@ -50,6 +50,19 @@ impl UrlBuilder {
         self.base.clone()
     }
 
+    /// Origin of the service, which WebAuthn clients report in their
+    /// responses
+    #[must_use]
+    pub fn webauthn_origin(&self) -> String {
+        self.base.origin().ascii_serialization()
+    }
+
+    /// WebAuthn relying party ID, which is the host of the public base URL
+    #[must_use]
+    pub fn webauthn_rp_id(&self) -> Option<&str> {
+        self.base.host_str()
+    }
+
     /// OIDC dicovery document URL
     #[must_use]
     pub fn oidc_discovery(&self) -> Url {
@@ -80,15 +93,68 @@ impl UrlBuilder {
         self.url_for(&crate::endpoints::OAuth2RegistrationEndpoint)
     }
 
+    /// CIBA backchannel authentication endpoint
+    #[must_use]
+    pub fn oauth_backchannel_authentication_endpoint(&self) -> Url {
+        self.url_for(&crate::endpoints::OAuth2BackchannelAuthentication)
+    }
+
     // OIDC userinfo endpoint
     #[must_use]
     pub fn oidc_userinfo_endpoint(&self) -> Url {
         self.url_for(&crate::endpoints::OidcUserinfo)
     }
 
+    /// Account management page
+    #[must_use]
+    pub fn account(&self) -> Url {
+        self.url_for(&crate::endpoints::Account)
+    }
+
+    /// Link sent to users who asked to reset their password
+    #[must_use]
+    pub fn password_reset(&self, token: &str) -> Url {
+        self.url_for(&crate::endpoints::PasswordReset(token.to_owned()))
+    }
+
+    /// Link sent to users who asked to sign in by email
+    #[must_use]
+    pub fn email_login_link(&self, link: &crate::endpoints::EmailLoginLink) -> Url {
+        self.url_for(link)
+    }
+
     /// JWKS URI
     #[must_use]
     pub fn jwks_uri(&self) -> Url {
         self.url_for(&crate::endpoints::OAuth2Keys)
     }
+
+    /// Redirect URI registered on an upstream OAuth 2.0 provider
+    #[must_use]
+    pub fn upstream_oauth2_callback(&self, provider: &str) -> Url {
+        self.url_for(&crate::endpoints::UpstreamOAuth2Callback(
+            provider.to_owned(),
+        ))
+    }
+
+    /// Entity ID of the service provider registered on an upstream SAML
+    /// identity provider, which is also the URL of its metadata
+    #[must_use]
+    pub fn upstream_saml_metadata(&self, provider: &str) -> Url {
+        self.url_for(&crate::endpoints::UpstreamSamlMetadata(provider.to_owned()))
+    }
+
+    /// Assertion consumer service registered on an upstream SAML identity
+    /// provider
+    #[must_use]
+    pub fn upstream_saml_acs(&self, provider: &str) -> Url {
+        self.url_for(&crate::endpoints::UpstreamSamlAcs(provider.to_owned()))
+    }
+
+    /// Where the assertion received by the assertion consumer service is
+    /// forwarded to
+    #[must_use]
+    pub fn upstream_saml_complete(&self, provider: &str) -> Url {
+        self.url_for(&crate::endpoints::UpstreamSamlComplete(provider.to_owned()))
+    }
 }
@@ -0,0 +1,197 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exclusive XML canonicalization, without comments
+//!
+//! See <https://www.w3.org/TR/xml-exc-c14n/>
+
+use std::collections::BTreeMap;
+
+use crate::xml::{Child, ElementRef};
+
+/// Canonicalize an element and its descendants.
+///
+/// The `exclude` element and its descendants are left out of the output,
+/// which is what the enveloped signature transform does. The
+/// `inclusive_prefixes` are the namespace prefixes which should be treated
+/// like in the inclusive canonicalization, `#default` designating the default
+/// namespace.
+#[must_use]
+pub fn canonicalize(
+    element: ElementRef<'_>,
+    exclude: Option<ElementRef<'_>>,
+    inclusive_prefixes: &[&str],
+) -> String {
+    let mut output = String::new();
+    let canonicalizer = Canonicalizer {
+        exclude,
+        inclusive_prefixes,
+    };
+    canonicalizer.element(element, &BTreeMap::new(), &mut output);
+    output
+}
+
+struct Canonicalizer<'a, 'b> {
+    exclude: Option<ElementRef<'a>>,
+    inclusive_prefixes: &'b [&'b str],
+}
+
+impl<'a> Canonicalizer<'a, '_> {
+    fn element(
+        &self,
+        element: ElementRef<'a>,
+        rendered: &BTreeMap<&'a str, &'a str>,
+        output: &mut String,
+    ) {
+        if self.exclude == Some(element) {
+            return;
+        }
+
+        // Figure out what namespace declarations need to be rendered on this
+        // element: the visibly utilized ones plus the inclusive ones, which
+        // were not already rendered with the same value on an ancestor
+        let mut needed: Vec<&'a str> = Vec::new();
+        needed.push(element.prefix().unwrap_or(""));
+        needed.extend(
+            element
+                .attributes()
+                .iter()
+                .filter_map(|a| a.prefix.as_deref())
+                .filter(|p| *p != "xml"),
+        );
+        for prefix in self.inclusive_prefixes {
+            let prefix = if *prefix == "#default" { "" } else { prefix };
+            // Borrow the prefix from the document, if it is in scope
+            if let Some(prefix) = find_declaration(element, prefix) {
+                needed.push(prefix);
+            }
+        }
+
+        let mut rendered = rendered.clone();
+        let mut declarations: BTreeMap<&'a str, &'a str> = BTreeMap::new();
+        for prefix in needed {
+            let uri = element.lookup_namespace(prefix).unwrap_or("");
+            let previous = rendered.get(prefix).copied().unwrap_or("");
+            if previous != uri {
+                declarations.insert(prefix, uri);
+            }
+        }
+        for (prefix, uri) in &declarations {
+            rendered.insert(prefix, uri);
+        }
+
+        output.push('<');
+        push_qname(output, element.prefix(), element.local_name());
+
+        // The BTreeMap sorts the declarations by prefix, the default one
+        // coming first
+        for (prefix, uri) in &declarations {
+            if prefix.is_empty() {
+                output.push_str(" xmlns=\"");
+            } else {
+                output.push_str(" xmlns:");
+                output.push_str(prefix);
+                output.push_str("=\"");
+            }
+            output.push_str(&escape_attribute(uri));
+            output.push('"');
+        }
+
+        // Attributes are sorted by namespace URI, then by local name
+        let mut attributes: Vec<_> = element
+            .attributes()
+            .iter()
+            .map(|a| {
+                let ns = a
+                    .prefix
+                    .as_deref()
+                    .and_then(|p| element.lookup_namespace(p))
+                    .unwrap_or("");
+                (ns, a)
+            })
+            .collect();
+        attributes.sort_by(|(ns_a, a), (ns_b, b)| {
+            ns_a.cmp(ns_b).then_with(|| a.local_name.cmp(&b.local_name))
+        });
+        for (_, attribute) in attributes {
+            output.push(' ');
+            push_qname(output, attribute.prefix.as_deref(), &attribute.local_name);
+            output.push_str("=\"");
+            output.push_str(&escape_attribute(&attribute.value));
+            output.push('"');
+        }
+        output.push('>');
+
+        for child in element.child_nodes() {
+            match child {
+                Child::Element(child) => self.element(child, &rendered, output),
+                Child::Text(text) => output.push_str(&escape_text(text)),
+            }
+        }
+
+        output.push_str("</");
+        push_qname(output, element.prefix(), element.local_name());
+        output.push('>');
+    }
+}
+
+/// Find the declaration of a prefix in the scope of an element
+fn find_declaration<'a>(element: ElementRef<'a>, prefix: &str) -> Option<&'a str> {
+    let mut current = Some(element);
+    while let Some(e) = current {
+        if let Some((p, _)) = e.namespace_declarations().iter().find(|(p, _)| p == prefix) {
+            return Some(p.as_str());
+        }
+        current = e.parent();
+    }
+    None
+}
+
+fn push_qname(output: &mut String, prefix: Option<&str>, local_name: &str) {
+    if let Some(prefix) = prefix {
+        output.push_str(prefix);
+        output.push(':');
+    }
+    output.push_str(local_name);
+}
+
+fn escape_text(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+fn escape_attribute(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '"' => output.push_str("&quot;"),
+            '\t' => output.push_str("&#x9;"),
+            '\n' => output.push_str("&#xA;"),
+            '\r' => output.push_str("&#xD;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
@@ -0,0 +1,443 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of enveloped XML signatures
+//!
+//! Only the algorithms used in practice by SAML identity providers are
+//! supported: exclusive canonicalization, the enveloped signature transform,
+//! SHA-256 digests and RSA PKCS#1 v1.5 signatures with SHA-256.
+
+use data_encoding::BASE64;
+use pkcs8::DecodePublicKey;
+use rsa::{PublicKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{c14n::canonicalize, xml::ElementRef};
+
+pub(crate) const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+#[derive(Debug, Error)]
+pub enum CertificateError {
+    #[error("invalid base64 encoding")]
+    Base64(#[from] data_encoding::DecodeError),
+
+    #[error("malformed certificate")]
+    Malformed,
+
+    #[error("certificate does not hold a valid RSA public key")]
+    PublicKey(#[from] pkcs8::spki::Error),
+}
+
+/// An X.509 certificate used to verify signatures.
+///
+/// Only the public key is used, the certificate is otherwise not validated,
+/// as it comes from trusted metadata.
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    der: Vec<u8>,
+    public_key: RsaPublicKey,
+}
+
+impl Certificate {
+    /// Load a certificate from its DER encoding
+    pub fn from_der(der: Vec<u8>) -> Result<Self, CertificateError> {
+        let spki = subject_public_key_info(&der).ok_or(CertificateError::Malformed)?;
+        let public_key = RsaPublicKey::from_public_key_der(spki)?;
+        Ok(Self { der, public_key })
+    }
+
+    /// Load a certificate from its base64 encoding, as found in metadata
+    /// documents. Whitespace is ignored.
+    pub fn from_base64(encoded: &str) -> Result<Self, CertificateError> {
+        let encoded: String = encoded.split_whitespace().collect();
+        let der = BASE64.decode(encoded.as_bytes())?;
+        Self::from_der(der)
+    }
+
+    /// The DER encoding of the certificate
+    #[must_use]
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let digest = Sha256::digest(message);
+        self.public_key
+            .verify(
+                rsa::PaddingScheme::new_pkcs1v15_sign(Some(rsa::Hash::SHA2_256)),
+                &digest,
+                signature,
+            )
+            .is_ok()
+    }
+}
+
+/// A DER tag-length-value: the tag, the full encoding, the contents of the
+/// value, and the remaining input
+type Tlv<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+fn read_tlv(input: &[u8]) -> Option<Tlv<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | usize::from(b));
+        (length, &rest[count..])
+    };
+
+    if rest.len() < length {
+        return None;
+    }
+
+    let header_length = input.len() - rest.len();
+    let (value, rest) = rest.split_at(length);
+    Some((tag, &input[..header_length + length], value, rest))
+}
+
+/// Find the encoded `SubjectPublicKeyInfo` in an X.509 certificate
+fn subject_public_key_info(der: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    // Certificate ::= SEQUENCE { tbsCertificate, ... }
+    let (tag, _, certificate, _) = read_tlv(der)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, _, mut tbs, _) = read_tlv(certificate)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+
+    // Skip the optional version, the serial number, the signature algorithm,
+    // the issuer, the validity and the subject
+    let (tag, _, _, rest) = read_tlv(tbs)?;
+    if tag == VERSION {
+        tbs = rest;
+    }
+    for _ in 0..5 {
+        let (_, _, _, rest) = read_tlv(tbs)?;
+        tbs = rest;
+    }
+
+    let (tag, spki, _, _) = read_tlv(tbs)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    Some(spki)
+}
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("element is not signed")]
+    Missing,
+
+    #[error("malformed signature")]
+    Malformed,
+
+    #[error("unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("signature does not reference the signed element")]
+    InvalidReference,
+
+    #[error("digest mismatch")]
+    DigestMismatch,
+
+    #[error("signature verification failed")]
+    Invalid,
+}
+
+/// Check if an element has an enveloped signature
+#[must_use]
+pub fn is_signed(element: ElementRef<'_>) -> bool {
+    element.child(DSIG_NS, "Signature").is_some()
+}
+
+/// Verify the enveloped signature of an element against a list of trusted
+/// certificates.
+///
+/// The certificates embedded in the signature are ignored.
+pub fn verify_enveloped(
+    element: ElementRef<'_>,
+    certificates: &[Certificate],
+) -> Result<(), SignatureError> {
+    let mut signatures = element.children_named(DSIG_NS, "Signature");
+    let signature = signatures.next().ok_or(SignatureError::Missing)?;
+    if signatures.next().is_some() {
+        return Err(SignatureError::Malformed);
+    }
+
+    let signed_info = signature
+        .child(DSIG_NS, "SignedInfo")
+        .ok_or(SignatureError::Malformed)?;
+
+    let c14n_method = signed_info
+        .child(DSIG_NS, "CanonicalizationMethod")
+        .ok_or(SignatureError::Malformed)?;
+    let signed_info_prefixes = check_c14n_method(c14n_method)?;
+
+    let signature_method = signed_info
+        .child(DSIG_NS, "SignatureMethod")
+        .and_then(|e| e.attribute("Algorithm"))
+        .ok_or(SignatureError::Malformed)?;
+    if signature_method != RSA_SHA256 {
+        return Err(SignatureError::UnsupportedAlgorithm(
+            signature_method.to_owned(),
+        ));
+    }
+
+    // There must be exactly one reference, pointing to the element itself
+    let mut references = signed_info.children_named(DSIG_NS, "Reference");
+    let reference = references.next().ok_or(SignatureError::Malformed)?;
+    if references.next().is_some() {
+        return Err(SignatureError::InvalidReference);
+    }
+
+    let id = element
+        .attribute("ID")
+        .ok_or(SignatureError::InvalidReference)?;
+    if reference.attribute("URI").and_then(|u| u.strip_prefix('#')) != Some(id) {
+        return Err(SignatureError::InvalidReference);
+    }
+
+    // Make sure the ID is unique in the document, to avoid signature wrapping
+    let mut root = element;
+    while let Some(parent) = root.parent() {
+        root = parent;
+    }
+    if root
+        .descendants()
+        .filter(|e| e.attribute("ID") == Some(id))
+        .count()
+        != 1
+    {
+        return Err(SignatureError::InvalidReference);
+    }
+
+    // The transforms must be the enveloped signature transform followed by
+    // the exclusive canonicalization
+    let transforms: Vec<_> = reference
+        .child(DSIG_NS, "Transforms")
+        .ok_or(SignatureError::Malformed)?
+        .children_named(DSIG_NS, "Transform")
+        .collect();
+    let (enveloped, c14n) = match transforms[..] {
+        [enveloped, c14n] => (enveloped, c14n),
+        _ => return Err(SignatureError::Malformed),
+    };
+    let enveloped = enveloped
+        .attribute("Algorithm")
+        .ok_or(SignatureError::Malformed)?;
+    if enveloped != ENVELOPED_SIGNATURE {
+        return Err(SignatureError::UnsupportedAlgorithm(enveloped.to_owned()));
+    }
+    let reference_prefixes = check_c14n_method(c14n)?;
+
+    let digest_method = reference
+        .child(DSIG_NS, "DigestMethod")
+        .and_then(|e| e.attribute("Algorithm"))
+        .ok_or(SignatureError::Malformed)?;
+    if digest_method != SHA256 {
+        return Err(SignatureError::UnsupportedAlgorithm(
+            digest_method.to_owned(),
+        ));
+    }
+
+    let digest_value = reference
+        .child(DSIG_NS, "DigestValue")
+        .map(|e| decode_base64(&e.text()))
+        .ok_or(SignatureError::Malformed)??;
+
+    let signature_value = signature
+        .child(DSIG_NS, "SignatureValue")
+        .map(|e| decode_base64(&e.text()))
+        .ok_or(SignatureError::Malformed)??;
+
+    // First check the signature of the SignedInfo, then the digest of the
+    // element
+    let canonical_signed_info = canonicalize(signed_info, None, &signed_info_prefixes);
+    if !certificates
+        .iter()
+        .any(|c| c.verify(canonical_signed_info.as_bytes(), &signature_value))
+    {
+        return Err(SignatureError::Invalid);
+    }
+
+    let canonical = canonicalize(element, Some(signature), &reference_prefixes);
+    if Sha256::digest(canonical.as_bytes())[..] != digest_value[..] {
+        return Err(SignatureError::DigestMismatch);
+    }
+
+    Ok(())
+}
+
+/// Check a canonicalization method and extract its inclusive namespace
+/// prefixes
+fn check_c14n_method(method: ElementRef<'_>) -> Result<Vec<&str>, SignatureError> {
+    let algorithm = method
+        .attribute("Algorithm")
+        .ok_or(SignatureError::Malformed)?;
+    if algorithm != EXC_C14N {
+        return Err(SignatureError::UnsupportedAlgorithm(algorithm.to_owned()));
+    }
+
+    let prefixes = method
+        .child(EXC_C14N, "InclusiveNamespaces")
+        .and_then(|e| e.attribute("PrefixList"))
+        .map(|list| list.split_whitespace().collect())
+        .unwrap_or_default();
+
+    Ok(prefixes)
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, SignatureError> {
+    let encoded: String = encoded.split_whitespace().collect();
+    BASE64
+        .decode(encoded.as_bytes())
+        .map_err(|_| SignatureError::Malformed)
+}
+
+/// Helpers to act as a local identity provider in tests
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use pkcs8::DecodePrivateKey;
+    use rsa::RsaPrivateKey;
+
+    use super::*;
+    use crate::xml::Document;
+
+    // Generated with:
+    //  openssl req -x509 -newkey rsa:2048 -nodes -days 36500 \
+    //    -subj "/CN=idp.example.com" -keyout idp.key -out idp.crt
+    pub const IDP_KEY: &str = include_str!("../tests/fixtures/idp.key");
+    pub const IDP_CERTIFICATE: &str = include_str!("../tests/fixtures/idp.crt");
+
+    pub fn idp_certificate() -> Certificate {
+        let encoded: String = IDP_CERTIFICATE
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect();
+        Certificate::from_base64(&encoded).unwrap()
+    }
+
+    /// Sign the element with the given ID, inserting the signature after its
+    /// `Issuer` element
+    pub fn sign(document: &str, id: &str) -> String {
+        let key = RsaPrivateKey::from_pkcs8_pem(IDP_KEY).unwrap();
+
+        let parsed = Document::parse(document).unwrap();
+        let element = parsed
+            .root()
+            .descendants()
+            .find(|e| e.attribute("ID") == Some(id))
+            .unwrap();
+        let digest = Sha256::digest(canonicalize(element, None, &[]).as_bytes());
+
+        let signed_info = format!(
+            r##"<ds:SignedInfo xmlns:ds="{DSIG_NS}"><ds:CanonicalizationMethod Algorithm="{EXC_C14N}"></ds:CanonicalizationMethod><ds:SignatureMethod Algorithm="{RSA_SHA256}"></ds:SignatureMethod><ds:Reference URI="#{id}"><ds:Transforms><ds:Transform Algorithm="{ENVELOPED_SIGNATURE}"></ds:Transform><ds:Transform Algorithm="{EXC_C14N}"></ds:Transform></ds:Transforms><ds:DigestMethod Algorithm="{SHA256}"></ds:DigestMethod><ds:DigestValue>{}</ds:DigestValue></ds:Reference></ds:SignedInfo>"##,
+            BASE64.encode(&digest),
+        );
+        let signature = key
+            .sign(
+                rsa::PaddingScheme::new_pkcs1v15_sign(Some(rsa::Hash::SHA2_256)),
+                &Sha256::digest(signed_info.as_bytes()),
+            )
+            .unwrap();
+        let signature = format!(
+            r#"<ds:Signature xmlns:ds="{DSIG_NS}">{}<ds:SignatureValue>{}</ds:SignatureValue></ds:Signature>"#,
+            signed_info.replacen(&format!(r#" xmlns:ds="{DSIG_NS}""#), "", 1),
+            BASE64.encode(&signature),
+        );
+
+        let start = document.find(&format!(r#"ID="{id}""#)).unwrap();
+        let issuer_end =
+            start + document[start..].find("</saml:Issuer>").unwrap() + "</saml:Issuer>".len();
+        format!(
+            "{}{}{}",
+            &document[..issuer_end],
+            signature,
+            &document[issuer_end..]
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_utils::*, *};
+    use crate::xml::Document;
+
+    const DOCUMENT: &str = r#"<root xmlns="urn:example"><signed ID="_abc" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion"><saml:Issuer>idp</saml:Issuer><value>hello</value></signed></root>"#;
+
+    #[test]
+    fn load_certificate() {
+        let certificate = idp_certificate();
+        assert!(!certificate.der().is_empty());
+        assert!(Certificate::from_base64("AAAA").is_err());
+    }
+
+    #[test]
+    fn verify_signature() {
+        let signed = sign(DOCUMENT, "_abc");
+        let document = Document::parse(&signed).unwrap();
+        let element = document.root().children().next().unwrap();
+        assert!(is_signed(element));
+        verify_enveloped(element, &[idp_certificate()]).unwrap();
+
+        // An unsigned element is rejected
+        let document = Document::parse(DOCUMENT).unwrap();
+        let element = document.root().children().next().unwrap();
+        assert!(matches!(
+            verify_enveloped(element, &[idp_certificate()]),
+            Err(SignatureError::Missing)
+        ));
+    }
+
+    #[test]
+    fn reject_tampered_signature() {
+        let signed = sign(DOCUMENT, "_abc").replace("hello", "evil");
+        let document = Document::parse(&signed).unwrap();
+        let element = document.root().children().next().unwrap();
+        assert!(matches!(
+            verify_enveloped(element, &[idp_certificate()]),
+            Err(SignatureError::DigestMismatch)
+        ));
+    }
+
+    #[test]
+    fn reject_duplicate_ids() {
+        let signed = sign(DOCUMENT, "_abc").replace(
+            "</root>",
+            r#"<signed ID="_abc"><value>evil</value></signed></root>"#,
+        );
+        let document = Document::parse(&signed).unwrap();
+        let element = document.root().children().next().unwrap();
+        assert!(matches!(
+            verify_enveloped(element, &[idp_certificate()]),
+            Err(SignatureError::InvalidReference)
+        ));
+    }
+}
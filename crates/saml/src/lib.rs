@@ -0,0 +1,41 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SAML 2.0 service provider
+//!
+//! This implements just enough of the SAML specifications to log in users
+//! through an upstream identity provider: generating the service provider
+//! metadata, sending authentication requests with the `HTTP-Redirect` or
+//! `HTTP-POST` bindings, and validating signed responses received through the
+//! `HTTP-POST` binding.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::all, rustdoc::broken_intra_doc_links)]
+#![warn(clippy::pedantic)]
+#![allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+
+pub(crate) mod c14n;
+pub(crate) mod dsig;
+pub(crate) mod metadata;
+pub(crate) mod request;
+pub(crate) mod response;
+pub(crate) mod xml;
+
+pub use self::{
+    dsig::{Certificate, CertificateError, SignatureError},
+    metadata::{Binding, IdentityProviderMetadata, MetadataError, ServiceProvider},
+    request::AuthnRequest,
+    response::{validate_response, Assertion, Attribute, ResponseError, ValidationOptions},
+    xml::ParseError,
+};
@@ -0,0 +1,262 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Identity provider and service provider metadata
+
+use thiserror::Error;
+use url::Url;
+
+use crate::{
+    dsig::{Certificate, CertificateError, DSIG_NS},
+    xml::{escape, Document, ElementRef, ParseError},
+};
+
+pub(crate) const METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const PERSISTENT_NAME_ID: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent";
+
+/// A SAML protocol binding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// The `HTTP-Redirect` binding, where messages are sent in the query
+    /// string
+    HttpRedirect,
+
+    /// The `HTTP-POST` binding, where messages are sent in an auto-submitted
+    /// form
+    HttpPost,
+}
+
+impl Binding {
+    /// The URN identifying this binding
+    #[must_use]
+    pub const fn urn(self) -> &'static str {
+        match self {
+            Self::HttpRedirect => "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect",
+            Self::HttpPost => "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST",
+        }
+    }
+
+    fn from_urn(urn: &str) -> Option<Self> {
+        [Self::HttpRedirect, Self::HttpPost]
+            .into_iter()
+            .find(|b| b.urn() == urn)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    #[error("no identity provider descriptor found")]
+    MissingDescriptor,
+
+    #[error("missing entity ID")]
+    MissingEntityId,
+
+    #[error("no supported single sign-on service found")]
+    MissingSingleSignOnService,
+
+    #[error("invalid single sign-on service location")]
+    InvalidLocation(#[from] url::ParseError),
+
+    #[error("no signing certificate found")]
+    MissingCertificate,
+
+    #[error("invalid signing certificate")]
+    InvalidCertificate(#[from] CertificateError),
+}
+
+/// The parts of the metadata of an identity provider needed to talk to it
+#[derive(Debug, Clone)]
+pub struct IdentityProviderMetadata {
+    entity_id: String,
+    single_sign_on_services: Vec<(Binding, Url)>,
+    certificates: Vec<Certificate>,
+}
+
+impl IdentityProviderMetadata {
+    /// Parse an `EntityDescriptor` document, or an `EntitiesDescriptor`
+    /// holding a single identity provider
+    pub fn parse(metadata: &str) -> Result<Self, MetadataError> {
+        let document = Document::parse(metadata)?;
+        let entity = document
+            .root()
+            .descendants()
+            .find(|e| {
+                e.is(METADATA_NS, "EntityDescriptor")
+                    && e.child(METADATA_NS, "IDPSSODescriptor").is_some()
+            })
+            .ok_or(MetadataError::MissingDescriptor)?;
+
+        let entity_id = entity
+            .attribute("entityID")
+            .ok_or(MetadataError::MissingEntityId)?
+            .to_owned();
+
+        let descriptor = entity
+            .child(METADATA_NS, "IDPSSODescriptor")
+            .ok_or(MetadataError::MissingDescriptor)?;
+
+        let mut single_sign_on_services = Vec::new();
+        for service in descriptor.children_named(METADATA_NS, "SingleSignOnService") {
+            let binding = service.attribute("Binding").and_then(Binding::from_urn);
+            let location = service.attribute("Location");
+            if let (Some(binding), Some(location)) = (binding, location) {
+                single_sign_on_services.push((binding, location.parse()?));
+            }
+        }
+
+        if single_sign_on_services.is_empty() {
+            return Err(MetadataError::MissingSingleSignOnService);
+        }
+
+        let certificates = descriptor
+            .children_named(METADATA_NS, "KeyDescriptor")
+            .filter(|k| k.attribute("use").map_or(true, |u| u == "signing"))
+            .filter_map(key_descriptor_certificate)
+            .map(|c| Certificate::from_base64(&c))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if certificates.is_empty() {
+            return Err(MetadataError::MissingCertificate);
+        }
+
+        Ok(Self {
+            entity_id,
+            single_sign_on_services,
+            certificates,
+        })
+    }
+
+    /// The entity ID of the identity provider
+    #[must_use]
+    pub fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+
+    /// The single sign-on service to send authentication requests to,
+    /// preferring the `HTTP-Redirect` binding
+    #[must_use]
+    pub fn single_sign_on_service(&self) -> (Binding, &Url) {
+        let (binding, url) = self
+            .single_sign_on_services
+            .iter()
+            .find(|(b, _)| *b == Binding::HttpRedirect)
+            .unwrap_or(&self.single_sign_on_services[0]);
+        (*binding, url)
+    }
+
+    /// The certificates trusted to sign responses
+    #[must_use]
+    pub fn certificates(&self) -> &[Certificate] {
+        &self.certificates
+    }
+}
+
+fn key_descriptor_certificate(descriptor: ElementRef<'_>) -> Option<String> {
+    let certificate = descriptor
+        .child(DSIG_NS, "KeyInfo")?
+        .child(DSIG_NS, "X509Data")?
+        .child(DSIG_NS, "X509Certificate")?;
+    Some(certificate.text())
+}
+
+/// A SAML service provider, as registered with an identity provider
+#[derive(Debug, Clone)]
+pub struct ServiceProvider {
+    /// The entity ID of the service provider, usually the URL of its metadata
+    pub entity_id: String,
+
+    /// Where the identity provider should send its responses
+    pub assertion_consumer_service: Url,
+}
+
+impl ServiceProvider {
+    /// Generate the metadata document of this service provider
+    #[must_use]
+    pub fn metadata(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="{METADATA_NS}" entityID="{entity_id}">
+  <md:SPSSODescriptor AuthnRequestsSigned="false" WantAssertionsSigned="true" protocolSupportEnumeration="urn:oasis:names:tc:SAML:2.0:protocol">
+    <md:NameIDFormat>{PERSISTENT_NAME_ID}</md:NameIDFormat>
+    <md:AssertionConsumerService Binding="{binding}" Location="{location}" index="0" isDefault="true"/>
+  </md:SPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+            entity_id = escape(&self.entity_id),
+            binding = Binding::HttpPost.urn(),
+            location = escape(self.assertion_consumer_service.as_str()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDP_METADATA: &str = include_str!("../tests/fixtures/idp-metadata.xml");
+
+    #[test]
+    fn parse_idp_metadata() {
+        let metadata = IdentityProviderMetadata::parse(IDP_METADATA).unwrap();
+        assert_eq!(metadata.entity_id(), "https://idp.example.com/metadata");
+        assert_eq!(metadata.certificates().len(), 1);
+
+        let (binding, url) = metadata.single_sign_on_service();
+        assert_eq!(binding, Binding::HttpRedirect);
+        assert_eq!(url.as_str(), "https://idp.example.com/sso/redirect");
+    }
+
+    #[test]
+    fn reject_invalid_metadata() {
+        assert!(matches!(
+            IdentityProviderMetadata::parse("<foo/>"),
+            Err(MetadataError::MissingDescriptor)
+        ));
+
+        let no_certificate = IDP_METADATA.replace("use=\"signing\"", "use=\"encryption\"");
+        assert!(matches!(
+            IdentityProviderMetadata::parse(&no_certificate),
+            Err(MetadataError::MissingCertificate)
+        ));
+    }
+
+    #[test]
+    fn generate_sp_metadata() {
+        let sp = ServiceProvider {
+            entity_id: "https://auth.example.com/upstream/saml/test/metadata?a=b&c=d".to_owned(),
+            assertion_consumer_service: "https://auth.example.com/upstream/saml/test/acs"
+                .parse()
+                .unwrap(),
+        };
+
+        let metadata = sp.metadata();
+        let document = Document::parse(&metadata).unwrap();
+        let root = document.root();
+        assert!(root.is(METADATA_NS, "EntityDescriptor"));
+        assert_eq!(root.attribute("entityID"), Some(sp.entity_id.as_str()));
+
+        let acs = root
+            .child(METADATA_NS, "SPSSODescriptor")
+            .and_then(|d| d.child(METADATA_NS, "AssertionConsumerService"))
+            .unwrap();
+        assert_eq!(acs.attribute("Binding"), Some(Binding::HttpPost.urn()));
+        assert_eq!(
+            acs.attribute("Location"),
+            Some("https://auth.example.com/upstream/saml/test/acs")
+        );
+    }
+}
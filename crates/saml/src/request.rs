@@ -0,0 +1,169 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication requests sent to identity providers
+
+use std::io::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use data_encoding::BASE64;
+use flate2::{write::DeflateEncoder, Compression};
+use rand::{distributions::Alphanumeric, Rng};
+use url::Url;
+
+use crate::{
+    metadata::{Binding, ServiceProvider},
+    xml::escape,
+};
+
+pub(crate) const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+pub(crate) const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+
+/// A SAML `AuthnRequest`
+#[derive(Debug, Clone)]
+pub struct AuthnRequest {
+    id: String,
+    issue_instant: DateTime<Utc>,
+    destination: Url,
+    issuer: String,
+    assertion_consumer_service: Url,
+}
+
+impl AuthnRequest {
+    /// Create a new request with a random ID
+    pub fn new(
+        rng: &mut impl Rng,
+        service_provider: &ServiceProvider,
+        destination: Url,
+        now: DateTime<Utc>,
+    ) -> Self {
+        // IDs must not start with a digit
+        let random: String = rng
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        Self {
+            id: format!("_{random}"),
+            issue_instant: now,
+            destination,
+            issuer: service_provider.entity_id.clone(),
+            assertion_consumer_service: service_provider.assertion_consumer_service.clone(),
+        }
+    }
+
+    /// The ID of the request, which should be echoed in the response
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Serialize the request to XML
+    #[must_use]
+    pub fn to_xml(&self) -> String {
+        format!(
+            r#"<samlp:AuthnRequest xmlns:samlp="{PROTOCOL_NS}" xmlns:saml="{ASSERTION_NS}" ID="{id}" Version="2.0" IssueInstant="{issue_instant}" Destination="{destination}" AssertionConsumerServiceURL="{acs}" ProtocolBinding="{binding}"><saml:Issuer>{issuer}</saml:Issuer><samlp:NameIDPolicy Format="urn:oasis:names:tc:SAML:2.0:nameid-format:persistent" AllowCreate="true"/></samlp:AuthnRequest>"#,
+            id = escape(&self.id),
+            issue_instant = self
+                .issue_instant
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            destination = escape(self.destination.as_str()),
+            acs = escape(self.assertion_consumer_service.as_str()),
+            binding = Binding::HttpPost.urn(),
+            issuer = escape(&self.issuer),
+        )
+    }
+
+    /// The URL to redirect the user to with the `HTTP-Redirect` binding
+    ///
+    /// # Panics
+    ///
+    /// Never, as compressing to a buffer can't fail
+    #[must_use]
+    pub fn redirect_url(&self, relay_state: Option<&str>) -> Url {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(self.to_xml().as_bytes())
+            .and_then(|()| encoder.try_finish())
+            .expect("failed to compress request");
+        let saml_request = BASE64.encode(encoder.get_ref());
+
+        let mut url = self.destination.clone();
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("SAMLRequest", &saml_request);
+            if let Some(relay_state) = relay_state {
+                query.append_pair("RelayState", relay_state);
+            }
+        }
+        url
+    }
+
+    /// The value of the `SAMLRequest` form field to send with the
+    /// `HTTP-POST` binding
+    #[must_use]
+    pub fn post_value(&self) -> String {
+        BASE64.encode(self.to_xml().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use chrono::TimeZone;
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+    use crate::xml::Document;
+
+    #[test]
+    fn redirect_binding() {
+        let mut rng = rand::thread_rng();
+        let sp = ServiceProvider {
+            entity_id: "https://auth.example.com/metadata".to_owned(),
+            assertion_consumer_service: "https://auth.example.com/acs".parse().unwrap(),
+        };
+        let destination: Url = "https://idp.example.com/sso?tenant=foo".parse().unwrap();
+        let now = Utc.ymd_opt(2022, 6, 14).and_hms_opt(10, 0, 0).unwrap();
+        let request = AuthnRequest::new(&mut rng, &sp, destination, now);
+        assert!(request.id().starts_with('_'));
+
+        let url = request.redirect_url(Some("state"));
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(query[0], ("tenant".to_owned(), "foo".to_owned()));
+        assert_eq!(query[2], ("RelayState".to_owned(), "state".to_owned()));
+
+        let compressed = BASE64.decode(query[1].1.as_bytes()).unwrap();
+        let mut xml = String::new();
+        DeflateDecoder::new(&compressed[..])
+            .read_to_string(&mut xml)
+            .unwrap();
+
+        let document = Document::parse(&xml).unwrap();
+        let root = document.root();
+        assert!(root.is(PROTOCOL_NS, "AuthnRequest"));
+        assert_eq!(root.attribute("ID"), Some(request.id()));
+        assert_eq!(root.attribute("IssueInstant"), Some("2022-06-14T10:00:00Z"));
+        assert_eq!(
+            root.attribute("AssertionConsumerServiceURL"),
+            Some("https://auth.example.com/acs")
+        );
+        assert_eq!(
+            root.child(ASSERTION_NS, "Issuer").unwrap().text(),
+            "https://auth.example.com/metadata"
+        );
+    }
+}
@@ -0,0 +1,633 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validation of responses sent by identity providers
+
+use chrono::{DateTime, Duration, Utc};
+use data_encoding::BASE64;
+use thiserror::Error;
+
+use crate::{
+    dsig::{is_signed, verify_enveloped, SignatureError},
+    metadata::{IdentityProviderMetadata, ServiceProvider},
+    request::{ASSERTION_NS, PROTOCOL_NS},
+    xml::{Document, ElementRef, ParseError},
+};
+
+const SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+
+/// How much clock skew is tolerated between us and the identity provider
+fn allowed_clock_skew() -> Duration {
+    Duration::minutes(5)
+}
+
+#[derive(Debug, Error)]
+pub enum ResponseError {
+    #[error("invalid base64 encoding")]
+    Base64(#[from] data_encoding::DecodeError),
+
+    #[error("response is not valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    #[error("malformed response: {0}")]
+    Malformed(&'static str),
+
+    #[error("authentication failed with status {0:?}")]
+    Status(String),
+
+    #[error("response was sent to another destination")]
+    WrongDestination,
+
+    #[error("response does not match the authentication request")]
+    WrongInResponseTo,
+
+    #[error("response was issued by another identity provider")]
+    WrongIssuer,
+
+    #[error("encrypted assertions are not supported")]
+    EncryptedAssertion,
+
+    #[error("neither the response nor the assertion is signed")]
+    Unsigned,
+
+    #[error("invalid signature")]
+    Signature(#[from] SignatureError),
+
+    #[error("no valid bearer subject confirmation")]
+    SubjectConfirmation,
+
+    #[error("assertion is not valid at this time")]
+    Expired,
+
+    #[error("assertion is intended for another audience")]
+    WrongAudience,
+}
+
+/// What a response is validated against
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationOptions<'a> {
+    /// The identity provider which should have issued the response
+    pub identity_provider: &'a IdentityProviderMetadata,
+
+    /// The service provider which should receive the response
+    pub service_provider: &'a ServiceProvider,
+
+    /// The ID of the authentication request this responds to
+    pub request_id: &'a str,
+
+    /// The current time
+    pub now: DateTime<Utc>,
+}
+
+/// An attribute of the authenticated subject
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: String,
+    pub friendly_name: Option<String>,
+    pub values: Vec<String>,
+}
+
+/// The validated content of an assertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    /// The ID of the assertion, to detect replays
+    pub id: String,
+
+    /// Until when the assertion could be accepted. Its ID has to be remembered
+    /// until then.
+    pub not_on_or_after: DateTime<Utc>,
+
+    /// The identifier of the subject at the identity provider
+    pub name_id: String,
+
+    /// The attributes released by the identity provider
+    pub attributes: Vec<Attribute>,
+}
+
+/// Decode and validate a base64-encoded `Response`, as received through the
+/// `HTTP-POST` binding
+pub fn validate_response(
+    encoded: &str,
+    options: &ValidationOptions<'_>,
+) -> Result<Assertion, ResponseError> {
+    let encoded: String = encoded.split_whitespace().collect();
+    let xml = String::from_utf8(BASE64.decode(encoded.as_bytes())?)?;
+    let document = Document::parse(&xml)?;
+    let response = document.root();
+
+    if !response.is(PROTOCOL_NS, "Response") {
+        return Err(ResponseError::Malformed("expected a Response"));
+    }
+
+    if response.attribute("Version") != Some("2.0") {
+        return Err(ResponseError::Malformed("unsupported version"));
+    }
+
+    if let Some(destination) = response.attribute("Destination") {
+        if destination != options.service_provider.assertion_consumer_service.as_str() {
+            return Err(ResponseError::WrongDestination);
+        }
+    }
+
+    if response.attribute("InResponseTo") != Some(options.request_id) {
+        return Err(ResponseError::WrongInResponseTo);
+    }
+
+    check_issuer(response, options, false)?;
+
+    let status = response
+        .child(PROTOCOL_NS, "Status")
+        .and_then(|s| s.child(PROTOCOL_NS, "StatusCode"))
+        .and_then(|c| c.attribute("Value"))
+        .ok_or(ResponseError::Malformed("missing status"))?;
+    if status != SUCCESS {
+        return Err(ResponseError::Status(status.to_owned()));
+    }
+
+    if response.child(ASSERTION_NS, "EncryptedAssertion").is_some() {
+        return Err(ResponseError::EncryptedAssertion);
+    }
+
+    let mut assertions = response.children_named(ASSERTION_NS, "Assertion");
+    let assertion = assertions
+        .next()
+        .ok_or(ResponseError::Malformed("missing assertion"))?;
+    if assertions.next().is_some() {
+        return Err(ResponseError::Malformed("more than one assertion"));
+    }
+
+    // Either the response or the assertion must be signed. Only the elements
+    // checked here are used afterwards, so that unsigned content can't be
+    // injected elsewhere in the document
+    let certificates = options.identity_provider.certificates();
+    let response_signed = is_signed(response);
+    if response_signed {
+        verify_enveloped(response, certificates)?;
+    }
+    if is_signed(assertion) {
+        verify_enveloped(assertion, certificates)?;
+    } else if !response_signed {
+        return Err(ResponseError::Unsigned);
+    }
+
+    let id = assertion
+        .attribute("ID")
+        .ok_or(ResponseError::Malformed("missing assertion ID"))?
+        .to_owned();
+
+    check_issuer(assertion, options, true)?;
+    let (name_id, not_on_or_after) = check_subject(assertion, options)?;
+    check_conditions(assertion, options)?;
+
+    let attributes = assertion
+        .children_named(ASSERTION_NS, "AttributeStatement")
+        .flat_map(|s| s.children_named(ASSERTION_NS, "Attribute"))
+        .filter_map(|attribute| {
+            Some(Attribute {
+                name: attribute.attribute("Name")?.to_owned(),
+                friendly_name: attribute.attribute("FriendlyName").map(ToOwned::to_owned),
+                values: attribute
+                    .children_named(ASSERTION_NS, "AttributeValue")
+                    .map(|v| v.text())
+                    .collect(),
+            })
+        })
+        .collect();
+
+    Ok(Assertion {
+        id,
+        not_on_or_after: not_on_or_after + allowed_clock_skew(),
+        name_id,
+        attributes,
+    })
+}
+
+fn check_issuer(
+    element: ElementRef<'_>,
+    options: &ValidationOptions<'_>,
+    required: bool,
+) -> Result<(), ResponseError> {
+    match element.child(ASSERTION_NS, "Issuer") {
+        Some(issuer) if issuer.text().trim() == options.identity_provider.entity_id() => Ok(()),
+        None if !required => Ok(()),
+        _ => Err(ResponseError::WrongIssuer),
+    }
+}
+
+fn parse_instant(value: Option<&str>) -> Result<Option<DateTime<Utc>>, ResponseError> {
+    value
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|d| d.with_timezone(&Utc))
+                .map_err(|_| ResponseError::Malformed("invalid timestamp"))
+        })
+        .transpose()
+}
+
+/// Check the subject of the assertion, returning its `NameID` and when the
+/// bearer confirmation expires
+fn check_subject(
+    assertion: ElementRef<'_>,
+    options: &ValidationOptions<'_>,
+) -> Result<(String, DateTime<Utc>), ResponseError> {
+    let subject = assertion
+        .child(ASSERTION_NS, "Subject")
+        .ok_or(ResponseError::Malformed("missing subject"))?;
+
+    let name_id = subject
+        .child(ASSERTION_NS, "NameID")
+        .map(|n| n.text().trim().to_owned())
+        .filter(|n| !n.is_empty())
+        .ok_or(ResponseError::Malformed("missing name ID"))?;
+
+    // At least one bearer confirmation must be valid for this response
+    let mut confirmed = None;
+    for confirmation in subject.children_named(ASSERTION_NS, "SubjectConfirmation") {
+        if confirmation.attribute("Method") != Some(BEARER) {
+            continue;
+        }
+
+        let data = match confirmation.child(ASSERTION_NS, "SubjectConfirmationData") {
+            Some(data) => data,
+            None => continue,
+        };
+
+        let recipient_valid = data.attribute("Recipient")
+            == Some(options.service_provider.assertion_consumer_service.as_str());
+        let in_response_to_valid = data
+            .attribute("InResponseTo")
+            .map_or(true, |id| id == options.request_id);
+        let not_on_or_after = parse_instant(data.attribute("NotOnOrAfter"))?
+            .filter(|t| options.now - allowed_clock_skew() < *t);
+
+        if let Some(not_on_or_after) = not_on_or_after {
+            if recipient_valid && in_response_to_valid {
+                confirmed = Some(not_on_or_after);
+                break;
+            }
+        }
+    }
+
+    let not_on_or_after = confirmed.ok_or(ResponseError::SubjectConfirmation)?;
+    Ok((name_id, not_on_or_after))
+}
+
+fn check_conditions(
+    assertion: ElementRef<'_>,
+    options: &ValidationOptions<'_>,
+) -> Result<(), ResponseError> {
+    let conditions = match assertion.child(ASSERTION_NS, "Conditions") {
+        Some(conditions) => conditions,
+        None => return Ok(()),
+    };
+
+    if let Some(not_before) = parse_instant(conditions.attribute("NotBefore"))? {
+        if options.now + allowed_clock_skew() < not_before {
+            return Err(ResponseError::Expired);
+        }
+    }
+
+    if let Some(not_on_or_after) = parse_instant(conditions.attribute("NotOnOrAfter"))? {
+        if options.now - allowed_clock_skew() >= not_on_or_after {
+            return Err(ResponseError::Expired);
+        }
+    }
+
+    // Each audience restriction must include us
+    for restriction in conditions.children_named(ASSERTION_NS, "AudienceRestriction") {
+        let included = restriction
+            .children_named(ASSERTION_NS, "Audience")
+            .any(|a| a.text().trim() == options.service_provider.entity_id);
+        if !included {
+            return Err(ResponseError::WrongAudience);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::dsig::test_utils::sign;
+
+    const IDP_METADATA: &str = include_str!("../tests/fixtures/idp-metadata.xml");
+    // Signed outside of this crate, see the fixtures README
+    const SIGNED_RESPONSE: &str = include_str!("../tests/fixtures/response.xml");
+
+    const RESPONSE: &str = r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_response" Version="2.0" IssueInstant="2022-06-14T10:00:00Z" Destination="https://auth.example.com/upstream/saml/test/acs" InResponseTo="_request">
+  <saml:Issuer>https://idp.example.com/metadata</saml:Issuer>
+  <samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status>
+  <saml:Assertion ID="_assertion" Version="2.0" IssueInstant="2022-06-14T10:00:00Z">
+    <saml:Issuer>https://idp.example.com/metadata</saml:Issuer>
+    <saml:Subject>
+      <saml:NameID Format="urn:oasis:names:tc:SAML:2.0:nameid-format:persistent">alice-id</saml:NameID>
+      <saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">
+        <saml:SubjectConfirmationData InResponseTo="_request" Recipient="https://auth.example.com/upstream/saml/test/acs" NotOnOrAfter="2022-06-14T10:05:00Z"/>
+      </saml:SubjectConfirmation>
+    </saml:Subject>
+    <saml:Conditions NotBefore="2022-06-14T09:59:00Z" NotOnOrAfter="2022-06-14T10:05:00Z">
+      <saml:AudienceRestriction><saml:Audience>https://auth.example.com/upstream/saml/test/metadata</saml:Audience></saml:AudienceRestriction>
+    </saml:Conditions>
+    <saml:AttributeStatement>
+      <saml:Attribute Name="urn:oid:0.9.2342.19200300.100.1.1" FriendlyName="uid"><saml:AttributeValue>alice</saml:AttributeValue></saml:Attribute>
+      <saml:Attribute Name="groups"><saml:AttributeValue>admins</saml:AttributeValue><saml:AttributeValue>users</saml:AttributeValue></saml:Attribute>
+    </saml:AttributeStatement>
+  </saml:Assertion>
+</samlp:Response>"#;
+
+    fn validate(response: &str, now: DateTime<Utc>) -> Result<Assertion, ResponseError> {
+        let identity_provider = IdentityProviderMetadata::parse(IDP_METADATA).unwrap();
+        let service_provider = ServiceProvider {
+            entity_id: "https://auth.example.com/upstream/saml/test/metadata".to_owned(),
+            assertion_consumer_service: "https://auth.example.com/upstream/saml/test/acs"
+                .parse()
+                .unwrap(),
+        };
+        let options = ValidationOptions {
+            identity_provider: &identity_provider,
+            service_provider: &service_provider,
+            request_id: "_request",
+            now,
+        };
+        validate_response(&BASE64.encode(response.as_bytes()), &options)
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd_opt(2022, 6, 14).and_hms_opt(10, 1, 0).unwrap()
+    }
+
+    #[test]
+    fn validate_signed_assertion() {
+        let assertion = validate(&sign(RESPONSE, "_assertion"), now()).unwrap();
+        assert_eq!(assertion.id, "_assertion");
+        assert_eq!(
+            assertion.not_on_or_after,
+            Utc.ymd_opt(2022, 6, 14).and_hms_opt(10, 10, 0).unwrap()
+        );
+        assert_eq!(assertion.name_id, "alice-id");
+        assert_eq!(
+            assertion.attributes,
+            vec![
+                Attribute {
+                    name: "urn:oid:0.9.2342.19200300.100.1.1".to_owned(),
+                    friendly_name: Some("uid".to_owned()),
+                    values: vec!["alice".to_owned()],
+                },
+                Attribute {
+                    name: "groups".to_owned(),
+                    friendly_name: None,
+                    values: vec!["admins".to_owned(), "users".to_owned()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_signed_response() {
+        let assertion = validate(&sign(RESPONSE, "_response"), now()).unwrap();
+        assert_eq!(assertion.name_id, "alice-id");
+    }
+
+    #[test]
+    fn validate_externally_signed_response() {
+        let assertion = validate(SIGNED_RESPONSE, now()).unwrap();
+        assert_eq!(assertion.name_id, "bob-id");
+        assert_eq!(assertion.attributes[0].values, vec!["bob@example.com"]);
+    }
+
+    #[test]
+    fn reject_unsigned_response() {
+        assert!(matches!(
+            validate(RESPONSE, now()),
+            Err(ResponseError::Unsigned)
+        ));
+    }
+
+    #[test]
+    fn reject_expired_response() {
+        let later = now() + Duration::minutes(10);
+        assert!(matches!(
+            validate(&sign(RESPONSE, "_assertion"), later),
+            Err(ResponseError::SubjectConfirmation)
+        ));
+    }
+
+    #[test]
+    fn reject_wrong_audience() {
+        let response = sign(
+            &RESPONSE.replace("test/metadata</saml:Audience>", "other</saml:Audience>"),
+            "_assertion",
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::WrongAudience)
+        ));
+    }
+
+    #[test]
+    fn reject_wrong_request() {
+        let response = sign(
+            &RESPONSE.replace("InResponseTo=\"_request\"", "InResponseTo=\"_other\""),
+            "_assertion",
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::WrongInResponseTo)
+        ));
+    }
+
+    #[test]
+    fn reject_failed_status() {
+        let response = sign(
+            &RESPONSE.replace("status:Success", "status:Responder"),
+            "_assertion",
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Status(_))
+        ));
+    }
+
+    #[test]
+    fn reject_tampered_assertion() {
+        let response = sign(RESPONSE, "_assertion").replace(">alice-id<", ">admin<");
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Signature(SignatureError::DigestMismatch))
+        ));
+    }
+
+    /// Split a signed response around its assertion
+    fn split_assertion(response: &str) -> (&str, &str, &str) {
+        let start = response.find("<saml:Assertion").unwrap();
+        let end = response.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+        (&response[..start], &response[start..end], &response[end..])
+    }
+
+    /// An unsigned assertion for another subject, with the given ID and
+    /// content inserted after its issuer
+    fn evil_assertion(id: &str, inner: &str) -> String {
+        let (_, assertion, _) = split_assertion(RESPONSE);
+        assertion
+            .replace(r#"ID="_assertion""#, &format!(r#"ID="{}""#, id))
+            .replace(">alice-id<", ">admin<")
+            .replacen("</saml:Issuer>", &format!("</saml:Issuer>{}", inner), 1)
+    }
+
+    #[test]
+    fn reject_assertion_wrapping() {
+        let signed = sign(RESPONSE, "_assertion");
+        let (before, original, after) = split_assertion(&signed);
+
+        // The signed assertion is nested in an unsigned one
+        let response = format!("{}{}{}", before, evil_assertion("_evil", original), after);
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Unsigned)
+        ));
+
+        // The signed assertion is moved in the extensions of the response
+        let response = format!(
+            "{}<samlp:Extensions>{}</samlp:Extensions>{}{}",
+            before,
+            original,
+            evil_assertion("_evil", ""),
+            after
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Unsigned)
+        ));
+
+        // Both assertions are in the response
+        let response = format!(
+            "{}{}{}{}",
+            before,
+            original,
+            evil_assertion("_evil", ""),
+            after
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn reject_copied_signature() {
+        let signed = sign(RESPONSE, "_assertion");
+        let (before, original, after) = split_assertion(&signed);
+        let start = original.find("<ds:Signature").unwrap();
+        let end = original.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let signature = &original[start..end];
+
+        // The signature is copied in an assertion with the same ID, and the
+        // original assertion is kept elsewhere for the reference to resolve
+        let response = format!(
+            "{}<samlp:Extensions>{}</samlp:Extensions>{}{}",
+            before,
+            original,
+            evil_assertion("_assertion", signature),
+            after
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Signature(SignatureError::InvalidReference))
+        ));
+
+        // The signature is copied in an assertion with another ID
+        let response = format!(
+            "{}<samlp:Extensions>{}</samlp:Extensions>{}{}",
+            before,
+            original,
+            evil_assertion("_evil", signature),
+            after
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Signature(SignatureError::InvalidReference))
+        ));
+    }
+
+    #[test]
+    fn reject_response_wrapping() {
+        // The signed response is nested in an unsigned one
+        let signed = sign(RESPONSE, "_response");
+        let (before, _, after) = split_assertion(RESPONSE);
+        let response = format!(
+            "{}<samlp:Extensions>{}</samlp:Extensions>{}{}",
+            before.replace(r#"ID="_response""#, r#"ID="_evil_response""#),
+            signed,
+            evil_assertion("_evil", ""),
+            after
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Unsigned)
+        ));
+    }
+
+    #[test]
+    fn reject_wrong_namespaces() {
+        // An assertion in another namespace is not an assertion
+        let response = sign(
+            &RESPONSE
+                .replace(
+                    "<saml:Assertion ",
+                    r#"<evil:Assertion xmlns:evil="urn:evil" "#,
+                )
+                .replace("</saml:Assertion>", "</evil:Assertion>"),
+            "_assertion",
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Malformed(_))
+        ));
+
+        // A signature in another namespace is not a signature
+        let response = sign(RESPONSE, "_assertion").replace(
+            r#"xmlns:ds="http://www.w3.org/2000/09/xmldsig#""#,
+            r#"xmlns:ds="urn:evil""#,
+        );
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Unsigned)
+        ));
+
+        // Rebinding a prefix changes the signed content
+        let response = sign(RESPONSE, "_assertion")
+            .replace("<saml:NameID ", r#"<saml:NameID xmlns:saml="urn:evil" "#);
+        assert!(matches!(
+            validate(&response, now()),
+            Err(ResponseError::Signature(SignatureError::DigestMismatch))
+        ));
+    }
+
+    #[test]
+    fn comments_do_not_truncate_text() {
+        // Comments are not part of the signed content, but must not cut the
+        // name ID short either
+        let response =
+            sign(RESPONSE, "_assertion").replace(">alice-id<", ">alice<!-- comment -->-id<");
+        let assertion = validate(&response, now()).unwrap();
+        assert_eq!(assertion.name_id, "alice-id");
+    }
+}
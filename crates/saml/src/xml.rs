@@ -0,0 +1,631 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small non-validating XML parser, just enough to process SAML messages.
+//!
+//! Documents with a DTD are rejected to avoid entity expansion attacks.
+
+use thiserror::Error;
+
+pub(crate) const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Maximum nesting depth of elements
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid XML document at offset {position}: {message}")]
+pub struct ParseError {
+    message: &'static str,
+    position: usize,
+}
+
+#[derive(Debug)]
+enum Node {
+    Element(ElementData),
+    Text(String),
+}
+
+/// Namespace declarations as `(prefix, uri)` pairs, the default namespace
+/// having an empty prefix
+type Namespaces = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct ElementData {
+    parent: Option<usize>,
+    prefix: Option<String>,
+    local_name: String,
+    namespaces: Namespaces,
+    attributes: Vec<Attribute>,
+    children: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Attribute {
+    pub prefix: Option<String>,
+    pub local_name: String,
+    pub value: String,
+}
+
+/// A parsed XML document
+#[derive(Debug)]
+pub struct Document {
+    nodes: Vec<Node>,
+}
+
+impl Document {
+    /// Parse a document
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            input,
+            pos: 0,
+            nodes: Vec::new(),
+        };
+        parser.parse_document()?;
+
+        let document = Self {
+            nodes: parser.nodes,
+        };
+
+        // Make sure all the prefixes in use are declared
+        for element in document.root().descendants() {
+            let undeclared = element
+                .prefix()
+                .into_iter()
+                .chain(
+                    element
+                        .attributes()
+                        .iter()
+                        .filter_map(|a| a.prefix.as_deref()),
+                )
+                .any(|prefix| element.lookup_namespace(prefix).is_none());
+            if undeclared {
+                return Err(ParseError {
+                    message: "undeclared namespace prefix",
+                    position: 0,
+                });
+            }
+        }
+
+        Ok(document)
+    }
+
+    /// The root element of the document
+    #[must_use]
+    pub fn root(&self) -> ElementRef<'_> {
+        ElementRef { doc: self, id: 0 }
+    }
+
+    fn element(&self, id: usize) -> &ElementData {
+        match &self.nodes[id] {
+            Node::Element(e) => e,
+            Node::Text(_) => unreachable!("node is not an element"),
+        }
+    }
+}
+
+/// A reference to an element in a [`Document`]
+#[derive(Debug, Clone, Copy)]
+pub struct ElementRef<'a> {
+    doc: &'a Document,
+    id: usize,
+}
+
+impl PartialEq for ElementRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.doc, other.doc) && self.id == other.id
+    }
+}
+
+/// A child of an element, either another element or some text
+pub(crate) enum Child<'a> {
+    Element(ElementRef<'a>),
+    Text(&'a str),
+}
+
+impl<'a> ElementRef<'a> {
+    fn data(&self) -> &'a ElementData {
+        self.doc.element(self.id)
+    }
+
+    /// The local name of the element
+    #[must_use]
+    pub fn local_name(&self) -> &'a str {
+        &self.data().local_name
+    }
+
+    /// The prefix of the element, if any
+    #[must_use]
+    pub fn prefix(&self) -> Option<&'a str> {
+        self.data().prefix.as_deref()
+    }
+
+    /// The namespace of the element, if any
+    #[must_use]
+    pub fn namespace(&self) -> Option<&'a str> {
+        self.lookup_namespace(self.prefix().unwrap_or(""))
+    }
+
+    /// Check the namespace and local name of the element
+    #[must_use]
+    pub fn is(&self, namespace: &str, local_name: &str) -> bool {
+        self.local_name() == local_name && self.namespace() == Some(namespace)
+    }
+
+    /// Find the namespace bound to a prefix in the scope of this element. Use
+    /// an empty prefix to get the default namespace.
+    #[must_use]
+    pub fn lookup_namespace(&self, prefix: &str) -> Option<&'a str> {
+        if prefix == "xml" {
+            return Some(XML_NS);
+        }
+
+        let mut current = Some(self.id);
+        while let Some(id) = current {
+            let data = self.doc.element(id);
+            if let Some((_, uri)) = data.namespaces.iter().find(|(p, _)| p == prefix) {
+                // An empty default namespace undeclares it
+                return if uri.is_empty() { None } else { Some(uri) };
+            }
+            current = data.parent;
+        }
+
+        None
+    }
+
+    pub(crate) fn namespace_declarations(&self) -> &'a [(String, String)] {
+        &self.data().namespaces
+    }
+
+    pub(crate) fn attributes(&self) -> &'a [Attribute] {
+        &self.data().attributes
+    }
+
+    /// Get the value of an unprefixed attribute
+    #[must_use]
+    pub fn attribute(&self, local_name: &str) -> Option<&'a str> {
+        self.attributes()
+            .iter()
+            .find(|a| a.prefix.is_none() && a.local_name == local_name)
+            .map(|a| a.value.as_str())
+    }
+
+    /// The parent element, if any
+    #[must_use]
+    pub fn parent(&self) -> Option<Self> {
+        self.data().parent.map(|id| Self { doc: self.doc, id })
+    }
+
+    pub(crate) fn child_nodes(&self) -> impl Iterator<Item = Child<'a>> + 'a {
+        let doc = self.doc;
+        self.data()
+            .children
+            .iter()
+            .map(move |&id| match &doc.nodes[id] {
+                Node::Element(_) => Child::Element(ElementRef { doc, id }),
+                Node::Text(text) => Child::Text(text),
+            })
+    }
+
+    /// Iterate over the child elements
+    pub fn children(&self) -> impl Iterator<Item = ElementRef<'a>> + 'a {
+        self.child_nodes().filter_map(|child| match child {
+            Child::Element(e) => Some(e),
+            Child::Text(_) => None,
+        })
+    }
+
+    /// Iterate over the child elements with the given name
+    pub fn children_named<'n>(
+        &self,
+        namespace: &'n str,
+        local_name: &'n str,
+    ) -> impl Iterator<Item = ElementRef<'a>> + 'n
+    where
+        'a: 'n,
+    {
+        self.children()
+            .filter(move |child| child.is(namespace, local_name))
+    }
+
+    /// Get the first child element with the given name
+    #[must_use]
+    pub fn child(&self, namespace: &str, local_name: &str) -> Option<ElementRef<'a>> {
+        self.children_named(namespace, local_name).next()
+    }
+
+    /// Iterate over this element and all its descendant elements, in
+    /// document order
+    pub fn descendants(&self) -> impl Iterator<Item = ElementRef<'a>> + 'a {
+        let mut stack = vec![*self];
+        std::iter::from_fn(move || {
+            let element = stack.pop()?;
+            let children: Vec<_> = element.children().collect();
+            stack.extend(children.into_iter().rev());
+            Some(element)
+        })
+    }
+
+    /// The concatenated text content of the element and its descendants
+    #[must_use]
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        self.collect_text(&mut text);
+        text
+    }
+
+    fn collect_text(&self, buf: &mut String) {
+        for child in self.child_nodes() {
+            match child {
+                Child::Element(e) => e.collect_text(buf),
+                Child::Text(t) => buf.push_str(t),
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    nodes: Vec<Node>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            message,
+            position: self.pos,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), ParseError> {
+        if self.starts_with(s) {
+            self.pos += s.len();
+            Ok(())
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        let trimmed = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+        self.pos += rest.len() - trimmed.len();
+    }
+
+    /// Consume everything up to and including `end`, returning what was before
+    fn take_until(&mut self, end: &str) -> Result<&'a str, ParseError> {
+        let rest = self.rest();
+        let idx = rest
+            .find(end)
+            .ok_or_else(|| self.error("unterminated construct"))?;
+        self.pos += idx + end.len();
+        Ok(&rest[..idx])
+    }
+
+    fn parse_document(&mut self) -> Result<(), ParseError> {
+        if self.starts_with("\u{feff}") {
+            self.pos += '\u{feff}'.len_utf8();
+        }
+
+        self.skip_misc()?;
+        if self.starts_with("<!DOCTYPE") {
+            return Err(self.error("DTDs are not allowed"));
+        }
+        if !self.starts_with("<") {
+            return Err(self.error("expected root element"));
+        }
+        self.parse_element(None, 0)?;
+        self.skip_misc()?;
+
+        if self.pos != self.input.len() {
+            return Err(self.error("trailing content after root element"));
+        }
+
+        Ok(())
+    }
+
+    /// Skip whitespace, comments and processing instructions
+    fn skip_misc(&mut self) -> Result<(), ParseError> {
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("<?") {
+                self.take_until("?>")?;
+            } else if self.starts_with("<!--") {
+                self.take_until("-->")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<(Option<String>, String), ParseError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        if name.is_empty() {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += len;
+
+        match name.split_once(':') {
+            Some((prefix, local)) if !prefix.is_empty() && !local.is_empty() => {
+                Ok((Some(prefix.to_owned()), local.to_owned()))
+            }
+            Some(_) => Err(self.error("invalid qualified name")),
+            None => Ok((None, name.to_owned())),
+        }
+    }
+
+    /// Parse the attributes of a start tag, up to its end, returning the
+    /// namespace declarations, the attributes, and whether the element is empty
+    fn parse_attributes(&mut self) -> Result<(Namespaces, Vec<Attribute>, bool), ParseError> {
+        let mut namespaces: Namespaces = Vec::new();
+        let mut attributes: Vec<Attribute> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("/>") {
+                self.pos += 2;
+                return Ok((namespaces, attributes, true));
+            }
+            if self.starts_with(">") {
+                self.pos += 1;
+                return Ok((namespaces, attributes, false));
+            }
+
+            let (attr_prefix, attr_name) = self.parse_name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = if self.starts_with("\"") {
+                "\""
+            } else if self.starts_with("'") {
+                "'"
+            } else {
+                return Err(self.error("expected a quoted attribute value"));
+            };
+            self.pos += 1;
+            let raw = self.take_until(quote)?;
+            if raw.contains('<') {
+                return Err(self.error("invalid character in attribute value"));
+            }
+            // Attribute value normalization: literal whitespace becomes spaces
+            let raw = raw.replace("\r\n", " ").replace(['\t', '\n', '\r'], " ");
+            let value = decode_entities(&raw).ok_or_else(|| self.error("invalid entity"))?;
+
+            match (attr_prefix.as_deref(), attr_name.as_str()) {
+                (None, "xmlns") => {
+                    if namespaces
+                        .iter()
+                        .any(|(p, _): &(String, String)| p.is_empty())
+                    {
+                        return Err(self.error("duplicate namespace declaration"));
+                    }
+                    namespaces.push((String::new(), value));
+                }
+                (Some("xmlns"), prefix) => {
+                    if value.is_empty() {
+                        return Err(self.error("empty namespace declaration"));
+                    }
+                    if namespaces.iter().any(|(p, _)| p == prefix) {
+                        return Err(self.error("duplicate namespace declaration"));
+                    }
+                    namespaces.push((prefix.to_owned(), value));
+                }
+                _ => {
+                    if attributes
+                        .iter()
+                        .any(|a| a.prefix == attr_prefix && a.local_name == attr_name)
+                    {
+                        return Err(self.error("duplicate attribute"));
+                    }
+                    attributes.push(Attribute {
+                        prefix: attr_prefix,
+                        local_name: attr_name,
+                        value,
+                    });
+                }
+            }
+        }
+    }
+
+    fn parse_element(&mut self, parent: Option<usize>, depth: usize) -> Result<usize, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("document is nested too deeply"));
+        }
+
+        self.expect("<")?;
+        let (prefix, local_name) = self.parse_name()?;
+
+        let (namespaces, attributes, empty) = self.parse_attributes()?;
+
+        let id = self.nodes.len();
+        self.nodes.push(Node::Element(ElementData {
+            parent,
+            prefix,
+            local_name,
+            namespaces,
+            attributes,
+            children: Vec::new(),
+        }));
+
+        if empty {
+            return Ok(id);
+        }
+
+        loop {
+            if self.pos >= self.input.len() {
+                return Err(self.error("unterminated element"));
+            }
+
+            if self.starts_with("</") {
+                self.pos += 2;
+                let (prefix, local_name) = self.parse_name()?;
+                let data = match &self.nodes[id] {
+                    Node::Element(e) => e,
+                    Node::Text(_) => unreachable!(),
+                };
+                if prefix != data.prefix || local_name != data.local_name {
+                    return Err(self.error("mismatched end tag"));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(id);
+            } else if self.starts_with("<!--") {
+                self.take_until("-->")?;
+            } else if self.starts_with("<![CDATA[") {
+                self.pos += "<![CDATA[".len();
+                let text = self.take_until("]]>")?;
+                self.push_text(id, &text.replace("\r\n", "\n").replace('\r', "\n"));
+            } else if self.starts_with("<?") {
+                self.take_until("?>")?;
+            } else if self.starts_with("<!") {
+                return Err(self.error("unsupported markup declaration"));
+            } else if self.starts_with("<") {
+                let child = self.parse_element(Some(id), depth + 1)?;
+                self.element_mut(id).children.push(child);
+            } else {
+                let rest = self.rest();
+                let len = rest.find('<').unwrap_or(rest.len());
+                let raw = rest[..len].replace("\r\n", "\n").replace('\r', "\n");
+                let text = decode_entities(&raw).ok_or_else(|| self.error("invalid entity"))?;
+                self.pos += len;
+                self.push_text(id, &text);
+            }
+        }
+    }
+
+    fn element_mut(&mut self, id: usize) -> &mut ElementData {
+        match &mut self.nodes[id] {
+            Node::Element(e) => e,
+            Node::Text(_) => unreachable!(),
+        }
+    }
+
+    fn push_text(&mut self, parent: usize, text: &str) {
+        // Merge with the previous text node, if any
+        if let Some(&last) = self.element_mut(parent).children.last() {
+            if let Node::Text(previous) = &mut self.nodes[last] {
+                previous.push_str(text);
+                return;
+            }
+        }
+
+        let id = self.nodes.len();
+        self.nodes.push(Node::Text(text.to_owned()));
+        self.element_mut(parent).children.push(id);
+    }
+}
+
+fn decode_entities(input: &str) -> Option<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(idx) = rest.find('&') {
+        output.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let end = rest.find(';')?;
+        let entity = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = entity.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()?
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok()?
+                } else {
+                    return None;
+                };
+                char::from_u32(code)?
+            }
+        };
+        output.push(c);
+    }
+    output.push_str(rest);
+    Some(output)
+}
+
+/// Escape a string to be used in text content or in a double-quoted
+/// attribute value
+pub(crate) fn escape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_document() {
+        let doc = Document::parse(
+            r#"<?xml version="1.0"?>
+            <!-- a comment -->
+            <a:root xmlns:a="urn:a" xmlns="urn:default" attr='value &amp; &#x41;'>
+                <child>Hello <![CDATA[<world>]]> &lt;3</child>
+                <a:other xmlns="" foo="bar"/>
+            </a:root>"#,
+        )
+        .unwrap();
+
+        let root = doc.root();
+        assert!(root.is("urn:a", "root"));
+        assert_eq!(root.attribute("attr"), Some("value & A"));
+
+        let child = root.child("urn:default", "child").unwrap();
+        assert_eq!(child.text(), "Hello <world> <3");
+
+        let other = root.child("urn:a", "other").unwrap();
+        assert_eq!(other.attribute("foo"), Some("bar"));
+        assert_eq!(other.lookup_namespace(""), None);
+        assert_eq!(other.parent(), Some(root));
+
+        assert_eq!(root.descendants().count(), 3);
+    }
+
+    #[test]
+    fn reject_invalid_documents() {
+        assert!(Document::parse("").is_err());
+        assert!(Document::parse("<a>").is_err());
+        assert!(Document::parse("<a></b>").is_err());
+        assert!(Document::parse("<a/><b/>").is_err());
+        assert!(Document::parse("<x:a/>").is_err());
+        assert!(Document::parse(r#"<a b="1" b="2"/>"#).is_err());
+        assert!(Document::parse("<a>&unknown;</a>").is_err());
+        assert!(Document::parse(r#"<!DOCTYPE a [<!ENTITY b "c">]><a>&b;</a>"#).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+ALTER TABLE oauth2_clients
+  ADD COLUMN "grant_type_ciba" BOOLEAN NOT NULL DEFAULT 'f',
+  ADD COLUMN "backchannel_token_delivery_mode" TEXT,
+  ADD COLUMN "backchannel_client_notification_endpoint" TEXT;
+
+CREATE TABLE oauth2_ciba_requests (
+  "id" BIGSERIAL PRIMARY KEY,
+
+  "oauth2_client_id" BIGINT NOT NULL REFERENCES oauth2_clients (id) ON DELETE CASCADE,
+  "user_id" BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+
+  -- This is what the client polls the token endpoint with
+  "auth_req_id" TEXT UNIQUE NOT NULL,
+  "scope" TEXT NOT NULL,
+  "binding_message" TEXT,
+  "client_notification_token" TEXT,
+
+  "created_at"   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  "expires_at"   TIMESTAMP WITH TIME ZONE NOT NULL,
+  "approved_at"  TIMESTAMP WITH TIME ZONE,
+  "denied_at"    TIMESTAMP WITH TIME ZONE,
+  "exchanged_at" TIMESTAMP WITH TIME ZONE,
+
+  -- Set when the user approves the request from one of their browser sessions
+  "oauth2_session_id" BIGINT REFERENCES oauth2_sessions (id) ON DELETE CASCADE,
+
+  -- A request can be either approved or denied, but not both
+  CONSTRAINT "oauth2_ciba_requests_approved_xor_denied"
+  CHECK ("approved_at" IS NULL OR "denied_at" IS NULL),
+
+  -- If it was approved there is an oauth2_session_id attached to it
+  CONSTRAINT "oauth2_ciba_requests_approved_and_session"
+  CHECK (("approved_at" IS NULL     AND "oauth2_session_id" IS NULL)
+      OR ("approved_at" IS NOT NULL AND "oauth2_session_id" IS NOT NULL)),
+
+  -- Tokens can only be exchanged after the request was approved
+  CONSTRAINT "oauth2_ciba_requests_exchanged_after_approval"
+  CHECK ("exchanged_at" IS NULL OR "approved_at" IS NOT NULL)
+);
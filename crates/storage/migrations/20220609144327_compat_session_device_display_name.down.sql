@@ -0,0 +1,21 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+DROP INDEX compat_sessions_active_device_id_key;
+
+ALTER TABLE compat_sessions
+  ADD CONSTRAINT compat_sessions_device_id_key UNIQUE (device_id);
+
+ALTER TABLE compat_sessions
+  DROP COLUMN initial_device_display_name;
@@ -0,0 +1,37 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+CREATE TABLE user_totp (
+  "id" BIGSERIAL PRIMARY KEY,
+  "user_id" BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  "encrypted_secret" TEXT NOT NULL,
+  "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  "confirmed_at" TIMESTAMP WITH TIME ZONE,
+  "last_used_step" BIGINT,
+
+  CONSTRAINT user_totp_user_unique UNIQUE ("user_id")
+);
+
+CREATE TABLE user_recovery_codes (
+  "id" BIGSERIAL PRIMARY KEY,
+  "user_id" BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  "code_hash" TEXT NOT NULL,
+  "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  "used_at" TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX user_recovery_codes_user_idx ON user_recovery_codes ("user_id");
+
+ALTER TABLE user_session_authentications
+  ADD COLUMN "second_factor" TEXT;
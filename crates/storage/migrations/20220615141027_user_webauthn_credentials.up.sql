@@ -0,0 +1,28 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+CREATE TABLE user_webauthn_credentials (
+  "id" BIGSERIAL PRIMARY KEY,
+  "user_id" BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  "credential_id" TEXT NOT NULL,
+  "public_key" JSONB NOT NULL,
+  "sign_count" BIGINT NOT NULL DEFAULT 0,
+  "name" TEXT NOT NULL,
+  "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+  "last_used_at" TIMESTAMP WITH TIME ZONE,
+
+  CONSTRAINT user_webauthn_credentials_credential_id_unique UNIQUE ("credential_id")
+);
+
+CREATE INDEX user_webauthn_credentials_user_idx ON user_webauthn_credentials ("user_id");
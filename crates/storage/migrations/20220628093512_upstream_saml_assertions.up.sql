@@ -0,0 +1,22 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Assertions consumed from upstream SAML identity providers, remembered until
+-- they expire so that they can't be replayed
+CREATE TABLE upstream_saml_assertions (
+  "provider" TEXT NOT NULL,
+  "assertion_id" TEXT NOT NULL,
+  "not_on_or_after" TIMESTAMP WITH TIME ZONE NOT NULL,
+  PRIMARY KEY ("provider", "assertion_id")
+);
@@ -0,0 +1,26 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Users provisioned from an external password backend, like an LDAP directory.
+-- Only those can log in through it, so that a directory entry can't take over
+-- a local account which happens to have the same name.
+ALTER TABLE users
+  ADD COLUMN "external_password" BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Users provisioned before this column existed are the ones without a local
+-- password nor an upstream account
+UPDATE users
+  SET "external_password" = TRUE
+  WHERE NOT EXISTS (SELECT 1 FROM user_passwords WHERE user_passwords.user_id = users.id)
+    AND NOT EXISTS (SELECT 1 FROM upstream_oauth_links WHERE upstream_oauth_links.user_id = users.id);
@@ -0,0 +1,28 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Requests for a password reset, kept for a while to rate limit them by
+-- address and by client IP, whether the address belongs to an account or not
+CREATE TABLE user_password_reset_requests (
+  "id" BIGSERIAL PRIMARY KEY,
+  "email" TEXT NOT NULL,
+  "ip_address" TEXT,
+  "requested_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now()
+);
+
+CREATE INDEX user_password_reset_requests_email_idx
+  ON user_password_reset_requests (LOWER("email"));
+
+CREATE INDEX user_password_reset_requests_ip_address_idx
+  ON user_password_reset_requests ("ip_address");
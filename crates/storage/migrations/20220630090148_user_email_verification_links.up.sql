@@ -0,0 +1,27 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Email logins send a short code and a link with a long token. Both live in
+-- the same verification, so that using one of them consumes the other, and
+-- only the token is accepted from the link.
+ALTER TABLE user_email_verifications
+  ADD COLUMN "link_token" TEXT UNIQUE,
+  ADD COLUMN "attempts" INTEGER NOT NULL DEFAULT 0;
+
+-- Short codes are only unique per address, they would run out otherwise
+ALTER TABLE user_email_verifications
+  DROP CONSTRAINT user_email_verifications_code_key;
+
+CREATE INDEX user_email_verifications_user_email_id_code_idx
+  ON user_email_verifications ("user_email_id", "code");
@@ -0,0 +1,18 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+ALTER SEQUENCE user_email_requests_id_seq RENAME TO user_password_reset_requests_id_seq;
+ALTER INDEX user_email_requests_ip_address_idx RENAME TO user_password_reset_requests_ip_address_idx;
+ALTER INDEX user_email_requests_email_idx RENAME TO user_password_reset_requests_email_idx;
+ALTER TABLE user_email_requests RENAME TO user_password_reset_requests;
@@ -0,0 +1,20 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Device IDs only need to be unique for each user
+DROP INDEX compat_sessions_active_device_id_key;
+
+CREATE UNIQUE INDEX compat_sessions_active_device_id_key
+  ON compat_sessions (user_id, device_id)
+  WHERE deleted_at IS NULL;
@@ -13,7 +13,6 @@
 // limitations under the License.
 
 use anyhow::{bail, Context};
-use argon2::{Argon2, PasswordHash};
 use chrono::{DateTime, Duration, Utc};
 use mas_data_model::{
     CompatAccessToken, CompatRefreshToken, CompatSession, CompatSsoLogin, CompatSsoLoginState,
@@ -21,12 +20,12 @@ use mas_data_model::{
 };
 use sqlx::{postgres::types::PgInterval, Acquire, PgExecutor, Postgres};
 use thiserror::Error;
-use tokio::task;
 use tracing::{info_span, Instrument};
 use url::Url;
 
 use crate::{
-    user::lookup_user_by_username, DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend,
+    password::{authenticate_user, ExternalUser, PasswordBackends, PasswordSchemes},
+    DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend,
 };
 
 struct CompatAccessTokenLookup {
@@ -38,6 +37,7 @@ struct CompatAccessTokenLookup {
     compat_session_created_at: DateTime<Utc>,
     compat_session_deleted_at: Option<DateTime<Utc>>,
     compat_session_device_id: String,
+    compat_session_initial_device_display_name: Option<String>,
     user_id: i64,
     user_username: String,
     user_email_id: Option<i64>,
@@ -83,6 +83,7 @@ pub async fn lookup_active_compat_access_token(
                 cs.created_at      AS "compat_session_created_at",
                 cs.deleted_at      AS "compat_session_deleted_at",
                 cs.device_id       AS "compat_session_device_id",
+                cs.initial_device_display_name AS "compat_session_initial_device_display_name",
                  u.id              AS "user_id!",
                  u.username        AS "user_username!",
                 ue.id              AS "user_email_id?",
@@ -144,6 +145,7 @@ pub async fn lookup_active_compat_access_token(
         data: res.compat_session_id,
         user,
         device,
+        initial_device_display_name: res.compat_session_initial_device_display_name,
         created_at: res.compat_session_created_at,
         deleted_at: res.compat_session_deleted_at,
     };
@@ -163,6 +165,7 @@ pub struct CompatRefreshTokenLookup {
     compat_session_created_at: DateTime<Utc>,
     compat_session_deleted_at: Option<DateTime<Utc>>,
     compat_session_device_id: String,
+    compat_session_initial_device_display_name: Option<String>,
     user_id: i64,
     user_username: String,
     user_email_id: Option<i64>,
@@ -212,6 +215,7 @@ pub async fn lookup_active_compat_refresh_token(
                 cs.created_at      AS "compat_session_created_at",
                 cs.deleted_at      AS "compat_session_deleted_at",
                 cs.device_id       AS "compat_session_device_id",
+                cs.initial_device_display_name AS "compat_session_initial_device_display_name",
                 u.id               AS "user_id!",
                 u.username         AS "user_username!",
                 ue.id              AS "user_email_id?",
@@ -281,6 +285,7 @@ pub async fn lookup_active_compat_refresh_token(
         data: res.compat_session_id,
         user,
         device,
+        initial_device_display_name: res.compat_session_initial_device_display_name,
         created_at: res.compat_session_created_at,
         deleted_at: res.compat_session_deleted_at,
     };
@@ -288,69 +293,168 @@ pub async fn lookup_active_compat_refresh_token(
     Ok((refresh_token, access_token, session))
 }
 
-#[tracing::instrument(skip(conn, password), err)]
+/// Lookup a user by username, check their password and start a new
+/// compatibility session.
+///
+/// If the user was provisioned from an external password backend during this
+/// login, the attributes it provided are returned alongside the session.
+#[tracing::instrument(skip(conn, backends, schemes, password), err)]
 pub async fn compat_login(
     conn: impl Acquire<'_, Database = Postgres>,
+    backends: &PasswordBackends,
+    schemes: &PasswordSchemes,
     username: &str,
     password: &str,
     device: Device,
-) -> Result<CompatSession<PostgresqlBackend>, anyhow::Error> {
+    initial_device_display_name: Option<String>,
+) -> Result<(CompatSession<PostgresqlBackend>, Option<ExternalUser>), anyhow::Error> {
     let mut txn = conn.begin().await.context("could not start transaction")?;
 
-    // First, lookup the user
-    let user = lookup_user_by_username(&mut txn, username).await?;
+    let (user, provisioned) =
+        authenticate_user(&mut txn, backends, schemes, username, password).await?;
+
+    // Logging in again with the same device replaces the previous session. The
+    // device keeps its display name, as the initial one is only used for new
+    // devices.
+    let previous = get_active_compat_sessions(&mut txn, &user)
+        .await?
+        .into_iter()
+        .find(|session| session.device == device);
+    let initial_device_display_name = match previous {
+        Some(previous) => previous.initial_device_display_name,
+        None => initial_device_display_name,
+    };
+    end_compat_sessions_for_device(&mut txn, &user, &device).await?;
 
-    // Now, fetch the hashed password from the user associated with that session
-    let hashed_password: String = sqlx::query_scalar!(
-        r#"
-            SELECT up.hashed_password
-            FROM user_passwords up
-            WHERE up.user_id = $1
-            ORDER BY up.created_at DESC
-            LIMIT 1
-        "#,
-        user.data,
-    )
-    .fetch_one(&mut txn)
-    .instrument(tracing::info_span!("Lookup hashed password"))
-    .await?;
+    let session = start_compat_session(&mut txn, user, device, initial_device_display_name).await?;
 
-    // TODO: pass verifiers list as parameter
-    // Verify the password in a blocking thread to avoid blocking the async executor
-    let password = password.to_string();
-    task::spawn_blocking(move || {
-        let context = Argon2::default();
-        let hasher = PasswordHash::new(&hashed_password)?;
-        hasher.verify_password(&[&context], &password)
-    })
-    .instrument(tracing::info_span!("Verify hashed password"))
-    .await??;
+    txn.commit().await.context("could not commit transaction")?;
+    Ok((session, provisioned))
+}
 
+#[tracing::instrument(skip_all, fields(user.id = user.data, device = device.as_str()), err)]
+pub async fn start_compat_session(
+    executor: impl PgExecutor<'_>,
+    user: User<PostgresqlBackend>,
+    device: Device,
+    initial_device_display_name: Option<String>,
+) -> Result<CompatSession<PostgresqlBackend>, anyhow::Error> {
     let res = sqlx::query_as!(
         IdAndCreationTime,
         r#"
-            INSERT INTO compat_sessions (user_id, device_id)
-            VALUES ($1, $2)
+            INSERT INTO compat_sessions (user_id, device_id, initial_device_display_name)
+            VALUES ($1, $2, $3)
             RETURNING id, created_at
         "#,
         user.data,
         device.as_str(),
+        initial_device_display_name,
     )
-    .fetch_one(&mut txn)
+    .fetch_one(executor)
     .instrument(tracing::info_span!("Insert compat session"))
     .await
     .context("could not insert compat session")?;
 
-    let session = CompatSession {
+    Ok(CompatSession {
         data: res.id,
         user,
         device,
+        initial_device_display_name,
         created_at: res.created_at,
         deleted_at: None,
-    };
+    })
+}
 
-    txn.commit().await.context("could not commit transaction")?;
-    Ok(session)
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn get_active_compat_sessions(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<Vec<CompatSession<PostgresqlBackend>>, anyhow::Error> {
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                cs.id,
+                cs.device_id,
+                cs.initial_device_display_name,
+                cs.created_at
+            FROM compat_sessions cs
+            WHERE cs.user_id = $1
+              AND cs.deleted_at IS NULL
+            ORDER BY cs.created_at
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .instrument(tracing::info_span!("Fetch active compat sessions"))
+    .await
+    .context("could not fetch compat sessions")?;
+
+    res.into_iter()
+        .map(|row| {
+            let device = Device::try_from(row.device_id).map_err(|_| DatabaseInconsistencyError)?;
+            Ok(CompatSession {
+                data: row.id,
+                user: user.clone(),
+                device,
+                initial_device_display_name: row.initial_device_display_name,
+                created_at: row.created_at,
+                deleted_at: None,
+            })
+        })
+        .collect()
+}
+
+/// End all the active compat sessions of a user, returning the devices which
+/// were logged out
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn end_compat_sessions_for_user(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<Vec<Device>, anyhow::Error> {
+    let device_ids: Vec<String> = sqlx::query_scalar!(
+        r#"
+            UPDATE compat_sessions
+            SET deleted_at = NOW()
+            WHERE user_id = $1
+              AND deleted_at IS NULL
+            RETURNING device_id
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .instrument(tracing::info_span!("End compat sessions for user"))
+    .await
+    .context("could not end compat sessions")?;
+
+    device_ids
+        .into_iter()
+        .map(|id| Device::try_from(id).map_err(|_| DatabaseInconsistencyError.into()))
+        .collect()
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data, device = device.as_str()), err)]
+pub async fn end_compat_sessions_for_device(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    device: &Device,
+) -> Result<u64, anyhow::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE compat_sessions
+            SET deleted_at = NOW()
+            WHERE user_id = $1
+              AND device_id = $2
+              AND deleted_at IS NULL
+        "#,
+        user.data,
+        device.as_str(),
+    )
+    .execute(executor)
+    .instrument(tracing::info_span!("End compat sessions for device"))
+    .await
+    .context("could not end compat sessions")?;
+
+    Ok(res.rows_affected())
 }
 
 #[tracing::instrument(skip(executor, token), err)]
@@ -561,6 +665,7 @@ struct CompatSsoLoginLookup {
     compat_session_created_at: Option<DateTime<Utc>>,
     compat_session_deleted_at: Option<DateTime<Utc>>,
     compat_session_device_id: Option<String>,
+    compat_session_initial_device_display_name: Option<String>,
     user_id: Option<i64>,
     user_username: Option<String>,
     user_email_id: Option<i64>,
@@ -616,6 +721,7 @@ impl TryFrom<CompatSsoLoginLookup> for CompatSsoLogin<PostgresqlBackend> {
                     data: id,
                     user,
                     device,
+                    initial_device_display_name: res.compat_session_initial_device_display_name,
                     created_at,
                     deleted_at,
                 })
@@ -688,6 +794,7 @@ pub async fn get_compat_sso_login_by_id(
                 cs.created_at      AS "compat_session_created_at?",
                 cs.deleted_at      AS "compat_session_deleted_at?",
                 cs.device_id       AS "compat_session_device_id?",
+                cs.initial_device_display_name AS "compat_session_initial_device_display_name?",
                 u.id               AS "user_id?",
                 u.username         AS "user_username?",
                 ue.id              AS "user_email_id?",
@@ -732,6 +839,7 @@ pub async fn get_compat_sso_login_by_token(
                 cs.created_at      AS "compat_session_created_at?",
                 cs.deleted_at      AS "compat_session_deleted_at?",
                 cs.device_id       AS "compat_session_device_id?",
+                cs.initial_device_display_name AS "compat_session_initial_device_display_name?",
                 u.id               AS "user_id?",
                 u.username         AS "user_username?",
                 ue.id              AS "user_email_id?",
@@ -787,6 +895,7 @@ pub async fn fullfill_compat_sso_login(
         data: res.id,
         user,
         device,
+        initial_device_display_name: None,
         created_at: res.created_at,
         deleted_at: None,
     };
@@ -857,3 +966,167 @@ pub async fn mark_compat_sso_login_as_exchanged(
     login.state = state;
     Ok(login)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{random_name, test_transaction},
+        user::{register_passwordless_user, register_user},
+    };
+
+    fn device(id: &str) -> Device {
+        Device::try_from(id.to_owned()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compat_login_device() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let backends = PasswordBackends::default();
+        let schemes = PasswordSchemes::default();
+        let alice = random_name("alice");
+        let bob = random_name("bob");
+        register_user(&mut txn, &schemes, &alice, "hunter2")
+            .await
+            .unwrap();
+        register_user(&mut txn, &schemes, &bob, "hunter2")
+            .await
+            .unwrap();
+
+        let (first, _) = compat_login(
+            &mut txn,
+            &backends,
+            &schemes,
+            &alice,
+            "hunter2",
+            device("PHONE00001"),
+            Some("Phone".to_owned()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.initial_device_display_name.as_deref(), Some("Phone"));
+        add_compat_access_token(&mut txn, &first, "first-token".to_owned(), None)
+            .await
+            .unwrap();
+
+        // Another user can use the same device ID without affecting the first one
+        let (bobs, _) = compat_login(
+            &mut txn,
+            &backends,
+            &schemes,
+            &bob,
+            "hunter2",
+            device("PHONE00001"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(bobs.device, first.device);
+        lookup_active_compat_access_token(&mut txn, "first-token")
+            .await
+            .unwrap();
+
+        // Logging in again with the device replaces its session, and keeps the
+        // name it was given first
+        let (second, _) = compat_login(
+            &mut txn,
+            &backends,
+            &schemes,
+            &alice,
+            "hunter2",
+            device("PHONE00001"),
+            Some("Renamed".to_owned()),
+        )
+        .await
+        .unwrap();
+        assert_ne!(first.data, second.data);
+        assert_eq!(second.device, device("PHONE00001"));
+        assert_eq!(second.initial_device_display_name.as_deref(), Some("Phone"));
+        let err = lookup_active_compat_access_token(&mut txn, "first-token")
+            .await
+            .unwrap_err();
+        assert!(err.not_found());
+
+        let sessions = get_active_compat_sessions(&mut txn, &second.user)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].data, second.data);
+
+        // A new device gets the name it asked for
+        let (tablet, _) = compat_login(
+            &mut txn,
+            &backends,
+            &schemes,
+            &alice,
+            "hunter2",
+            device("TABLET0001"),
+            Some("Tablet".to_owned()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tablet.initial_device_display_name.as_deref(),
+            Some("Tablet")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_end_compat_sessions_for_user() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let alice = register_passwordless_user(&mut txn, &random_name("alice"))
+            .await
+            .unwrap();
+        let bob = register_passwordless_user(&mut txn, &random_name("bob"))
+            .await
+            .unwrap();
+        for id in ["PHONE00001", "TABLET0001"] {
+            start_compat_session(&mut txn, alice.clone(), device(id), None)
+                .await
+                .unwrap();
+        }
+        let bobs = start_compat_session(&mut txn, bob.clone(), device("PHONE00001"), None)
+            .await
+            .unwrap();
+
+        // Logging out everywhere only touches that user's devices
+        let mut ended = end_compat_sessions_for_user(&mut txn, &alice)
+            .await
+            .unwrap();
+        ended.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(ended, [device("PHONE00001"), device("TABLET0001")]);
+        assert!(get_active_compat_sessions(&mut txn, &alice)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let sessions = get_active_compat_sessions(&mut txn, &bob).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].data, bobs.data);
+
+        // Ending a device of a user leaves the same device ID of others alone
+        assert_eq!(
+            end_compat_sessions_for_device(&mut txn, &alice, &device("PHONE00001"))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            get_active_compat_sessions(&mut txn, &bob)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits on the emails anonymous clients can have sent, shared by the
+//! password reset and the email login, so that they can't be used to flood
+//! someone's inbox
+
+use std::net::IpAddr;
+
+use anyhow::Context;
+use chrono::Duration;
+use sqlx::{postgres::types::PgInterval, PgConnection};
+use tracing::{info_span, warn, Instrument};
+
+/// Requests allowed for the same address within the window
+const MAX_REQUESTS_PER_ADDRESS: i64 = 3;
+
+/// Requests allowed from the same client IP within the window
+const MAX_REQUESTS_PER_IP: i64 = 10;
+
+/// How long requests are remembered
+const REQUEST_WINDOW_SECONDS: i64 = 60 * 60;
+
+/// Record a request to email an address, from a client IP. Returns `false` if
+/// there were too many recent requests for this address or from this IP, in
+/// which case nothing should be sent.
+#[tracing::instrument(skip(conn), err)]
+pub async fn record_email_request(
+    conn: &mut PgConnection,
+    email: &str,
+    ip: Option<IpAddr>,
+) -> anyhow::Result<bool> {
+    let window = PgInterval::try_from(Duration::seconds(REQUEST_WINDOW_SECONDS))
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+    let ip = ip.map(|ip| ip.to_string());
+
+    sqlx::query!(
+        r#"
+            DELETE FROM user_email_requests
+            WHERE requested_at + $1 < NOW()
+        "#,
+        window,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("Prune email requests"))
+    .await
+    .context("could not prune email requests")?;
+
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                COUNT(*) FILTER (WHERE LOWER(email) = LOWER($1)) AS "by_address!",
+                COUNT(*) FILTER (WHERE ip_address = $2)          AS "by_ip!"
+            FROM user_email_requests
+            WHERE LOWER(email) = LOWER($1) OR ip_address = $2
+        "#,
+        email,
+        ip,
+    )
+    .fetch_one(&mut *conn)
+    .instrument(info_span!("Count email requests"))
+    .await
+    .context("could not count email requests")?;
+
+    if res.by_address >= MAX_REQUESTS_PER_ADDRESS || res.by_ip >= MAX_REQUESTS_PER_IP {
+        warn!(
+            by_address = res.by_address,
+            by_ip = res.by_ip,
+            "Too many email requests"
+        );
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        r#"
+            INSERT INTO user_email_requests (email, ip_address)
+            VALUES ($1, $2)
+        "#,
+        email,
+        ip,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("Record email request"))
+    .await
+    .context("could not record email request")?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_name, test_transaction};
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let ip: Option<IpAddr> = Some([192, 0, 2, 1].into());
+        let email = format!("{}@example.com", random_name("request"));
+        for _ in 0..MAX_REQUESTS_PER_ADDRESS {
+            assert!(record_email_request(&mut txn, &email, ip).await.unwrap());
+        }
+
+        // Addresses are compared case-insensitively
+        assert!(!record_email_request(&mut txn, &email.to_uppercase(), None)
+            .await
+            .unwrap());
+
+        // Other addresses are still limited by the client IP
+        for _ in MAX_REQUESTS_PER_ADDRESS..MAX_REQUESTS_PER_IP {
+            let other = format!("{}@example.com", random_name("request"));
+            assert!(record_email_request(&mut txn, &other, ip).await.unwrap());
+        }
+        let other = format!("{}@example.com", random_name("request"));
+        assert!(!record_email_request(&mut txn, &other, ip).await.unwrap());
+        assert!(record_email_request(&mut txn, &other, None).await.unwrap());
+    }
+}
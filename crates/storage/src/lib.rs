@@ -41,6 +41,7 @@ impl StorageBackend for PostgresqlBackend {
     type AuthenticationData = i64;
     type AuthorizationGrantData = i64;
     type BrowserSessionData = i64;
+    type CibaRequestData = i64;
     type ClientData = i64;
     type CompatAccessTokenData = i64;
     type CompatRefreshTokenData = i64;
@@ -61,8 +62,19 @@ struct IdAndCreationTime {
 }
 
 pub mod compat;
+pub mod email_request;
+pub mod login_throttle;
 pub mod oauth2;
+pub mod password;
+pub mod password_reset;
+pub mod totp;
+pub mod upstream_oauth2;
+pub mod upstream_saml;
 pub mod user;
+pub mod webauthn;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 /// Embedded migrations, allowing them to run on startup
 pub static MIGRATOR: Migrator = sqlx::migrate!();
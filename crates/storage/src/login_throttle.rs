@@ -0,0 +1,385 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Throttling of failed login attempts.
+//!
+//! Failures are counted both for the username which was tried and for the
+//! client IP which tried it. After a few free attempts, each new failure makes
+//! the client wait exponentially longer before trying again, and too many
+//! failures on the same username lock it for a while. Counters are forgotten
+//! after a day without failures.
+
+use std::net::IpAddr;
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{postgres::types::PgInterval, PgConnection, PgExecutor};
+use tracing::{info_span, warn, Instrument};
+
+/// Consecutive failures on a username after which it gets locked
+const LOCKOUT_THRESHOLD: i32 = 10;
+
+/// How long a username stays locked
+const LOCKOUT_SECONDS: i64 = 60 * 60;
+
+/// Upper bound of the exponential backoff
+const MAX_BACKOFF_SECONDS: i64 = 5 * 60;
+
+/// How long without failures before a counter starts over
+const RESET_AFTER_SECONDS: i64 = 24 * 60 * 60;
+
+/// Length of the backoff schedule handed to the database. Failures past the
+/// last step reuse its backoff, which is the maximum by then.
+const BACKOFF_STEPS: i32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Username,
+    Ip,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Username => "username",
+            Scope::Ip => "ip",
+        }
+    }
+
+    /// Failures which don't slow down the next attempt. IPs get more of them,
+    /// since many users can share an address.
+    fn free_attempts(self) -> i32 {
+        match self {
+            Scope::Username => 3,
+            Scope::Ip => 10,
+        }
+    }
+
+    /// How long to refuse new attempts after the given number of consecutive
+    /// failures
+    fn backoff(self, failures: i32) -> Duration {
+        if self == Scope::Username && failures >= LOCKOUT_THRESHOLD {
+            return Duration::seconds(LOCKOUT_SECONDS);
+        }
+
+        let exponent = failures - self.free_attempts() - 1;
+        if exponent < 0 {
+            Duration::zero()
+        } else if exponent >= 16 {
+            Duration::seconds(MAX_BACKOFF_SECONDS)
+        } else {
+            Duration::seconds((1 << exponent).min(MAX_BACKOFF_SECONDS))
+        }
+    }
+
+    /// The backoff in seconds after each number of consecutive failures,
+    /// starting at one
+    fn schedule(self) -> Vec<i64> {
+        (1..=BACKOFF_STEPS)
+            .map(|failures| self.backoff(failures).num_seconds())
+            .collect()
+    }
+}
+
+fn encode_interval(duration: Duration) -> anyhow::Result<PgInterval> {
+    PgInterval::try_from(duration).map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))
+}
+
+/// The outcome of recording a failed login attempt
+#[derive(Debug, Clone, Copy)]
+pub struct LoginFailure {
+    /// Consecutive failures on the username
+    pub failures: i32,
+
+    /// Until when new attempts are refused
+    pub blocked_until: DateTime<Utc>,
+
+    /// Whether the username got locked by this failure
+    pub locked: bool,
+}
+
+/// Check whether attempts on a username, or from a client IP, are currently
+/// refused, and until when.
+///
+/// This locks the throttle of the username until the transaction ends, so it
+/// has to run in the transaction which then records the outcome of the
+/// attempt. Concurrent attempts on the same username wait for it instead of
+/// all passing the check before any failure is recorded.
+#[tracing::instrument(skip(executor), err)]
+pub async fn login_blocked_until(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let blocked_until = sqlx::query_scalar!(
+        r#"
+            WITH throttle AS (
+                INSERT INTO login_throttles (scope, key, failures, last_failure_at, blocked_until)
+                VALUES ('username', $1, 0, NOW(), NOW())
+                ON CONFLICT (scope, key) DO UPDATE
+                SET key = EXCLUDED.key
+                RETURNING blocked_until
+            )
+            SELECT MAX(blocked_until) AS "blocked_until"
+            FROM (
+                SELECT blocked_until FROM throttle
+                UNION ALL
+                SELECT blocked_until
+                FROM login_throttles
+                WHERE scope = 'ip' AND key = $2
+            ) AS throttles
+            WHERE blocked_until > NOW()
+        "#,
+        username,
+        ip.map(|ip| ip.to_string()),
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Check login throttle"))
+    .await?;
+
+    if let Some(blocked_until) = blocked_until {
+        warn!(%blocked_until, "Refusing login attempt while throttled");
+    }
+
+    Ok(blocked_until)
+}
+
+async fn record_failure(
+    executor: impl PgExecutor<'_>,
+    scope: Scope,
+    key: &str,
+) -> anyhow::Result<(i32, DateTime<Utc>)> {
+    let reset_after = encode_interval(Duration::seconds(RESET_AFTER_SECONDS))?;
+
+    // Counting the failure and extending the block happen in one statement, so
+    // that concurrent failures can't both compute their backoff from the same
+    // count
+    let res = sqlx::query!(
+        r#"
+            INSERT INTO login_throttles (scope, key, failures, last_failure_at, blocked_until)
+            VALUES ($1, $2, 1, NOW(), NOW() + ($4::BIGINT[])[1] * INTERVAL '1 second')
+            ON CONFLICT (scope, key) DO UPDATE
+            SET failures = CASE
+                  WHEN login_throttles.last_failure_at + $3 < NOW() THEN 1
+                  ELSE login_throttles.failures + 1
+                END,
+                last_failure_at = NOW(),
+                blocked_until = GREATEST(
+                  login_throttles.blocked_until,
+                  NOW() + ($4::BIGINT[])[LEAST(
+                    CASE
+                      WHEN login_throttles.last_failure_at + $3 < NOW() THEN 1
+                      ELSE login_throttles.failures + 1
+                    END,
+                    CARDINALITY($4::BIGINT[])
+                  )] * INTERVAL '1 second'
+                )
+            RETURNING failures, blocked_until
+        "#,
+        scope.as_str(),
+        key,
+        reset_after,
+        &scope.schedule(),
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Record login failure"))
+    .await
+    .context("could not record login failure")?;
+
+    Ok((res.failures, res.blocked_until))
+}
+
+/// Record a failed login attempt on a username from a client IP. The caller
+/// has to commit it even though the login failed.
+#[tracing::instrument(skip(conn), err)]
+pub async fn record_login_failure(
+    conn: &mut PgConnection,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> anyhow::Result<LoginFailure> {
+    let (failures, mut blocked_until) =
+        record_failure(&mut *conn, Scope::Username, username).await?;
+
+    if let Some(ip) = ip {
+        let (_, ip_blocked_until) = record_failure(&mut *conn, Scope::Ip, &ip.to_string()).await?;
+        blocked_until = blocked_until.max(ip_blocked_until);
+    }
+
+    let locked = failures >= LOCKOUT_THRESHOLD;
+    if locked {
+        warn!(failures, %blocked_until, "Too many failed login attempts, locking the account");
+    } else {
+        warn!(failures, %blocked_until, "Failed login attempt");
+    }
+
+    Ok(LoginFailure {
+        failures,
+        blocked_until,
+        locked,
+    })
+}
+
+/// Forget the failed attempts on a username, after a successful login or
+/// when an admin unlocks it. Returns `false` if there were none.
+#[tracing::instrument(skip(executor), err)]
+pub async fn clear_login_failures(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM login_throttles
+            WHERE scope = 'username' AND key = $1
+        "#,
+        username,
+    )
+    .execute(executor)
+    .instrument(info_span!("Clear login failures"))
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_name, test_transaction};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Scope::Username.backoff(1), Duration::zero());
+        assert_eq!(Scope::Username.backoff(3), Duration::zero());
+        assert_eq!(Scope::Username.backoff(4), Duration::seconds(1));
+        assert_eq!(Scope::Username.backoff(6), Duration::seconds(4));
+        assert_eq!(Scope::Username.backoff(9), Duration::seconds(32));
+        assert_eq!(
+            Scope::Username.backoff(10),
+            Duration::seconds(LOCKOUT_SECONDS)
+        );
+
+        assert_eq!(Scope::Ip.backoff(10), Duration::zero());
+        assert_eq!(Scope::Ip.backoff(11), Duration::seconds(1));
+        assert_eq!(
+            Scope::Ip.backoff(20),
+            Duration::seconds(MAX_BACKOFF_SECONDS)
+        );
+        assert_eq!(
+            Scope::Ip.backoff(200),
+            Duration::seconds(MAX_BACKOFF_SECONDS)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_login_failure() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+        let username = random_name("throttled");
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(login_blocked_until(&mut txn, &username, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The free attempts don't block anything
+        for expected in 1..=3 {
+            let failure = record_login_failure(&mut txn, &username, None)
+                .await
+                .unwrap();
+            assert_eq!(failure.failures, expected);
+            assert!(!failure.locked);
+            assert!(login_blocked_until(&mut txn, &username, None)
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        let failure = record_login_failure(&mut txn, &username, Some(ip))
+            .await
+            .unwrap();
+        assert_eq!(failure.failures, 4);
+        assert!(!failure.locked);
+        let blocked_until = login_blocked_until(&mut txn, &username, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(blocked_until, failure.blocked_until);
+
+        for _ in 5..LOCKOUT_THRESHOLD {
+            record_login_failure(&mut txn, &username, None)
+                .await
+                .unwrap();
+        }
+        let failure = record_login_failure(&mut txn, &username, None)
+            .await
+            .unwrap();
+        assert_eq!(failure.failures, LOCKOUT_THRESHOLD);
+        assert!(failure.locked);
+        assert!(failure.blocked_until > Utc::now() + Duration::seconds(LOCKOUT_SECONDS - 60));
+        let blocked_until = login_blocked_until(&mut txn, &username, None)
+            .await
+            .unwrap();
+        assert_eq!(blocked_until, Some(failure.blocked_until));
+
+        // Further attempts keep the username locked
+        let again = record_login_failure(&mut txn, &username, None)
+            .await
+            .unwrap();
+        assert_eq!(again.failures, LOCKOUT_THRESHOLD + 1);
+        assert!(again.locked);
+        assert!(again.blocked_until >= failure.blocked_until);
+
+        // The IP only saw one failure, which is free
+        assert!(
+            login_blocked_until(&mut txn, &random_name("other"), Some(ip))
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        assert!(clear_login_failures(&mut txn, &username).await.unwrap());
+        assert!(login_blocked_until(&mut txn, &username, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_attempts_wait() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+        let mut other = test_transaction().await.unwrap();
+        let username = random_name("throttled");
+
+        login_blocked_until(&mut txn, &username, None)
+            .await
+            .unwrap();
+
+        // A concurrent check on the same username waits until the first attempt
+        // is over
+        sqlx::query("SET LOCAL lock_timeout = '100ms'")
+            .execute(&mut other)
+            .await
+            .unwrap();
+        assert!(login_blocked_until(&mut other, &username, None)
+            .await
+            .is_err());
+    }
+}
@@ -45,6 +45,7 @@ pub async fn new_authorization_grant(
     response_type_token: bool,
     response_type_id_token: bool,
     requires_consent: bool,
+    login_hint: Option<String>,
 ) -> anyhow::Result<AuthorizationGrant<PostgresqlBackend>> {
     let code_challenge = code
         .as_ref()
@@ -62,9 +63,9 @@ pub async fn new_authorization_grant(
                 (oauth2_client_id, redirect_uri, scope, state, nonce, max_age,
                  acr_values, response_mode, code_challenge, code_challenge_method,
                  response_type_code, response_type_token, response_type_id_token,
-                 code, requires_consent)
+                 code, requires_consent, login_hint)
             VALUES
-                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
             RETURNING id, created_at
         "#,
         &client.data,
@@ -83,6 +84,7 @@ pub async fn new_authorization_grant(
         response_type_id_token,
         code_str,
         requires_consent,
+        login_hint,
     )
     .fetch_one(executor)
     .await
@@ -104,6 +106,7 @@ pub async fn new_authorization_grant(
         response_type_token,
         response_type_id_token,
         requires_consent,
+        login_hint,
     })
 }
 
@@ -128,14 +131,20 @@ struct GrantLookup {
     grant_code_challenge: Option<String>,
     grant_code_challenge_method: Option<String>,
     grant_requires_consent: bool,
+    grant_login_hint: Option<String>,
     oauth2_client_id: i64,
     session_id: Option<i64>,
     user_session_id: Option<i64>,
     user_session_created_at: Option<DateTime<Utc>>,
+    user_session_user_agent: Option<String>,
+    user_session_ip_address: Option<String>,
+    user_session_last_active_at: Option<DateTime<Utc>>,
     user_id: Option<i64>,
     user_username: Option<String>,
     user_session_last_authentication_id: Option<i64>,
     user_session_last_authentication_created_at: Option<DateTime<Utc>>,
+    user_session_last_authentication_method: Option<String>,
+    user_session_last_authentication_second_factor: Option<String>,
     user_email_id: Option<i64>,
     user_email: Option<String>,
     user_email_created_at: Option<DateTime<Utc>>,
@@ -161,12 +170,19 @@ impl GrantLookup {
         let last_authentication = match (
             self.user_session_last_authentication_id,
             self.user_session_last_authentication_created_at,
+            self.user_session_last_authentication_method,
         ) {
-            (Some(id), Some(created_at)) => Some(Authentication {
+            (Some(id), Some(created_at), Some(method)) => Some(Authentication {
                 data: id,
                 created_at,
+                method: method.parse().map_err(|_e| DatabaseInconsistencyError)?,
+                second_factor: self
+                    .user_session_last_authentication_second_factor
+                    .map(|f| f.parse())
+                    .transpose()
+                    .map_err(|_e| DatabaseInconsistencyError)?,
             }),
-            (None, None) => None,
+            (None, None, None) => None,
             _ => return Err(DatabaseInconsistencyError),
         };
 
@@ -216,6 +232,13 @@ impl GrantLookup {
                     user,
                     created_at: user_session_created_at,
                     last_authentication,
+                    user_agent: self.user_session_user_agent,
+                    ip_address: self
+                        .user_session_ip_address
+                        .map(|ip| ip.parse())
+                        .transpose()
+                        .map_err(|_e| DatabaseInconsistencyError)?,
+                    last_active_at: self.user_session_last_active_at,
                 };
 
                 let client = client.clone();
@@ -321,6 +344,7 @@ impl GrantLookup {
             response_type_token: self.grant_response_type_token,
             response_type_id_token: self.grant_response_type_id_token,
             requires_consent: self.grant_requires_consent,
+            login_hint: self.grant_login_hint,
         })
     }
 }
@@ -354,13 +378,19 @@ pub async fn get_grant_by_id(
                 og.code_challenge         AS grant_code_challenge,
                 og.code_challenge_method  AS grant_code_challenge_method,
                 og.requires_consent       AS grant_requires_consent,
+                og.login_hint             AS grant_login_hint,
                 os.id              AS "session_id?",
                 us.id              AS "user_session_id?",
                 us.created_at      AS "user_session_created_at?",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
                  u.id              AS "user_id?",
                  u.username        AS "user_username?",
                 usa.id             AS "user_session_last_authentication_id?",
                 usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?",
                 ue.id              AS "user_email_id?",
                 ue.email           AS "user_email?",
                 ue.created_at      AS "user_email_created_at?",
@@ -423,13 +453,19 @@ pub async fn lookup_grant_by_code(
                 og.code_challenge         AS grant_code_challenge,
                 og.code_challenge_method  AS grant_code_challenge_method,
                 og.requires_consent       AS grant_requires_consent,
+                og.login_hint             AS grant_login_hint,
                 os.id              AS "session_id?",
                 us.id              AS "user_session_id?",
                 us.created_at      AS "user_session_created_at?",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
                  u.id              AS "user_id?",
                  u.username        AS "user_username?",
                 usa.id             AS "user_session_last_authentication_id?",
                 usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?",
                 ue.id              AS "user_email_id?",
                 ue.email           AS "user_email?",
                 ue.created_at      AS "user_email_created_at?",
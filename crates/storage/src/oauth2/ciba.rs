@@ -0,0 +1,574 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use mas_data_model::{
+    Authentication, BrowserSession, CibaRequest, CibaRequestState, Client, Session, User, UserEmail,
+};
+use oauth2_types::scope::Scope;
+use sqlx::{PgConnection, PgExecutor};
+use thiserror::Error;
+
+use super::client::{lookup_client, ClientFetchError};
+use crate::{DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend};
+
+#[allow(clippy::too_many_arguments)]
+pub async fn add_ciba_request(
+    executor: impl PgExecutor<'_>,
+    client: Client<PostgresqlBackend>,
+    user: User<PostgresqlBackend>,
+    auth_req_id: String,
+    scope: Scope,
+    binding_message: Option<String>,
+    client_notification_token: Option<String>,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<CibaRequest<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO oauth2_ciba_requests
+                (oauth2_client_id, user_id, auth_req_id, scope, binding_message,
+                 client_notification_token, expires_at)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, created_at
+        "#,
+        client.data,
+        user.data,
+        &auth_req_id,
+        scope.to_string(),
+        binding_message.as_deref(),
+        client_notification_token.as_deref(),
+        expires_at,
+    )
+    .fetch_one(executor)
+    .await
+    .context("could not insert backchannel authentication request")?;
+
+    Ok(CibaRequest {
+        data: res.id,
+        state: CibaRequestState::Pending,
+        auth_req_id,
+        client,
+        user,
+        scope,
+        binding_message,
+        client_notification_token,
+        created_at: res.created_at,
+        expires_at,
+    })
+}
+
+struct CibaRequestLookup {
+    ciba_id: i64,
+    ciba_auth_req_id: String,
+    ciba_scope: String,
+    ciba_binding_message: Option<String>,
+    ciba_client_notification_token: Option<String>,
+    ciba_created_at: DateTime<Utc>,
+    ciba_expires_at: DateTime<Utc>,
+    ciba_approved_at: Option<DateTime<Utc>>,
+    ciba_denied_at: Option<DateTime<Utc>>,
+    ciba_exchanged_at: Option<DateTime<Utc>>,
+    oauth2_client_id: i64,
+    user_id: i64,
+    user_username: String,
+    user_email_id: Option<i64>,
+    user_email: Option<String>,
+    user_email_created_at: Option<DateTime<Utc>>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+    session_id: Option<i64>,
+    user_session_id: Option<i64>,
+    user_session_created_at: Option<DateTime<Utc>>,
+    user_session_user_agent: Option<String>,
+    user_session_ip_address: Option<String>,
+    user_session_last_active_at: Option<DateTime<Utc>>,
+    user_session_last_authentication_id: Option<i64>,
+    user_session_last_authentication_created_at: Option<DateTime<Utc>>,
+    user_session_last_authentication_method: Option<String>,
+    user_session_last_authentication_second_factor: Option<String>,
+}
+
+#[derive(Error, Debug)]
+#[error("could not lookup backchannel authentication request")]
+pub enum CibaRequestLookupError {
+    Fetch(#[from] sqlx::Error),
+    ClientFetch(#[from] ClientFetchError),
+    Conversion(#[from] DatabaseInconsistencyError),
+}
+
+impl CibaRequestLookupError {
+    #[must_use]
+    pub fn not_found(&self) -> bool {
+        matches!(self, Self::Fetch(sqlx::Error::RowNotFound))
+    }
+}
+
+impl CibaRequestLookup {
+    #[allow(clippy::too_many_lines)]
+    async fn into_ciba_request(
+        self,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<CibaRequest<PostgresqlBackend>, CibaRequestLookupError> {
+        let client = lookup_client(executor, self.oauth2_client_id).await?;
+
+        let scope: Scope = self
+            .ciba_scope
+            .parse()
+            .map_err(|_e| DatabaseInconsistencyError)?;
+
+        let primary_email = match (
+            self.user_email_id,
+            self.user_email,
+            self.user_email_created_at,
+            self.user_email_confirmed_at,
+        ) {
+            (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
+                data: id,
+                email,
+                created_at,
+                confirmed_at,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError.into()),
+        };
+
+        let user = User {
+            data: self.user_id,
+            username: self.user_username,
+            sub: format!("fake-sub-{}", self.user_id),
+            primary_email,
+        };
+
+        let last_authentication = match (
+            self.user_session_last_authentication_id,
+            self.user_session_last_authentication_created_at,
+            self.user_session_last_authentication_method,
+        ) {
+            (Some(id), Some(created_at), Some(method)) => Some(Authentication {
+                data: id,
+                created_at,
+                method: method.parse().map_err(|_e| DatabaseInconsistencyError)?,
+                second_factor: self
+                    .user_session_last_authentication_second_factor
+                    .map(|f| f.parse())
+                    .transpose()
+                    .map_err(|_e| DatabaseInconsistencyError)?,
+            }),
+            (None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError.into()),
+        };
+
+        let session = match (
+            self.session_id,
+            self.user_session_id,
+            self.user_session_created_at,
+            last_authentication,
+        ) {
+            (
+                Some(session_id),
+                Some(user_session_id),
+                Some(user_session_created_at),
+                last_authentication,
+            ) => Some(Session {
+                data: session_id,
+                client: client.clone(),
+                browser_session: BrowserSession {
+                    data: user_session_id,
+                    user: user.clone(),
+                    created_at: user_session_created_at,
+                    last_authentication,
+                    user_agent: self.user_session_user_agent,
+                    ip_address: self
+                        .user_session_ip_address
+                        .map(|ip| ip.parse())
+                        .transpose()
+                        .map_err(|_e| DatabaseInconsistencyError)?,
+                    last_active_at: self.user_session_last_active_at,
+                },
+                scope: scope.clone(),
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError.into()),
+        };
+
+        let state = match (
+            self.ciba_approved_at,
+            self.ciba_denied_at,
+            self.ciba_exchanged_at,
+            session,
+        ) {
+            (None, None, None, None) => CibaRequestState::Pending,
+            (Some(approved_at), None, None, Some(session)) => CibaRequestState::Approved {
+                session,
+                approved_at,
+            },
+            (Some(approved_at), None, Some(exchanged_at), Some(session)) => {
+                CibaRequestState::Exchanged {
+                    session,
+                    approved_at,
+                    exchanged_at,
+                }
+            }
+            (None, Some(denied_at), None, None) => CibaRequestState::Denied { denied_at },
+            _ => return Err(DatabaseInconsistencyError.into()),
+        };
+
+        Ok(CibaRequest {
+            data: self.ciba_id,
+            state,
+            auth_req_id: self.ciba_auth_req_id,
+            client,
+            user,
+            scope,
+            binding_message: self.ciba_binding_message,
+            client_notification_token: self.ciba_client_notification_token,
+            created_at: self.ciba_created_at,
+            expires_at: self.ciba_expires_at,
+        })
+    }
+}
+
+pub async fn lookup_ciba_request_by_auth_req_id(
+    conn: &mut PgConnection,
+    auth_req_id: &str,
+) -> Result<CibaRequest<PostgresqlBackend>, CibaRequestLookupError> {
+    let res = sqlx::query_as!(
+        CibaRequestLookup,
+        r#"
+            SELECT
+                r.id                        AS ciba_id,
+                r.auth_req_id               AS ciba_auth_req_id,
+                r.scope                     AS ciba_scope,
+                r.binding_message           AS ciba_binding_message,
+                r.client_notification_token AS ciba_client_notification_token,
+                r.created_at                AS ciba_created_at,
+                r.expires_at                AS ciba_expires_at,
+                r.approved_at               AS ciba_approved_at,
+                r.denied_at                 AS ciba_denied_at,
+                r.exchanged_at              AS ciba_exchanged_at,
+                r.oauth2_client_id          AS oauth2_client_id,
+                 u.id              AS user_id,
+                 u.username        AS user_username,
+                ue.id              AS "user_email_id?",
+                ue.email           AS "user_email?",
+                ue.created_at      AS "user_email_created_at?",
+                ue.confirmed_at    AS "user_email_confirmed_at?",
+                os.id              AS "session_id?",
+                us.id              AS "user_session_id?",
+                us.created_at      AS "user_session_created_at?",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
+                usa.id             AS "user_session_last_authentication_id?",
+                usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?"
+            FROM oauth2_ciba_requests r
+            INNER JOIN users u
+              ON u.id = r.user_id
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+            LEFT JOIN oauth2_sessions os
+              ON os.id = r.oauth2_session_id
+            LEFT JOIN user_sessions us
+              ON us.id = os.user_session_id
+            LEFT JOIN user_session_authentications usa
+              ON usa.session_id = us.id
+
+            WHERE r.auth_req_id = $1
+
+            ORDER BY usa.created_at DESC
+            LIMIT 1
+        "#,
+        auth_req_id,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    res.into_ciba_request(&mut *conn).await
+}
+
+pub async fn get_ciba_request_by_id(
+    conn: &mut PgConnection,
+    id: i64,
+) -> Result<CibaRequest<PostgresqlBackend>, CibaRequestLookupError> {
+    let res = sqlx::query_as!(
+        CibaRequestLookup,
+        r#"
+            SELECT
+                r.id                        AS ciba_id,
+                r.auth_req_id               AS ciba_auth_req_id,
+                r.scope                     AS ciba_scope,
+                r.binding_message           AS ciba_binding_message,
+                r.client_notification_token AS ciba_client_notification_token,
+                r.created_at                AS ciba_created_at,
+                r.expires_at                AS ciba_expires_at,
+                r.approved_at               AS ciba_approved_at,
+                r.denied_at                 AS ciba_denied_at,
+                r.exchanged_at              AS ciba_exchanged_at,
+                r.oauth2_client_id          AS oauth2_client_id,
+                 u.id              AS user_id,
+                 u.username        AS user_username,
+                ue.id              AS "user_email_id?",
+                ue.email           AS "user_email?",
+                ue.created_at      AS "user_email_created_at?",
+                ue.confirmed_at    AS "user_email_confirmed_at?",
+                os.id              AS "session_id?",
+                us.id              AS "user_session_id?",
+                us.created_at      AS "user_session_created_at?",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
+                usa.id             AS "user_session_last_authentication_id?",
+                usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?"
+            FROM oauth2_ciba_requests r
+            INNER JOIN users u
+              ON u.id = r.user_id
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+            LEFT JOIN oauth2_sessions os
+              ON os.id = r.oauth2_session_id
+            LEFT JOIN user_sessions us
+              ON us.id = os.user_session_id
+            LEFT JOIN user_session_authentications usa
+              ON usa.session_id = us.id
+
+            WHERE r.id = $1
+
+            ORDER BY usa.created_at DESC
+            LIMIT 1
+        "#,
+        id,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    res.into_ciba_request(&mut *conn).await
+}
+
+/// Get the requests which are still waiting for the user to approve or deny
+/// them
+pub async fn get_pending_ciba_requests_for_user(
+    conn: &mut PgConnection,
+    user: &User<PostgresqlBackend>,
+) -> Result<Vec<CibaRequest<PostgresqlBackend>>, CibaRequestLookupError> {
+    // Pending requests have no session attached, so the session joins never
+    // yield more than one row per request
+    let res = sqlx::query_as!(
+        CibaRequestLookup,
+        r#"
+            SELECT
+                r.id                        AS ciba_id,
+                r.auth_req_id               AS ciba_auth_req_id,
+                r.scope                     AS ciba_scope,
+                r.binding_message           AS ciba_binding_message,
+                r.client_notification_token AS ciba_client_notification_token,
+                r.created_at                AS ciba_created_at,
+                r.expires_at                AS ciba_expires_at,
+                r.approved_at               AS ciba_approved_at,
+                r.denied_at                 AS ciba_denied_at,
+                r.exchanged_at              AS ciba_exchanged_at,
+                r.oauth2_client_id          AS oauth2_client_id,
+                 u.id              AS user_id,
+                 u.username        AS user_username,
+                ue.id              AS "user_email_id?",
+                ue.email           AS "user_email?",
+                ue.created_at      AS "user_email_created_at?",
+                ue.confirmed_at    AS "user_email_confirmed_at?",
+                os.id              AS "session_id?",
+                us.id              AS "user_session_id?",
+                us.created_at      AS "user_session_created_at?",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
+                usa.id             AS "user_session_last_authentication_id?",
+                usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?"
+            FROM oauth2_ciba_requests r
+            INNER JOIN users u
+              ON u.id = r.user_id
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+            LEFT JOIN oauth2_sessions os
+              ON os.id = r.oauth2_session_id
+            LEFT JOIN user_sessions us
+              ON us.id = os.user_session_id
+            LEFT JOIN user_session_authentications usa
+              ON usa.session_id = us.id
+
+            WHERE r.user_id = $1
+              AND r.approved_at IS NULL
+              AND r.denied_at IS NULL
+              AND r.expires_at > NOW()
+
+            ORDER BY r.created_at ASC
+        "#,
+        user.data,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut requests = Vec::with_capacity(res.len());
+    for r in res {
+        requests.push(r.into_ciba_request(&mut *conn).await?);
+    }
+
+    Ok(requests)
+}
+
+/// Count the requests a client made for a user which are still waiting for
+/// their answer
+pub async fn count_pending_ciba_requests(
+    executor: impl PgExecutor<'_>,
+    client: &Client<PostgresqlBackend>,
+    user: &User<PostgresqlBackend>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "count!"
+            FROM oauth2_ciba_requests
+            WHERE oauth2_client_id = $1
+              AND user_id = $2
+              AND approved_at IS NULL
+              AND denied_at IS NULL
+              AND expires_at > NOW()
+        "#,
+        client.data,
+        user.data,
+    )
+    .fetch_one(executor)
+    .await
+}
+
+pub async fn approve_ciba_request(
+    conn: &mut PgConnection,
+    mut request: CibaRequest<PostgresqlBackend>,
+    browser_session: BrowserSession<PostgresqlBackend>,
+) -> anyhow::Result<CibaRequest<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO oauth2_sessions
+                (user_session_id, oauth2_client_id, scope)
+            SELECT
+                $1,
+                r.oauth2_client_id,
+                r.scope
+            FROM
+                oauth2_ciba_requests r
+            WHERE
+                r.id = $2
+            RETURNING id, created_at
+        "#,
+        browser_session.data,
+        request.data,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context("could not insert oauth2 session")?;
+
+    let session = Session {
+        data: res.id,
+        browser_session,
+        client: request.client.clone(),
+        scope: request.scope.clone(),
+    };
+
+    let approved_at = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_ciba_requests AS r
+            SET
+                oauth2_session_id = os.id,
+                approved_at = os.created_at
+            FROM oauth2_sessions os
+            WHERE
+                r.id = $1 AND os.id = $2
+            RETURNING approved_at AS "approved_at!: DateTime<Utc>"
+        "#,
+        request.data,
+        session.data,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context("could not mark backchannel authentication request as approved")?;
+
+    request.state = request.state.approve(approved_at, session)?;
+
+    Ok(request)
+}
+
+pub async fn deny_ciba_request(
+    executor: impl PgExecutor<'_>,
+    mut request: CibaRequest<PostgresqlBackend>,
+) -> anyhow::Result<CibaRequest<PostgresqlBackend>> {
+    let denied_at = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_ciba_requests
+            SET
+                denied_at = NOW()
+            WHERE
+                id = $1
+            RETURNING denied_at AS "denied_at!: DateTime<Utc>"
+        "#,
+        request.data,
+    )
+    .fetch_one(executor)
+    .await
+    .context("could not mark backchannel authentication request as denied")?;
+
+    request.state = request.state.deny(denied_at)?;
+
+    Ok(request)
+}
+
+/// Mark an approved request as exchanged for tokens. Returns `None` if it was
+/// already exchanged or expired in the meantime, so that concurrent polls
+/// can't both get tokens.
+pub async fn exchange_ciba_request(
+    executor: impl PgExecutor<'_>,
+    mut request: CibaRequest<PostgresqlBackend>,
+) -> anyhow::Result<Option<CibaRequest<PostgresqlBackend>>> {
+    let exchanged_at = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_ciba_requests
+            SET
+                exchanged_at = NOW()
+            WHERE
+                id = $1
+                AND approved_at IS NOT NULL
+                AND exchanged_at IS NULL
+                AND expires_at > NOW()
+            RETURNING exchanged_at AS "exchanged_at!: DateTime<Utc>"
+        "#,
+        request.data,
+    )
+    .fetch_optional(executor)
+    .await
+    .context("could not mark backchannel authentication request as exchanged")?;
+
+    let exchanged_at = if let Some(exchanged_at) = exchanged_at {
+        exchanged_at
+    } else {
+        return Ok(None);
+    };
+
+    request.state = request.state.exchange(exchanged_at)?;
+
+    Ok(Some(request))
+}
@@ -16,11 +16,11 @@ use std::string::ToString;
 
 use mas_data_model::{Client, JwksOrJwksUri};
 use mas_iana::{
-    jose::JsonWebSignatureAlg,
+    jose::{JsonWebEncryptionAlg, JsonWebEncryptionEnc, JsonWebSignatureAlg},
     oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod},
 };
 use mas_jose::JsonWebKeySet;
-use oauth2_types::requests::GrantType;
+use oauth2_types::{oidc::BackchannelTokenDeliveryMode, requests::GrantType};
 use sqlx::{PgConnection, PgExecutor};
 use thiserror::Error;
 use url::Url;
@@ -36,6 +36,7 @@ pub struct OAuth2ClientLookup {
     response_types: Vec<String>,
     grant_type_authorization_code: bool,
     grant_type_refresh_token: bool,
+    grant_type_ciba: bool,
     contacts: Vec<String>,
     client_name: Option<String>,
     logo_uri: Option<String>,
@@ -45,10 +46,17 @@ pub struct OAuth2ClientLookup {
     jwks_uri: Option<String>,
     jwks: Option<serde_json::Value>,
     id_token_signed_response_alg: Option<String>,
+    id_token_encrypted_response_alg: Option<String>,
+    id_token_encrypted_response_enc: Option<String>,
     userinfo_signed_response_alg: Option<String>,
+    userinfo_encrypted_response_alg: Option<String>,
+    userinfo_encrypted_response_enc: Option<String>,
     token_endpoint_auth_method: Option<String>,
     token_endpoint_auth_signing_alg: Option<String>,
     initiate_login_uri: Option<String>,
+    authorization_signed_response_alg: Option<String>,
+    backchannel_token_delivery_mode: Option<String>,
+    backchannel_client_notification_endpoint: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -71,6 +79,9 @@ pub enum ClientFetchError {
         source: mas_iana::ParseError,
     },
 
+    #[error("invalid backchannel token delivery mode {0:?}")]
+    InvalidDeliveryMode(String),
+
     #[error(transparent)]
     Database(#[from] sqlx::Error),
 }
@@ -108,6 +119,9 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
         if self.grant_type_refresh_token {
             grant_types.push(GrantType::RefreshToken);
         }
+        if self.grant_type_ciba {
+            grant_types.push(GrantType::ClientInitiatedBackchannelAuthentication);
+        }
 
         let logo_uri = self
             .logo_uri
@@ -154,6 +168,42 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
                 source,
             })?;
 
+        let id_token_encrypted_response_alg = self
+            .id_token_encrypted_response_alg
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseField {
+                field: "id_token_encrypted_response_alg",
+                source,
+            })?;
+
+        let id_token_encrypted_response_enc = self
+            .id_token_encrypted_response_enc
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseField {
+                field: "id_token_encrypted_response_enc",
+                source,
+            })?;
+
+        let userinfo_encrypted_response_alg = self
+            .userinfo_encrypted_response_alg
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseField {
+                field: "userinfo_encrypted_response_alg",
+                source,
+            })?;
+
+        let userinfo_encrypted_response_enc = self
+            .userinfo_encrypted_response_enc
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseField {
+                field: "userinfo_encrypted_response_enc",
+                source,
+            })?;
+
         let userinfo_signed_response_alg = self
             .userinfo_signed_response_alg
             .map(|s| s.parse())
@@ -190,6 +240,33 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
                 source,
             })?;
 
+        let authorization_signed_response_alg = self
+            .authorization_signed_response_alg
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseField {
+                field: "authorization_signed_response_alg",
+                source,
+            })?;
+
+        let backchannel_token_delivery_mode = self
+            .backchannel_token_delivery_mode
+            .map(|s| match s.as_str() {
+                "poll" => Ok(BackchannelTokenDeliveryMode::Poll),
+                "ping" => Ok(BackchannelTokenDeliveryMode::Ping),
+                _ => Err(ClientFetchError::InvalidDeliveryMode(s)),
+            })
+            .transpose()?;
+
+        let backchannel_client_notification_endpoint = self
+            .backchannel_client_notification_endpoint
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|source| ClientFetchError::ParseUrl {
+                field: "backchannel_client_notification_endpoint",
+                source,
+            })?;
+
         let jwks = match (self.jwks, self.jwks_uri) {
             (None, None) => None,
             (Some(jwks), None) => {
@@ -224,10 +301,17 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
             tos_uri,
             jwks,
             id_token_signed_response_alg,
+            id_token_encrypted_response_alg,
+            id_token_encrypted_response_enc,
             userinfo_signed_response_alg,
+            userinfo_encrypted_response_alg,
+            userinfo_encrypted_response_enc,
             token_endpoint_auth_method,
             token_endpoint_auth_signing_alg,
             initiate_login_uri,
+            authorization_signed_response_alg,
+            backchannel_token_delivery_mode,
+            backchannel_client_notification_endpoint,
         })
     }
 }
@@ -247,6 +331,7 @@ pub async fn lookup_client(
                 c.response_types,
                 c.grant_type_authorization_code,
                 c.grant_type_refresh_token,
+                c.grant_type_ciba,
                 c.contacts,
                 c.client_name,
                 c.logo_uri,
@@ -259,7 +344,14 @@ pub async fn lookup_client(
                 c.userinfo_signed_response_alg,
                 c.token_endpoint_auth_method,
                 c.token_endpoint_auth_signing_alg,
-                c.initiate_login_uri
+                c.initiate_login_uri,
+                c.authorization_signed_response_alg,
+                c.backchannel_token_delivery_mode,
+                c.backchannel_client_notification_endpoint,
+                c.id_token_encrypted_response_alg,
+                c.id_token_encrypted_response_enc,
+                c.userinfo_encrypted_response_alg,
+                c.userinfo_encrypted_response_enc
             FROM oauth2_clients c
 
             WHERE c.id = $1
@@ -289,6 +381,7 @@ pub async fn lookup_client_by_client_id(
                 c.response_types,
                 c.grant_type_authorization_code,
                 c.grant_type_refresh_token,
+                c.grant_type_ciba,
                 c.contacts,
                 c.client_name,
                 c.logo_uri,
@@ -301,7 +394,14 @@ pub async fn lookup_client_by_client_id(
                 c.userinfo_signed_response_alg,
                 c.token_endpoint_auth_method,
                 c.token_endpoint_auth_signing_alg,
-                c.initiate_login_uri
+                c.initiate_login_uri,
+                c.authorization_signed_response_alg,
+                c.backchannel_token_delivery_mode,
+                c.backchannel_client_notification_endpoint,
+                c.id_token_encrypted_response_alg,
+                c.id_token_encrypted_response_enc,
+                c.userinfo_encrypted_response_alg,
+                c.userinfo_encrypted_response_enc
             FROM oauth2_clients c
 
             WHERE c.client_id = $1
@@ -316,7 +416,7 @@ pub async fn lookup_client_by_client_id(
     Ok(client)
 }
 
-#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 pub async fn insert_client(
     conn: &mut PgConnection,
     client_id: &str,
@@ -337,10 +437,19 @@ pub async fn insert_client(
     token_endpoint_auth_method: Option<OAuthClientAuthenticationMethod>,
     token_endpoint_auth_signing_alg: Option<JsonWebSignatureAlg>,
     initiate_login_uri: Option<&Url>,
+    authorization_signed_response_alg: Option<JsonWebSignatureAlg>,
+    backchannel_token_delivery_mode: Option<BackchannelTokenDeliveryMode>,
+    backchannel_client_notification_endpoint: Option<&Url>,
+    id_token_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
+    id_token_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
+    userinfo_encrypted_response_alg: Option<JsonWebEncryptionAlg>,
+    userinfo_encrypted_response_enc: Option<JsonWebEncryptionEnc>,
 ) -> Result<(), sqlx::Error> {
     let response_types: Vec<String> = response_types.iter().map(ToString::to_string).collect();
     let grant_type_authorization_code = grant_types.contains(&GrantType::AuthorizationCode);
     let grant_type_refresh_token = grant_types.contains(&GrantType::RefreshToken);
+    let grant_type_ciba =
+        grant_types.contains(&GrantType::ClientInitiatedBackchannelAuthentication);
     let logo_uri = logo_uri.map(Url::as_str);
     let client_uri = client_uri.map(Url::as_str);
     let policy_uri = policy_uri.map(Url::as_str);
@@ -352,6 +461,18 @@ pub async fn insert_client(
     let token_endpoint_auth_method = token_endpoint_auth_method.map(|v| v.to_string());
     let token_endpoint_auth_signing_alg = token_endpoint_auth_signing_alg.map(|v| v.to_string());
     let initiate_login_uri = initiate_login_uri.map(Url::as_str);
+    let authorization_signed_response_alg =
+        authorization_signed_response_alg.map(|v| v.to_string());
+    let backchannel_token_delivery_mode = backchannel_token_delivery_mode.map(|v| match v {
+        BackchannelTokenDeliveryMode::Poll => "poll",
+        BackchannelTokenDeliveryMode::Ping => "ping",
+    });
+    let backchannel_client_notification_endpoint =
+        backchannel_client_notification_endpoint.map(Url::as_str);
+    let id_token_encrypted_response_alg = id_token_encrypted_response_alg.map(|v| v.to_string());
+    let id_token_encrypted_response_enc = id_token_encrypted_response_enc.map(|v| v.to_string());
+    let userinfo_encrypted_response_alg = userinfo_encrypted_response_alg.map(|v| v.to_string());
+    let userinfo_encrypted_response_enc = userinfo_encrypted_response_enc.map(|v| v.to_string());
 
     let id = sqlx::query_scalar!(
         r#"
@@ -361,6 +482,7 @@ pub async fn insert_client(
                  response_types,
                  grant_type_authorization_code,
                  grant_type_refresh_token,
+                 grant_type_ciba,
                  contacts,
                  client_name,
                  logo_uri,
@@ -373,9 +495,17 @@ pub async fn insert_client(
                  userinfo_signed_response_alg,
                  token_endpoint_auth_method,
                  token_endpoint_auth_signing_alg,
-                 initiate_login_uri)
+                 initiate_login_uri,
+                 authorization_signed_response_alg,
+                 backchannel_token_delivery_mode,
+                 backchannel_client_notification_endpoint,
+                 id_token_encrypted_response_alg,
+                 id_token_encrypted_response_enc,
+                 userinfo_encrypted_response_alg,
+                 userinfo_encrypted_response_enc)
             VALUES
-                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18)
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                 $19, $20, $21, $22, $23, $24, $25, $26)
             RETURNING id
         "#,
         client_id,
@@ -383,6 +513,7 @@ pub async fn insert_client(
         &response_types,
         grant_type_authorization_code,
         grant_type_refresh_token,
+        grant_type_ciba,
         contacts,
         client_name,
         logo_uri,
@@ -396,6 +527,13 @@ pub async fn insert_client(
         token_endpoint_auth_method,
         token_endpoint_auth_signing_alg,
         initiate_login_uri,
+        authorization_signed_response_alg,
+        backchannel_token_delivery_mode,
+        backchannel_client_notification_endpoint,
+        id_token_encrypted_response_alg,
+        id_token_encrypted_response_enc,
+        userinfo_encrypted_response_alg,
+        userinfo_encrypted_response_enc,
     )
     .fetch_one(&mut *conn)
     .await?;
@@ -449,12 +587,13 @@ pub async fn insert_client_from_config(
                  response_types,
                  grant_type_authorization_code,
                  grant_type_refresh_token,
+                 grant_type_ciba,
                  token_endpoint_auth_method,
                  jwks,
                  jwks_uri,
                  contacts)
             VALUES
-                ($1, $2, $3, $4, $5, $6, $7, $8, '{}')
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, '{}')
             RETURNING id
         "#,
         client_id,
@@ -462,6 +601,7 @@ pub async fn insert_client_from_config(
         &response_types,
         true,
         true,
+        true,
         client_auth_method,
         jwks,
         jwks_uri,
@@ -12,13 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use mas_data_model::Session;
+use anyhow::Context;
+use mas_data_model::{Session, User};
+use oauth2_types::scope::Scope;
 use sqlx::PgExecutor;
 
-use crate::PostgresqlBackend;
+use crate::{DatabaseInconsistencyError, PostgresqlBackend};
 
 pub mod access_token;
 pub mod authorization_grant;
+pub mod ciba;
 pub mod client;
 pub mod consent;
 pub mod refresh_token;
@@ -42,3 +45,168 @@ pub async fn end_oauth_session(
 
     Ok(())
 }
+
+/// End the active OAuth sessions which were started from the given browser
+/// sessions, returning their scopes
+#[tracing::instrument(skip(executor), err)]
+pub async fn end_oauth_sessions_for_browser_sessions(
+    executor: impl PgExecutor<'_>,
+    user_session_ids: &[i64],
+) -> anyhow::Result<Vec<Scope>> {
+    let scopes: Vec<String> = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_sessions
+            SET ended_at = NOW()
+            WHERE user_session_id = ANY($1)
+              AND ended_at IS NULL
+            RETURNING scope
+        "#,
+        user_session_ids,
+    )
+    .fetch_all(executor)
+    .await
+    .context("could not end oauth2 sessions")?;
+
+    scopes
+        .into_iter()
+        .map(|scope| {
+            scope
+                .parse()
+                .map_err(|_e| DatabaseInconsistencyError.into())
+        })
+        .collect()
+}
+
+/// End all the active OAuth sessions of a user, returning the scopes of the
+/// sessions which were ended
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn end_oauth_sessions_for_user(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Vec<Scope>> {
+    let scopes: Vec<String> = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_sessions os
+            SET ended_at = NOW()
+            FROM user_sessions us
+            WHERE us.id = os.user_session_id
+              AND us.user_id = $1
+              AND os.ended_at IS NULL
+            RETURNING os.scope
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .await
+    .context("could not end oauth2 sessions")?;
+
+    scopes
+        .into_iter()
+        .map(|scope| {
+            scope
+                .parse()
+                .map_err(|_e| DatabaseInconsistencyError.into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::SessionExpiration;
+    use mas_iana::oauth::OAuthClientAuthenticationMethod;
+    use oauth2_types::requests::ResponseMode;
+    use sqlx::{Postgres, Transaction};
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        oauth2::{
+            authorization_grant::{derive_session, new_authorization_grant},
+            client::{insert_client_from_config, lookup_client_by_client_id},
+        },
+        test_utils::{random_name, test_transaction},
+        user::{register_passwordless_user, start_session},
+    };
+
+    /// Register a user with an OAuth session for the given scope
+    async fn user_with_session(
+        txn: &mut Transaction<'_, Postgres>,
+        client_id: &str,
+        scope: &str,
+    ) -> User<PostgresqlBackend> {
+        let user = register_passwordless_user(&mut *txn, &random_name("user"))
+            .await
+            .unwrap();
+        let browser_session = start_session(
+            &mut *txn,
+            user.clone(),
+            None,
+            None,
+            &SessionExpiration::default(),
+        )
+        .await
+        .unwrap();
+
+        let client = lookup_client_by_client_id(&mut *txn, client_id)
+            .await
+            .unwrap();
+        let grant = new_authorization_grant(
+            &mut *txn,
+            client,
+            "https://example.com/callback".parse().unwrap(),
+            scope.parse().unwrap(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            ResponseMode::Query,
+            false,
+            false,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        derive_session(&mut *txn, &grant, browser_session)
+            .await
+            .unwrap();
+
+        user
+    }
+
+    #[tokio::test]
+    async fn test_end_oauth_sessions_for_user() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let client_id = random_name("client");
+        let redirect_uri: Url = "https://example.com/callback".parse().unwrap();
+        insert_client_from_config(
+            &mut txn,
+            &client_id,
+            OAuthClientAuthenticationMethod::None,
+            None,
+            None,
+            None,
+            &[redirect_uri],
+        )
+        .await
+        .unwrap();
+
+        let alice = user_with_session(&mut txn, &client_id, "openid alice").await;
+        let bob = user_with_session(&mut txn, &client_id, "openid bob").await;
+
+        let scopes = end_oauth_sessions_for_user(&mut txn, &alice).await.unwrap();
+        assert_eq!(scopes, ["openid alice".parse::<Scope>().unwrap()]);
+
+        // Already ended, and the sessions of other users are left alone
+        let scopes = end_oauth_sessions_for_user(&mut txn, &alice).await.unwrap();
+        assert!(scopes.is_empty());
+        let scopes = end_oauth_sessions_for_user(&mut txn, &bob).await.unwrap();
+        assert_eq!(scopes, ["openid bob".parse::<Scope>().unwrap()]);
+    }
+}
@@ -68,10 +68,15 @@ struct OAuth2RefreshTokenLookup {
     scope: String,
     user_session_id: i64,
     user_session_created_at: DateTime<Utc>,
+    user_session_user_agent: Option<String>,
+    user_session_ip_address: Option<String>,
+    user_session_last_active_at: Option<DateTime<Utc>>,
     user_id: i64,
     user_username: String,
     user_session_last_authentication_id: Option<i64>,
     user_session_last_authentication_created_at: Option<DateTime<Utc>>,
+    user_session_last_authentication_method: Option<String>,
+    user_session_last_authentication_second_factor: Option<String>,
     user_email_id: Option<i64>,
     user_email: Option<String>,
     user_email_created_at: Option<DateTime<Utc>>,
@@ -115,10 +120,15 @@ pub async fn lookup_active_refresh_token(
                 os.scope           AS "scope!",
                 us.id              AS "user_session_id!",
                 us.created_at      AS "user_session_created_at!",
+                us.user_agent      AS "user_session_user_agent?",
+                us.ip_address      AS "user_session_ip_address?",
+                us.last_active_at  AS "user_session_last_active_at?",
                  u.id              AS "user_id!",
                  u.username        AS "user_username!",
                 usa.id             AS "user_session_last_authentication_id?",
                 usa.created_at     AS "user_session_last_authentication_created_at?",
+                usa.method         AS "user_session_last_authentication_method?",
+                usa.second_factor  AS "user_session_last_authentication_second_factor?",
                 ue.id              AS "user_email_id?",
                 ue.email           AS "user_email?",
                 ue.created_at      AS "user_email_created_at?",
@@ -202,11 +212,18 @@ pub async fn lookup_active_refresh_token(
     let last_authentication = match (
         res.user_session_last_authentication_id,
         res.user_session_last_authentication_created_at,
+        res.user_session_last_authentication_method,
     ) {
-        (None, None) => None,
-        (Some(id), Some(created_at)) => Some(Authentication {
+        (None, None, None) => None,
+        (Some(id), Some(created_at), Some(method)) => Some(Authentication {
             data: id,
             created_at,
+            method: method.parse().map_err(|_e| DatabaseInconsistencyError)?,
+            second_factor: res
+                .user_session_last_authentication_second_factor
+                .map(|f| f.parse())
+                .transpose()
+                .map_err(|_e| DatabaseInconsistencyError)?,
         }),
         _ => return Err(DatabaseInconsistencyError.into()),
     };
@@ -216,6 +233,13 @@ pub async fn lookup_active_refresh_token(
         created_at: res.user_session_created_at,
         user,
         last_authentication,
+        user_agent: res.user_session_user_agent,
+        ip_address: res
+            .user_session_ip_address
+            .map(|ip| ip.parse())
+            .transpose()
+            .map_err(|_e| DatabaseInconsistencyError)?,
+        last_active_at: res.user_session_last_active_at,
     };
 
     let scope = res.scope.parse().map_err(|_e| DatabaseInconsistencyError)?;
@@ -0,0 +1,748 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Password verification, against external backends like an LDAP directory
+//! and against the local password hashes
+
+use std::{borrow::BorrowMut, collections::HashSet, sync::Arc};
+
+use anyhow::Context;
+use argon2::Argon2;
+use async_trait::async_trait;
+use mas_data_model::User;
+use password_hash::{PasswordHash, PasswordHasher, SaltString};
+use pbkdf2::Pbkdf2;
+use rand::rngs::OsRng;
+use sqlx::{PgConnection, PgExecutor, Postgres, Transaction};
+use tokio::task;
+use tracing::{info, warn, Instrument};
+
+use crate::{
+    user::{
+        add_user_email, lookup_user_by_username, mark_user_email_as_verified,
+        register_passwordless_user, set_user_email_as_primary, AuthenticationError, LoginError,
+    },
+    PostgresqlBackend,
+};
+
+/// Attributes of a user, as known by an external password backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalUser {
+    /// The canonical username of the user, as opposed to the one they typed,
+    /// which may differ in case or be an alias
+    pub username: String,
+
+    /// The email address of the user, trusted as verified
+    pub email: Option<String>,
+
+    /// The display name of the user
+    pub displayname: Option<String>,
+}
+
+/// The outcome of checking a password against an external backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordVerification {
+    /// The password is valid for this user
+    Valid(ExternalUser),
+
+    /// The backend knows this user, but the password is wrong
+    Invalid,
+
+    /// The backend does not know this user, the next backend should be tried
+    UnknownUser,
+}
+
+/// A source of truth for user passwords other than the local password hashes
+#[async_trait]
+pub trait PasswordBackend: std::fmt::Debug + Send + Sync {
+    /// Check the password of a user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend could not be reached, in which case the
+    /// next backend is tried
+    async fn verify_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<PasswordVerification>;
+}
+
+/// The list of external password backends, tried in order before falling
+/// back to the local password hashes
+#[derive(Debug, Clone, Default)]
+pub struct PasswordBackends {
+    backends: Vec<Arc<dyn PasswordBackend>>,
+}
+
+impl PasswordBackends {
+    #[must_use]
+    pub fn new(backends: Vec<Arc<dyn PasswordBackend>>) -> Self {
+        Self { backends }
+    }
+
+    /// Ask each backend in turn, until one of them knows about the user
+    async fn verify(&self, username: &str, password: &str) -> PasswordVerification {
+        for backend in &self.backends {
+            match backend.verify_password(username, password).await {
+                Ok(PasswordVerification::UnknownUser) => {}
+                Ok(verification) => return verification,
+                Err(e) => {
+                    tracing::warn!(?backend, error = ?e, "Password backend failed, trying the next one");
+                }
+            }
+        }
+
+        PasswordVerification::UnknownUser
+    }
+}
+
+/// A password hashing algorithm, along with its parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordAlgorithm {
+    /// Argon2id, in the PHC string format
+    Argon2id {
+        /// Memory size, in KiB
+        m_cost: u32,
+
+        /// Number of iterations
+        t_cost: u32,
+
+        /// Degree of parallelism
+        p_cost: u32,
+    },
+
+    /// bcrypt, as used by Synapse
+    Bcrypt {
+        /// Logarithmic cost
+        cost: u32,
+    },
+
+    /// PBKDF2 with HMAC-SHA256, in the PHC string format
+    Pbkdf2 {
+        /// Number of iterations
+        rounds: u32,
+    },
+}
+
+impl Default for PasswordAlgorithm {
+    fn default() -> Self {
+        Self::Argon2id {
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordAlgorithm {
+    fn hash(self, password: &[u8]) -> anyhow::Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hashed_password = match self {
+            Self::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => {
+                let params = argon2::Params::new(m_cost, t_cost, p_cost, None)
+                    .map_err(|e| anyhow::anyhow!("invalid Argon2 parameters: {}", e))?;
+                let argon2 =
+                    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+                argon2.hash_password(password, &salt)?.to_string()
+            }
+            Self::Bcrypt { cost } => bcrypt::hash(password, cost)?,
+            Self::Pbkdf2 { rounds } => {
+                let params = pbkdf2::Params {
+                    rounds,
+                    ..pbkdf2::Params::default()
+                };
+                Pbkdf2
+                    .hash_password_customized(password, None, None, params, salt.as_salt())?
+                    .to_string()
+            }
+        };
+
+        Ok(hashed_password)
+    }
+
+    fn verify(self, password: &[u8], hashed_password: &str) -> Result<(), password_hash::Error> {
+        match self {
+            Self::Argon2id { .. } => {
+                PasswordHash::new(hashed_password)?.verify_password(&[&Argon2::default()], password)
+            }
+            Self::Bcrypt { .. } => match bcrypt::verify(password, hashed_password) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(password_hash::Error::Password),
+                Err(_) => Err(password_hash::Error::PhcStringInvalid),
+            },
+            Self::Pbkdf2 { .. } => {
+                PasswordHash::new(hashed_password)?.verify_password(&[&Pbkdf2], password)
+            }
+        }
+    }
+}
+
+/// A versioned password hashing scheme. The version is stored alongside each
+/// hash, so that the scheme can change without invalidating existing hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordScheme {
+    pub version: u16,
+    pub algorithm: PasswordAlgorithm,
+
+    /// Whether the server-side pepper is appended to passwords before hashing
+    /// them
+    pub peppered: bool,
+}
+
+/// The password hashing schemes known by the server. New passwords are hashed
+/// with the scheme of the highest version, and passwords hashed with another
+/// scheme are rehashed after the next successful login.
+#[derive(Debug, Clone)]
+pub struct PasswordSchemes {
+    /// Sorted by version, the last one being the current scheme
+    schemes: Arc<Vec<PasswordScheme>>,
+    pepper: Option<Arc<str>>,
+}
+
+impl Default for PasswordSchemes {
+    fn default() -> Self {
+        Self {
+            schemes: Arc::new(vec![PasswordScheme {
+                version: 1,
+                algorithm: PasswordAlgorithm::default(),
+                peppered: false,
+            }]),
+            pepper: None,
+        }
+    }
+}
+
+impl PasswordSchemes {
+    /// Build the list of schemes, along with the server-side pepper
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no schemes, if two schemes share the same
+    /// version, or if a scheme is peppered but no pepper is given
+    pub fn new(mut schemes: Vec<PasswordScheme>, pepper: Option<String>) -> anyhow::Result<Self> {
+        if schemes.is_empty() {
+            anyhow::bail!("at least one password scheme is needed");
+        }
+
+        let mut versions = HashSet::new();
+        for scheme in &schemes {
+            if !versions.insert(scheme.version) {
+                anyhow::bail!("password scheme version {} is duplicated", scheme.version);
+            }
+
+            if scheme.peppered && pepper.is_none() {
+                anyhow::bail!(
+                    "password scheme version {} is peppered, but no pepper is set",
+                    scheme.version
+                );
+            }
+        }
+
+        schemes.sort_by_key(|scheme| scheme.version);
+
+        Ok(Self {
+            schemes: Arc::new(schemes),
+            pepper: pepper.map(Into::into),
+        })
+    }
+
+    fn current(&self) -> PasswordScheme {
+        // There is always at least one scheme
+        self.schemes[self.schemes.len() - 1]
+    }
+
+    /// Check whether hashes of the given version can be verified
+    #[must_use]
+    pub fn knows(&self, version: u16) -> bool {
+        self.schemes.iter().any(|scheme| scheme.version == version)
+    }
+
+    fn peppered(&self, scheme: PasswordScheme, password: &str) -> Vec<u8> {
+        let mut bytes = password.as_bytes().to_vec();
+        if let (true, Some(pepper)) = (scheme.peppered, &self.pepper) {
+            bytes.extend_from_slice(pepper.as_bytes());
+        }
+        bytes
+    }
+
+    /// Hash a password with the current scheme. Returns the version of the
+    /// scheme along with the hash.
+    pub(crate) fn hash(&self, password: &str) -> anyhow::Result<(u16, String)> {
+        let scheme = self.current();
+        let hashed_password = scheme.algorithm.hash(&self.peppered(scheme, password))?;
+        Ok((scheme.version, hashed_password))
+    }
+
+    /// Check a password against a hash made with the given scheme version.
+    /// If it matches and the scheme is not the current one, the password is
+    /// hashed again with the current scheme.
+    fn verify_and_upgrade(
+        &self,
+        version: u16,
+        password: &str,
+        hashed_password: &str,
+    ) -> Result<Option<(u16, String)>, password_hash::Error> {
+        let scheme = self
+            .schemes
+            .iter()
+            .find(|scheme| scheme.version == version)
+            .copied()
+            .ok_or(password_hash::Error::Version)?;
+
+        scheme
+            .algorithm
+            .verify(&self.peppered(scheme, password), hashed_password)?;
+
+        if scheme == self.current() {
+            return Ok(None);
+        }
+
+        match self.hash(password) {
+            Ok(upgraded) => Ok(Some(upgraded)),
+            Err(e) => {
+                warn!(error = %e, "Could not rehash password with the current scheme");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Check the password of an existing user, first against the external
+/// backends, then against the local password hash
+#[tracing::instrument(skip_all, fields(user.id = user.data, %user.username))]
+pub async fn verify_password(
+    conn: &mut PgConnection,
+    backends: &PasswordBackends,
+    schemes: &PasswordSchemes,
+    user: &User<PostgresqlBackend>,
+    password: &str,
+) -> Result<(), AuthenticationError> {
+    // Only users provisioned from an external backend can be vouched for by it
+    if !has_external_password(&mut *conn, user)
+        .await
+        .map_err(AuthenticationError::Fetch)?
+    {
+        return verify_local_password(conn, schemes, user, password).await;
+    }
+
+    // Directories bound as the user don't tell apart unknown users from wrong
+    // passwords, and the local password of those users isn't used
+    match backends.verify(&user.username, password).await {
+        PasswordVerification::Valid(_) => Ok(()),
+        PasswordVerification::Invalid | PasswordVerification::UnknownUser => {
+            Err(password_hash::Error::Password.into())
+        }
+    }
+}
+
+async fn verify_local_password(
+    conn: &mut PgConnection,
+    schemes: &PasswordSchemes,
+    user: &User<PostgresqlBackend>,
+    password: &str,
+) -> Result<(), AuthenticationError> {
+    // Fetch the latest hashed password of the user
+    let res = sqlx::query!(
+        r#"
+            SELECT up.hashed_password, up.version
+            FROM user_passwords up
+            WHERE up.user_id = $1
+            ORDER BY up.created_at DESC, up.id DESC
+            LIMIT 1
+        "#,
+        user.data,
+    )
+    .fetch_optional(&mut *conn)
+    .instrument(tracing::info_span!("Lookup hashed password"))
+    .await
+    .map_err(AuthenticationError::Fetch)?;
+
+    // Users without a password can't log in with one
+    let res = res.ok_or(password_hash::Error::Password)?;
+
+    let version = u16::try_from(res.version).map_err(|_| password_hash::Error::Version)?;
+
+    // Verify the password in a blocking thread to avoid blocking the async executor
+    let schemes = schemes.clone();
+    let password = password.to_string();
+    let upgraded = task::spawn_blocking(move || {
+        schemes.verify_and_upgrade(version, &password, &res.hashed_password)
+    })
+    .instrument(tracing::info_span!("Verify hashed password"))
+    .await??;
+
+    if let Some((version, hashed_password)) = upgraded {
+        add_password_hash(&mut *conn, user, version, &hashed_password)
+            .await
+            .map_err(AuthenticationError::Save)?;
+        info!(version, "Upgraded the password hash to the current scheme");
+    }
+
+    Ok(())
+}
+
+/// Save a password hash made with the given scheme version
+#[tracing::instrument(skip(executor, user, hashed_password), fields(user.id = user.data))]
+pub async fn add_password_hash(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    version: u16,
+    hashed_password: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO user_passwords (user_id, hashed_password, version)
+            VALUES ($1, $2, $3)
+        "#,
+        user.data,
+        hashed_password,
+        i32::from(version),
+    )
+    .execute(executor)
+    .instrument(tracing::info_span!("Save user password hash"))
+    .await?;
+
+    Ok(())
+}
+
+/// Lookup a user by username and check their password.
+///
+/// Users vouched for by an external backend but unknown locally are
+/// provisioned on the fly under the canonical username given by the backend,
+/// in which case their external attributes are returned alongside the new
+/// user. The caller is expected to check that this username is acceptable
+/// before committing the transaction.
+#[tracing::instrument(skip(txn, backends, schemes, password))]
+pub async fn authenticate_user(
+    txn: &mut Transaction<'_, Postgres>,
+    backends: &PasswordBackends,
+    schemes: &PasswordSchemes,
+    username: &str,
+    password: &str,
+) -> Result<(User<PostgresqlBackend>, Option<ExternalUser>), LoginError> {
+    let external = match backends.verify(username, password).await {
+        PasswordVerification::Valid(external) => external,
+        PasswordVerification::Invalid => {
+            return Err(LoginError::Authentication {
+                username: username.to_string(),
+                source: password_hash::Error::Password.into(),
+            })
+        }
+        PasswordVerification::UnknownUser => {
+            let user = lookup_user(txn, username).await?;
+
+            // Users from a directory only log in through it
+            let external = has_external_password(txn.borrow_mut(), &user)
+                .await
+                .context("could not check the user password backend")?;
+            let res = if external {
+                Err(password_hash::Error::Password.into())
+            } else {
+                verify_local_password(txn, schemes, &user, password).await
+            };
+
+            res.map_err(|source| {
+                if matches!(source, AuthenticationError::Password { .. }) {
+                    LoginError::Authentication {
+                        username: username.to_string(),
+                        source,
+                    }
+                } else {
+                    LoginError::Other(source.into())
+                }
+            })?;
+
+            return Ok((user, None));
+        }
+    };
+
+    // The local account is the one of the canonical username from the backend,
+    // not the one typed by the user
+    match lookup_user_by_username(txn.borrow_mut(), &external.username).await {
+        Ok(user) => {
+            // A local account with the same name isn't the one of this directory user
+            if !has_external_password(txn.borrow_mut(), &user)
+                .await
+                .context("could not check the user password backend")?
+            {
+                return Err(LoginError::NotExternal {
+                    username: user.username,
+                });
+            }
+
+            Ok((user, None))
+        }
+        Err(e) if e.not_found() => {
+            let user = provision_external_user(txn, &external).await?;
+            Ok((user, Some(external)))
+        }
+        Err(e) => Err(LoginError::Other(e.into())),
+    }
+}
+
+async fn has_external_password(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT external_password
+            FROM users
+            WHERE id = $1
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(tracing::info_span!("Lookup user password backend"))
+    .await
+}
+
+async fn lookup_user(
+    txn: &mut Transaction<'_, Postgres>,
+    username: &str,
+) -> Result<User<PostgresqlBackend>, LoginError> {
+    lookup_user_by_username(txn.borrow_mut(), username)
+        .await
+        .map_err(|source| {
+            if source.not_found() {
+                LoginError::NotFound {
+                    username: username.to_string(),
+                    source,
+                }
+            } else {
+                LoginError::Other(source.into())
+            }
+        })
+}
+
+#[tracing::instrument(skip_all, fields(%external.username))]
+async fn provision_external_user(
+    txn: &mut Transaction<'_, Postgres>,
+    external: &ExternalUser,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let mut user = register_passwordless_user(txn.borrow_mut(), &external.username)
+        .await
+        .context("could not provision user from password backend")?;
+
+    sqlx::query!(
+        r#"
+            UPDATE users
+            SET external_password = TRUE
+            WHERE id = $1
+        "#,
+        user.data,
+    )
+    .execute(txn.borrow_mut())
+    .instrument(tracing::info_span!("Mark user as external"))
+    .await
+    .context("could not mark user as external")?;
+
+    if let Some(email) = &external.email {
+        let email = add_user_email(txn.borrow_mut(), &user, email).await?;
+        let email = mark_user_email_as_verified(txn.borrow_mut(), email).await?;
+        set_user_email_as_primary(txn.borrow_mut(), &email).await?;
+        user.primary_email = Some(email);
+    }
+
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{random_name, test_transaction},
+        user::register_user,
+    };
+
+    const BCRYPT: PasswordAlgorithm = PasswordAlgorithm::Bcrypt { cost: 4 };
+    const PBKDF2: PasswordAlgorithm = PasswordAlgorithm::Pbkdf2 { rounds: 1000 };
+    const ARGON2: PasswordAlgorithm = PasswordAlgorithm::Argon2id {
+        m_cost: 256,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn scheme(version: u16, algorithm: PasswordAlgorithm, peppered: bool) -> PasswordScheme {
+        PasswordScheme {
+            version,
+            algorithm,
+            peppered,
+        }
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        for algorithm in [BCRYPT, PBKDF2, ARGON2] {
+            let schemes = PasswordSchemes::new(vec![scheme(1, algorithm, false)], None).unwrap();
+            let (version, hashed_password) = schemes.hash("hunter2").unwrap();
+            assert_eq!(version, 1);
+
+            assert_eq!(
+                schemes.verify_and_upgrade(1, "hunter2", &hashed_password),
+                Ok(None)
+            );
+            assert_eq!(
+                schemes.verify_and_upgrade(1, "hunter3", &hashed_password),
+                Err(password_hash::Error::Password)
+            );
+            assert_eq!(
+                schemes.verify_and_upgrade(2, "hunter2", &hashed_password),
+                Err(password_hash::Error::Version)
+            );
+        }
+    }
+
+    #[test]
+    fn test_upgrade_peppered_hash() {
+        let pepper = Some("pepper".to_owned());
+        let old = PasswordSchemes::new(vec![scheme(1, BCRYPT, true)], pepper.clone()).unwrap();
+        let (_, hashed_password) = old.hash("hunter2").unwrap();
+
+        // The pepper is part of the hashed password
+        assert!(bcrypt::verify("hunter2pepper", &hashed_password).unwrap());
+
+        let schemes = PasswordSchemes::new(
+            vec![scheme(2, ARGON2, false), scheme(1, BCRYPT, true)],
+            pepper,
+        )
+        .unwrap();
+        let (version, upgraded) = schemes
+            .verify_and_upgrade(1, "hunter2", &hashed_password)
+            .unwrap()
+            .unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(
+            schemes.verify_and_upgrade(2, "hunter2", &upgraded),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_invalid_schemes() {
+        assert!(PasswordSchemes::new(vec![], None).is_err());
+        assert!(PasswordSchemes::new(
+            vec![scheme(1, ARGON2, false), scheme(1, BCRYPT, false)],
+            None
+        )
+        .is_err());
+        assert!(PasswordSchemes::new(vec![scheme(1, BCRYPT, true)], None).is_err());
+    }
+
+    /// A directory which knows everyone, under their lowercased name. Like
+    /// LDAP servers bound as the user, it can't tell a wrong password from an
+    /// unknown user.
+    #[derive(Debug)]
+    struct Directory;
+
+    #[async_trait]
+    impl PasswordBackend for Directory {
+        async fn verify_password(
+            &self,
+            username: &str,
+            password: &str,
+        ) -> anyhow::Result<PasswordVerification> {
+            if password != "directory-password" {
+                return Ok(PasswordVerification::UnknownUser);
+            }
+
+            Ok(PasswordVerification::Valid(ExternalUser {
+                username: username.to_lowercase(),
+                email: None,
+                displayname: None,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_external_users() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let backends = PasswordBackends::new(vec![Arc::new(Directory)]);
+        let schemes = PasswordSchemes::new(vec![scheme(1, BCRYPT, false)], None).unwrap();
+
+        // Users are provisioned under the canonical username
+        let username = random_name("directory");
+        let (user, provisioned) = authenticate_user(
+            &mut txn,
+            &backends,
+            &schemes,
+            &username.to_uppercase(),
+            "directory-password",
+        )
+        .await
+        .unwrap();
+        assert_eq!(user.username, username);
+        assert!(provisioned.is_some());
+
+        let (again, provisioned) = authenticate_user(
+            &mut txn,
+            &backends,
+            &schemes,
+            &username,
+            "directory-password",
+        )
+        .await
+        .unwrap();
+        assert_eq!(again.data, user.data);
+        assert!(provisioned.is_none());
+        verify_password(&mut txn, &backends, &schemes, &user, "directory-password")
+            .await
+            .unwrap();
+
+        // Wrong passwords of directory users are plain authentication failures
+        assert!(matches!(
+            authenticate_user(&mut txn, &backends, &schemes, &username, "wrong").await,
+            Err(LoginError::Authentication { .. })
+        ));
+        assert!(matches!(
+            verify_password(&mut txn, &backends, &schemes, &user, "wrong").await,
+            Err(AuthenticationError::Password(_))
+        ));
+
+        // Local users with the same name can't be logged into from the directory
+        let username = random_name("local");
+        let local = register_user(&mut txn, &schemes, &username, "local-password")
+            .await
+            .unwrap();
+        assert!(matches!(
+            authenticate_user(
+                &mut txn,
+                &backends,
+                &schemes,
+                &username,
+                "directory-password"
+            )
+            .await,
+            Err(LoginError::NotExternal { .. })
+        ));
+        assert!(
+            verify_password(&mut txn, &backends, &schemes, &local, "directory-password")
+                .await
+                .is_err()
+        );
+        verify_password(&mut txn, &backends, &schemes, &local, "local-password")
+            .await
+            .unwrap();
+    }
+}
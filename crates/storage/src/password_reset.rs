@@ -0,0 +1,292 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Single-use links letting users who forgot their password set a new one
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use mas_data_model::{User, UserEmail};
+use sqlx::{postgres::types::PgInterval, PgExecutor};
+use tracing::{info_span, Instrument};
+
+use crate::{user::UserLookup, PostgresqlBackend};
+
+#[derive(Debug, Clone)]
+pub struct PasswordReset {
+    pub id: i64,
+    pub user: User<PostgresqlBackend>,
+    pub created_at: DateTime<Utc>,
+}
+
+struct VerifiedEmailLookup {
+    user_email_id: i64,
+    user_email_address: String,
+    user_email_created_at: DateTime<Utc>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+    user_id: i64,
+    user_username: String,
+    primary_email_id: Option<i64>,
+    primary_email: Option<String>,
+    primary_email_created_at: Option<DateTime<Utc>>,
+    primary_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+/// Find the users who verified the given email address. Addresses are
+/// compared case-insensitively.
+#[tracing::instrument(skip(executor), err)]
+pub async fn lookup_users_by_verified_email(
+    executor: impl PgExecutor<'_>,
+    email: &str,
+) -> anyhow::Result<Vec<(User<PostgresqlBackend>, UserEmail<PostgresqlBackend>)>> {
+    let res = sqlx::query_as!(
+        VerifiedEmailLookup,
+        r#"
+            SELECT
+                ue.id           AS "user_email_id",
+                ue.email        AS "user_email_address",
+                ue.created_at   AS "user_email_created_at",
+                ue.confirmed_at AS "user_email_confirmed_at",
+                u.id            AS "user_id",
+                u.username      AS "user_username",
+                pe.id           AS "primary_email_id?",
+                pe.email        AS "primary_email?",
+                pe.created_at   AS "primary_email_created_at?",
+                pe.confirmed_at AS "primary_email_confirmed_at?"
+            FROM user_emails ue
+
+            INNER JOIN users u
+              ON u.id = ue.user_id
+
+            LEFT JOIN user_emails pe
+              ON pe.id = u.primary_email_id
+
+            WHERE LOWER(ue.email) = LOWER($1)
+              AND ue.confirmed_at IS NOT NULL
+        "#,
+        email,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Lookup users by verified email"))
+    .await
+    .context("could not lookup users by email")?;
+
+    res.into_iter()
+        .map(|res| {
+            let user = UserLookup {
+                user_id: res.user_id,
+                user_username: res.user_username,
+                user_email_id: res.primary_email_id,
+                user_email: res.primary_email,
+                user_email_created_at: res.primary_email_created_at,
+                user_email_confirmed_at: res.primary_email_confirmed_at,
+            }
+            .try_into()?;
+
+            let email = UserEmail {
+                data: res.user_email_id,
+                email: res.user_email_address,
+                created_at: res.user_email_created_at,
+                confirmed_at: res.user_email_confirmed_at,
+            };
+
+            Ok((user, email))
+        })
+        .collect()
+}
+
+#[tracing::instrument(skip_all, fields(user_email.id = email.data), err)]
+pub async fn add_password_reset(
+    executor: impl PgExecutor<'_>,
+    email: &UserEmail<PostgresqlBackend>,
+    token: &str,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO user_password_resets (user_email_id, token)
+            VALUES ($1, $2)
+        "#,
+        email.data,
+        token,
+    )
+    .execute(executor)
+    .instrument(info_span!("Add password reset"))
+    .await
+    .context("could not insert password reset")?;
+
+    Ok(())
+}
+
+struct PasswordResetLookup {
+    id: i64,
+    created_at: DateTime<Utc>,
+    user_id: i64,
+    user_username: String,
+    user_email_id: Option<i64>,
+    user_email: Option<String>,
+    user_email_created_at: Option<DateTime<Utc>>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+/// Find a password reset which can still be used, meaning it was not
+/// consumed, is younger than `max_age`, and was sent to an address which is
+/// still verified
+#[tracing::instrument(skip_all, err)]
+pub async fn lookup_password_reset(
+    executor: impl PgExecutor<'_>,
+    token: &str,
+    max_age: Duration,
+) -> anyhow::Result<Option<PasswordReset>> {
+    let max_age = PgInterval::try_from(max_age)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let res = sqlx::query_as!(
+        PasswordResetLookup,
+        r#"
+            SELECT
+                r.id,
+                r.created_at,
+                u.id            AS user_id,
+                u.username      AS user_username,
+                pe.id           AS "user_email_id?",
+                pe.email        AS "user_email?",
+                pe.created_at   AS "user_email_created_at?",
+                pe.confirmed_at AS "user_email_confirmed_at?"
+            FROM user_password_resets r
+
+            INNER JOIN user_emails ue
+              ON ue.id = r.user_email_id
+
+            INNER JOIN users u
+              ON u.id = ue.user_id
+
+            LEFT JOIN user_emails pe
+              ON pe.id = u.primary_email_id
+
+            WHERE r.token = $1
+              AND r.consumed_at IS NULL
+              AND r.created_at + $2 > NOW()
+              AND ue.confirmed_at IS NOT NULL
+        "#,
+        token,
+        max_age,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Lookup password reset"))
+    .await
+    .context("could not lookup password reset")?;
+
+    let res = if let Some(res) = res {
+        res
+    } else {
+        return Ok(None);
+    };
+
+    let user = UserLookup {
+        user_id: res.user_id,
+        user_username: res.user_username,
+        user_email_id: res.user_email_id,
+        user_email: res.user_email,
+        user_email_created_at: res.user_email_created_at,
+        user_email_confirmed_at: res.user_email_confirmed_at,
+    }
+    .try_into()?;
+
+    Ok(Some(PasswordReset {
+        id: res.id,
+        user,
+        created_at: res.created_at,
+    }))
+}
+
+/// Consume a password reset, along with every other pending reset of the
+/// same user. Returns `false` if it was already consumed in the meantime.
+#[tracing::instrument(skip_all, fields(password_reset.id = reset.id, user.id = reset.user.data), err)]
+pub async fn consume_password_reset(
+    executor: impl PgExecutor<'_>,
+    reset: &PasswordReset,
+) -> Result<bool, sqlx::Error> {
+    let consumed: Vec<i64> = sqlx::query_scalar!(
+        r#"
+            UPDATE user_password_resets
+            SET consumed_at = NOW()
+            WHERE consumed_at IS NULL
+              AND user_email_id IN (
+                SELECT id FROM user_emails WHERE user_id = $1
+              )
+            RETURNING id
+        "#,
+        reset.user.data,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Consume password reset"))
+    .await?;
+
+    Ok(consumed.contains(&reset.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        password::PasswordSchemes,
+        test_utils::{random_name, test_transaction},
+        user::{add_user_email, mark_user_email_as_verified, register_user},
+    };
+
+    #[tokio::test]
+    async fn test_single_use_and_expiry() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let schemes = PasswordSchemes::default();
+        let user = register_user(&mut txn, &schemes, &random_name("forgot"), "hunter2")
+            .await
+            .unwrap();
+        let email = add_user_email(&mut txn, &user, "forgot@example.com")
+            .await
+            .unwrap();
+        let email = mark_user_email_as_verified(&mut txn, email).await.unwrap();
+
+        let first = random_name("token");
+        let second = random_name("token");
+        add_password_reset(&mut txn, &email, &first).await.unwrap();
+        add_password_reset(&mut txn, &email, &second).await.unwrap();
+
+        // Resets expire
+        let max_age = Duration::hours(1);
+        assert!(lookup_password_reset(&mut txn, &first, Duration::zero())
+            .await
+            .unwrap()
+            .is_none());
+
+        let reset = lookup_password_reset(&mut txn, &first, max_age)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reset.user.data, user.data);
+
+        // Using one of them consumes the others as well
+        assert!(consume_password_reset(&mut txn, &reset).await.unwrap());
+        assert!(!consume_password_reset(&mut txn, &reset).await.unwrap());
+        for token in [&first, &second] {
+            assert!(lookup_password_reset(&mut txn, token, max_age)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for tests which need a database, here and in the crates using the
+//! storage, through the `test-utils` feature.
+//!
+//! Those tests run against the database given in `DATABASE_URL`, and are
+//! skipped when it is not set.
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::MIGRATOR;
+
+/// Start a transaction on the test database, after applying the migrations.
+///
+/// The transaction is rolled back when dropped, so tests don't see each
+/// other's changes.
+pub async fn test_transaction() -> Option<Transaction<'static, Postgres>> {
+    let url = std::env::var("DATABASE_URL").ok()?;
+    let pool = PgPool::connect(&url).await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    Some(pool.begin().await.unwrap())
+}
+
+/// A random name, to avoid conflicts with existing data
+pub fn random_name(prefix: &str) -> String {
+    let suffix: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    format!("{}-{}", prefix, suffix.to_lowercase())
+}
@@ -0,0 +1,265 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TOTP authenticators and recovery codes, the second factors users can enroll
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use mas_data_model::User;
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, Postgres, Transaction};
+use tracing::{info_span, Instrument};
+
+use crate::PostgresqlBackend;
+
+/// The TOTP authenticator of a user, which only counts as a second factor
+/// once confirmed
+#[derive(Debug, Clone)]
+pub struct UserTotp {
+    pub id: i64,
+    pub encrypted_secret: String,
+    pub created_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl UserTotp {
+    #[must_use]
+    pub fn is_confirmed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn lookup_user_totp(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<Option<UserTotp>, sqlx::Error> {
+    sqlx::query_as!(
+        UserTotp,
+        r#"
+            SELECT id, encrypted_secret, created_at, confirmed_at
+            FROM user_totp
+            WHERE user_id = $1
+        "#,
+        user.data,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Lookup user TOTP"))
+    .await
+}
+
+/// Start enrolling a TOTP authenticator, replacing any unconfirmed one
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn add_pending_user_totp(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    encrypted_secret: &str,
+) -> anyhow::Result<UserTotp> {
+    sqlx::query_as!(
+        UserTotp,
+        r#"
+            INSERT INTO user_totp (user_id, encrypted_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET encrypted_secret = EXCLUDED.encrypted_secret,
+                created_at       = now(),
+                last_used_step   = NULL
+            WHERE user_totp.confirmed_at IS NULL
+            RETURNING id, encrypted_secret, created_at, confirmed_at
+        "#,
+        user.data,
+        encrypted_secret,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Add pending user TOTP"))
+    .await
+    .context("could not insert user TOTP")?
+    .context("the user already has a confirmed TOTP authenticator")
+}
+
+/// Mark a TOTP authenticator as confirmed, with the time step of the code
+/// which confirmed it
+#[tracing::instrument(skip_all, fields(totp.id = totp.id), err)]
+pub async fn confirm_user_totp(
+    executor: impl PgExecutor<'_>,
+    totp: &mut UserTotp,
+    step: i64,
+) -> Result<(), sqlx::Error> {
+    let confirmed_at = sqlx::query_scalar!(
+        r#"
+            UPDATE user_totp
+            SET confirmed_at = now(), last_used_step = $2
+            WHERE id = $1
+            RETURNING confirmed_at
+        "#,
+        totp.id,
+        step,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Confirm user TOTP"))
+    .await?;
+
+    totp.confirmed_at = confirmed_at;
+    Ok(())
+}
+
+/// Record the use of a TOTP code. Returns `false` if a code of this time step
+/// or a later one was already used, to prevent replays
+#[tracing::instrument(skip_all, fields(totp.id = totp.id), err)]
+pub async fn use_user_totp_step(
+    executor: impl PgExecutor<'_>,
+    totp: &UserTotp,
+    step: i64,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_totp
+            SET last_used_step = $2
+            WHERE id = $1
+              AND confirmed_at IS NOT NULL
+              AND (last_used_step IS NULL OR last_used_step < $2)
+        "#,
+        totp.id,
+        step,
+    )
+    .execute(executor)
+    .instrument(info_span!("Use user TOTP step"))
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Remove the TOTP authenticator and the recovery codes of a user
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn remove_user_second_factors(
+    txn: &mut Transaction<'_, Postgres>,
+    user: &User<PostgresqlBackend>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM user_totp
+            WHERE user_id = $1
+        "#,
+        user.data,
+    )
+    .execute(&mut *txn)
+    .instrument(info_span!("Remove user TOTP"))
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM user_recovery_codes
+            WHERE user_id = $1
+        "#,
+        user.data,
+    )
+    .execute(&mut *txn)
+    .instrument(info_span!("Remove user recovery codes"))
+    .await?;
+
+    Ok(())
+}
+
+/// Normalize a recovery code, so that casing, dashes and spaces don't matter
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Recovery codes are random enough that a fast hash is good enough. They are
+/// normalized first, so that they match however they are typed.
+fn hash_recovery_code(code: &str) -> String {
+    let code = normalize_recovery_code(code);
+    format!("{:x}", Sha256::digest(code.as_bytes()))
+}
+
+/// Replace the recovery codes of a user with new ones
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn replace_user_recovery_codes(
+    txn: &mut Transaction<'_, Postgres>,
+    user: &User<PostgresqlBackend>,
+    codes: &[String],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM user_recovery_codes
+            WHERE user_id = $1
+        "#,
+        user.data,
+    )
+    .execute(&mut *txn)
+    .instrument(info_span!("Remove user recovery codes"))
+    .await?;
+
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    sqlx::query!(
+        r#"
+            INSERT INTO user_recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::TEXT[])
+        "#,
+        user.data,
+        &hashes,
+    )
+    .execute(&mut *txn)
+    .instrument(info_span!("Add user recovery codes"))
+    .await?;
+
+    Ok(())
+}
+
+/// Consume a recovery code. Returns `false` if the code is unknown or was
+/// already used
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn use_user_recovery_code(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_recovery_codes
+            SET used_at = now()
+            WHERE user_id = $1
+              AND code_hash = $2
+              AND used_at IS NULL
+        "#,
+        user.data,
+        hash_recovery_code(code),
+    )
+    .execute(executor)
+    .instrument(info_span!("Use user recovery code"))
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn count_unused_user_recovery_codes(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT COUNT(*) AS "count!"
+            FROM user_recovery_codes
+            WHERE user_id = $1 AND used_at IS NULL
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Count unused user recovery codes"))
+    .await
+}
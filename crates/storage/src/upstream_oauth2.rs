@@ -0,0 +1,84 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use mas_data_model::User;
+use sqlx::PgExecutor;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    user::{UserLookup, UserLookupError},
+    PostgresqlBackend,
+};
+
+/// Find the local user linked to a subject of an upstream provider, if any
+#[tracing::instrument(skip(executor), err)]
+pub async fn lookup_upstream_oauth_link(
+    executor: impl PgExecutor<'_>,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<User<PostgresqlBackend>>, UserLookupError> {
+    let res = sqlx::query_as!(
+        UserLookup,
+        r#"
+            SELECT
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM upstream_oauth_links l
+
+            INNER JOIN users u
+              ON u.id = l.user_id
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE l.provider = $1 AND l.subject = $2
+        "#,
+        provider,
+        subject,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Fetch linked user"))
+    .await?;
+
+    let user = res.map(TryInto::try_into).transpose()?;
+    Ok(user)
+}
+
+/// Link a subject of an upstream provider to a local user
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data), err)]
+pub async fn add_upstream_oauth_link(
+    executor: impl PgExecutor<'_>,
+    provider: &str,
+    subject: &str,
+    user: &User<PostgresqlBackend>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO upstream_oauth_links (provider, subject, user_id)
+            VALUES ($1, $2, $3)
+        "#,
+        provider,
+        subject,
+        user.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("Insert upstream link"))
+    .await?;
+
+    Ok(())
+}
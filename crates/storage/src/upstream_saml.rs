@@ -0,0 +1,104 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replay protection for assertions received from upstream SAML identity
+//! providers
+
+use chrono::{DateTime, Utc};
+use sqlx::PgConnection;
+use tracing::{info_span, Instrument};
+
+/// Remember an assertion as consumed until it expires.
+///
+/// Returns `false` if the assertion was already consumed.
+#[tracing::instrument(skip(conn), err)]
+pub async fn consume_saml_assertion(
+    conn: &mut PgConnection,
+    provider: &str,
+    assertion_id: &str,
+    not_on_or_after: DateTime<Utc>,
+) -> Result<bool, sqlx::Error> {
+    // Forget the assertions which can't be accepted anymore anyway
+    sqlx::query!(
+        r#"
+            DELETE FROM upstream_saml_assertions
+            WHERE not_on_or_after < NOW()
+        "#,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("Delete expired SAML assertions"))
+    .await?;
+
+    let res = sqlx::query!(
+        r#"
+            INSERT INTO upstream_saml_assertions (provider, assertion_id, not_on_or_after)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (provider, assertion_id) DO NOTHING
+        "#,
+        provider,
+        assertion_id,
+        not_on_or_after,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("Insert SAML assertion"))
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::test_utils::{random_name, test_transaction};
+
+    #[tokio::test]
+    async fn test_replayed_assertion() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let provider = random_name("provider");
+        let valid_until = Utc::now() + Duration::minutes(5);
+
+        assert!(
+            consume_saml_assertion(&mut txn, &provider, "_a", valid_until)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !consume_saml_assertion(&mut txn, &provider, "_a", valid_until)
+                .await
+                .unwrap()
+        );
+
+        // The same ID from another provider is another assertion
+        let other = random_name("provider");
+        assert!(consume_saml_assertion(&mut txn, &other, "_a", valid_until)
+            .await
+            .unwrap());
+
+        // Expired assertions are forgotten
+        let expired = Utc::now() - Duration::minutes(5);
+        assert!(consume_saml_assertion(&mut txn, &provider, "_b", expired)
+            .await
+            .unwrap());
+        assert!(consume_saml_assertion(&mut txn, &provider, "_b", expired)
+            .await
+            .unwrap());
+    }
+}
@@ -12,33 +12,66 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::borrow::BorrowMut;
+use std::{borrow::BorrowMut, cmp::Reverse, net::IpAddr};
 
 use anyhow::{bail, Context};
-use argon2::Argon2;
 use chrono::{DateTime, Utc};
 use mas_data_model::{
-    Authentication, BrowserSession, User, UserEmail, UserEmailVerification,
-    UserEmailVerificationState,
+    Authentication, AuthenticationMethod, BrowserSession, SecondFactor, SessionExpiration, User,
+    UserEmail, UserEmailVerification, UserEmailVerificationState,
 };
-use password_hash::{PasswordHash, PasswordHasher, SaltString};
-use rand::rngs::OsRng;
-use sqlx::{postgres::types::PgInterval, Acquire, PgExecutor, Postgres, Transaction};
+use oauth2_types::scope::Scope;
+use sqlx::{postgres::types::PgInterval, Acquire, PgConnection, PgExecutor, Postgres, Transaction};
 use thiserror::Error;
-use tokio::task;
 use tracing::{info_span, Instrument};
 
 use super::{DatabaseInconsistencyError, PostgresqlBackend};
-use crate::IdAndCreationTime;
+use crate::{
+    oauth2::end_oauth_sessions_for_browser_sessions,
+    password::{
+        add_password_hash, authenticate_user, verify_password, ExternalUser, PasswordBackends,
+        PasswordSchemes,
+    },
+    IdAndCreationTime,
+};
 
 #[derive(Debug, Clone)]
-struct UserLookup {
-    user_id: i64,
-    user_username: String,
-    user_email_id: Option<i64>,
-    user_email: Option<String>,
-    user_email_created_at: Option<DateTime<Utc>>,
-    user_email_confirmed_at: Option<DateTime<Utc>>,
+pub(crate) struct UserLookup {
+    pub user_id: i64,
+    pub user_username: String,
+    pub user_email_id: Option<i64>,
+    pub user_email: Option<String>,
+    pub user_email_created_at: Option<DateTime<Utc>>,
+    pub user_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<UserLookup> for User<PostgresqlBackend> {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_from(res: UserLookup) -> Result<Self, Self::Error> {
+        let primary_email = match (
+            res.user_email_id,
+            res.user_email,
+            res.user_email_created_at,
+            res.user_email_confirmed_at,
+        ) {
+            (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
+                data: id,
+                email,
+                created_at,
+                confirmed_at,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError),
+        };
+
+        Ok(User {
+            data: res.user_id,
+            username: res.user_username,
+            sub: format!("fake-sub-{}", res.user_id),
+            primary_email,
+        })
+    }
 }
 
 #[derive(Debug, Error)]
@@ -57,46 +90,40 @@ pub enum LoginError {
         source: AuthenticationError,
     },
 
+    #[error("user {username:?} is a local user, not one of the password backend")]
+    NotExternal { username: String },
+
     #[error("failed to login")]
     Other(#[from] anyhow::Error),
 }
 
-#[tracing::instrument(skip(conn, password))]
+/// Lookup a user by username, check their password and start a new session.
+///
+/// If the user was provisioned from an external password backend during this
+/// login, the attributes it provided are returned alongside the session.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(conn, backends, schemes, password))]
 pub async fn login(
     conn: impl Acquire<'_, Database = Postgres>,
+    backends: &PasswordBackends,
+    schemes: &PasswordSchemes,
     username: &str,
     password: &str,
-) -> Result<BrowserSession<PostgresqlBackend>, LoginError> {
+    user_agent: Option<&str>,
+    ip_address: Option<IpAddr>,
+    expiration: &SessionExpiration,
+) -> Result<(BrowserSession<PostgresqlBackend>, Option<ExternalUser>), LoginError> {
     let mut txn = conn.begin().await.context("could not start transaction")?;
-    let user = lookup_user_by_username(&mut txn, username)
-        .await
-        .map_err(|source| {
-            if source.not_found() {
-                LoginError::NotFound {
-                    username: username.to_string(),
-                    source,
-                }
-            } else {
-                LoginError::Other(source.into())
-            }
-        })?;
-
-    let mut session = start_session(&mut txn, user).await?;
-    authenticate_session(&mut txn, &mut session, password)
+    let (user, provisioned) =
+        authenticate_user(&mut txn, backends, schemes, username, password).await?;
+
+    let mut session = start_session(&mut txn, user, user_agent, ip_address, expiration).await?;
+    record_session_authentication(&mut txn, &mut session, AuthenticationMethod::Password, None)
         .await
-        .map_err(|source| {
-            if matches!(source, AuthenticationError::Password { .. }) {
-                LoginError::Authentication {
-                    username: username.to_string(),
-                    source,
-                }
-            } else {
-                LoginError::Other(source.into())
-            }
-        })?;
+        .context("could not save session auth")?;
 
     txn.commit().await.context("could not commit transaction")?;
-    Ok(session)
+    Ok((session, provisioned))
 }
 
 #[derive(Debug, Error)]
@@ -118,8 +145,13 @@ struct SessionLookup {
     user_id: i64,
     username: String,
     created_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip_address: Option<String>,
+    last_active_at: Option<DateTime<Utc>>,
     last_authentication_id: Option<i64>,
     last_authd_at: Option<DateTime<Utc>>,
+    last_authentication_method: Option<String>,
+    last_authentication_second_factor: Option<String>,
     user_email_id: Option<i64>,
     user_email: Option<String>,
     user_email_created_at: Option<DateTime<Utc>>,
@@ -153,20 +185,39 @@ impl TryInto<BrowserSession<PostgresqlBackend>> for SessionLookup {
             primary_email,
         };
 
-        let last_authentication = match (self.last_authentication_id, self.last_authd_at) {
-            (Some(id), Some(created_at)) => Some(Authentication {
+        let last_authentication = match (
+            self.last_authentication_id,
+            self.last_authd_at,
+            self.last_authentication_method,
+        ) {
+            (Some(id), Some(created_at), Some(method)) => Some(Authentication {
                 data: id,
                 created_at,
+                method: method.parse().map_err(|_e| DatabaseInconsistencyError)?,
+                second_factor: self
+                    .last_authentication_second_factor
+                    .map(|f| f.parse())
+                    .transpose()
+                    .map_err(|_e| DatabaseInconsistencyError)?,
             }),
-            (None, None) => None,
+            (None, None, None) => None,
             _ => return Err(DatabaseInconsistencyError),
         };
 
+        let ip_address = self
+            .ip_address
+            .map(|ip| ip.parse())
+            .transpose()
+            .map_err(|_e| DatabaseInconsistencyError)?;
+
         Ok(BrowserSession {
             data: self.id,
             user,
             created_at: self.created_at,
             last_authentication,
+            user_agent: self.user_agent,
+            ip_address,
+            last_active_at: self.last_active_at,
         })
     }
 }
@@ -184,8 +235,13 @@ pub async fn lookup_active_session(
                 u.id AS user_id,
                 u.username,
                 s.created_at,
+                s.user_agent,
+                s.ip_address,
+                s.last_active_at,
                 a.id               AS "last_authentication_id?",
                 a.created_at       AS "last_authd_at?",
+                a.method           AS "last_authentication_method?",
+                a.second_factor    AS "last_authentication_second_factor?",
                 ue.id              AS "user_email_id?",
                 ue.email           AS "user_email?",
                 ue.created_at      AS "user_email_created_at?",
@@ -198,6 +254,9 @@ pub async fn lookup_active_session(
             LEFT JOIN user_emails ue
               ON ue.id = u.primary_email_id
             WHERE s.id = $1 AND s.active
+              AND (s.expires_at IS NULL OR s.expires_at > NOW())
+              AND (s.idle_timeout IS NULL
+                OR COALESCE(s.last_active_at, s.created_at) + s.idle_timeout > NOW())
             ORDER BY a.created_at DESC
             LIMIT 1
         "#,
@@ -210,19 +269,89 @@ pub async fn lookup_active_session(
     Ok(res)
 }
 
+/// List the active browser sessions of a user, most recently active first
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn get_user_sessions(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<Vec<BrowserSession<PostgresqlBackend>>, ActiveSessionLookupError> {
+    let res = sqlx::query_as!(
+        SessionLookup,
+        r#"
+            SELECT DISTINCT ON (s.id)
+                s.id,
+                u.id AS user_id,
+                u.username,
+                s.created_at,
+                s.user_agent,
+                s.ip_address,
+                s.last_active_at,
+                a.id               AS "last_authentication_id?",
+                a.created_at       AS "last_authd_at?",
+                a.method           AS "last_authentication_method?",
+                a.second_factor    AS "last_authentication_second_factor?",
+                ue.id              AS "user_email_id?",
+                ue.email           AS "user_email?",
+                ue.created_at      AS "user_email_created_at?",
+                ue.confirmed_at    AS "user_email_confirmed_at?"
+            FROM user_sessions s
+            INNER JOIN users u
+                ON s.user_id = u.id
+            LEFT JOIN user_session_authentications a
+                ON a.session_id = s.id
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+            WHERE s.user_id = $1 AND s.active
+              AND (s.expires_at IS NULL OR s.expires_at > NOW())
+              AND (s.idle_timeout IS NULL
+                OR COALESCE(s.last_active_at, s.created_at) + s.idle_timeout > NOW())
+            ORDER BY s.id, a.created_at DESC
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let mut sessions = res
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<Vec<BrowserSession<PostgresqlBackend>>, _>>()?;
+    sessions.sort_by_key(|s| Reverse(s.last_active_at.unwrap_or(s.created_at)));
+
+    Ok(sessions)
+}
+
+fn encode_interval(duration: Option<chrono::Duration>) -> anyhow::Result<Option<PgInterval>> {
+    duration
+        .map(PgInterval::try_from)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))
+}
+
 #[tracing::instrument(skip_all, fields(user.id = user.data))]
 pub async fn start_session(
     executor: impl PgExecutor<'_>,
     user: User<PostgresqlBackend>,
+    user_agent: Option<&str>,
+    ip_address: Option<IpAddr>,
+    expiration: &SessionExpiration,
 ) -> anyhow::Result<BrowserSession<PostgresqlBackend>> {
+    let idle_timeout = encode_interval(expiration.idle_timeout)?;
+    let max_lifetime = encode_interval(expiration.max_lifetime)?;
+
     let res = sqlx::query_as!(
         IdAndCreationTime,
         r#"
-            INSERT INTO user_sessions (user_id)
-            VALUES ($1)
+            INSERT INTO user_sessions
+                (user_id, user_agent, ip_address, last_active_at, idle_timeout, expires_at)
+            VALUES ($1, $2, $3, NOW(), $4, NOW() + $5)
             RETURNING id, created_at
         "#,
         user.data,
+        user_agent,
+        ip_address.map(|ip| ip.to_string()),
+        idle_timeout,
+        max_lifetime,
     )
     .fetch_one(executor)
     .await
@@ -233,11 +362,36 @@ pub async fn start_session(
         user,
         created_at: res.created_at,
         last_authentication: None,
+        user_agent: user_agent.map(ToOwned::to_owned),
+        ip_address,
+        last_active_at: Some(res.created_at),
     };
 
     Ok(session)
 }
 
+/// Record that the session is being used. To avoid writing on every request,
+/// this is only done once a minute.
+#[tracing::instrument(skip_all, fields(session.id = session.data))]
+pub async fn touch_session(
+    executor: impl PgExecutor<'_>,
+    session: &BrowserSession<PostgresqlBackend>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE user_sessions
+            SET last_active_at = NOW()
+            WHERE id = $1
+              AND (last_active_at IS NULL OR last_active_at < NOW() - INTERVAL '1 minute')
+        "#,
+        session.data,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
 #[tracing::instrument(skip_all, fields(user.id = user.data))]
 pub async fn count_active_sessions(
     executor: impl PgExecutor<'_>,
@@ -248,6 +402,9 @@ pub async fn count_active_sessions(
             SELECT COUNT(*) as "count!"
             FROM user_sessions s
             WHERE s.user_id = $1 AND s.active
+              AND (s.expires_at IS NULL OR s.expires_at > NOW())
+              AND (s.idle_timeout IS NULL
+                OR COALESCE(s.last_active_at, s.created_at) + s.idle_timeout > NOW())
         "#,
         user.data,
     )
@@ -276,67 +433,75 @@ pub enum AuthenticationError {
 #[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
 pub async fn authenticate_session(
     txn: &mut Transaction<'_, Postgres>,
+    backends: &PasswordBackends,
+    schemes: &PasswordSchemes,
     session: &mut BrowserSession<PostgresqlBackend>,
     password: &str,
+    method: AuthenticationMethod,
 ) -> Result<(), AuthenticationError> {
-    // First, fetch the hashed password from the user associated with that session
-    let hashed_password: String = sqlx::query_scalar!(
-        r#"
-            SELECT up.hashed_password
-            FROM user_passwords up
-            WHERE up.user_id = $1
-            ORDER BY up.created_at DESC
-            LIMIT 1
-        "#,
-        session.user.data,
-    )
-    .fetch_one(txn.borrow_mut())
-    .instrument(tracing::info_span!("Lookup hashed password"))
-    .await
-    .map_err(AuthenticationError::Fetch)?;
-
-    // TODO: pass verifiers list as parameter
-    // Verify the password in a blocking thread to avoid blocking the async executor
-    let password = password.to_string();
-    task::spawn_blocking(move || {
-        let context = Argon2::default();
-        let hasher = PasswordHash::new(&hashed_password).map_err(AuthenticationError::Password)?;
-        hasher
-            .verify_password(&[&context], &password)
-            .map_err(AuthenticationError::Password)
-    })
-    .instrument(tracing::info_span!("Verify hashed password"))
-    .await??;
+    verify_password(txn, backends, schemes, &session.user, password).await?;
 
     // That went well, let's insert the auth info
+    record_session_authentication(txn.borrow_mut(), session, method, None)
+        .await
+        .map_err(AuthenticationError::Save)?;
+
+    Ok(())
+}
+
+/// Record a successful authentication on a [`BrowserSession`], e.g. after the
+/// user came back from an upstream provider, along with the second factor
+/// they proved if any
+#[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
+pub async fn record_session_authentication(
+    executor: impl PgExecutor<'_>,
+    session: &mut BrowserSession<PostgresqlBackend>,
+    method: AuthenticationMethod,
+    second_factor: Option<SecondFactor>,
+) -> Result<(), sqlx::Error> {
     let res = sqlx::query_as!(
         IdAndCreationTime,
         r#"
-            INSERT INTO user_session_authentications (session_id)
-            VALUES ($1)
+            INSERT INTO user_session_authentications (session_id, method, second_factor)
+            VALUES ($1, $2, $3)
             RETURNING id, created_at
         "#,
         session.data,
+        method.as_str(),
+        second_factor.as_ref().map(SecondFactor::as_str),
     )
-    .fetch_one(txn.borrow_mut())
+    .fetch_one(executor)
     .instrument(tracing::info_span!("Save authentication"))
-    .await
-    .map_err(AuthenticationError::Save)?;
+    .await?;
 
     session.last_authentication = Some(Authentication {
         data: res.id,
         created_at: res.created_at,
+        method,
+        second_factor,
     });
 
     Ok(())
 }
 
-#[tracing::instrument(skip(txn, phf, password))]
+#[tracing::instrument(skip(txn, schemes, password))]
 pub async fn register_user(
     txn: &mut Transaction<'_, Postgres>,
-    phf: impl PasswordHasher,
+    schemes: &PasswordSchemes,
     username: &str,
     password: &str,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let user = register_passwordless_user(txn.borrow_mut(), username).await?;
+    set_password(txn.borrow_mut(), schemes, &user, password).await?;
+    Ok(user)
+}
+
+/// Create a user without any password, e.g. for users coming from an upstream
+/// provider
+#[tracing::instrument(skip(executor))]
+pub async fn register_passwordless_user(
+    executor: impl PgExecutor<'_>,
+    username: &str,
 ) -> anyhow::Result<User<PostgresqlBackend>> {
     let id: i64 = sqlx::query_scalar!(
         r#"
@@ -346,68 +511,130 @@ pub async fn register_user(
         "#,
         username,
     )
-    .fetch_one(txn.borrow_mut())
+    .fetch_one(executor)
     .instrument(info_span!("Register user"))
     .await
     .context("could not insert user")?;
 
-    let user = User {
+    Ok(User {
         data: id,
         username: username.to_string(),
         sub: format!("fake-sub-{}", id),
         primary_email: None,
-    };
-
-    set_password(txn.borrow_mut(), phf, &user, password).await?;
-
-    Ok(user)
+    })
 }
 
+/// Hash a new password for the user with the current password scheme
 #[tracing::instrument(skip_all, fields(user.id = user.data))]
 pub async fn set_password(
     executor: impl PgExecutor<'_>,
-    phf: impl PasswordHasher,
+    schemes: &PasswordSchemes,
     user: &User<PostgresqlBackend>,
     password: &str,
 ) -> anyhow::Result<()> {
-    let salt = SaltString::generate(&mut OsRng);
-    let hashed_password = PasswordHash::generate(phf, password, salt.as_str())?;
+    let (version, hashed_password) = schemes.hash(password)?;
 
-    sqlx::query_scalar!(
-        r#"
-            INSERT INTO user_passwords (user_id, hashed_password)
-            VALUES ($1, $2)
-        "#,
-        user.data,
-        hashed_password.to_string(),
-    )
-    .execute(executor)
-    .instrument(info_span!("Save user credentials"))
-    .await
-    .context("could not insert user password")?;
+    add_password_hash(executor, user, version, &hashed_password)
+        .await
+        .context("could not insert user password")?;
 
     Ok(())
 }
 
+/// End a browser session, along with the OAuth sessions which were started
+/// from it, returning the scopes of those
 #[tracing::instrument(skip_all, fields(session.id = session.data))]
 pub async fn end_session(
-    executor: impl PgExecutor<'_>,
+    conn: &mut PgConnection,
     session: &BrowserSession<PostgresqlBackend>,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<Scope>> {
     let res = sqlx::query!(
         "UPDATE user_sessions SET active = FALSE WHERE id = $1",
         session.data,
     )
-    .execute(executor)
+    .execute(&mut *conn)
     .instrument(info_span!("End session"))
     .await
     .context("could not end session")?;
 
     match res.rows_affected() {
-        1 => Ok(()),
-        0 => Err(anyhow::anyhow!("no row affected")),
-        _ => Err(anyhow::anyhow!("too many row affected")),
+        1 => {}
+        0 => bail!("no row affected"),
+        _ => bail!("too many row affected"),
+    }
+
+    end_oauth_sessions_for_browser_sessions(conn, &[session.data]).await
+}
+
+/// End all the active browser sessions of a user but the given one, along with
+/// the OAuth sessions which were started from them, returning the scopes of
+/// those
+#[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
+pub async fn end_other_sessions(
+    conn: &mut PgConnection,
+    session: &BrowserSession<PostgresqlBackend>,
+) -> anyhow::Result<Vec<Scope>> {
+    let ids: Vec<i64> = sqlx::query_scalar!(
+        r#"
+            UPDATE user_sessions
+            SET active = FALSE
+            WHERE user_id = $1 AND id <> $2 AND active
+            RETURNING id
+        "#,
+        session.user.data,
+        session.data,
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("End other sessions"))
+    .await
+    .context("could not end sessions")?;
+
+    end_oauth_sessions_for_browser_sessions(conn, &ids).await
+}
+
+/// End the browser sessions which went unused for longer than their idle
+/// timeout or outlived their maximum lifetime, along with the OAuth sessions
+/// which were started from them. Returns how many browser sessions were ended
+#[tracing::instrument(skip_all, err)]
+pub async fn end_expired_sessions(conn: &mut PgConnection) -> anyhow::Result<usize> {
+    let ids: Vec<i64> = sqlx::query_scalar!(
+        r#"
+            UPDATE user_sessions
+            SET active = FALSE
+            WHERE active
+              AND (expires_at <= NOW()
+                OR COALESCE(last_active_at, created_at) + idle_timeout <= NOW())
+            RETURNING id
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("End expired sessions"))
+    .await
+    .context("could not end expired sessions")?;
+
+    if !ids.is_empty() {
+        end_oauth_sessions_for_browser_sessions(conn, &ids).await?;
     }
+
+    Ok(ids.len())
+}
+
+/// End all the active browser sessions of a user
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn end_user_sessions(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<u64> {
+    let res = sqlx::query!(
+        "UPDATE user_sessions SET active = FALSE WHERE user_id = $1 AND active",
+        user.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("End user sessions"))
+    .await
+    .context("could not end user sessions")?;
+
+    Ok(res.rows_affected())
 }
 
 #[derive(Debug, Error)]
@@ -452,28 +679,7 @@ pub async fn lookup_user_by_username(
     .instrument(info_span!("Fetch user"))
     .await?;
 
-    let primary_email = match (
-        res.user_email_id,
-        res.user_email,
-        res.user_email_created_at,
-        res.user_email_confirmed_at,
-    ) {
-        (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
-            data: id,
-            email,
-            created_at,
-            confirmed_at,
-        }),
-        (None, None, None, None) => None,
-        _ => return Err(DatabaseInconsistencyError.into()),
-    };
-
-    Ok(User {
-        data: res.user_id,
-        username: res.user_username,
-        sub: format!("fake-sub-{}", res.user_id),
-        primary_email,
-    })
+    Ok(res.try_into()?)
 }
 
 pub async fn username_exists(
@@ -731,13 +937,27 @@ struct UserEmailVerificationLookup {
     verification_consumed_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Debug, Error)]
+#[error("could not lookup user email verification")]
+pub enum UserEmailVerificationLookupError {
+    Fetch(#[from] sqlx::Error),
+    Other(#[from] anyhow::Error),
+}
+
+impl UserEmailVerificationLookupError {
+    #[must_use]
+    pub fn not_found(&self) -> bool {
+        matches!(self, Self::Fetch(sqlx::Error::RowNotFound))
+    }
+}
+
 #[tracing::instrument(skip(executor))]
 pub async fn lookup_user_email_verification_code(
     executor: impl PgExecutor<'_>,
     email: UserEmail<PostgresqlBackend>,
     code: &str,
     max_age: chrono::Duration,
-) -> anyhow::Result<UserEmailVerification<PostgresqlBackend>> {
+) -> Result<UserEmailVerification<PostgresqlBackend>, UserEmailVerificationLookupError> {
     // For some reason, we need to convert the type first
     let max_age = PgInterval::try_from(max_age)
         // For some reason, this error type does not let me to just bubble up the error here
@@ -755,6 +975,8 @@ pub async fn lookup_user_email_verification_code(
             FROM user_email_verifications ev
             WHERE ev.code = $1
               AND ev.user_email_id = $2
+            ORDER BY ev.id DESC
+            LIMIT 1
         "#,
         code,
         email.data,
@@ -762,8 +984,7 @@ pub async fn lookup_user_email_verification_code(
     )
     .fetch_one(executor)
     .instrument(info_span!("Lookup user email verification"))
-    .await
-    .context("could not lookup user email verification")?;
+    .await?;
 
     let state = if res.verification_expired {
         UserEmailVerificationState::Expired
@@ -782,6 +1003,92 @@ pub async fn lookup_user_email_verification_code(
     })
 }
 
+/// Find the verification holding a login link token, sent to the given email
+#[tracing::instrument(skip(executor, token))]
+pub async fn lookup_user_email_login_link(
+    executor: impl PgExecutor<'_>,
+    email: UserEmail<PostgresqlBackend>,
+    token: &str,
+    max_age: chrono::Duration,
+) -> anyhow::Result<Option<UserEmailVerification<PostgresqlBackend>>> {
+    let max_age = PgInterval::try_from(max_age)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let res = sqlx::query_as!(
+        UserEmailVerificationLookup,
+        r#"
+            SELECT
+                ev.id              AS "verification_id",
+                ev.code            AS "verification_code",
+                (ev.created_at + $3 < NOW()) AS "verification_expired!",
+                ev.created_at      AS "verification_created_at",
+                ev.consumed_at     AS "verification_consumed_at"
+            FROM user_email_verifications ev
+            WHERE ev.link_token = $1
+              AND ev.user_email_id = $2
+        "#,
+        token,
+        email.data,
+        max_age,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Lookup user email login link"))
+    .await
+    .context("could not lookup user email login link")?;
+
+    let res = if let Some(res) = res {
+        res
+    } else {
+        return Ok(None);
+    };
+
+    let state = if res.verification_expired {
+        UserEmailVerificationState::Expired
+    } else if let Some(when) = res.verification_consumed_at {
+        UserEmailVerificationState::AlreadyUsed { when }
+    } else {
+        UserEmailVerificationState::Valid
+    };
+
+    Ok(Some(UserEmailVerification {
+        data: res.verification_id,
+        code: res.verification_code,
+        email,
+        state,
+        created_at: res.verification_created_at,
+    }))
+}
+
+/// Count a wrong code against a verification. The verification is used up
+/// once it reaches `max_attempts`, in which case this returns `false`.
+#[tracing::instrument(skip(executor))]
+pub async fn record_email_verification_failure(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+    max_attempts: i32,
+) -> anyhow::Result<bool> {
+    let usable = sqlx::query_scalar!(
+        r#"
+            UPDATE user_email_verifications
+            SET attempts = attempts + 1,
+                consumed_at = CASE
+                  WHEN attempts + 1 >= $2 THEN NOW()
+                  ELSE consumed_at
+                END
+            WHERE id = $1 AND consumed_at IS NULL
+            RETURNING consumed_at IS NULL AS "usable!"
+        "#,
+        id,
+        max_attempts,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Record user email verification failure"))
+    .await
+    .context("could not update user email verification")?;
+
+    Ok(usable.unwrap_or(false))
+}
+
 #[tracing::instrument(skip(executor))]
 pub async fn consume_email_verification(
     executor: impl PgExecutor<'_>,
@@ -810,6 +1117,40 @@ pub async fn consume_email_verification(
     Ok(verification)
 }
 
+/// Add a verification for an email login, holding both the short code and the
+/// token of the link
+#[tracing::instrument(skip(executor, email, code, link_token), fields(email.id = email.data, %email.email))]
+pub async fn add_user_email_login_code(
+    executor: impl PgExecutor<'_>,
+    email: UserEmail<PostgresqlBackend>,
+    code: String,
+    link_token: &str,
+) -> anyhow::Result<UserEmailVerification<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO user_email_verifications (user_email_id, code, link_token)
+            VALUES ($1, $2, $3)
+            RETURNING id, created_at
+        "#,
+        email.data,
+        code,
+        link_token,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Add user email login code"))
+    .await
+    .context("could not insert user email login code")?;
+
+    Ok(UserEmailVerification {
+        data: res.id,
+        email,
+        code,
+        created_at: res.created_at,
+        state: UserEmailVerificationState::Valid,
+    })
+}
+
 #[tracing::instrument(skip(executor, email), fields(email.id = email.data, %email.email))]
 pub async fn add_user_email_verification_code(
     executor: impl PgExecutor<'_>,
@@ -841,3 +1182,321 @@ pub async fn add_user_email_verification_code(
 
     Ok(verification)
 }
+
+#[cfg(test)]
+mod tests {
+    use mas_iana::oauth::OAuthClientAuthenticationMethod;
+    use oauth2_types::requests::ResponseMode;
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        oauth2::{
+            authorization_grant::{derive_session, new_authorization_grant},
+            client::{insert_client_from_config, lookup_client_by_client_id},
+        },
+        test_utils::{random_name, test_transaction},
+    };
+
+    async fn verified_email(txn: &mut Transaction<'_, Postgres>) -> UserEmail<PostgresqlBackend> {
+        let user = register_passwordless_user(txn.borrow_mut(), &random_name("email"))
+            .await
+            .unwrap();
+        let email = add_user_email(txn.borrow_mut(), &user, "email@example.com")
+            .await
+            .unwrap();
+        mark_user_email_as_verified(txn.borrow_mut(), email)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_email_login_code_and_link() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let email = verified_email(&mut txn).await;
+        let token = random_name("token");
+        let max_age = chrono::Duration::minutes(10);
+        add_user_email_login_code(&mut txn, email.clone(), "012345".to_owned(), &token)
+            .await
+            .unwrap();
+
+        // The short code isn't accepted as a link, nor the token as a code
+        assert!(
+            lookup_user_email_login_link(&mut txn, email.clone(), "012345", max_age)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            lookup_user_email_verification_code(&mut txn, email.clone(), &token, max_age)
+                .await
+                .is_err()
+        );
+
+        // Using the code consumes the link
+        let verification =
+            lookup_user_email_verification_code(&mut txn, email.clone(), "012345", max_age)
+                .await
+                .unwrap();
+        consume_email_verification(&mut txn, verification)
+            .await
+            .unwrap();
+        let link = lookup_user_email_login_link(&mut txn, email, &token, max_age)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            link.state,
+            UserEmailVerificationState::AlreadyUsed { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_email_verification_attempts() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let email = verified_email(&mut txn).await;
+        let token = random_name("token");
+        let max_age = chrono::Duration::minutes(10);
+        let verification =
+            add_user_email_login_code(&mut txn, email.clone(), "123456".to_owned(), &token)
+                .await
+                .unwrap();
+
+        for _ in 0..4 {
+            assert!(
+                record_email_verification_failure(&mut txn, verification.data, 5)
+                    .await
+                    .unwrap()
+            );
+        }
+        assert!(
+            !record_email_verification_failure(&mut txn, verification.data, 5)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !record_email_verification_failure(&mut txn, verification.data, 5)
+                .await
+                .unwrap()
+        );
+
+        // Neither the code nor the link can be used anymore
+        let code = lookup_user_email_verification_code(&mut txn, email.clone(), "123456", max_age)
+            .await
+            .unwrap();
+        assert!(matches!(
+            code.state,
+            UserEmailVerificationState::AlreadyUsed { .. }
+        ));
+        let link = lookup_user_email_login_link(&mut txn, email, &token, max_age)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            link.state,
+            UserEmailVerificationState::AlreadyUsed { .. }
+        ));
+    }
+
+    fn expiration(idle_timeout: Option<i64>, max_lifetime: Option<i64>) -> SessionExpiration {
+        SessionExpiration {
+            idle_timeout: idle_timeout.map(chrono::Duration::minutes),
+            max_lifetime: max_lifetime.map(chrono::Duration::minutes),
+        }
+    }
+
+    /// Start an OAuth session from the browser session, returning its scope
+    async fn start_oauth_session(
+        txn: &mut Transaction<'_, Postgres>,
+        session: &BrowserSession<PostgresqlBackend>,
+    ) -> Scope {
+        let client_id = random_name("client");
+        let redirect_uri: Url = "https://example.com/callback".parse().unwrap();
+        insert_client_from_config(
+            txn.borrow_mut(),
+            &client_id,
+            OAuthClientAuthenticationMethod::None,
+            None,
+            None,
+            None,
+            std::slice::from_ref(&redirect_uri),
+        )
+        .await
+        .unwrap();
+        let client = lookup_client_by_client_id(txn.borrow_mut(), &client_id)
+            .await
+            .unwrap();
+
+        let scope: Scope = "openid".parse().unwrap();
+        let grant = new_authorization_grant(
+            txn.borrow_mut(),
+            client,
+            redirect_uri,
+            scope.clone(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            ResponseMode::Query,
+            false,
+            false,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+        derive_session(txn.borrow_mut(), &grant, session.clone())
+            .await
+            .unwrap();
+
+        scope
+    }
+
+    #[tokio::test]
+    async fn test_session_expiration() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let user = register_passwordless_user(txn.borrow_mut(), &random_name("expiry"))
+            .await
+            .unwrap();
+
+        // NOW() doesn't move within the transaction, so expired sessions are
+        // started with negative durations
+        let unlimited = start_session(&mut txn, user.clone(), None, None, &expiration(None, None))
+            .await
+            .unwrap();
+        let fresh = start_session(
+            &mut txn,
+            user.clone(),
+            None,
+            None,
+            &expiration(Some(60), Some(24 * 60)),
+        )
+        .await
+        .unwrap();
+        let idle = start_session(
+            &mut txn,
+            user.clone(),
+            None,
+            None,
+            &expiration(Some(-1), None),
+        )
+        .await
+        .unwrap();
+        let old = start_session(
+            &mut txn,
+            user.clone(),
+            None,
+            None,
+            &expiration(None, Some(-1)),
+        )
+        .await
+        .unwrap();
+        start_oauth_session(&mut txn, &idle).await;
+
+        for session in [&unlimited, &fresh] {
+            lookup_active_session(&mut txn, session.data).await.unwrap();
+        }
+        for session in [&idle, &old] {
+            assert!(lookup_active_session(&mut txn, session.data)
+                .await
+                .unwrap_err()
+                .not_found());
+        }
+
+        let mut listed: Vec<i64> = get_user_sessions(&mut txn, &user)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| s.data)
+            .collect();
+        listed.sort_unstable();
+        let mut expected = vec![unlimited.data, fresh.data];
+        expected.sort_unstable();
+        assert_eq!(listed, expected);
+        assert_eq!(count_active_sessions(&mut txn, &user).await.unwrap(), 2);
+
+        // Ending the expired sessions also ends the OAuth sessions started
+        // from them
+        assert!(end_expired_sessions(&mut txn).await.unwrap() >= 2);
+        assert_eq!(end_expired_sessions(&mut txn).await.unwrap(), 0);
+        assert!(
+            end_oauth_sessions_for_browser_sessions(&mut txn, &[idle.data])
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        lookup_active_session(&mut txn, fresh.data).await.unwrap();
+        assert_eq!(count_active_sessions(&mut txn, &user).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_end_session_cascade() {
+        let mut txn = if let Some(txn) = test_transaction().await {
+            txn
+        } else {
+            return;
+        };
+
+        let user = register_passwordless_user(txn.borrow_mut(), &random_name("cascade"))
+            .await
+            .unwrap();
+        let never = expiration(None, None);
+        let current = start_session(&mut txn, user.clone(), None, None, &never)
+            .await
+            .unwrap();
+        let first = start_session(&mut txn, user.clone(), None, None, &never)
+            .await
+            .unwrap();
+        let second = start_session(&mut txn, user.clone(), None, None, &never)
+            .await
+            .unwrap();
+        let current_scope = start_oauth_session(&mut txn, &current).await;
+        let first_scope = start_oauth_session(&mut txn, &first).await;
+        let second_scope = start_oauth_session(&mut txn, &second).await;
+
+        // Ending a session returns the scopes of the OAuth sessions it ended
+        assert_eq!(
+            end_session(&mut txn, &first).await.unwrap(),
+            vec![first_scope]
+        );
+        assert!(lookup_active_session(&mut txn, first.data)
+            .await
+            .unwrap_err()
+            .not_found());
+
+        // Ending the other sessions leaves the current one and its OAuth
+        // sessions alone
+        assert_eq!(
+            end_other_sessions(&mut txn, &current).await.unwrap(),
+            vec![second_scope]
+        );
+        assert!(lookup_active_session(&mut txn, second.data)
+            .await
+            .unwrap_err()
+            .not_found());
+        lookup_active_session(&mut txn, current.data).await.unwrap();
+        assert_eq!(
+            end_oauth_sessions_for_browser_sessions(&mut txn, &[current.data])
+                .await
+                .unwrap(),
+            vec![current_scope]
+        );
+    }
+}
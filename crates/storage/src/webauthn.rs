@@ -0,0 +1,278 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebAuthn credentials, used both for passwordless login and as a second
+//! factor
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use mas_data_model::User;
+use mas_jose::JsonWebKey;
+use sqlx::PgExecutor;
+use tracing::{info_span, Instrument};
+
+use crate::{user::UserLookup, DatabaseInconsistencyError, PostgresqlBackend};
+
+#[derive(Debug, Clone)]
+pub struct WebauthnCredential {
+    pub id: i64,
+
+    /// The credential ID chosen by the authenticator, base64url-encoded
+    pub credential_id: String,
+
+    pub public_key: JsonWebKey,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+struct WebauthnCredentialLookup {
+    id: i64,
+    credential_id: String,
+    public_key: serde_json::Value,
+    sign_count: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<WebauthnCredentialLookup> for WebauthnCredential {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_from(res: WebauthnCredentialLookup) -> Result<Self, Self::Error> {
+        let public_key =
+            serde_json::from_value(res.public_key).map_err(|_e| DatabaseInconsistencyError)?;
+
+        Ok(WebauthnCredential {
+            id: res.id,
+            credential_id: res.credential_id,
+            public_key,
+            sign_count: res.sign_count,
+            name: res.name,
+            created_at: res.created_at,
+            last_used_at: res.last_used_at,
+        })
+    }
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn get_user_webauthn_credentials(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Vec<WebauthnCredential>> {
+    let res = sqlx::query_as!(
+        WebauthnCredentialLookup,
+        r#"
+            SELECT id, credential_id, public_key, sign_count, name, created_at, last_used_at
+            FROM user_webauthn_credentials
+            WHERE user_id = $1
+            ORDER BY created_at ASC
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Fetch user WebAuthn credentials"))
+    .await?;
+
+    let res: Result<Vec<_>, _> = res.into_iter().map(TryInto::try_into).collect();
+    Ok(res?)
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data), err)]
+pub async fn has_user_webauthn_credentials(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_webauthn_credentials WHERE user_id = $1
+            ) AS "exists!"
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .await
+}
+
+struct UserWebauthnCredentialLookup {
+    id: i64,
+    credential_id: String,
+    public_key: serde_json::Value,
+    sign_count: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    user_id: i64,
+    user_username: String,
+    user_email_id: Option<i64>,
+    user_email: Option<String>,
+    user_email_created_at: Option<DateTime<Utc>>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+/// Find a credential from the ID the authenticator returned, along with the
+/// user it belongs to
+#[tracing::instrument(skip(executor), err)]
+pub async fn lookup_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    credential_id: &str,
+) -> anyhow::Result<Option<(User<PostgresqlBackend>, WebauthnCredential)>> {
+    let res = sqlx::query_as!(
+        UserWebauthnCredentialLookup,
+        r#"
+            SELECT
+                c.id,
+                c.credential_id,
+                c.public_key,
+                c.sign_count,
+                c.name,
+                c.created_at,
+                c.last_used_at,
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM user_webauthn_credentials c
+
+            INNER JOIN users u
+              ON u.id = c.user_id
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE c.credential_id = $1
+        "#,
+        credential_id,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Lookup WebAuthn credential"))
+    .await?;
+
+    let res = if let Some(res) = res {
+        res
+    } else {
+        return Ok(None);
+    };
+
+    let user = UserLookup {
+        user_id: res.user_id,
+        user_username: res.user_username,
+        user_email_id: res.user_email_id,
+        user_email: res.user_email,
+        user_email_created_at: res.user_email_created_at,
+        user_email_confirmed_at: res.user_email_confirmed_at,
+    }
+    .try_into()?;
+
+    let credential = WebauthnCredentialLookup {
+        id: res.id,
+        credential_id: res.credential_id,
+        public_key: res.public_key,
+        sign_count: res.sign_count,
+        name: res.name,
+        created_at: res.created_at,
+        last_used_at: res.last_used_at,
+    }
+    .try_into()?;
+
+    Ok(Some((user, credential)))
+}
+
+#[tracing::instrument(skip(executor, user, public_key), fields(user.id = user.data), err)]
+pub async fn add_user_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    credential_id: &str,
+    public_key: &JsonWebKey,
+    sign_count: u32,
+    name: &str,
+) -> anyhow::Result<WebauthnCredential> {
+    let public_key_json = serde_json::to_value(public_key)?;
+
+    let res = sqlx::query_as!(
+        WebauthnCredentialLookup,
+        r#"
+            INSERT INTO user_webauthn_credentials
+                (user_id, credential_id, public_key, sign_count, name)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, credential_id, public_key, sign_count, name, created_at, last_used_at
+        "#,
+        user.data,
+        credential_id,
+        public_key_json,
+        i64::from(sign_count),
+        name,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Add user WebAuthn credential"))
+    .await
+    .context("could not insert WebAuthn credential")?;
+
+    Ok(res.try_into()?)
+}
+
+/// Record a successful assertion with a credential. Returns `false` if the
+/// signature counter went backwards, which hints at a cloned authenticator.
+#[tracing::instrument(skip_all, fields(credential.id = credential.id), err)]
+pub async fn use_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    credential: &WebauthnCredential,
+    sign_count: u32,
+) -> Result<bool, sqlx::Error> {
+    // Authenticators which don't implement the counter always return zero
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_webauthn_credentials
+            SET sign_count = $2, last_used_at = now()
+            WHERE id = $1
+              AND (sign_count < $2 OR (sign_count = 0 AND $2 = 0))
+        "#,
+        credential.id,
+        i64::from(sign_count),
+    )
+    .execute(executor)
+    .instrument(info_span!("Use WebAuthn credential"))
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data), err)]
+pub async fn remove_user_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    id: i64,
+) -> anyhow::Result<()> {
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM user_webauthn_credentials
+            WHERE id = $1 AND user_id = $2
+        "#,
+        id,
+        user.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("Remove user WebAuthn credential"))
+    .await?;
+
+    match res.rows_affected() {
+        1 => Ok(()),
+        0 => Err(anyhow::anyhow!("no row affected")),
+        _ => Err(anyhow::anyhow!("too many row affected")),
+    }
+}
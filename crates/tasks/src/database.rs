@@ -51,3 +51,46 @@ impl Task for CleanupExpired {
 pub fn cleanup_expired(pool: &Pool<Postgres>) -> impl Task + Clone {
     CleanupExpired(pool.clone())
 }
+
+#[derive(Clone)]
+struct EndExpiredSessions(Pool<Postgres>);
+
+impl std::fmt::Debug for EndExpiredSessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EndExpiredSessions").finish_non_exhaustive()
+    }
+}
+
+impl EndExpiredSessions {
+    async fn end_expired(&self) -> anyhow::Result<usize> {
+        let mut txn = self.0.begin().await?;
+        let count = mas_storage::user::end_expired_sessions(&mut txn).await?;
+        txn.commit().await?;
+        Ok(count)
+    }
+}
+
+#[async_trait::async_trait]
+impl Task for EndExpiredSessions {
+    async fn run(&self) {
+        let res = self.end_expired().await;
+        match res {
+            Ok(0) => {
+                debug!("no browser session to end");
+            }
+            Ok(count) => {
+                info!(count, "ended expired browser sessions");
+            }
+            Err(error) => {
+                error!(?error, "failed to end expired browser sessions");
+            }
+        }
+    }
+}
+
+/// End the browser sessions which timed out, along with the OAuth sessions
+/// started from them
+#[must_use]
+pub fn end_expired_sessions(pool: &Pool<Postgres>) -> impl Task + Clone {
+    EndExpiredSessions(pool.clone())
+}
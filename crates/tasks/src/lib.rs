@@ -34,7 +34,7 @@ use tracing::debug;
 
 mod database;
 
-pub use self::database::cleanup_expired;
+pub use self::database::{cleanup_expired, end_expired_sessions};
 
 /// A [`Task`] can be executed by a [`TaskQueue`]
 #[async_trait::async_trait]
@@ -16,16 +16,16 @@
 
 #![allow(clippy::trait_duplication_in_bounds)]
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use mas_data_model::{
-    AuthorizationGrant, BrowserSession, CompatSsoLogin, CompatSsoLoginState, StorageBackend, User,
-    UserEmail, UserEmailVerification,
+    AuthorizationGrant, BrowserSession, CibaRequest, CompatSsoLogin, CompatSsoLoginState,
+    StorageBackend, User, UserEmail, UserEmailVerification,
 };
 use mas_router::PostAuthAction;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use url::Url;
 
-use crate::{FormField, FormState};
+use crate::{FieldError, FormError, FormField, FormState};
 
 /// Helper trait to construct context wrappers
 pub trait TemplateContext: Serialize {
@@ -262,6 +262,24 @@ pub enum PostAuthContext {
 
     /// Change the account password
     ChangePassword,
+
+    /// Manage the second factors of the account
+    ManageSecondFactor,
+}
+
+/// A link to log in through an upstream provider, shown on the login page
+#[derive(Serialize, Debug, Clone)]
+pub struct UpstreamProviderLink {
+    name: String,
+    href: String,
+}
+
+impl UpstreamProviderLink {
+    /// Constructs a new link to an upstream provider
+    #[must_use]
+    pub fn new(name: String, href: String) -> Self {
+        Self { name, href }
+    }
 }
 
 /// Context used by the `login.html` template
@@ -270,6 +288,9 @@ pub struct LoginContext {
     form: FormState<LoginFormField>,
     next: Option<PostAuthContext>,
     register_link: String,
+    webauthn_link: Option<String>,
+    email_login_link: Option<String>,
+    providers: Vec<UpstreamProviderLink>,
 }
 
 impl TemplateContext for LoginContext {
@@ -282,6 +303,12 @@ impl TemplateContext for LoginContext {
             form: FormState::default(),
             next: None,
             register_link: "/register".to_string(),
+            webauthn_link: Some("/login/webauthn".to_string()),
+            email_login_link: Some("/login/email".to_string()),
+            providers: vec![UpstreamProviderLink::new(
+                "Example".to_string(),
+                "/upstream/authorize/example".to_string(),
+            )],
         }]
     }
 }
@@ -310,6 +337,135 @@ impl LoginContext {
             ..self
         }
     }
+
+    /// Set the upstream providers users can log in with
+    #[must_use]
+    pub fn with_upstream_providers(self, providers: Vec<UpstreamProviderLink>) -> Self {
+        Self { providers, ..self }
+    }
+
+    /// Offer to log in with a passkey, through the given endpoint
+    #[must_use]
+    pub fn with_webauthn_link(self, webauthn_link: String) -> Self {
+        Self {
+            webauthn_link: Some(webauthn_link),
+            ..self
+        }
+    }
+
+    /// Offer to log in with a code sent by email, through the given page
+    #[must_use]
+    pub fn with_email_login_link(self, email_login_link: String) -> Self {
+        Self {
+            email_login_link: Some(email_login_link),
+            ..self
+        }
+    }
+}
+
+/// Fields of the email login form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailLoginFormField {
+    /// The username of the account to send a code to
+    Username,
+
+    /// The code received by email
+    Code,
+}
+
+impl FormField for EmailLoginFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Username => true,
+            Self::Code => false,
+        }
+    }
+}
+
+/// Context used by the `pages/email_login.html` template
+#[derive(Serialize, Default)]
+pub struct EmailLoginContext {
+    form: FormState<EmailLoginFormField>,
+    next: Option<PostAuthContext>,
+    code_sent: bool,
+}
+
+impl TemplateContext for EmailLoginContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let invalid = FormState::default()
+            .with_error_on_field(EmailLoginFormField::Code, FieldError::Invalid);
+        let expired = FormState::default().with_error_on_form(FormError::InvalidLink);
+        vec![
+            Self::default(),
+            Self::default().with_code_sent(),
+            Self::default().with_code_sent().with_form_state(invalid),
+            Self::default().with_form_state(expired),
+        ]
+    }
+}
+
+impl EmailLoginContext {
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<EmailLoginFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Add a post authentication action to the context
+    #[must_use]
+    pub fn with_post_action(self, next: PostAuthContext) -> Self {
+        Self {
+            next: Some(next),
+            ..self
+        }
+    }
+
+    /// Ask for the code which was sent by email
+    #[must_use]
+    pub fn with_code_sent(self) -> Self {
+        Self {
+            code_sent: true,
+            ..self
+        }
+    }
+}
+
+/// Context used by the `emails/login.{txt,html,subject}` templates
+#[derive(Serialize)]
+pub struct EmailLoginEmailContext {
+    user: User<()>,
+    code: String,
+    link: Url,
+}
+
+impl EmailLoginEmailContext {
+    /// Constructs a context for the login email
+    #[must_use]
+    pub fn new(user: User<()>, code: String, link: Url) -> Self {
+        Self { user, code, link }
+    }
+}
+
+impl TemplateContext for EmailLoginEmailContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        User::samples()
+            .into_iter()
+            .map(|user| Self {
+                user,
+                code: "123456".to_string(),
+                link: "https://example.com/login/email/link?username=john&token=abcdef"
+                    .parse()
+                    .unwrap(),
+            })
+            .collect()
+    }
 }
 
 /// Fields of the registration form
@@ -383,6 +539,77 @@ impl RegisterContext {
     }
 }
 
+/// Fields of the form used to pick a username after logging in through an
+/// upstream provider
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamRegisterFormField {
+    /// The username field
+    Username,
+}
+
+impl FormField for UpstreamRegisterFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Username => true,
+        }
+    }
+}
+
+/// Context used by the `upstream_register.html` template
+#[derive(Serialize)]
+pub struct UpstreamRegisterContext {
+    form: FormState<UpstreamRegisterFormField>,
+    provider_name: String,
+    displayname: Option<String>,
+    email: Option<String>,
+}
+
+impl TemplateContext for UpstreamRegisterContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        vec![UpstreamRegisterContext::new("Example".to_string())
+            .with_displayname(Some("John Doe".to_string()))
+            .with_email(Some("john@example.com".to_string()))]
+    }
+}
+
+impl UpstreamRegisterContext {
+    /// Constructs a context for a user coming from the given provider
+    #[must_use]
+    pub fn new(provider_name: String) -> Self {
+        Self {
+            form: FormState::default(),
+            provider_name,
+            displayname: None,
+            email: None,
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<UpstreamRegisterFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Set the display name imported from the provider
+    #[must_use]
+    pub fn with_displayname(self, displayname: Option<String>) -> Self {
+        Self {
+            displayname,
+            ..self
+        }
+    }
+
+    /// Set the email address imported from the provider
+    #[must_use]
+    pub fn with_email(self, email: Option<String>) -> Self {
+        Self { email, ..self }
+    }
+}
+
 /// Context used by the `consent.html` template
 #[derive(Serialize)]
 pub struct ConsentContext {
@@ -443,12 +670,18 @@ impl TemplateContext for ReauthContext {
     where
         Self: Sized,
     {
-        // TODO: samples with errors
-        vec![ReauthContext {
-            form: FormState::default(),
-            next: None,
-            action: None,
-        }]
+        vec![
+            ReauthContext {
+                form: FormState::default(),
+                next: None,
+                action: None,
+            },
+            ReauthContext {
+                form: FormState::default().with_error_on_form(FormError::TooManyAttempts),
+                next: None,
+                action: None,
+            },
+        ]
     }
 }
 
@@ -470,6 +703,137 @@ impl ReauthContext {
     }
 }
 
+/// Fields of the second factor form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecondFactorFormField {
+    /// The TOTP or recovery code field
+    Code,
+}
+
+impl FormField for SecondFactorFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Code => false,
+        }
+    }
+}
+
+/// Context used by the `second_factor.html` template
+#[derive(Serialize)]
+pub struct SecondFactorContext {
+    form: FormState<SecondFactorFormField>,
+    username: String,
+    totp: bool,
+    webauthn_link: Option<String>,
+    next: Option<PostAuthContext>,
+    action: Option<PostAuthAction>,
+}
+
+impl TemplateContext for SecondFactorContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let invalid = FormState::default()
+            .with_error_on_field(SecondFactorFormField::Code, FieldError::Invalid);
+        vec![
+            Self::new("john".to_string()).with_totp(),
+            Self::new("john".to_string())
+                .with_totp()
+                .with_form_state(invalid),
+            Self::new("john".to_string()).with_webauthn_link("/second-factor/webauthn".to_string()),
+        ]
+    }
+}
+
+impl SecondFactorContext {
+    /// Constructs a context for the second factor step of the given user
+    #[must_use]
+    pub fn new(username: String) -> Self {
+        Self {
+            form: FormState::default(),
+            username,
+            totp: false,
+            webauthn_link: None,
+            next: None,
+            action: None,
+        }
+    }
+
+    /// Ask for a TOTP or recovery code
+    #[must_use]
+    pub fn with_totp(self) -> Self {
+        Self { totp: true, ..self }
+    }
+
+    /// Offer to use a WebAuthn credential, through the given endpoint
+    #[must_use]
+    pub fn with_webauthn_link(self, webauthn_link: String) -> Self {
+        Self {
+            webauthn_link: Some(webauthn_link),
+            ..self
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<SecondFactorFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Add a post authentication action to the context
+    #[must_use]
+    pub fn with_post_action(self, next: PostAuthContext, action: PostAuthAction) -> Self {
+        Self {
+            next: Some(next),
+            action: Some(action),
+            ..self
+        }
+    }
+}
+
+/// Context used by the `select_account.html` template
+#[derive(Serialize)]
+#[serde(bound = "T: StorageBackend")]
+pub struct SelectAccountContext<T: StorageBackend> {
+    sessions: Vec<BrowserSession<T>>,
+    next: Option<PostAuthContext>,
+}
+
+impl<T: StorageBackend> SelectAccountContext<T> {
+    /// Constructs a context for the account picker
+    #[must_use]
+    pub fn new(sessions: Vec<BrowserSession<T>>) -> Self {
+        Self {
+            sessions,
+            next: None,
+        }
+    }
+
+    /// Add a post authentication action to the context
+    #[must_use]
+    pub fn with_post_action(self, next: PostAuthContext) -> Self {
+        Self {
+            next: Some(next),
+            ..self
+        }
+    }
+}
+
+impl<T: StorageBackend> TemplateContext for SelectAccountContext<T>
+where
+    T::BrowserSessionData: Default,
+    T::UserData: Default,
+{
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        vec![Self::new(BrowserSession::samples())]
+    }
+}
+
 /// Context used by the `sso.html` template
 #[derive(Serialize)]
 pub struct CompatSsoContext {
@@ -514,6 +878,7 @@ impl CompatSsoContext {
 pub struct AccountContext {
     active_sessions: usize,
     emails: Vec<UserEmail<()>>,
+    ciba_requests: Vec<CibaRequest<()>>,
 }
 
 impl AccountContext {
@@ -526,8 +891,20 @@ impl AccountContext {
         Self {
             active_sessions,
             emails: emails.into_iter().map(Into::into).collect(),
+            ciba_requests: Vec::new(),
         }
     }
+
+    /// Add the backchannel authentication requests waiting for the user's
+    /// approval
+    #[must_use]
+    pub fn with_ciba_requests<T>(mut self, ciba_requests: Vec<T>) -> Self
+    where
+        T: Into<CibaRequest<()>>,
+    {
+        self.ciba_requests = ciba_requests.into_iter().map(Into::into).collect();
+        self
+    }
 }
 
 impl TemplateContext for AccountContext {
@@ -536,7 +913,260 @@ impl TemplateContext for AccountContext {
         Self: Sized,
     {
         let emails: Vec<UserEmail<()>> = UserEmail::samples();
-        vec![Self::new(5, emails)]
+        let ciba_requests: Vec<CibaRequest<()>> = CibaRequest::samples();
+        vec![
+            Self::new(5, emails.clone()),
+            Self::new(5, emails).with_ciba_requests(ciba_requests),
+        ]
+    }
+}
+
+/// A TOTP authenticator waiting for the user to confirm they saved it
+#[derive(Serialize, Clone)]
+pub struct TotpEnrollment {
+    secret: String,
+    qr_code: String,
+}
+
+impl TotpEnrollment {
+    /// Constructs an enrollment from the base32-encoded secret and a `data:`
+    /// URI of the QR code to scan
+    #[must_use]
+    pub fn new(secret: String, qr_code: String) -> Self {
+        Self { secret, qr_code }
+    }
+}
+
+/// Context used by the `account/totp.html` template
+#[derive(Serialize, Default)]
+pub struct AccountTotpContext {
+    form: FormState<SecondFactorFormField>,
+    enrollment: Option<TotpEnrollment>,
+    enabled: bool,
+    recovery_codes_left: i64,
+    recovery_codes: Vec<String>,
+}
+
+impl AccountTotpContext {
+    /// Constructs a context for a user who still has to confirm the given
+    /// authenticator
+    #[must_use]
+    pub fn enrolling(enrollment: TotpEnrollment) -> Self {
+        Self {
+            enrollment: Some(enrollment),
+            ..Self::default()
+        }
+    }
+
+    /// Constructs a context for a user who has TOTP enabled
+    #[must_use]
+    pub fn enabled(recovery_codes_left: i64) -> Self {
+        Self {
+            enabled: true,
+            recovery_codes_left,
+            ..Self::default()
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<SecondFactorFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Show newly generated recovery codes. They can't be retrieved later on.
+    #[must_use]
+    pub fn with_recovery_codes(self, recovery_codes: Vec<String>) -> Self {
+        Self {
+            recovery_codes,
+            ..self
+        }
+    }
+}
+
+impl TemplateContext for AccountTotpContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let enrollment = TotpEnrollment::new(
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_string(),
+            "data:image/svg+xml;base64,".to_string(),
+        );
+        let codes = vec!["abcde-fghij".to_string(), "klmno-pqrst".to_string()];
+        vec![
+            Self::enrolling(enrollment),
+            Self::enabled(8),
+            Self::enabled(10).with_recovery_codes(codes),
+        ]
+    }
+}
+
+/// A WebAuthn credential, as shown on the credential management page
+#[derive(Serialize, Clone)]
+pub struct WebauthnCredentialInfo {
+    id: i64,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl WebauthnCredentialInfo {
+    /// Describe a credential
+    #[must_use]
+    pub fn new(
+        id: i64,
+        name: String,
+        created_at: DateTime<Utc>,
+        last_used_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            created_at,
+            last_used_at,
+        }
+    }
+}
+
+/// Fields of the WebAuthn credential registration form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountWebauthnFormField {
+    /// The name the user gave to the credential
+    Name,
+}
+
+impl FormField for AccountWebauthnFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Name => true,
+        }
+    }
+}
+
+/// Context used by the `account/webauthn.html` template
+#[derive(Serialize)]
+pub struct AccountWebauthnContext {
+    form: FormState<AccountWebauthnFormField>,
+    credentials: Vec<WebauthnCredentialInfo>,
+    options_link: String,
+}
+
+impl AccountWebauthnContext {
+    /// Constructs a context for the credential management page. The
+    /// registration options are fetched from `options_link`.
+    #[must_use]
+    pub fn new(credentials: Vec<WebauthnCredentialInfo>, options_link: String) -> Self {
+        Self {
+            form: FormState::default(),
+            credentials,
+            options_link,
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<AccountWebauthnFormField>) -> Self {
+        Self { form, ..self }
+    }
+}
+
+impl TemplateContext for AccountWebauthnContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let options_link = "/account/webauthn/options".to_string();
+        let credentials = vec![
+            WebauthnCredentialInfo::new(1, "Laptop".to_string(), Utc::now(), Some(Utc::now())),
+            WebauthnCredentialInfo::new(2, "Security key".to_string(), Utc::now(), None),
+        ];
+        let failed = FormState::default().with_error_on_form(FormError::WebauthnFailed);
+        vec![
+            Self::new(Vec::new(), options_link.clone()),
+            Self::new(credentials, options_link.clone()),
+            Self::new(Vec::new(), options_link).with_form_state(failed),
+        ]
+    }
+}
+
+/// Fields of the password change form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountPasswordFormField {
+    /// The current password
+    CurrentPassword,
+
+    /// The new password
+    NewPassword,
+
+    /// The new password, again
+    NewPasswordConfirm,
+}
+
+impl FormField for AccountPasswordFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::CurrentPassword | Self::NewPassword | Self::NewPasswordConfirm => false,
+        }
+    }
+}
+
+/// Context used by the `account/password.html` template
+#[derive(Serialize, Default)]
+pub struct AccountPasswordContext {
+    form: FormState<AccountPasswordFormField>,
+}
+
+impl AccountPasswordContext {
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<AccountPasswordFormField>) -> Self {
+        Self { form }
+    }
+}
+
+impl TemplateContext for AccountPasswordContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let rejected = FormState::default().with_error_on_field(
+            AccountPasswordFormField::NewPassword,
+            FieldError::Policy {
+                message: "This password appeared in a data breach".to_string(),
+            },
+        );
+        vec![Self::default(), Self::default().with_form_state(rejected)]
+    }
+}
+
+/// Context used by the `account/sessions.html` template
+#[derive(Serialize)]
+#[serde(bound(serialize = "T: StorageBackend"))]
+pub struct AccountSessionsContext<T: StorageBackend> {
+    sessions: Vec<BrowserSession<T>>,
+}
+
+impl<T: StorageBackend> AccountSessionsContext<T> {
+    /// Constructs a context for the browser sessions management page
+    #[must_use]
+    pub fn new(sessions: Vec<BrowserSession<T>>) -> Self {
+        Self { sessions }
+    }
+}
+
+impl<T: StorageBackend> TemplateContext for AccountSessionsContext<T>
+where
+    T::BrowserSessionData: Default,
+    T::UserData: Default,
+{
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        vec![Self::new(BrowserSession::samples())]
     }
 }
 
@@ -609,6 +1239,188 @@ impl TemplateContext for EmailVerificationContext {
     }
 }
 
+/// Context used by the `emails/ciba.{txt,html,subject}` templates
+#[derive(Serialize)]
+pub struct CibaRequestEmailContext {
+    request: CibaRequest<()>,
+    account_url: Url,
+}
+
+impl CibaRequestEmailContext {
+    /// Constructs a context for the backchannel authentication request email
+    #[must_use]
+    pub fn new<T>(request: T, account_url: Url) -> Self
+    where
+        T: Into<CibaRequest<()>>,
+    {
+        Self {
+            request: request.into(),
+            account_url,
+        }
+    }
+}
+
+impl TemplateContext for CibaRequestEmailContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        CibaRequest::<()>::samples()
+            .into_iter()
+            .map(|request| Self {
+                request,
+                account_url: "https://example.com/account".parse().unwrap(),
+            })
+            .collect()
+    }
+}
+
+/// Context used by the `emails/password_reset.{txt,html,subject}` templates
+#[derive(Serialize)]
+pub struct PasswordResetEmailContext {
+    user: User<()>,
+    link: Url,
+}
+
+impl PasswordResetEmailContext {
+    /// Constructs a context for the password reset email
+    #[must_use]
+    pub fn new(user: User<()>, link: Url) -> Self {
+        Self { user, link }
+    }
+}
+
+impl TemplateContext for PasswordResetEmailContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        User::samples()
+            .into_iter()
+            .map(|user| Self {
+                user,
+                link: "https://example.com/password/reset/abcdef".parse().unwrap(),
+            })
+            .collect()
+    }
+}
+
+/// Fields of the forgotten password form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordForgotFormField {
+    /// The email address to send the reset link to
+    Email,
+}
+
+impl FormField for PasswordForgotFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Email => true,
+        }
+    }
+}
+
+/// Context used by the `pages/password/forgot.html` template
+#[derive(Serialize, Default)]
+pub struct PasswordForgotContext {
+    form: FormState<PasswordForgotFormField>,
+    sent: bool,
+}
+
+impl PasswordForgotContext {
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<PasswordForgotFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Tell the user a reset link was sent, if the address they gave belongs
+    /// to an account
+    #[must_use]
+    pub fn sent(self) -> Self {
+        Self { sent: true, ..self }
+    }
+}
+
+impl TemplateContext for PasswordForgotContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        vec![Self::default(), Self::default().sent()]
+    }
+}
+
+/// Fields of the password reset form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordResetFormField {
+    /// The new password
+    Password,
+
+    /// The new password, again
+    PasswordConfirm,
+}
+
+impl FormField for PasswordResetFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Password | Self::PasswordConfirm => false,
+        }
+    }
+}
+
+/// Context used by the `pages/password/reset.html` template
+#[derive(Serialize)]
+pub struct PasswordResetContext {
+    form: FormState<PasswordResetFormField>,
+
+    /// The user whose password is being reset, or `None` if the link is
+    /// invalid, expired or already used
+    username: Option<String>,
+}
+
+impl PasswordResetContext {
+    /// Constructs a context for a valid reset link
+    #[must_use]
+    pub fn new(username: String) -> Self {
+        Self {
+            form: FormState::default(),
+            username: Some(username),
+        }
+    }
+
+    /// Constructs a context for a link which can't be used
+    #[must_use]
+    pub fn invalid() -> Self {
+        Self {
+            form: FormState::default(),
+            username: None,
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<PasswordResetFormField>) -> Self {
+        Self { form, ..self }
+    }
+}
+
+impl TemplateContext for PasswordResetContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let mismatch = FormState::default().with_error_on_form(FormError::PasswordMismatch);
+        vec![
+            Self::new("john".to_string()),
+            Self::new("john".to_string()).with_form_state(mismatch),
+            Self::invalid(),
+        ]
+    }
+}
+
 /// Fields of the email verification form
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -44,6 +44,15 @@ pub enum FieldError {
         /// Message for this policy violation
         message: String,
     },
+
+    /// Feedback on how to make a password stronger
+    PasswordFeedback {
+        /// What makes the password weak
+        warning: Option<String>,
+
+        /// How to make it stronger
+        suggestions: Vec<String>,
+    },
 }
 
 /// An error on the whole form
@@ -59,6 +68,15 @@ pub enum FormError {
     /// There was an internal error
     Internal,
 
+    /// The WebAuthn ceremony failed
+    WebauthnFailed,
+
+    /// The link the user followed is invalid or expired
+    InvalidLink,
+
+    /// Too many failed attempts, the user has to wait before trying again
+    TooManyAttempts,
+
     /// Denied by the policy
     Policy {
         /// Message for this policy violation
@@ -24,6 +24,7 @@ pub fn register(tera: &mut Tera) {
     tera.register_function("add_params_to_uri", function_add_params_to_uri);
     tera.register_function("merge", function_merge);
     tera.register_function("dict", function_dict);
+    tera.register_filter("device_name", filter_device_name);
 }
 
 fn tester_empty(value: Option<&Value>, params: &[Value]) -> Result<bool, tera::Error> {
@@ -115,3 +116,53 @@ fn function_dict(params: &HashMap<String, Value>) -> Result<Value, tera::Error>
     let ret = params.clone().into_iter().collect();
     Ok(Value::Object(ret))
 }
+
+/// Turn a user agent into a short description like "Firefox on Linux"
+#[allow(clippy::unnecessary_wraps)]
+fn filter_device_name(value: &Value, _args: &HashMap<String, Value>) -> Result<Value, tera::Error> {
+    let user_agent = if let Some(user_agent) = value.as_str() {
+        user_agent
+    } else {
+        return Ok(Value::String("Unknown device".to_owned()));
+    };
+
+    // Order matters, as most browsers also claim to be the ones before them
+    let browser = [
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("FxiOS/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("CriOS/", "Chrome"),
+        ("Safari/", "Safari"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+
+    let os = [
+        ("Windows", "Windows"),
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iPadOS"),
+        ("Mac OS X", "macOS"),
+        ("CrOS", "ChromeOS"),
+        ("Linux", "Linux"),
+    ]
+    .into_iter()
+    .find(|(token, _)| user_agent.contains(token))
+    .map(|(_, name)| name);
+
+    let name = match (browser, os) {
+        (Some(browser), Some(os)) => format!("{} on {}", browser, os),
+        (Some(name), None) | (None, Some(name)) => name.to_owned(),
+        // Use the product name of unknown clients, e.g. "curl"
+        (None, None) => user_agent
+            .split(&['/', ' '][..])
+            .next()
+            .unwrap_or(user_agent)
+            .to_owned(),
+    };
+
+    Ok(Value::String(name))
+}
@@ -39,19 +39,28 @@ use tracing::{debug, info, warn};
 mod context;
 mod forms;
 mod functions;
+mod mapping;
 
 #[macro_use]
 mod macros;
 
 pub use self::{
     context::{
-        AccountContext, AccountEmailsContext, CompatSsoContext, ConsentContext, EmailAddContext,
+        AccountContext, AccountEmailsContext, AccountPasswordContext, AccountPasswordFormField,
+        AccountSessionsContext, AccountTotpContext, AccountWebauthnContext,
+        AccountWebauthnFormField, CibaRequestEmailContext, CompatSsoContext, ConsentContext,
+        EmailAddContext, EmailLoginContext, EmailLoginEmailContext, EmailLoginFormField,
         EmailVerificationContext, EmailVerificationPageContext, EmptyContext, ErrorContext,
-        FormPostContext, IndexContext, LoginContext, LoginFormField, PostAuthContext,
-        ReauthContext, ReauthFormField, RegisterContext, RegisterFormField, TemplateContext,
-        WithCsrf, WithOptionalSession, WithSession,
+        FormPostContext, IndexContext, LoginContext, LoginFormField, PasswordForgotContext,
+        PasswordForgotFormField, PasswordResetContext, PasswordResetEmailContext,
+        PasswordResetFormField, PostAuthContext, ReauthContext, ReauthFormField, RegisterContext,
+        RegisterFormField, SecondFactorContext, SecondFactorFormField, SelectAccountContext,
+        TemplateContext, TotpEnrollment, UpstreamProviderLink, UpstreamRegisterContext,
+        UpstreamRegisterFormField, WebauthnCredentialInfo, WithCsrf, WithOptionalSession,
+        WithSession,
     },
     forms::{FieldError, FormError, FormField, FormState, ToFormState},
+    mapping::{render_claims_mapping, ClaimsMappingError},
 };
 
 /// Wrapper around [`tera::Tera`] helping rendering the various templates
@@ -287,15 +296,28 @@ register_templates! {
         "components/logout.html",
         "components/navbar.html",
         "components/errors.html",
+        "components/webauthn.html",
         "base.html",
     };
 
     /// Render the login page
     pub fn render_login(WithCsrf<LoginContext>) { "pages/login.html" }
 
+    /// Render the page used to log in with a code sent by email
+    pub fn render_email_login(WithCsrf<EmailLoginContext>) { "pages/email_login.html" }
+
     /// Render the registration page
     pub fn render_register(WithCsrf<RegisterContext>) { "pages/register.html" }
 
+    /// Render the page where users coming from an upstream provider pick a username
+    pub fn render_upstream_register(WithCsrf<UpstreamRegisterContext>) { "pages/upstream_register.html" }
+
+    /// Render the form used to ask for a password reset link
+    pub fn render_password_forgot(WithCsrf<PasswordForgotContext>) { "pages/password/forgot.html" }
+
+    /// Render the form used to set a new password from a reset link
+    pub fn render_password_reset(WithCsrf<PasswordResetContext>) { "pages/password/reset.html" }
+
     /// Render the client consent page
     pub fn render_consent(WithCsrf<WithSession<ConsentContext>>) { "pages/consent.html" }
 
@@ -309,7 +331,16 @@ register_templates! {
     pub fn render_account_index(WithCsrf<WithSession<AccountContext>>) { "pages/account/index.html" }
 
     /// Render the password change page
-    pub fn render_account_password(WithCsrf<WithSession<EmptyContext>>) { "pages/account/password.html" }
+    pub fn render_account_password(WithCsrf<WithSession<AccountPasswordContext>>) { "pages/account/password.html" }
+
+    /// Render the TOTP authenticator management page
+    pub fn render_account_totp(WithCsrf<WithSession<AccountTotpContext>>) { "pages/account/totp.html" }
+
+    /// Render the WebAuthn credential management page
+    pub fn render_account_webauthn(WithCsrf<WithSession<AccountWebauthnContext>>) { "pages/account/webauthn.html" }
+
+    /// Render the browser sessions management page
+    pub fn render_account_sessions<T: StorageBackend>(WithCsrf<WithSession<AccountSessionsContext<T>>>) { "pages/account/sessions.html" }
 
     /// Render the emails management
     pub fn render_account_emails<T: StorageBackend>(WithCsrf<WithSession<AccountEmailsContext<T>>>) { "pages/account/emails/index.html" }
@@ -323,6 +354,12 @@ register_templates! {
     /// Render the re-authentication form
     pub fn render_reauth(WithCsrf<WithSession<ReauthContext>>) { "pages/reauth.html" }
 
+    /// Render the second factor step of the login and reauthentication flows
+    pub fn render_second_factor(WithCsrf<SecondFactorContext>) { "pages/second_factor.html" }
+
+    /// Render the account picker
+    pub fn render_select_account<T: StorageBackend>(WithCsrf<SelectAccountContext<T>>) { "pages/select_account.html" }
+
     /// Render the form used by the form_post response mode
     pub fn render_form_post<T: Serialize>(FormPostContext<T>) { "form_post.html" }
 
@@ -337,6 +374,33 @@ register_templates! {
 
     /// Render the email verification subject
     pub fn render_email_verification_subject(EmailVerificationContext) { "emails/verification.subject" }
+
+    /// Render the backchannel authentication request email (plain text variant)
+    pub fn render_email_ciba_txt(CibaRequestEmailContext) { "emails/ciba.txt" }
+
+    /// Render the backchannel authentication request email (HTML text variant)
+    pub fn render_email_ciba_html(CibaRequestEmailContext) { "emails/ciba.html" }
+
+    /// Render the backchannel authentication request email subject
+    pub fn render_email_ciba_subject(CibaRequestEmailContext) { "emails/ciba.subject" }
+
+    /// Render the login email (plain text variant)
+    pub fn render_email_login_txt(EmailLoginEmailContext) { "emails/login.txt" }
+
+    /// Render the login email (HTML text variant)
+    pub fn render_email_login_html(EmailLoginEmailContext) { "emails/login.html" }
+
+    /// Render the login email subject
+    pub fn render_email_login_subject(EmailLoginEmailContext) { "emails/login.subject" }
+
+    /// Render the password reset email (plain text variant)
+    pub fn render_email_password_reset_txt(PasswordResetEmailContext) { "emails/password_reset.txt" }
+
+    /// Render the password reset email (HTML text variant)
+    pub fn render_email_password_reset_html(PasswordResetEmailContext) { "emails/password_reset.html" }
+
+    /// Render the password reset email subject
+    pub fn render_email_password_reset_subject(PasswordResetEmailContext) { "emails/password_reset.subject" }
 }
 
 impl Templates {
@@ -344,21 +408,39 @@ impl Templates {
     /// properly
     pub async fn check_render(&self) -> anyhow::Result<()> {
         check::render_login(self).await?;
+        check::render_email_login(self).await?;
         check::render_register(self).await?;
+        check::render_upstream_register(self).await?;
+        check::render_password_forgot(self).await?;
+        check::render_password_reset(self).await?;
         check::render_consent(self).await?;
         check::render_sso_login(self).await?;
         check::render_index(self).await?;
         check::render_account_index(self).await?;
         check::render_account_password(self).await?;
+        check::render_account_totp(self).await?;
+        check::render_account_webauthn(self).await?;
+        check::render_account_sessions::<()>(self).await?;
         check::render_account_emails::<()>(self).await?;
         check::render_account_add_email(self).await?;
         check::render_account_verify_email(self).await?;
         check::render_reauth(self).await?;
+        check::render_second_factor(self).await?;
+        check::render_select_account::<()>(self).await?;
         check::render_form_post::<EmptyContext>(self).await?;
         check::render_error(self).await?;
         check::render_email_verification_txt(self).await?;
         check::render_email_verification_html(self).await?;
         check::render_email_verification_subject(self).await?;
+        check::render_email_ciba_txt(self).await?;
+        check::render_email_ciba_html(self).await?;
+        check::render_email_ciba_subject(self).await?;
+        check::render_email_login_txt(self).await?;
+        check::render_email_login_html(self).await?;
+        check::render_email_login_subject(self).await?;
+        check::render_email_password_reset_txt(self).await?;
+        check::render_email_password_reset_html(self).await?;
+        check::render_email_password_reset_subject(self).await?;
         Ok(())
     }
 }
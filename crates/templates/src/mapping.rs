@@ -0,0 +1,77 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Render the templates mapping upstream claims to local user attributes
+
+use serde::Serialize;
+use tera::{Context, Error as TeraError, Tera};
+use thiserror::Error;
+
+/// Failed to render a claims mapping template
+#[derive(Error, Debug)]
+#[error("could not render claims mapping template")]
+pub struct ClaimsMappingError(#[from] TeraError);
+
+/// Render a claims mapping template, like `{{ user.preferred_username }}`.
+///
+/// The claims are available under the `user` variable. Returns `None` if the
+/// template renders to an empty string.
+pub fn render_claims_mapping<T: Serialize>(
+    template: &str,
+    claims: &T,
+) -> Result<Option<String>, ClaimsMappingError> {
+    let mut tera = Tera::default();
+    crate::functions::register(&mut tera);
+
+    let mut context = Context::new();
+    context.insert("user", claims);
+
+    let rendered = tera.render_str(template, &context)?;
+    let rendered = rendered.trim();
+    if rendered.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(rendered.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn render_mapping() {
+        let claims = json!({
+            "preferred_username": "john",
+            "name": "John Doe",
+        });
+
+        assert_eq!(
+            render_claims_mapping("{{ user.preferred_username }}", &claims).unwrap(),
+            Some("john".to_owned())
+        );
+        assert_eq!(
+            render_claims_mapping(
+                "{{ user.name | lower | replace(from=' ', to='.') }}",
+                &claims
+            )
+            .unwrap(),
+            Some("john.doe".to_owned())
+        );
+        assert_eq!(render_claims_mapping("", &claims).unwrap(), None);
+        assert!(render_claims_mapping("{{ user.email }}", &claims).is_err());
+    }
+}